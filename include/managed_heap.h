@@ -0,0 +1,63 @@
+/* The C interface of the managed-heap crate (built with the "ffi" feature).
+ * Addresses are plain integers and 0 is never a valid address. */
+#ifndef MANAGED_HEAP_H
+#define MANAGED_HEAP_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The call succeeded */
+#define MANAGED_HEAP_OK 0
+/* A required pointer argument was null */
+#define MANAGED_HEAP_NULL -1
+/* The address doesn't belong to a used block or the word lies outside of it */
+#define MANAGED_HEAP_INVALID_ADDRESS -2
+/* The heap panicked, it should be destroyed */
+#define MANAGED_HEAP_PANIC -3
+
+typedef struct ManagedHeap ManagedHeap;
+
+/* Called with every child of an object during a collection */
+typedef void (*managed_heap_mark_fn)(void *context, uintptr_t child);
+
+/* Called once for every live object during a collection. It has to call mark
+ * with context and every address the object references. */
+typedef void (*managed_heap_trace_fn)(uintptr_t address, managed_heap_mark_fn mark,
+                                      void *context, void *userdata);
+
+/* Creates a heap of size bytes. Returns NULL if the size is invalid. */
+ManagedHeap *managed_heap_new(size_t size);
+
+/* Destroys a heap created by managed_heap_new. NULL is ignored. */
+void managed_heap_destroy(ManagedHeap *heap);
+
+/* Allocates size words and returns their address or 0 on failure. */
+uintptr_t managed_heap_alloc(ManagedHeap *heap, size_t size);
+
+/* Frees the object at address, which has to be returned by managed_heap_alloc. */
+int32_t managed_heap_free(ManagedHeap *heap, uintptr_t address);
+
+/* Stores the word at offset (in words) inside the object at address in value. */
+int32_t managed_heap_read(const ManagedHeap *heap, uintptr_t address, size_t offset,
+                          uintptr_t *value);
+
+/* Writes value to offset (in words) inside the object at address. */
+int32_t managed_heap_write(ManagedHeap *heap, uintptr_t address, size_t offset,
+                           uintptr_t value);
+
+/* Collects every object, which is not reachable from the roots_len addresses
+ * at roots. trace may be NULL for objects without references. The number of
+ * freed blocks is stored in freed_blocks, unless it is NULL. */
+int32_t managed_heap_gc_addresses(ManagedHeap *heap, const uintptr_t *roots,
+                                  size_t roots_len, managed_heap_trace_fn trace,
+                                  void *userdata, size_t *freed_blocks);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MANAGED_HEAP_H */
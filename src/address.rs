@@ -1,9 +1,14 @@
 use crate::block::header::BlockHeader;
 use crate::block::Block;
-use core::ptr::NonNull;
+use crate::types::WORD_SIZE;
+use core::ptr::{self, NonNull};
+use std::fmt;
 use std::ops::{Add, Deref};
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq)]
+#[repr(transparent)]
 pub struct Address {
     ptr: usize,
 }
@@ -22,6 +27,36 @@ impl Address {
     }
 }
 
+impl Address {
+    /// The address with the raw value ptr, e.g. a reference which was read
+    /// from a heap word. Returns None for 0 and values which are not word
+    /// aligned, which can't be the address of an object.
+    pub fn from_raw(ptr: usize) -> Option<Address> {
+        if ptr == 0 || !ptr.is_multiple_of(WORD_SIZE) {
+            None
+        } else {
+            Some(Address { ptr })
+        }
+    }
+
+    /// The raw value of the address (the inverse of from_raw)
+    pub fn into_raw(self) -> usize {
+        self.ptr
+    }
+
+    /// Turns an optional reference into a word, which can be stored in the
+    /// heap. None is stored as 0.
+    pub fn encode_option(address: Option<Address>) -> usize {
+        address.map_or(0, Address::into_raw)
+    }
+
+    /// The inverse of encode_option. Words, which can't be an address, are
+    /// decoded as None.
+    pub fn decode_option(word: usize) -> Option<Address> {
+        Address::from_raw(word)
+    }
+}
+
 impl Address {
     #[inline]
     pub fn as_mut(&mut self) -> *mut usize {
@@ -34,6 +69,292 @@ impl Address {
             *ptr = value;
         }
     }
+
+    /// Interprets the word self points to as an Address.
+    /// This is useful for implementing Traceable::trace for objects which
+    /// store references to other objects.
+    ///
+    /// # Safety
+    /// The word has to stay valid for the lifetime 'a and must not be
+    /// accessed in any other way during that time.
+    pub unsafe fn as_address_mut<'a>(mut self) -> &'a mut Address {
+        &mut *(self.as_mut() as *mut Address)
+    }
+
+    /// Stores tag in the low bits of self, which are always 0 for word
+    /// aligned addresses.
+    /// Panics if tag doesn't fit into TaggedAddress::TAG_BITS bits or self
+    /// is not word aligned.
+    pub fn with_tag(self, tag: usize) -> TaggedAddress {
+        assert!(
+            tag <= TaggedAddress::TAG_MASK,
+            "Tag {} doesn't fit into {} bits",
+            tag,
+            TaggedAddress::TAG_BITS
+        );
+        assert_eq!(0, self.ptr & TaggedAddress::TAG_MASK, "Unaligned address");
+        TaggedAddress {
+            bits: self.ptr | tag,
+        }
+    }
+
+    /// Copies words values from self to dst.
+    /// The source and destination ranges must not overlap, otherwise the
+    /// result is undefined behaviour. Use copy_to_overlapping in that case.
+    pub fn copy_to(&self, dst: &mut Address, words: usize) {
+        unsafe {
+            ptr::copy_nonoverlapping(self.ptr as *const usize, dst.as_mut(), words);
+        }
+    }
+
+    /// Copies words values from self to dst.
+    /// The ranges may overlap, in which case the copy behaves as if the
+    /// source was first copied into a temporary buffer.
+    pub fn copy_to_overlapping(&self, dst: &mut Address, words: usize) {
+        dst.range(words).copy_from(&self.range(words));
+    }
+
+    /// The len_words words starting at self
+    pub fn range(self, len_words: usize) -> AddressRange {
+        AddressRange {
+            start: self,
+            len_words,
+        }
+    }
+}
+
+/// A span of words, e.g. the fields of an object.
+/// Like an Address, a range is not checked, ManagedHeap::payload_range and
+/// ManagedHeap::checked_range create ranges inside a single block.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AddressRange {
+    pub start: Address,
+    pub len_words: usize,
+}
+
+impl AddressRange {
+    /// The address after the last word
+    pub fn end(&self) -> Address {
+        self.start + self.len_words
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len_words == 0
+    }
+
+    /// Checks if address points into one of the words
+    pub fn contains(&self, address: Address) -> bool {
+        self.start <= address && address < self.end()
+    }
+
+    /// The addresses of the words in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = Address> {
+        let start = self.start;
+        (0..self.len_words).map(move |i| start + i)
+    }
+
+    /// Writes value into every word
+    pub fn fill(&mut self, value: usize) {
+        unsafe { slice::from_raw_parts_mut(self.start.as_mut(), self.len_words).fill(value) };
+    }
+
+    /// Copies the words of src to the start of self.
+    /// The ranges may overlap, in which case the copy behaves as if the
+    /// source was first copied into a temporary buffer.
+    /// Panics if src is longer than self.
+    pub fn copy_from(&mut self, src: &AddressRange) {
+        assert!(
+            src.len_words <= self.len_words,
+            "Can't copy {} words into {} words",
+            src.len_words,
+            self.len_words
+        );
+        unsafe {
+            ptr::copy(
+                src.start.ptr as *const usize,
+                self.start.as_mut(),
+                src.len_words,
+            );
+        }
+    }
+}
+
+/// Typed accessors for values which don't fit into a usize on every platform.
+/// 64 bit values use U64_WORDS words. On 32 bit platforms the lower half is
+/// stored in the first word and the upper half in the second one.
+/// All offsets are in words.
+impl Address {
+    #[cfg(target_pointer_width = "64")]
+    fn read_u64_at(&self, offset: usize) -> u64 {
+        *(*self + offset) as u64
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    fn read_u64_at(&self, offset: usize) -> u64 {
+        let low = *(*self + offset) as u64;
+        let high = *(*self + offset + 1) as u64;
+        low | (high << 32)
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    fn write_u64_at(&mut self, offset: usize, value: u64) {
+        (*self + offset).write(value as usize);
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    fn write_u64_at(&mut self, offset: usize, value: u64) {
+        (*self + offset).write(value as usize);
+        (*self + offset + 1).write((value >> 32) as usize);
+    }
+
+    pub fn read_f64(&self) -> f64 {
+        self.read_f64_at(0)
+    }
+
+    pub fn read_f64_at(&self, offset: usize) -> f64 {
+        f64::from_bits(self.read_u64_at(offset))
+    }
+
+    /// Writes the bit pattern of value, so NaN payloads are preserved
+    pub fn write_f64(&mut self, value: f64) {
+        self.write_f64_at(0, value);
+    }
+
+    pub fn write_f64_at(&mut self, offset: usize, value: f64) {
+        self.write_u64_at(offset, value.to_bits());
+    }
+
+    pub fn read_i64(&self) -> i64 {
+        self.read_i64_at(0)
+    }
+
+    pub fn read_i64_at(&self, offset: usize) -> i64 {
+        self.read_u64_at(offset) as i64
+    }
+
+    pub fn write_i64(&mut self, value: i64) {
+        self.write_i64_at(0, value);
+    }
+
+    pub fn write_i64_at(&mut self, offset: usize, value: i64) {
+        self.write_u64_at(offset, value as u64);
+    }
+
+    pub fn read_u32_pair(&self) -> (u32, u32) {
+        self.read_u32_pair_at(0)
+    }
+
+    pub fn read_u32_pair_at(&self, offset: usize) -> (u32, u32) {
+        let value = self.read_u64_at(offset);
+        (value as u32, (value >> 32) as u32)
+    }
+
+    pub fn write_u32_pair(&mut self, value: (u32, u32)) {
+        self.write_u32_pair_at(0, value);
+    }
+
+    pub fn write_u32_pair_at(&mut self, offset: usize, (first, second): (u32, u32)) {
+        self.write_u64_at(offset, u64::from(first) | (u64::from(second) << 32));
+    }
+}
+
+/// Byte granular accessors. Byte i is stored in the word i / WORD_SIZE at
+/// the bits 8 * (i % WORD_SIZE) (see pack_bytes), so the lanes of a word are
+/// little endian on every host and the word values of a heap image don't
+/// depend on the host. Bigger values are stored little endian as well and
+/// may span two words. All offsets are in bytes.
+impl Address {
+    pub fn read_u8(&self, byte_offset: usize) -> u8 {
+        let (word, shift) = byte_lane(byte_offset);
+        (*(*self + word) >> shift) as u8
+    }
+
+    pub fn write_u8(&mut self, byte_offset: usize, value: u8) {
+        let (word, shift) = byte_lane(byte_offset);
+        let mut address = *self + word;
+        let others = *address & !(0xFF << shift);
+        address.write(others | (value as usize) << shift);
+    }
+
+    pub fn read_u16(&self, byte_offset: usize) -> u16 {
+        u16::from_le_bytes(self.read_byte_array(byte_offset))
+    }
+
+    pub fn write_u16(&mut self, byte_offset: usize, value: u16) {
+        self.write_byte_array(byte_offset, value.to_le_bytes());
+    }
+
+    pub fn read_u32(&self, byte_offset: usize) -> u32 {
+        u32::from_le_bytes(self.read_byte_array(byte_offset))
+    }
+
+    pub fn write_u32(&mut self, byte_offset: usize, value: u32) {
+        self.write_byte_array(byte_offset, value.to_le_bytes());
+    }
+
+    fn read_byte_array<const N: usize>(&self, byte_offset: usize) -> [u8; N] {
+        let mut bytes = [0; N];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.read_u8(byte_offset + i);
+        }
+        bytes
+    }
+
+    fn write_byte_array<const N: usize>(&mut self, byte_offset: usize, bytes: [u8; N]) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.write_u8(byte_offset + i, byte);
+        }
+    }
+}
+
+/// The word value, which contains bytes in the layout of the byte accessors
+/// (see Address::read_u8)
+pub fn pack_bytes(bytes: [u8; WORD_SIZE]) -> usize {
+    usize::from_le_bytes(bytes)
+}
+
+/// The word index and the bit shift of a byte
+fn byte_lane(byte_offset: usize) -> (usize, usize) {
+    (byte_offset / WORD_SIZE, byte_offset % WORD_SIZE * 8)
+}
+
+/// Atomic accessors for words, which are shared between threads, e.g. the
+/// slots of a lock-free queue.
+/// The heap has to outlive every thread which uses them and it must not
+/// free or move the object in the meantime. Mixing atomic and plain accesses
+/// (write, deref, copy_to) of the same word is a data race, unless the caller
+/// synchronizes them in another way.
+impl Address {
+    fn atomic(&self) -> &AtomicUsize {
+        // Addresses are always word aligned
+        unsafe { AtomicUsize::from_ptr(self.ptr as *mut usize) }
+    }
+
+    pub fn load_atomic(&self, order: Ordering) -> usize {
+        self.atomic().load(order)
+    }
+
+    pub fn store_atomic(&self, value: usize, order: Ordering) {
+        self.atomic().store(value, order)
+    }
+
+    /// Stores new if the word is current. Returns the previous value, which
+    /// is Ok if it was current.
+    pub fn compare_exchange(
+        &self,
+        current: usize,
+        new: usize,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<usize, usize> {
+        self.atomic()
+            .compare_exchange(current, new, success, failure)
+    }
+
+    /// Adds value (wrapping on overflow) and returns the previous value
+    pub fn fetch_add(&self, value: usize, order: Ordering) -> usize {
+        self.atomic().fetch_add(value, order)
+    }
 }
 
 impl From<Block> for Address {
@@ -43,21 +364,25 @@ impl From<Block> for Address {
     }
 }
 
-impl Into<Block> for Address {
-    fn into(self) -> Block {
+impl From<Address> for Block {
+    fn from(value: Address) -> Block {
         unsafe {
-            let ptr = (self.ptr as *mut usize).offset(-1) as *mut BlockHeader;
+            let ptr = (value.ptr as *mut usize).offset(-1) as *mut BlockHeader;
             Block::from(ptr)
         }
     }
 }
 
-impl Into<usize> for Address {
-    fn into(self) -> usize {
-        self.ptr
+impl From<Address> for usize {
+    fn from(value: Address) -> usize {
+        value.ptr
     }
 }
 
+/// Deprecated, because any integer (including 0) becomes an Address, which
+/// may get dereferenced later. Use Address::from_raw or Address::decode_option
+/// instead, this impl will be removed in the next release.
+/// (#[deprecated] has no effect on trait impls, so there is no warning.)
 impl From<usize> for Address {
     fn from(value: usize) -> Address {
         Address { ptr: value }
@@ -83,13 +408,398 @@ impl Deref for Address {
     }
 }
 
+/// An Address with a tag in its low bits (see Address::with_tag), e.g. to
+/// tell small integers and references apart.
+/// It can't be dereferenced or written through, it has to be untagged first:
+/// ```compile_fail
+/// use managed_heap::address::Address;
+///
+/// let mut word = 0usize;
+/// let tagged = Address::from(&mut word as *mut usize as usize).with_tag(1);
+/// let value = *tagged;
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct TaggedAddress {
+    bits: usize,
+}
+
+impl TaggedAddress {
+    /// The number of low bits, which are available for tags
+    pub const TAG_BITS: u32 = WORD_SIZE.trailing_zeros();
+
+    /// The bits of the tag
+    pub const TAG_MASK: usize = (1 << Self::TAG_BITS) - 1;
+
+    pub fn tag(self) -> usize {
+        self.bits & Self::TAG_MASK
+    }
+
+    /// The address without its tag
+    pub fn untagged(self) -> Address {
+        Address::from(self.bits & !Self::TAG_MASK)
+    }
+
+    /// Checks if the tag is 0, the tag of references into the heap
+    pub fn is_heap_ref(self) -> bool {
+        self.has_tag(0)
+    }
+
+    /// Checks for a tag other than 0, for VMs which tag their references
+    pub fn has_tag(self, tag: usize) -> bool {
+        self.tag() == tag
+    }
+}
+
+/// The raw bits, including the tag
+impl From<TaggedAddress> for usize {
+    fn from(value: TaggedAddress) -> usize {
+        value.bits
+    }
+}
+
+/// Interprets raw bits (e.g. a field of an object) as a TaggedAddress
+impl From<usize> for TaggedAddress {
+    fn from(bits: usize) -> TaggedAddress {
+        TaggedAddress { bits }
+    }
+}
+
+/// An address, which is printed as its offset in words from the start of its
+/// heap ("heap+{offset}"), so the output doesn't change between runs.
+/// Addresses outside of the heap are printed as raw pointers.
+/// Created by ManagedHeap::debug_address.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct RelativeAddress {
+    pub(crate) address: Address,
+    pub(crate) offset: Option<usize>,
+}
+
+impl fmt::Debug for RelativeAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for RelativeAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "heap+{}", offset),
+            None => write!(f, "{:#x}", self.address.ptr),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::managed::ManagedHeap;
+    use crate::types::U64_WORDS;
     use std::mem;
+    use std::thread;
 
     #[test]
     fn test_address_has_same_size_as_usize() {
         assert_eq!(mem::size_of::<usize>(), mem::size_of::<Address>());
     }
+
+    fn address_of(buffer: &mut [usize]) -> Address {
+        Address::from_raw(buffer.as_mut_ptr() as usize).unwrap()
+    }
+
+    #[test]
+    fn test_from_raw_rejects_null_and_misaligned_values() {
+        assert_eq!(None, Address::from_raw(0));
+        assert_eq!(None, Address::from_raw(WORD_SIZE + 1));
+        assert_eq!(None, Address::from_raw(WORD_SIZE / 2));
+
+        let address = Address::from_raw(4 * WORD_SIZE).unwrap();
+        assert_eq!(4 * WORD_SIZE, address.into_raw());
+    }
+
+    #[test]
+    fn test_option_round_trip() {
+        let mut buffer = [0usize; 2];
+        let address = address_of(&mut buffer);
+
+        assert_eq!(0, Address::encode_option(None));
+        assert_eq!(None, Address::decode_option(0));
+        let word = Address::encode_option(Some(address));
+        assert_eq!(Some(address), Address::decode_option(word));
+        assert_eq!(None, Address::decode_option(word + 1));
+    }
+
+    #[test]
+    fn test_tag_bits_match_word_alignment() {
+        assert_eq!(WORD_SIZE, 1 << TaggedAddress::TAG_BITS);
+        assert_eq!(WORD_SIZE - 1, TaggedAddress::TAG_MASK);
+    }
+
+    #[test]
+    fn test_all_tags_round_trip() {
+        let mut buffer = [42, 0];
+        let address = address_of(&mut buffer);
+
+        for tag in 0..=TaggedAddress::TAG_MASK {
+            let tagged = address.with_tag(tag);
+            assert_eq!(tag, tagged.tag());
+            assert_eq!(address, tagged.untagged());
+            assert_eq!(42, *tagged.untagged());
+            assert_eq!(tag == 0, tagged.is_heap_ref());
+            assert!(tagged.has_tag(tag));
+
+            let bits = usize::from(tagged);
+            assert_eq!(usize::from(address) | tag, bits);
+            assert_eq!(tagged, TaggedAddress::from(bits));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't fit into")]
+    fn test_with_tag_rejects_too_large_tags() {
+        let mut buffer = [0];
+        address_of(&mut buffer).with_tag(WORD_SIZE);
+    }
+
+    #[test]
+    fn test_copy_to_overlapping_forward() {
+        let mut buffer = [0, 1, 2, 3, 4, 5];
+        let src = address_of(&mut buffer);
+        let mut dst = src + 2;
+
+        src.copy_to_overlapping(&mut dst, 4);
+        assert_eq!([0, 1, 0, 1, 2, 3], buffer);
+    }
+
+    #[test]
+    fn test_copy_to_overlapping_backward() {
+        let mut buffer = [0, 1, 2, 3, 4, 5];
+        let mut dst = address_of(&mut buffer);
+        let src = dst + 2;
+
+        src.copy_to_overlapping(&mut dst, 4);
+        assert_eq!([2, 3, 4, 5, 4, 5], buffer);
+    }
+
+    #[test]
+    fn test_fill_and_iterate_range() {
+        let mut buffer = [0; 6];
+        let start = address_of(&mut buffer) + 1;
+        let mut range = start.range(3);
+
+        range.fill(7);
+        assert_eq!([0, 7, 7, 7, 0, 0], buffer);
+        let words: Vec<usize> = range.iter().map(|word| *word).collect();
+        assert_eq!(vec![7, 7, 7], words);
+
+        assert!(range.contains(start + 2));
+        assert!(!range.contains(range.end()));
+        assert!(start.range(0).is_empty());
+    }
+
+    #[test]
+    fn test_range_copy_from_overlapping() {
+        let mut buffer = [0, 1, 2, 3, 4, 5];
+        let start = address_of(&mut buffer);
+
+        (start + 1).range(5).copy_from(&start.range(3));
+        assert_eq!([0, 0, 1, 2, 4, 5], buffer);
+        start.range(3).copy_from(&(start + 3).range(3));
+        assert_eq!([2, 4, 5, 2, 4, 5], buffer);
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't copy 3 words into 2 words")]
+    fn test_range_copy_from_longer_range_panics() {
+        let mut buffer = [0; 4];
+        let start = address_of(&mut buffer);
+        start.range(2).copy_from(&(start + 1).range(3));
+    }
+
+    #[test]
+    fn test_f64_round_trip_keeps_nan_payload() {
+        let mut buffer = [0; 2 * U64_WORDS];
+        let mut address = address_of(&mut buffer);
+
+        let nan = f64::from_bits(0x7FF8_0000_DEAD_BEEF);
+        address.write_f64(nan);
+        address.write_f64_at(U64_WORDS, -0.5);
+
+        assert!(address.read_f64().is_nan());
+        assert_eq!(0x7FF8_0000_DEAD_BEEF, address.read_f64().to_bits());
+        assert_eq!(-0.5, address.read_f64_at(U64_WORDS));
+
+        let signaling = f64::from_bits(0x7FF0_0000_0000_0001);
+        address.write_f64_at(U64_WORDS, signaling);
+        assert_eq!(
+            signaling.to_bits(),
+            address.read_f64_at(U64_WORDS).to_bits()
+        );
+    }
+
+    #[test]
+    fn test_i64_round_trip_extremes() {
+        let mut buffer = [0; 3 * U64_WORDS];
+        let mut address = address_of(&mut buffer);
+
+        address.write_i64(i64::MIN);
+        address.write_i64_at(U64_WORDS, -1);
+        address.write_i64_at(2 * U64_WORDS, i64::MAX);
+
+        assert_eq!(i64::MIN, address.read_i64());
+        assert_eq!(-1, address.read_i64_at(U64_WORDS));
+        assert_eq!(i64::MAX, address.read_i64_at(2 * U64_WORDS));
+    }
+
+    #[test]
+    fn test_u32_pair_round_trip() {
+        let mut buffer = [0; 2 * U64_WORDS];
+        let mut address = address_of(&mut buffer);
+
+        address.write_u32_pair((u32::MAX, 0));
+        address.write_u32_pair_at(U64_WORDS, (1, u32::MAX - 1));
+
+        assert_eq!((u32::MAX, 0), address.read_u32_pair());
+        assert_eq!((1, u32::MAX - 1), address.read_u32_pair_at(U64_WORDS));
+    }
+
+    #[test]
+    fn test_bytes_round_trip_across_word_boundaries() {
+        let mut buffer = [0; 3];
+        let mut address = address_of(&mut buffer);
+
+        for i in 0..3 * WORD_SIZE {
+            address.write_u8(i, i as u8 + 1);
+        }
+        for i in 0..3 * WORD_SIZE {
+            assert_eq!(i as u8 + 1, address.read_u8(i));
+        }
+
+        // the values span two words
+        address.write_u16(WORD_SIZE - 1, 0xBEEF);
+        address.write_u32(2 * WORD_SIZE - 2, 0xDEAD_C0DE);
+        assert_eq!(0xBEEF, address.read_u16(WORD_SIZE - 1));
+        assert_eq!(0xDEAD_C0DE, address.read_u32(2 * WORD_SIZE - 2));
+        assert_eq!(0xEF, address.read_u8(WORD_SIZE - 1));
+        assert_eq!(0xBE, address.read_u8(WORD_SIZE));
+        assert_eq!(0xDE, address.read_u8(2 * WORD_SIZE + 1));
+
+        // the last byte doesn't touch the neighbours
+        address.write_u8(3 * WORD_SIZE - 1, 0xFF);
+        assert_eq!(0xFF, address.read_u8(3 * WORD_SIZE - 1));
+        assert_eq!(3 * WORD_SIZE as u8 - 1, address.read_u8(3 * WORD_SIZE - 2));
+    }
+
+    #[test]
+    fn test_bytes_are_packed_little_endian_into_words() {
+        let mut buffer = [usize::MAX; 2];
+        let mut address = address_of(&mut buffer);
+
+        let mut bytes = [0; WORD_SIZE];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = 0x10 + i as u8;
+            address.write_u8(i, *byte);
+        }
+        assert_eq!(pack_bytes(bytes), buffer[0]);
+        assert_eq!(usize::MAX, buffer[1]);
+
+        // the first byte is always the least significant one, on big endian
+        // hosts as well
+        assert_eq!(0x10, pack_bytes(bytes) & 0xFF);
+        assert_eq!(0x1110, pack_bytes(bytes) as u16);
+
+        let mut address = address_of(&mut buffer);
+        address.write_u32(0, 0x0403_0201);
+        assert_eq!(0x0403_0201, buffer[0] as u32);
+    }
+
+    #[test]
+    fn test_64_bit_values_use_u64_words() {
+        let mut buffer = [usize::MAX; U64_WORDS + 1];
+        let mut address = address_of(&mut buffer);
+
+        address.write_i64(0);
+        assert_eq!(usize::MAX, buffer[U64_WORDS]);
+        assert!(buffer[..U64_WORDS].iter().all(|&w| w == 0));
+
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(2, buffer.len());
+        #[cfg(target_pointer_width = "32")]
+        assert_eq!(3, buffer.len());
+    }
+
+    #[test]
+    fn test_copy_to() {
+        let mut src_buffer = [1, 2, 3];
+        let mut dst_buffer = [0; 4];
+        let src = address_of(&mut src_buffer);
+        let mut dst = address_of(&mut dst_buffer) + 1;
+
+        src.copy_to(&mut dst, 3);
+        assert_eq!([0, 1, 2, 3], dst_buffer);
+    }
+
+    #[test]
+    fn test_atomic_operations_with_all_orderings() {
+        let mut buffer = [0];
+        let address = address_of(&mut buffer);
+
+        for &order in &[Ordering::Relaxed, Ordering::Release, Ordering::SeqCst] {
+            address.store_atomic(1, order);
+        }
+        for &order in &[Ordering::Relaxed, Ordering::Acquire, Ordering::SeqCst] {
+            assert_eq!(1, address.load_atomic(order));
+        }
+        for &order in &[
+            Ordering::Relaxed,
+            Ordering::Acquire,
+            Ordering::Release,
+            Ordering::AcqRel,
+            Ordering::SeqCst,
+        ] {
+            let previous = address.fetch_add(1, order);
+            assert_eq!(
+                Ok(previous + 1),
+                address.compare_exchange(previous + 1, previous + 1, order, Ordering::Relaxed)
+            );
+        }
+
+        assert_eq!(6, *address);
+        assert_eq!(
+            Err(6),
+            address.compare_exchange(0, 1, Ordering::SeqCst, Ordering::SeqCst)
+        );
+    }
+
+    #[test]
+    fn test_compare_exchange_loop_from_multiple_threads() {
+        const THREADS: usize = 4;
+        const INCREMENTS: usize = 1000;
+
+        let mut heap = ManagedHeap::new(1000);
+        let mut slot = heap.alloc(1).unwrap();
+        slot.write(0);
+
+        // the scope keeps the heap alive until every thread is done
+        thread::scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    for _ in 0..INCREMENTS {
+                        let mut current = slot.load_atomic(Ordering::Relaxed);
+                        while let Err(actual) = slot.compare_exchange(
+                            current,
+                            current + 1,
+                            Ordering::AcqRel,
+                            Ordering::Relaxed,
+                        ) {
+                            current = actual;
+                        }
+                    }
+                });
+            }
+        });
+
+        assert_eq!(THREADS * INCREMENTS, slot.load_atomic(Ordering::SeqCst));
+        heap.free(slot);
+    }
 }
@@ -0,0 +1,119 @@
+use crate::address::Address;
+use crate::managed::ManagedHeap;
+
+use std::mem;
+
+/// The write barrier.
+/// Every reference stored into an object should go through write_ref, so that
+/// collectors which need to know about changed references (e.g. the
+/// incremental collector) can observe them.
+impl ManagedHeap {
+    /// Stores child in the word at field_offset inside of parent.
+    /// If the write barrier is enabled, the pair is appended to the dirty list
+    /// and the card of the field is dirtied.
+    /// During an incremental collection, child is kept alive
+    /// (see record_write).
+    /// Like Address::write, this doesn't check that the field is inside of
+    /// parent.
+    #[inline]
+    pub fn write_ref(&mut self, parent: Address, field_offset: usize, child: Address) {
+        (parent + field_offset).write(child.into());
+
+        if self.write_barrier {
+            self.dirty.push((parent, child));
+            self.dirty_card(parent + field_offset);
+        }
+
+        if self.incremental.is_some() {
+            self.record_write(parent, child);
+        }
+    }
+
+    /// Starts recording every write_ref in the dirty list
+    pub fn enable_write_barrier(&mut self) {
+        self.write_barrier = true;
+    }
+
+    /// Stops recording writes. The dirty list is kept until it is drained.
+    pub fn disable_write_barrier(&mut self) {
+        self.write_barrier = false;
+    }
+
+    pub fn is_write_barrier_enabled(&self) -> bool {
+        self.write_barrier
+    }
+
+    /// Returns all (parent, child) pairs, which were written since the last
+    /// call, in the order of the writes.
+    pub fn drain_dirty(&mut self) -> Vec<(Address, Address)> {
+        mem::take(&mut self.dirty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::incremental::GcPhase;
+    use crate::testing::*;
+    use crate::trace::GcRoot;
+
+    #[test]
+    fn test_writes_are_recorded_once_with_barrier() {
+        let mut heap = ManagedHeap::new(1000);
+        let parent = heap.alloc(3).unwrap();
+        let first = heap.alloc(1).unwrap();
+        let second = heap.alloc(1).unwrap();
+
+        heap.enable_write_barrier();
+        heap.write_ref(parent, 1, first);
+        heap.write_ref(parent, 2, second);
+        heap.write_ref(parent, 1, second);
+
+        assert_eq!(Ok(usize::from(second)), heap.read(parent, 1));
+        assert_eq!(
+            vec![(parent, first), (parent, second), (parent, second)],
+            heap.drain_dirty()
+        );
+        assert!(heap.drain_dirty().is_empty());
+    }
+
+    #[test]
+    fn test_writes_are_not_recorded_without_barrier() {
+        let mut heap = ManagedHeap::new(1000);
+        let mut plain = heap.alloc(2).unwrap();
+        let parent = heap.alloc(2).unwrap();
+        let child = heap.alloc(1).unwrap();
+
+        plain.write(child.into());
+        heap.write_ref(parent, 0, child);
+        heap.enable_write_barrier();
+        heap.disable_write_barrier();
+        heap.write_ref(parent, 1, child);
+
+        assert!(!heap.is_write_barrier_enabled());
+        assert!(heap.drain_dirty().is_empty());
+        assert_eq!(heap.read(plain, 0), heap.read(parent, 0));
+        assert_eq!(heap.read(plain, 0), heap.read(parent, 1));
+    }
+
+    #[test]
+    fn test_write_ref_shades_child_during_incremental_gc() {
+        let mut heap = ManagedHeap::new(1000);
+        let list = list![&mut heap; 1, 2];
+        let unrooted = list![&mut heap; 4];
+
+        let mut root = MockGcRoot::new(vec![list]);
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        heap.gc_begin(&mut roots[..]);
+        while heap.gc_step(1) == GcPhase::Marking {}
+
+        // new is allocated black, so storing unrooted in it has to shade it
+        let new = LinkedList::new(&mut heap, 3, None);
+        heap.write_ref(new.0, 2, unrooted.0);
+        heap.write_ref(list.0, 2, new.0);
+
+        let stats = heap.gc_finish();
+        assert_eq!(0, stats.freed_blocks);
+        assert_eq!("[1, 3, 4]", format!("{:?}", list));
+    }
+}
@@ -0,0 +1,245 @@
+use crate::address::Address;
+use crate::managed::ManagedHeap;
+use crate::stats::{GcKind, GcStats};
+use crate::trace::{GcRoot, Traceable};
+use crate::types::WORD_SIZE;
+
+use std::mem;
+
+/// One mark bit for every word of the heap, stored outside of the heap.
+pub(crate) struct MarkBitmap {
+    bits: Vec<u64>,
+}
+
+impl MarkBitmap {
+    fn new(words: usize) -> Self {
+        MarkBitmap {
+            bits: vec![0; words.div_ceil(64)],
+        }
+    }
+
+    /// Sets the bit and returns true if it wasn't set before
+    fn mark(&mut self, index: usize) -> bool {
+        let (word, bit) = (index / 64, 1 << (index % 64));
+        let unmarked = self.bits[word] & bit == 0;
+        self.bits[word] |= bit;
+        unmarked
+    }
+
+    fn is_marked(&self, index: usize) -> bool {
+        self.bits[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn clear(&mut self) {
+        self.bits.fill(0);
+    }
+}
+
+/// A mark & sweep collector, which keeps the mark state in a bitmap instead
+/// of the objects or their headers, so marking doesn't write to the heap.
+/// The bitmap is only allocated when it is used for the first time.
+/// It only covers the reserved memory of the heap, so large objects and
+/// objects in additional segments are marked in their headers.
+impl ManagedHeap {
+    /// Marks the object containing address in the mark bitmap.
+    /// Returns true if it wasn't marked before and false if it was already
+    /// marked or address doesn't point into an object.
+    pub fn bitmap_mark(&mut self, address: Address) -> bool {
+        self.bitmap_mark_object(address).is_some()
+    }
+
+    /// Checks if the object containing address is marked in the mark bitmap
+    pub fn bitmap_is_marked(&self, address: Address) -> bool {
+        let block = match self.block_of(address) {
+            Some(block) => block,
+            None => return false,
+        };
+
+        let object = Address::from(block);
+        match self.bitmap_index(object) {
+            Some(index) => self
+                .mark_bitmap
+                .as_ref()
+                .is_some_and(|b| b.is_marked(index)),
+            None => block.is_marked_in(self.epoch),
+        }
+    }
+
+    /// Like gc, but uses the mark bitmap instead of Traceable::mark.
+    /// Traceable::trace has to return every reference of an object.
+    /// The bitmap is cleared at the start of every collection, so afterwards
+    /// it contains the marks of the last collection.
+    pub fn gc_bitmap<T>(&mut self, roots: &mut [&mut dyn GcRoot<T>]) -> GcStats
+    where
+        T: Traceable + From<Address> + Into<Address> + Copy,
+    {
+        self.gc_abort();
+        self.release_reservations();
+        self.begin_epoch();
+        self.bitmap().clear();
+
+        let mut worklist = mem::take(&mut self.mark_buffer);
+        for root in roots.iter_mut().flat_map(|r| r.children()) {
+            worklist.extend(self.bitmap_mark_object((*root).into()));
+        }
+        for address in self.region_objects().collect::<Vec<_>>() {
+            worklist.extend(self.bitmap_mark_object(address));
+        }
+
+        while let Some(address) = worklist.pop() {
+            let mut object = T::from(address);
+            object.trace_with(&mut |child| worklist.extend(self.bitmap_mark_object(*child)));
+        }
+        self.mark_buffer = worklist;
+
+        let mut freeable = mem::take(&mut self.sweep_buffer);
+        {
+            let reservations = self.reservations();
+            freeable.extend(
+                self.used_blocks()
+                    .map(Address::from)
+                    .filter(|&a| !self.bitmap_is_marked(a) && !reservations.is_held(a)),
+            );
+        }
+
+        let mut stats = self.free_all(&mut freeable);
+        self.sweep_buffer = freeable;
+
+        // only headers, which change, are written, so a collection without
+        // new objects doesn't touch the memory of the survivors
+        for mut block in self.used_blocks() {
+            stats.live_blocks += 1;
+            stats.live_words += block.size() as usize;
+            if !block.is_old() {
+                block.set_old(true);
+            }
+            // only large objects and objects of segments carry a mark
+            if block.is_marked() {
+                block.set_marked(false);
+            }
+        }
+
+        self.end_epoch(&mut stats, GcKind::Full);
+        stats
+    }
+
+    /// Marks the object containing address and returns its address if it
+    /// wasn't marked before
+    fn bitmap_mark_object(&mut self, address: Address) -> Option<Address> {
+        let mut block = self.block_of(address)?;
+        let object = Address::from(block);
+
+        let unmarked = match self.bitmap_index(object) {
+            Some(index) => self.bitmap().mark(index),
+            None => {
+                let unmarked = !block.is_marked_in(self.epoch);
+                block.mark_in(self.epoch);
+                unmarked
+            }
+        };
+
+        Some(object).filter(|_| unmarked)
+    }
+
+    /// The bit of an object inside of the heap
+    fn bitmap_index(&self, object: Address) -> Option<usize> {
+        let offset = usize::from(object).checked_sub(self.heap.start())? / WORD_SIZE;
+        Some(offset).filter(|&offset| offset < self.heap.size())
+    }
+
+    fn bitmap(&mut self) -> &mut MarkBitmap {
+        let words = self.heap.max_size();
+        self.mark_bitmap
+            .get_or_insert_with(|| MarkBitmap::new(words))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Block;
+    use crate::testing::*;
+
+    fn checksum(words: &[usize]) -> usize {
+        words.iter().fold(0, |sum, w| sum.rotate_left(7) ^ w)
+    }
+
+    #[test]
+    fn test_bitmap_mark_does_not_write_to_heap() {
+        let mut heap = ManagedHeap::new(1000);
+        let list = list![&mut heap; 1, 2, 3];
+        let before = checksum(heap.raw_words());
+
+        assert!(!heap.bitmap_is_marked(list.0));
+        assert!(heap.bitmap_mark(list.0 + 1));
+        assert!(!heap.bitmap_mark(list.0));
+        assert!(heap.bitmap_is_marked(list.0));
+        assert!(!heap.bitmap_is_marked(list.next().unwrap().0));
+        assert!(!heap.bitmap_mark(Address::from(8)));
+
+        assert_eq!(before, checksum(heap.raw_words()));
+    }
+
+    #[test]
+    fn test_gc_bitmap_matches_classic_gc() {
+        let mut bitmap_heap = ManagedHeap::new(1000);
+        let mut heap = ManagedHeap::new(1000);
+
+        let bitmap_live = list![&mut bitmap_heap; 1, 2, 3];
+        let _bitmap_dead = list![&mut bitmap_heap; 4, 5];
+        let live = list![&mut heap; 1, 2, 3];
+        let _dead = list![&mut heap; 4, 5];
+
+        let payloads = |heap: &ManagedHeap, list: LinkedList| -> Vec<usize> {
+            list.iter()
+                .map(|l| {
+                    let size = Block::from(l.0).size() as usize - 1;
+                    checksum(&heap.raw_words()[heap.offset_of(l.0)..][..size])
+                })
+                .collect()
+        };
+        let before = payloads(&bitmap_heap, bitmap_live);
+
+        let mut bitmap_root = MockGcRoot::new(vec![bitmap_live]);
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut bitmap_root];
+        let bitmap_stats = bitmap_heap.gc_bitmap(&mut roots[..]);
+
+        let mut root = MockGcRoot::new(vec![live]);
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        let stats = heap.gc(&mut roots[..]);
+
+        assert_eq!(outcome(stats), outcome(bitmap_stats));
+        let offsets = |heap: &ManagedHeap| -> Vec<usize> {
+            heap.heap
+                .used()
+                .map(|b| heap.offset_of(Address::from(b)))
+                .collect()
+        };
+        assert_eq!(offsets(&heap), offsets(&bitmap_heap));
+        assert_eq!(before, payloads(&bitmap_heap, bitmap_live));
+        assert_eq!("[1, 2, 3]", format!("{:?}", bitmap_live));
+
+        // the next collection starts with a clean bitmap
+        bitmap_root.clear();
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut bitmap_root];
+        assert_eq!(3, bitmap_heap.gc_bitmap(&mut roots[..]).freed_blocks);
+        assert_eq!(0, bitmap_heap.num_used_blocks());
+    }
+
+    #[test]
+    fn test_gc_bitmap_marks_large_objects_in_header() {
+        let mut heap = ManagedHeap::new(1000);
+        heap.set_large_object_threshold(2);
+        let large = list![&mut heap; 1];
+        let _dead = list![&mut heap; 2];
+        assert!(heap.is_large_object(large.0));
+
+        let mut root = MockGcRoot::new(vec![large]);
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        let stats = heap.gc_bitmap(&mut roots[..]);
+
+        assert_eq!(1, stats.freed_blocks);
+        assert_eq!(1, heap.num_large_objects());
+        assert!(heap.used_blocks().all(|b| !b.is_marked()));
+    }
+}
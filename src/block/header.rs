@@ -1,24 +1,63 @@
-use crate::types::{HalfWord, Word};
-use std::cmp::Ordering;
+use crate::types::{HalfWord, Word, HALF_WORD_MAX};
 use std::mem;
 
 /// The first field in a block of memory.
 /// Contains the size of the previous block in its first half and its own
 /// size in the second half.
+/// The two highest bits of each half are reserved for flags, which limits the
+/// size of a block to MAX_SIZE words.
+///
+/// The encoding is part of the stable API (see decode), so tools can inspect
+/// the raw words of a heap.
 #[derive(Copy, Clone)]
 pub struct BlockHeader(usize);
 
+/// The flags stored in a BlockHeader.
+/// Later versions may add flags, so values have to be created with default
+/// (or decode) and changed field by field.
+///
+/// # Example
+/// ```
+/// use managed_heap::{BlockHeader, HeaderFlags};
+///
+/// let mut flags = HeaderFlags::default();
+/// flags.used = true;
+/// let raw = BlockHeader::encode(0, 4, flags);
+/// assert_eq!((0, 4, flags), BlockHeader::decode(raw));
+/// ```
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HeaderFlags {
+    /// The block is allocated
+    pub used: bool,
+    /// The block was marked by the garbage collector
+    pub marked: bool,
+    /// The block survived a minor collection
+    pub old: bool,
+    /// The parity of the collection epoch, in which the block was marked
+    pub epoch: bool,
+}
+
 impl BlockHeader {
-    #[cfg(target_pointer_width = "64")]
-    const PRED_FLAG: usize = 0xFFFF_FFFF_0000_0000;
-    #[cfg(target_pointer_width = "32")]
-    const PRED_FLAG: usize = 0xFFFF_0000;
+    const SHIFT: usize = mem::size_of::<HalfWord>() * 8;
 
-    const SIZE_FLAG: usize = !BlockHeader::PRED_FLAG;
+    const FLAG_BITS: usize = 2;
 
-    const SHIFT: usize = mem::size_of::<HalfWord>() * 8;
+    /// The biggest size (in words, including the header) a block can have
+    pub const MAX_SIZE: HalfWord = HALF_WORD_MAX >> BlockHeader::FLAG_BITS;
+
+    const SIZE_FLAG: usize = BlockHeader::MAX_SIZE as usize;
+    const PRED_FLAG: usize = BlockHeader::SIZE_FLAG << BlockHeader::SHIFT;
+
+    const MARK_FLAG: usize = 1 << (BlockHeader::SHIFT - 1);
+    const USED_FLAG: usize = 1 << (2 * BlockHeader::SHIFT - 1);
+    const OLD_FLAG: usize = 1 << (BlockHeader::SHIFT - 2);
+    const EPOCH_FLAG: usize = 1 << (2 * BlockHeader::SHIFT - 2);
 
     pub fn new(pred_size: HalfWord, size: HalfWord) -> Self {
+        debug_assert!(pred_size <= BlockHeader::MAX_SIZE, "pred_size too big");
+        debug_assert!(size <= BlockHeader::MAX_SIZE, "size too big");
+
         let pred = Word::from(pred_size) << BlockHeader::SHIFT;
         let own = Word::from(size);
         let word = pred | own;
@@ -26,54 +65,118 @@ impl BlockHeader {
         BlockHeader(word as usize)
     }
 
+    /// Splits a raw header word into pred size, size and flags.
+    ///
+    /// The layout is stable across semver compatible versions:
+    /// - the lower half word contains the size in its lowest bits, the mark
+    ///   flag in its highest bit and the old flag in its second highest bit
+    /// - the upper half word contains the pred size in its lowest bits, the
+    ///   used flag in its highest bit and the epoch parity in its second
+    ///   highest bit
+    pub fn decode(raw: usize) -> (HalfWord, HalfWord, HeaderFlags) {
+        let header = BlockHeader(raw);
+        let flags = HeaderFlags {
+            used: header.is_used(),
+            marked: header.is_marked(),
+            old: header.is_old(),
+            epoch: header.epoch_parity(),
+        };
+
+        (header.pred_block_size(), header.block_size(), flags)
+    }
+
+    /// The inverse of decode.
+    /// Panics if one of the sizes is bigger than MAX_SIZE.
+    pub fn encode(pred_size: HalfWord, size: HalfWord, flags: HeaderFlags) -> usize {
+        assert!(pred_size <= BlockHeader::MAX_SIZE, "pred_size too big");
+        assert!(size <= BlockHeader::MAX_SIZE, "size too big");
+
+        let mut header = BlockHeader::new(pred_size, size);
+        header.set_used(flags.used);
+        header.set_marked(flags.marked);
+        header.set_old(flags.old);
+        header.set_epoch_parity(flags.epoch);
+        header.0
+    }
+
     pub fn block_size(self) -> HalfWord {
-        self.0 as HalfWord
+        (self.0 & BlockHeader::SIZE_FLAG) as HalfWord
     }
 
     pub fn pred_block_size(self) -> HalfWord {
-        (self.0 as Word >> BlockHeader::SHIFT) as HalfWord
+        ((self.0 & BlockHeader::PRED_FLAG) as Word >> BlockHeader::SHIFT) as HalfWord
+    }
+
+    pub fn is_marked(self) -> bool {
+        self.0 & BlockHeader::MARK_FLAG != 0
+    }
+
+    pub fn is_used(self) -> bool {
+        self.0 & BlockHeader::USED_FLAG != 0
+    }
+
+    pub fn is_old(self) -> bool {
+        self.0 & BlockHeader::OLD_FLAG != 0
+    }
+
+    pub fn epoch_parity(self) -> bool {
+        self.0 & BlockHeader::EPOCH_FLAG != 0
     }
 }
 
 impl BlockHeader {
-    pub fn inc_size(&mut self, value: HalfWord) {
-        let size = Word::from(self.block_size() + value);
-        self.0 = (self.0 & BlockHeader::PRED_FLAG) + size as usize;
+    /// Increases the size by value and returns the new size.
+    /// If the new size can't be represented, None is returned and the header
+    /// stays unchanged.
+    pub fn checked_inc_size(&mut self, value: HalfWord) -> Option<HalfWord> {
+        let new_size = self
+            .block_size()
+            .checked_add(value)
+            .filter(|&size| size <= BlockHeader::MAX_SIZE)?;
+
+        self.set_size(new_size);
+        Some(new_size)
     }
 
     pub fn set_size(&mut self, value: HalfWord) {
-        self.0 = (self.0 & BlockHeader::PRED_FLAG) + value as usize;
+        debug_assert!(value <= BlockHeader::MAX_SIZE, "size too big");
+        self.0 = (self.0 & !BlockHeader::SIZE_FLAG) | value as usize;
     }
 
     pub fn set_pred_size(&mut self, value: HalfWord) {
+        debug_assert!(value <= BlockHeader::MAX_SIZE, "pred_size too big");
         let size = (Word::from(value) << BlockHeader::SHIFT) as usize;
-        let cleared = self.0 & BlockHeader::SIZE_FLAG;
+        let cleared = self.0 & !BlockHeader::PRED_FLAG;
         self.0 = size | cleared;
     }
-}
 
-impl PartialOrd for BlockHeader {
-    fn partial_cmp(&self, other: &BlockHeader) -> Option<Ordering> {
-        Some(self.block_size().cmp(&other.block_size()))
+    pub fn set_marked(&mut self, marked: bool) {
+        self.set_flag(BlockHeader::MARK_FLAG, marked);
     }
-}
 
-impl Ord for BlockHeader {
-    fn cmp(&self, other: &BlockHeader) -> Ordering {
-        self.block_size().cmp(&other.block_size())
+    pub fn set_used(&mut self, used: bool) {
+        self.set_flag(BlockHeader::USED_FLAG, used);
     }
-}
 
-impl PartialEq for BlockHeader {
-    fn eq(&self, other: &BlockHeader) -> bool {
-        self.block_size() == other.block_size()
+    pub fn set_old(&mut self, old: bool) {
+        self.set_flag(BlockHeader::OLD_FLAG, old);
+    }
+
+    pub fn set_epoch_parity(&mut self, parity: bool) {
+        self.set_flag(BlockHeader::EPOCH_FLAG, parity);
     }
-}
 
-impl Eq for BlockHeader {}
+    fn set_flag(&mut self, flag: usize, value: bool) {
+        if value {
+            self.0 |= flag;
+        } else {
+            self.0 &= !flag;
+        }
+    }
+}
 
-impl Into<usize> for BlockHeader {
-    fn into(self) -> usize {
-        self.0
+impl From<BlockHeader> for usize {
+    fn from(value: BlockHeader) -> usize {
+        value.0
     }
 }
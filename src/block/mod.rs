@@ -1,4 +1,8 @@
 use self::header::BlockHeader;
+#[cfg(test)]
+use self::header::HeaderFlags;
+use super::address::Address;
+use super::error::WriteError;
 use super::types::{HalfWord, WORD_SIZE};
 
 use std::cmp::Ordering;
@@ -11,9 +15,13 @@ pub mod set;
 #[derive(Copy, Clone)]
 pub struct Block(NonNull<BlockHeader>);
 
+// like an Address, a block is only a pointer into the heap, which owns the
+// memory
+unsafe impl Send for Block {}
+
 impl Block {
     /// Takes a ptr to allocated memory of the specified size in usizes
-    pub fn new(ptr: *mut usize, size: HalfWord, pred_size: HalfWord) -> Self {
+    pub(crate) fn new(ptr: *mut usize, size: HalfWord, pred_size: HalfWord) -> Self {
         let header = BlockHeader::new(pred_size, size);
         unsafe {
             *ptr = header.into();
@@ -27,36 +35,78 @@ impl Block {
 }
 
 impl Block {
-    /// Writes value to memory after offset * size_of::<usize>() bytes.
+    /// Writes value to the payload word at offset.
+    /// Panics if offset is not smaller than payload_len.
     pub fn write_at(&mut self, offset: HalfWord, value: usize) {
-        assert!(
-            (offset as usize * WORD_SIZE) < self.size() as usize,
-            "Offset is out of bounds"
-        );
+        if let Err(e) = self.try_write_at(offset, value) {
+            panic!("{}", e);
+        }
+    }
+
+    /// Writes value to the payload word at offset, unless it lies outside of
+    /// the payload.
+    pub fn try_write_at(&mut self, offset: HalfWord, value: usize) -> Result<(), WriteError> {
+        let offset = offset as usize;
+        if offset >= self.payload_len() {
+            return Err(WriteError {
+                offset,
+                payload_len: self.payload_len(),
+                block: Address::from(*self),
+            });
+        }
 
         unsafe {
             // add one to offset, to skip header
-            *(self.0.as_ptr() as *mut usize).add(1 + offset as usize) = value;
+            *(self.0.as_ptr() as *mut usize).add(1 + offset) = value;
         }
+        Ok(())
     }
 
-    pub fn inc_size(&mut self, value: HalfWord) {
-        unsafe {
-            self.0.as_mut().inc_size(value);
-        }
+    /// Increases the size by value and returns the new size, or None if the
+    /// new size can't be represented.
+    pub(crate) fn checked_inc_size(&mut self, value: HalfWord) -> Option<HalfWord> {
+        unsafe { self.0.as_mut().checked_inc_size(value) }
     }
 
-    pub fn set_size(&mut self, value: HalfWord) {
+    pub(crate) fn set_size(&mut self, value: HalfWord) {
         unsafe {
             self.0.as_mut().set_size(value);
         }
     }
 
-    pub fn set_pred_size(&mut self, value: HalfWord) {
+    pub(crate) fn set_pred_size(&mut self, value: HalfWord) {
         unsafe {
             self.0.as_mut().set_pred_size(value);
         }
     }
+
+    pub(crate) fn set_marked(&mut self, marked: bool) {
+        unsafe {
+            self.0.as_mut().set_marked(marked);
+        }
+    }
+
+    pub(crate) fn set_used(&mut self, used: bool) {
+        unsafe {
+            self.0.as_mut().set_used(used);
+        }
+    }
+
+    /// Marks the block as part of the old generation
+    pub(crate) fn set_old(&mut self, old: bool) {
+        unsafe {
+            self.0.as_mut().set_old(old);
+        }
+    }
+
+    /// Sets the mark flag together with the parity of the epoch it belongs to
+    pub(crate) fn mark_in(&mut self, epoch: u64) {
+        unsafe {
+            let header = self.0.as_mut();
+            header.set_marked(true);
+            header.set_epoch_parity(epoch % 2 == 1);
+        }
+    }
 }
 
 impl Block {
@@ -68,6 +118,53 @@ impl Block {
         unsafe { self.0.as_ref().pred_block_size() }
     }
 
+    /// The number of words after the header
+    pub fn payload_len(self) -> usize {
+        self.size() as usize - 1
+    }
+
+    /// Checks the mark flag in the header of this block
+    pub fn is_marked(self) -> bool {
+        unsafe { self.0.as_ref().is_marked() }
+    }
+
+    /// Checks if the block was marked in epoch. A single bit of the epoch is
+    /// stored, so marks from two epochs ago can't be told apart.
+    pub fn is_marked_in(self, epoch: u64) -> bool {
+        let header = unsafe { self.0.as_ref() };
+        header.is_marked() && header.epoch_parity() == (epoch % 2 == 1)
+    }
+
+    /// Checks the used flag in the header of this block
+    pub fn is_used(self) -> bool {
+        unsafe { self.0.as_ref().is_used() }
+    }
+
+    /// Checks if the block survived a minor collection
+    pub fn is_old(self) -> bool {
+        unsafe { self.0.as_ref().is_old() }
+    }
+
+    /// A pointer to the header of this block
+    pub fn as_ptr(self) -> *mut usize {
+        self.0.as_ptr() as *mut usize
+    }
+
+    /// The block at the same position in a copy of the heap, which starts
+    /// delta bytes after the original
+    pub fn relocated(self, delta: isize) -> Block {
+        let ptr = (self.as_ptr() as usize).wrapping_add_signed(delta);
+        Block::from(ptr as *mut BlockHeader)
+    }
+
+    /// Checks if ptr points into the memory of this block (including the
+    /// header)
+    pub fn contains(self, ptr: usize) -> bool {
+        let start = self.as_ptr() as usize;
+        let end = start + self.size() as usize * WORD_SIZE;
+        start <= ptr && ptr < end
+    }
+
     pub fn next_block(self, heap_end: usize) -> Option<Block> {
         let next_ptr = unsafe { self.0.as_ptr().add(self.size() as usize) };
 
@@ -95,8 +192,10 @@ impl Block {
         NonNull::new(pred_ptr).map(Block)
     }
 
-    /// Splits the block by inserting a new header at self + size
-    pub unsafe fn split_after(self, size: HalfWord) -> (Block, Block) {
+    /// Splits the block by inserting a new header at self + size.
+    /// The pred size of the block after self (if it lies before heap_end) is
+    /// updated to the size of the second part.
+    pub(crate) unsafe fn split_after(self, size: HalfWord, heap_end: usize) -> (Block, Block) {
         let current_size = self.size();
         assert!(current_size > size, "size too big");
 
@@ -111,6 +210,10 @@ impl Block {
 
         *ptr = BlockHeader::new(pred_size, size).into();
 
+        if let Some(mut next) = second.next_block(heap_end) {
+            next.set_pred_size(second_size);
+        }
+
         (self, second)
     }
 }
@@ -126,9 +229,9 @@ impl fmt::Debug for Block {
     }
 }
 
-impl Into<NonNull<BlockHeader>> for Block {
-    fn into(self) -> NonNull<BlockHeader> {
-        self.0
+impl From<Block> for NonNull<BlockHeader> {
+    fn from(value: Block) -> NonNull<BlockHeader> {
+        value.0
     }
 }
 
@@ -146,21 +249,102 @@ impl PartialEq for Block {
 
 impl Eq for Block {}
 
-impl PartialOrd for Block {
-    fn partial_cmp(&self, other: &Block) -> Option<Ordering> {
-        Some(self.0.cmp(&other.0))
+/// Orders blocks by their address.
+/// Block itself has no order, because the free block stores need two
+/// different ones (see BySize).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ByAddress(pub Block);
+
+impl PartialOrd for ByAddress {
+    fn partial_cmp(&self, other: &ByAddress) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
-impl Ord for Block {
-    fn cmp(&self, other: &Block) -> Ordering {
-        self.0.cmp(&other.0)
+impl Ord for ByAddress {
+    fn cmp(&self, other: &ByAddress) -> Ordering {
+        (self.0).0.cmp(&(other.0).0)
+    }
+}
+
+/// Orders blocks by their size and blocks of equal size by their address,
+/// so two distinct blocks are never equal
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BySize(pub Block);
+
+impl PartialOrd for BySize {
+    fn partial_cmp(&self, other: &BySize) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for BySize {
+    fn cmp(&self, other: &BySize) -> Ordering {
+        self.0
+            .size()
+            .cmp(&other.0.size())
+            .then_with(|| ByAddress(self.0).cmp(&ByAddress(other.0)))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::HALF_WORD_MAX;
+
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_split_after_updates_pred_size_of_successor() {
+        let mut words = [0usize; 10];
+        let start = words.as_mut_ptr();
+        let heap_end = unsafe { start.add(10) } as usize;
+        let block = Block::new(start, 6, 0);
+        let next = Block::new(unsafe { start.add(6) }, 4, 6);
+
+        let (first, second) = unsafe { block.split_after(2, heap_end) };
+
+        assert_eq!((0, 2), (first.pred_size(), first.size()));
+        assert_eq!((2, 4), (second.pred_size(), second.size()));
+        assert_eq!(4, next.pred_size());
+        assert_eq!(Some(second), next.pred_block(start as usize));
+    }
+
+    #[test]
+    fn test_split_after_last_block() {
+        let mut words = [0usize; 6];
+        let start = words.as_mut_ptr();
+        let heap_end = unsafe { start.add(6) } as usize;
+        let block = Block::new(start, 6, 0);
+
+        let (_, second) = unsafe { block.split_after(4, heap_end) };
+
+        assert_eq!(None, second.next_block(heap_end));
+        assert_eq!(Some(block), second.pred_block(start as usize));
+    }
+
+    #[test]
+    fn test_equal_sized_blocks_stay_distinct() {
+        let mut words = [0usize; 12];
+        let start = words.as_mut_ptr();
+        let small = Block::new(start, 4, 0);
+        let a = Block::new(unsafe { start.add(4) }, 2, 4);
+        let b = Block::new(unsafe { start.add(6) }, 2, 2);
+        let big = Block::new(unsafe { start.add(8) }, 4, 2);
+
+        let by_address: BTreeSet<_> = [b, a, big].iter().map(|&b| ByAddress(b)).collect();
+        assert_eq!(
+            vec![a, b, big],
+            by_address.iter().map(|b| b.0).collect::<Vec<_>>()
+        );
+
+        let by_size: BTreeSet<_> = [big, b, small, a].iter().map(|&b| BySize(b)).collect();
+        assert_eq!(
+            vec![a, b, small, big],
+            by_size.iter().map(|b| b.0).collect::<Vec<_>>()
+        );
+        assert!(by_size.contains(&BySize(a)) && by_size.contains(&BySize(b)));
+    }
 
     #[test]
     fn test_block_header_new() {
@@ -179,7 +363,7 @@ mod tests {
         assert_eq!(10, header.block_size());
         assert_eq!(42, header.pred_block_size());
 
-        header.inc_size(2);
+        assert_eq!(Some(12), header.checked_inc_size(2));
         assert_eq!(12, header.block_size());
         assert_eq!(42, header.pred_block_size());
 
@@ -189,7 +373,191 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "Offset is out of bounds")]
+    fn test_block_header_checked_inc_size_at_max() {
+        let max = BlockHeader::MAX_SIZE;
+        let mut header = BlockHeader::new(7, max - 1);
+
+        assert_eq!(Some(max), header.checked_inc_size(1));
+        assert_eq!(max, header.block_size());
+        assert_eq!(7, header.pred_block_size());
+
+        assert_eq!(None, header.checked_inc_size(1));
+        assert_eq!(max, header.block_size());
+        assert_eq!(7, header.pred_block_size());
+    }
+
+    #[test]
+    fn test_block_header_checked_inc_size_overflow_keeps_header() {
+        let mut header = BlockHeader::new(BlockHeader::MAX_SIZE, 2);
+
+        assert_eq!(None, header.checked_inc_size(HALF_WORD_MAX - 1));
+        assert_eq!(2, header.block_size());
+        assert_eq!(BlockHeader::MAX_SIZE, header.pred_block_size());
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_block_header_max_size_64_bit() {
+        assert_eq!(0x3FFF_FFFF, BlockHeader::MAX_SIZE);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "32")]
+    fn test_block_header_max_size_32_bit() {
+        assert_eq!(0x3FFF, BlockHeader::MAX_SIZE);
+    }
+
+    #[test]
+    fn test_block_header_mark_flag_keeps_sizes() {
+        let max = BlockHeader::MAX_SIZE;
+        let mut header = BlockHeader::new(max, max);
+        assert!(!header.is_marked());
+
+        header.set_marked(true);
+        assert!(header.is_marked());
+        assert_eq!(max, header.block_size());
+        assert_eq!(max, header.pred_block_size());
+
+        header.set_size(3);
+        header.set_pred_size(4);
+        assert!(header.is_marked());
+        assert_eq!(Some(5), header.checked_inc_size(2));
+        assert!(header.is_marked());
+
+        header.set_marked(false);
+        assert!(!header.is_marked());
+        assert_eq!(5, header.block_size());
+        assert_eq!(4, header.pred_block_size());
+    }
+
+    #[test]
+    fn test_block_header_used_flag_keeps_sizes() {
+        let max = BlockHeader::MAX_SIZE;
+        let mut header = BlockHeader::new(max, max);
+        assert!(!header.is_used());
+
+        header.set_used(true);
+        header.set_marked(true);
+        assert!(header.is_used());
+        assert_eq!(max, header.block_size());
+        assert_eq!(max, header.pred_block_size());
+
+        header.set_pred_size(1);
+        header.set_marked(false);
+        assert!(header.is_used());
+        assert_eq!(1, header.pred_block_size());
+
+        header.set_used(false);
+        assert!(!header.is_used());
+        assert_eq!(max, header.block_size());
+    }
+
+    #[test]
+    fn test_block_header_encode_decode_round_trip() {
+        let max = BlockHeader::MAX_SIZE;
+        let flags = [
+            HeaderFlags::default(),
+            HeaderFlags {
+                used: true,
+                ..HeaderFlags::default()
+            },
+            HeaderFlags {
+                marked: true,
+                ..HeaderFlags::default()
+            },
+            HeaderFlags {
+                old: true,
+                ..HeaderFlags::default()
+            },
+            HeaderFlags {
+                epoch: true,
+                ..HeaderFlags::default()
+            },
+            HeaderFlags {
+                used: true,
+                marked: true,
+                old: true,
+                epoch: true,
+            },
+        ];
+
+        for &pred_size in &[0, 1, max - 1, max] {
+            for &size in &[0, 1, max - 1, max] {
+                for &flags in &flags {
+                    let raw = BlockHeader::encode(pred_size, size, flags);
+                    assert_eq!((pred_size, size, flags), BlockHeader::decode(raw));
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_block_header_encoding_64_bit() {
+        let flags = HeaderFlags {
+            used: true,
+            marked: true,
+            old: true,
+            epoch: false,
+        };
+        assert_eq!(0x8000_0001_C000_0002, BlockHeader::encode(1, 2, flags));
+        let decoded = BlockHeader::decode(0x4000_0003_0000_0004);
+        let epoch = HeaderFlags {
+            epoch: true,
+            ..HeaderFlags::default()
+        };
+        assert_eq!((3, 4, epoch), decoded);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "32")]
+    fn test_block_header_encoding_32_bit() {
+        let flags = HeaderFlags {
+            used: true,
+            marked: true,
+            old: true,
+            epoch: false,
+        };
+        assert_eq!(0x8001_C002, BlockHeader::encode(1, 2, flags));
+        let decoded = BlockHeader::decode(0x4003_0004);
+        let epoch = HeaderFlags {
+            epoch: true,
+            ..HeaderFlags::default()
+        };
+        assert_eq!((3, 4, epoch), decoded);
+    }
+
+    #[test]
+    #[should_panic(expected = "size too big")]
+    fn test_block_header_encode_rejects_big_size() {
+        BlockHeader::encode(0, BlockHeader::MAX_SIZE + 1, HeaderFlags::default());
+    }
+
+    #[test]
+    fn test_block_header_old_flag_is_independent() {
+        let max = BlockHeader::MAX_SIZE;
+        let mut header = BlockHeader::new(max, max);
+        assert!(!header.is_old());
+
+        header.set_old(true);
+        header.set_marked(true);
+        header.set_used(true);
+        assert!(header.is_old());
+        assert_eq!(max, header.block_size());
+        assert_eq!(max, header.pred_block_size());
+
+        header.set_marked(false);
+        header.set_size(7);
+        assert!(header.is_old());
+        assert_eq!(7, header.block_size());
+
+        header.set_old(false);
+        assert!(!header.is_old());
+        assert!(header.is_used());
+    }
+
+    #[test]
+    #[should_panic(expected = "Offset 2 is out of bounds for the payload of 2 words")]
     fn test_block_write_panics_if_out_of_bounds() {
         use super::super::address::Address;
         use std::alloc::{alloc, dealloc, Layout};
@@ -204,7 +572,7 @@ mod tests {
             let ptr = NonNull::new_unchecked(alloc(layout)).cast::<usize>();
             let ptr = ptr.as_ptr();
 
-            let mut block = Block::new(ptr, size as HalfWord, 0);
+            let mut block = Block::new(ptr, 3, 0);
             block.write_at(0, 20);
 
             let address = Address::from(block);
@@ -216,9 +584,33 @@ mod tests {
             assert_eq!(21, *(address + 1));
 
             // this should panic
-            block.write_at(3, 13);
+            block.write_at(2, 13);
 
             dealloc(ptr as *mut u8, layout);
         }
     }
+
+    #[test]
+    fn test_try_write_at_checks_payload_len() {
+        for size in [2, 3, 8, 65] {
+            let mut memory = vec![0usize; size as usize + 1];
+            let mut block = Block::new(memory.as_mut_ptr(), size, 0);
+            let len = size as usize - 1;
+            assert_eq!(len, block.payload_len());
+
+            assert_eq!(Ok(()), block.try_write_at(size - 2, 7));
+            assert_eq!(7, memory[len]);
+
+            for offset in [size - 1, size, size * WORD_SIZE as HalfWord] {
+                let error = WriteError {
+                    offset: offset as usize,
+                    payload_len: len,
+                    block: Address::from(block),
+                };
+                assert_eq!(Err(error), block.try_write_at(offset, 13));
+            }
+            // the word after the block is untouched
+            assert_eq!(0, memory[size as usize]);
+        }
+    }
 }
@@ -1,52 +1,126 @@
-use super::Block;
+use super::{Block, ByAddress, BySize};
 use crate::types::HalfWord;
 
+use std::slice;
+
 #[derive(Default)]
 pub struct BlockSet(Vec<Block>);
 
 impl BlockSet {
-    pub fn from_raw(ptr: *mut usize, size: HalfWord) -> Self {
-        let mut block_vec = Self::default();
+    pub fn contains(&self, block: Block) -> bool {
+        self.0
+            .binary_search_by_key(&ByAddress(block), |&b| ByAddress(b))
+            .is_ok()
+    }
 
-        let block = Block::new(ptr, size, 0);
-        block_vec.add_block(block);
+    /// All blocks whose memory overlaps the byte range [start, end)
+    pub fn overlapping(&self, start: usize, end: usize) -> slice::Iter<'_, Block> {
+        let mut first = self.0.partition_point(|b| b.as_ptr() as usize <= start);
+        if first > 0 && self.0[first - 1].contains(start) {
+            first -= 1;
+        }
 
-        block_vec
+        let last = self.0.partition_point(|b| (b.as_ptr() as usize) < end);
+        self.0[first..last.max(first)].iter()
     }
-}
 
-impl BlockSet {
-    pub fn contains(&self, block: Block) -> bool {
-        self.0.binary_search(&block).is_ok()
+    /// Finds the block whose memory contains ptr
+    pub fn find_containing(&self, ptr: usize) -> Option<Block> {
+        let index = self.0.partition_point(|b| b.as_ptr() as usize <= ptr);
+        let block = *self.0.get(index.checked_sub(1)?)?;
+
+        if block.contains(ptr) {
+            Some(block)
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, Block> {
+        self.0.iter()
     }
 
-    pub fn iter<'a>(&'a self) -> Box<Iterator<Item = &Block> + 'a> {
-        Box::new(self.0.iter())
+    /// The block with the lowest address
+    pub fn first(&self) -> Option<Block> {
+        self.0.first().copied()
     }
 }
 
 impl BlockSet {
-    pub fn add_block(&mut self, block: Block) {
-        let index = match self.0.binary_search(&block) {
-            Ok(index) => index,
-            Err(index) => index,
-        };
-        self.0.insert(index, block);
-    }
-
-    pub fn get_block(&mut self, min_size: HalfWord) -> Option<Block> {
-        let block = self.0.iter().find(|b| b.size() >= min_size);
-        if let Some(b) = block {
-            let b = *b;
-            let index = self.0.binary_search(&b).ok()?;
-            Some(self.0.remove(index))
-        } else {
-            None
+    /// Inserts block and returns true, unless the set already contains a
+    /// block at the same address
+    pub fn add_block(&mut self, block: Block) -> bool {
+        match self
+            .0
+            .binary_search_by_key(&ByAddress(block), |&b| ByAddress(b))
+        {
+            Ok(_) => false,
+            Err(index) => {
+                self.0.insert(index, block);
+                true
+            }
+        }
+    }
+
+    /// Removes the block with the lowest address, which has at least
+    /// min_size words and satisfies predicate
+    pub fn get_first_fit<P>(&mut self, min_size: HalfWord, predicate: P) -> Option<Block>
+    where
+        P: Fn(&Block) -> bool,
+    {
+        let index = self
+            .0
+            .iter()
+            .position(|b| b.size() >= min_size && predicate(b))?;
+        Some(self.0.remove(index))
+    }
+
+    /// Removes the smallest block, which has at least min_size words and
+    /// satisfies predicate. Blocks of equal size are ordered by address.
+    pub fn get_best_fit<P>(&mut self, min_size: HalfWord, predicate: P) -> Option<Block>
+    where
+        P: Fn(&Block) -> bool,
+    {
+        let (index, _) = self
+            .0
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.size() >= min_size && predicate(b))
+            .min_by_key(|&(_, &b)| BySize(b))?;
+        Some(self.0.remove(index))
+    }
+
+    /// Replaces the block with the lowest address.
+    /// block must still be lower than all other blocks.
+    pub fn replace_first(&mut self, block: Block) {
+        debug_assert!(self
+            .0
+            .get(1)
+            .is_none_or(|&next| ByAddress(block) < ByAddress(next)));
+        self.0[0] = block;
+    }
+
+    /// Replaces old with block, which must keep its position in the set
+    /// (e.g. because it grew downwards over memory, which wasn't in the set)
+    pub fn replace(&mut self, old: Block, block: Block) {
+        // most replaced blocks are the first one (see Heap::alloc)
+        if self.0.first() == Some(&old) {
+            self.0[0] = block;
+            return;
         }
+
+        let index = self
+            .0
+            .binary_search_by_key(&ByAddress(old), |&b| ByAddress(b))
+            .expect("Replaced block is not in the set");
+        debug_assert!(index == 0 || ByAddress(self.0[index - 1]) < ByAddress(block));
+        self.0[index] = block;
     }
 
     pub fn remove_block(&mut self, block: Block) {
-        let index = self.0.binary_search(&block);
+        let index = self
+            .0
+            .binary_search_by_key(&ByAddress(block), |&b| ByAddress(b));
         if let Ok(i) = index {
             self.0.remove(i);
         }
@@ -54,7 +128,49 @@ impl BlockSet {
 }
 
 impl BlockSet {
+    /// The same blocks in a copy of the heap (see Block::relocated)
+    pub fn relocated(&self, delta: isize) -> Self {
+        BlockSet(self.0.iter().map(|b| b.relocated(delta)).collect())
+    }
+
     pub fn len(&self) -> usize {
         self.0.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_same_block_twice_is_rejected() {
+        let mut data = [0usize; 8];
+        let block = Block::new(data.as_mut_ptr(), 8, 0);
+        let mut set = BlockSet::default();
+
+        assert!(set.add_block(block));
+        assert!(!set.add_block(block));
+        assert_eq!(1, set.len());
+
+        set.remove_block(block);
+        assert_eq!(0, set.len());
+        assert!(!set.contains(block));
+    }
+
+    #[test]
+    fn test_blocks_with_same_size_are_kept() {
+        let mut data = [0usize; 8];
+        let first = Block::new(data.as_mut_ptr(), 4, 0);
+        let second = Block::new(unsafe { data.as_mut_ptr().add(4) }, 4, 4);
+        let mut set = BlockSet::default();
+
+        assert!(set.add_block(second));
+        assert!(set.add_block(first));
+        assert_eq!(2, set.len());
+        assert_eq!(vec![first, second], set.iter().copied().collect::<Vec<_>>());
+    }
 }
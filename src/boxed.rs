@@ -0,0 +1,298 @@
+use crate::address::Address;
+use crate::managed::ManagedHeap;
+use crate::trace::Leaf;
+use crate::types::{HalfWord, WORD_SIZE};
+
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+
+/// Drops the value inside of the block at address
+#[derive(Copy, Clone)]
+pub(crate) struct Dropper {
+    drop: unsafe fn(Address),
+    // the alignment of the value, which decides where it starts
+    align: usize,
+}
+
+impl Dropper {
+    pub(crate) unsafe fn drop_value(self, address: Address) {
+        (self.drop)(address)
+    }
+
+    /// The start of the value depends on the address of the block, if its
+    /// alignment is bigger than WORD_SIZE, so copying the words of the
+    /// block doesn't move it
+    pub(crate) fn is_movable(self) -> bool {
+        self.align <= WORD_SIZE
+    }
+
+    /// Moves the value inside of new, which is a copy of the block at old
+    /// with the given number of payload words, to where it starts at new
+    pub(crate) unsafe fn realign(self, old: Address, new: Address, words: usize) {
+        let from = value_start(old, self.align) - usize::from(old);
+        let to = value_start(new, self.align) - usize::from(new);
+        let start = usize::from(new) as *mut u8;
+        let len = words * WORD_SIZE - from.max(to);
+        ptr::copy(start.add(from), start.add(to), len);
+    }
+}
+
+fn value_start(address: Address, align: usize) -> usize {
+    let start = usize::from(address + 1);
+    start.div_ceil(align) * align
+}
+
+/// A Rust value, which lives inside of a ManagedHeap.
+/// The value is dropped when its block is freed (by free, gc, reset or when
+/// the heap is dropped).
+/// Layout: [mark word, padding, value]
+/// The padding is only needed for types with an alignment bigger than
+/// WORD_SIZE.
+/// HeapBox implements Leaf, so it can be used with the garbage collector,
+/// but the value must not contain references to other objects on the heap.
+pub struct HeapBox<T> {
+    address: Address,
+    _value: PhantomData<T>,
+}
+
+impl<T> HeapBox<T> {
+    /// The number of words needed for the mark word and the value
+    fn words() -> usize {
+        let padding = mem::align_of::<T>().saturating_sub(WORD_SIZE);
+        1 + (padding + mem::size_of::<T>()).div_ceil(WORD_SIZE)
+    }
+
+    fn value_ptr(address: Address) -> *mut T {
+        value_start(address, mem::align_of::<T>()) as *mut T
+    }
+
+    pub fn address(&self) -> Address {
+        self.address
+    }
+}
+
+impl<T> Deref for HeapBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*Self::value_ptr(self.address) }
+    }
+}
+
+impl<T> DerefMut for HeapBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *Self::value_ptr(self.address) }
+    }
+}
+
+impl<T> From<Address> for HeapBox<T> {
+    fn from(address: Address) -> Self {
+        HeapBox {
+            address,
+            _value: PhantomData,
+        }
+    }
+}
+
+impl<T> From<HeapBox<T>> for Address {
+    fn from(value: HeapBox<T>) -> Address {
+        value.address
+    }
+}
+
+unsafe impl<T> Leaf for HeapBox<T> {
+    fn address(&self) -> Address {
+        self.address
+    }
+}
+
+unsafe fn drop_value<T>(address: Address) {
+    ptr::drop_in_place(HeapBox::<T>::value_ptr(address));
+}
+
+impl ManagedHeap {
+    /// Checks if the object at address can be moved by copying its words
+    /// (see Dropper::is_movable)
+    pub(crate) fn is_movable(&self, address: Address) -> bool {
+        self.droppers
+            .get(&address.into())
+            .is_none_or(|dropper| dropper.is_movable())
+    }
+
+    /// Moves value into a new block.
+    /// Returns None (and drops value) if there is not enough space.
+    pub fn alloc_rust<T>(&mut self, value: T) -> Option<HeapBox<T>>
+    where
+        T: Send + 'static,
+    {
+        let size = HalfWord::try_from(HeapBox::<T>::words()).ok()?;
+        let mut address = self.alloc(size)?;
+
+        address.write(false as usize);
+        unsafe { ptr::write(HeapBox::<T>::value_ptr(address), value) };
+        let dropper = Dropper {
+            drop: drop_value::<T>,
+            align: mem::align_of::<T>(),
+        };
+        self.droppers.insert(address.into(), dropper);
+
+        Some(HeapBox::from(address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::trace::GcRoot;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Counts how often it was dropped
+    struct DropCounter(Arc<AtomicUsize>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[repr(align(16))]
+    struct Aligned(u8);
+
+    #[repr(align(16))]
+    struct AlignedString(String);
+
+    /// The address of a HeapBox, which can be used as a root
+    #[derive(Copy, Clone)]
+    struct Boxed(Address);
+
+    unsafe impl Leaf for Boxed {
+        fn address(&self) -> Address {
+            self.0
+        }
+    }
+
+    impl From<Address> for Boxed {
+        fn from(address: Address) -> Self {
+            Boxed(address)
+        }
+    }
+
+    impl From<Boxed> for Address {
+        fn from(boxed: Boxed) -> Address {
+            boxed.0
+        }
+    }
+
+    #[test]
+    fn test_value_is_dropped_by_gc() {
+        let mut heap = ManagedHeap::new(1000);
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let kept = heap.alloc_rust(DropCounter(Arc::clone(&drops))).unwrap();
+        heap.alloc_rust(DropCounter(Arc::clone(&drops))).unwrap();
+
+        let mut root = MockGcRoot::new(vec![kept]);
+        let mut roots: Vec<&mut dyn GcRoot<HeapBox<DropCounter>>> = vec![&mut root];
+        let stats = heap.gc(&mut roots[..]);
+
+        assert_eq!(1, stats.freed_blocks);
+        assert_eq!(1, drops.load(Ordering::SeqCst));
+
+        root.clear();
+        let mut roots: Vec<&mut dyn GcRoot<HeapBox<DropCounter>>> = vec![&mut root];
+        heap.gc(&mut roots[..]);
+        heap.gc(&mut roots[..]);
+        assert_eq!(2, drops.load(Ordering::SeqCst));
+        assert!(heap.droppers.is_empty());
+    }
+
+    #[test]
+    fn test_deref_and_free() {
+        let mut heap = ManagedHeap::new(1000);
+        let mut string = heap.alloc_rust(String::from("Hello")).unwrap();
+
+        string.push_str(", World");
+        assert_eq!("Hello, World", string.as_str());
+
+        heap.free(string.address());
+        assert!(heap.droppers.is_empty());
+        assert_eq!(0, heap.num_used_blocks());
+    }
+
+    #[test]
+    fn test_alignment_is_respected() {
+        let mut heap = ManagedHeap::new(1000);
+
+        for i in 0..4 {
+            // shift the following allocations by one word
+            heap.alloc(i).unwrap();
+            let aligned = heap.alloc_rust(Aligned(i as u8)).unwrap();
+
+            assert_eq!(0, &*aligned as *const Aligned as usize % 16);
+            assert_eq!(i as u8, aligned.0);
+        }
+
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_aligned_values_are_not_relocated() {
+        let mut heap = ManagedHeap::new(1000);
+        let aligned = heap.alloc_rust(Aligned(7)).unwrap();
+        let string = heap.alloc_rust(String::from("moved")).unwrap();
+
+        assert!(heap.relocate(aligned.address(), None).is_none());
+        assert_eq!(7, aligned.0);
+
+        let relocation = heap.relocate(string.address(), None).unwrap();
+        assert_eq!("moved", HeapBox::<String>::from(relocation.new).as_str());
+    }
+
+    #[test]
+    fn test_migrate_realigns_values() {
+        for shift in 0..2 {
+            // shifts the box by one word in one of the heaps
+            let mut heap = ManagedHeap::new(1000);
+            heap.alloc(shift).unwrap();
+            let value = heap
+                .alloc_rust(AlignedString(String::from("moved")))
+                .unwrap();
+
+            let mut root = MockGcRoot::new(vec![Boxed(value.address())]);
+            let mut roots: Vec<&mut dyn GcRoot<Boxed>> = vec![&mut root];
+            let new_heap = heap.migrate(1000, &mut roots[..]);
+
+            let moved = HeapBox::<AlignedString>::from(root.used_elems[0].0);
+            assert_eq!(0, &*moved as *const AlignedString as usize % 16);
+            assert_eq!("moved", moved.0);
+            drop(new_heap);
+        }
+    }
+
+    #[test]
+    fn test_reset_and_drop_run_every_dropper_once() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut heap = ManagedHeap::new(1000);
+
+        let freed = heap.alloc_rust(DropCounter(Arc::clone(&drops))).unwrap();
+        heap.alloc_rust(DropCounter(Arc::clone(&drops))).unwrap();
+        IntegerObject::new(&mut heap, 1);
+        heap.free(freed.address());
+        assert_eq!(1, drops.load(Ordering::SeqCst));
+
+        heap.reset();
+        assert_eq!(2, drops.load(Ordering::SeqCst));
+        assert_eq!(0, heap.num_used_blocks());
+        assert_eq!(1, heap.num_free_blocks());
+
+        heap.alloc_rust(DropCounter(Arc::clone(&drops))).unwrap();
+        drop(heap);
+        assert_eq!(3, drops.load(Ordering::SeqCst));
+    }
+}
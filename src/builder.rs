@@ -0,0 +1,444 @@
+use crate::error::NewHeapError;
+use crate::heap::{FitPolicy, Heap, SizeRounding};
+use crate::managed::ManagedHeap;
+use crate::types::{HalfWord, WORD_SIZE};
+
+/// Collects all settings of a ManagedHeap before creating it.
+///
+/// # Example
+/// ```
+/// use managed_heap::managed::ManagedHeap;
+///
+/// let heap = ManagedHeap::builder()
+///     .initial(64 * 1024)
+///     .max(8 * 1024 * 1024)
+///     .growth_factor(2.0)
+///     .build()
+///     .unwrap();
+///
+/// assert_eq!(64 * 1024, heap.capacity_bytes());
+/// assert_eq!(8 * 1024 * 1024, heap.max_capacity_bytes());
+/// ```
+#[derive(Clone, Debug)]
+pub struct ManagedHeapBuilder {
+    initial: usize,
+    max: Option<usize>,
+    growth_factor: f64,
+    max_segments: usize,
+    fit_policy: FitPolicy,
+    size_rounding: SizeRounding,
+    split_threshold: HalfWord,
+    poisoning: bool,
+    alloc_checks: bool,
+    header_checksums: bool,
+    quarantine: usize,
+    large_object_threshold: HalfWord,
+    gc_threshold: f64,
+    gc_history_len: usize,
+}
+
+impl Default for ManagedHeapBuilder {
+    fn default() -> Self {
+        ManagedHeapBuilder {
+            initial: 0,
+            max: None,
+            growth_factor: 1.0,
+            max_segments: 1,
+            fit_policy: FitPolicy::default(),
+            size_rounding: SizeRounding::default(),
+            split_threshold: Heap::DEFAULT_SPLIT_THRESHOLD,
+            poisoning: false,
+            alloc_checks: false,
+            header_checksums: false,
+            quarantine: 0,
+            large_object_threshold: ManagedHeap::DEFAULT_LARGE_OBJECT_THRESHOLD,
+            gc_threshold: ManagedHeap::DEFAULT_GC_THRESHOLD,
+            gc_history_len: ManagedHeap::DEFAULT_GC_HISTORY_LEN,
+        }
+    }
+}
+
+impl ManagedHeapBuilder {
+    /// The initial size of the heap in bytes. Same as initial_bytes, which
+    /// makes the unit explicit.
+    pub fn initial(self, bytes: usize) -> Self {
+        self.initial_bytes(bytes)
+    }
+
+    /// The initial size of the heap in bytes, which is rounded up to a
+    /// multiple of WORD_SIZE
+    pub fn initial_bytes(mut self, bytes: usize) -> Self {
+        self.initial = bytes;
+        self
+    }
+
+    /// The initial size of the heap in words, the unit of alloc
+    pub fn initial_words(self, words: usize) -> Self {
+        // sizes, which overflow, are rejected by build
+        self.initial_bytes(words.saturating_mul(WORD_SIZE))
+    }
+
+    /// The size in bytes the heap may grow to (defaults to the initial size).
+    /// The memory is reserved up front, so objects never move.
+    pub fn max(mut self, bytes: usize) -> Self {
+        self.max = Some(bytes);
+        self
+    }
+
+    /// If an allocation fails, the heap grows to its current size times the
+    /// factor (or more if the allocation needs it), up to the maximum.
+    /// A factor of 1.0 or less disables growing.
+    pub fn growth_factor(mut self, factor: f64) -> Self {
+        self.growth_factor = factor;
+        self
+    }
+
+    /// The number of memory regions the heap may consist of (defaults to 1).
+    /// Once the maximum is reached, a growing heap chains a new segment of
+    /// its size times the growth factor instead of failing the allocation.
+    pub fn max_segments(mut self, segments: usize) -> Self {
+        self.max_segments = segments;
+        self
+    }
+
+    pub fn fit_policy(mut self, policy: FitPolicy) -> Self {
+        self.fit_policy = policy;
+        self
+    }
+
+    /// See ManagedHeap::set_size_rounding
+    pub fn size_rounding(mut self, rounding: SizeRounding) -> Self {
+        self.size_rounding = rounding;
+        self
+    }
+
+    /// See Heap::set_split_threshold
+    pub fn split_threshold(mut self, words: HalfWord) -> Self {
+        self.split_threshold = words;
+        self
+    }
+
+    /// See ManagedHeap::set_poisoning
+    pub fn poisoning(mut self, enabled: bool) -> Self {
+        self.poisoning = enabled;
+        self
+    }
+
+    /// See ManagedHeap::set_alloc_checks
+    pub fn alloc_checks(mut self, enabled: bool) -> Self {
+        self.alloc_checks = enabled;
+        self
+    }
+
+    /// See ManagedHeap::set_header_checksums
+    pub fn header_checksums(mut self, enabled: bool) -> Self {
+        self.header_checksums = enabled;
+        self
+    }
+
+    /// See ManagedHeap::set_quarantine
+    pub fn quarantine(mut self, blocks: usize) -> Self {
+        self.quarantine = blocks;
+        self
+    }
+
+    pub fn large_object_threshold(mut self, words: HalfWord) -> Self {
+        self.large_object_threshold = words;
+        self
+    }
+
+    /// The fraction of the capacity which has to be used before needs_gc
+    /// returns true
+    pub fn gc_threshold(mut self, fraction: f64) -> Self {
+        self.gc_threshold = fraction;
+        self
+    }
+
+    /// See ManagedHeap::set_gc_history_len
+    pub fn gc_history_len(mut self, len: usize) -> Self {
+        self.gc_history_len = len;
+        self
+    }
+
+    pub fn build(self) -> Result<ManagedHeap, NewHeapError> {
+        let max = self.max.unwrap_or(self.initial);
+        let mut heap = Heap::try_with_max(self.initial, max)?;
+        heap.set_fit_policy(self.fit_policy);
+        heap.set_size_rounding(self.size_rounding);
+        heap.set_split_threshold(self.split_threshold);
+        heap.set_header_checksums(self.header_checksums);
+        heap.set_poisoning(self.poisoning);
+        heap.set_alloc_checks(self.alloc_checks);
+        heap.set_quarantine(self.quarantine);
+
+        let mut heap = ManagedHeap::from_heap(heap);
+        heap.growth_factor = self.growth_factor;
+        heap.max_segments = self.max_segments;
+        heap.gc_threshold = self.gc_threshold;
+        heap.set_large_object_threshold(self.large_object_threshold);
+        heap.set_gc_history_len(self.gc_history_len);
+        Ok(heap)
+    }
+}
+
+impl ManagedHeap {
+    pub fn builder() -> ManagedHeapBuilder {
+        ManagedHeapBuilder::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::trace::GcRoot;
+
+    /// Allocates objects of size words until the allocation fails and
+    /// returns the capacities (in words) seen after every allocation
+    fn fill(heap: &mut ManagedHeap, size: HalfWord) -> Vec<usize> {
+        let mut capacities = vec![heap.total_size()];
+        while heap.alloc(size).is_some() {
+            if capacities.last() != Some(&heap.total_size()) {
+                capacities.push(heap.total_size());
+            }
+        }
+        capacities
+    }
+
+    #[test]
+    fn test_alloc_grows_heap_by_factor_up_to_max() {
+        let mut heap = ManagedHeap::builder()
+            .initial(64 * WORD_SIZE)
+            .max(1024 * WORD_SIZE)
+            .growth_factor(2.0)
+            .build()
+            .unwrap();
+
+        assert_eq!(vec![64, 128, 256, 512, 1024], fill(&mut heap, 9));
+        assert_eq!(102, heap.num_used_blocks());
+        assert_eq!(1024, heap.stats().total_words);
+        assert_eq!(1024, heap.stats().max_words);
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_big_allocation_grows_more_than_factor() {
+        let mut heap = ManagedHeap::builder()
+            .initial(16 * WORD_SIZE)
+            .max(1024 * WORD_SIZE)
+            .growth_factor(1.5)
+            .build()
+            .unwrap();
+
+        heap.alloc(100).unwrap();
+        assert_eq!(117, heap.total_size());
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_zero_growth_behaves_like_fixed_heap() {
+        let mut fixed = ManagedHeap::new(100 * WORD_SIZE);
+        let mut built = ManagedHeap::builder()
+            .initial(100 * WORD_SIZE)
+            .max(1000 * WORD_SIZE)
+            .build()
+            .unwrap();
+
+        assert_eq!(fill(&mut fixed, 4), fill(&mut built, 4));
+        assert_eq!(fixed.num_used_blocks(), built.num_used_blocks());
+        assert_eq!(fixed.stats().total_words, built.stats().total_words);
+        assert_eq!(1000, built.stats().max_words);
+    }
+
+    #[test]
+    fn test_builder_applies_settings() {
+        let mut heap = ManagedHeap::builder()
+            .initial(100 * WORD_SIZE)
+            .fit_policy(FitPolicy::BestFit)
+            .size_rounding(SizeRounding::MultipleOf(2))
+            .split_threshold(10)
+            .quarantine(1)
+            .large_object_threshold(95)
+            .gc_threshold(0.5)
+            .gc_history_len(0)
+            .build()
+            .unwrap();
+
+        assert_eq!(FitPolicy::BestFit, heap.fit_policy());
+        assert_eq!(SizeRounding::MultipleOf(2), heap.size_rounding());
+        assert_eq!(95, heap.large_object_threshold());
+        assert!(heap.alloc(96).is_some_and(|a| heap.is_large_object(a)));
+
+        // the remainder of 9 words is too small to be split off
+        let a = heap.alloc(90).unwrap();
+        assert_eq!(0, heap.num_free_blocks());
+        assert!(heap.needs_gc());
+
+        heap.free(a);
+        assert_eq!(1, heap.num_quarantined_blocks());
+        assert!(!heap.needs_gc());
+
+        heap.gc_addresses(&[], |_, _| {});
+        assert_eq!(None, heap.last_gc());
+    }
+
+    #[test]
+    fn test_sizes_in_bytes_and_words_agree() {
+        let by_bytes = ManagedHeap::builder()
+            .initial_bytes(64 * WORD_SIZE)
+            .max(128 * WORD_SIZE)
+            .build()
+            .unwrap();
+        let by_words = ManagedHeap::builder()
+            .initial_words(64)
+            .max(128 * WORD_SIZE)
+            .build()
+            .unwrap();
+
+        for heap in [by_bytes, by_words].iter() {
+            assert_eq!(64, heap.capacity_words());
+            assert_eq!(64 * WORD_SIZE, heap.capacity_bytes());
+            assert_eq!(
+                heap.capacity_words() * ManagedHeap::word_size(),
+                heap.capacity_bytes()
+            );
+
+            let stats = heap.stats();
+            assert_eq!(heap.capacity_words(), stats.total_words);
+            assert_eq!(heap.capacity_bytes(), stats.total_bytes());
+            assert_eq!(128 * WORD_SIZE, stats.max_bytes());
+            assert_eq!(stats.used_words * WORD_SIZE, stats.used_bytes());
+        }
+    }
+
+    #[test]
+    fn test_unaligned_byte_size_is_rounded_up() {
+        let mut heap = ManagedHeap::builder()
+            .initial_bytes(10 * WORD_SIZE + 1)
+            .build()
+            .unwrap();
+        assert_eq!(11, heap.capacity_words());
+        assert_eq!(11 * WORD_SIZE, heap.capacity_bytes());
+
+        // the whole rounded capacity can be allocated
+        heap.alloc(10).unwrap();
+        assert_eq!(11 * WORD_SIZE, heap.stats().used_bytes());
+    }
+
+    #[test]
+    fn test_build_validates_sizes() {
+        let too_small = ManagedHeap::builder().initial(100).max(50).build();
+        assert_eq!(
+            Some(NewHeapError::MaxBelowInitial {
+                initial: 100,
+                max: 50
+            }),
+            too_small.err()
+        );
+        assert_eq!(
+            Some(NewHeapError::ZeroSize),
+            ManagedHeap::builder().build().err()
+        );
+    }
+
+    #[test]
+    fn test_alloc_chains_segments_beyond_max() {
+        let mut heap = ManagedHeap::builder()
+            .initial(64 * WORD_SIZE)
+            .growth_factor(2.0)
+            .max_segments(3)
+            .build()
+            .unwrap();
+
+        assert_eq!(vec![64, 128, 256], fill(&mut heap, 9));
+        assert_eq!(3, heap.num_segments());
+        assert_eq!(3, heap.stats().segments);
+        assert_eq!(256, heap.stats().total_words);
+        assert_eq!(64, heap.stats().max_words);
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_offsets_of_objects_in_segments() {
+        let mut heap = ManagedHeap::builder()
+            .initial(16 * WORD_SIZE)
+            .growth_factor(2.0)
+            .max_segments(2)
+            .build()
+            .unwrap();
+
+        let addresses: Vec<_> = (0..6).map(|_| heap.alloc(4).unwrap()).collect();
+        assert_eq!(2, heap.num_segments());
+
+        let blocks: Vec<_> = heap.blocks().filter(|b| b.used).collect();
+        assert_eq!(6, blocks.len());
+        for (info, &address) in blocks.iter().zip(&addresses) {
+            assert_eq!(address, info.address);
+            assert_eq!(info.offset + 1, heap.offset_of(address));
+            assert_eq!(Some(address), heap.address_at(heap.offset_of(address)));
+            assert_eq!(
+                format!("heap+{}", info.offset + 1),
+                heap.debug_address(address).to_string()
+            );
+        }
+        assert!(blocks.last().unwrap().offset >= 16);
+    }
+
+    #[test]
+    fn test_gc_with_roots_in_multiple_segments() {
+        let mut heap = ManagedHeap::builder()
+            .initial(32 * WORD_SIZE)
+            .growth_factor(2.0)
+            .max_segments(3)
+            .build()
+            .unwrap();
+
+        // every node needs 4 words, so the list spans all segments
+        let live = list![&mut heap; 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+        let dead = list![&mut heap; 17, 18, 19];
+        let head = list![&mut heap; 0];
+        head.set_next(Some(live));
+        assert_eq!(3, heap.num_segments());
+
+        let mut root = MockGcRoot::new(vec![head]);
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        let stats = heap.gc(&mut roots[..]);
+
+        assert_eq!(3, stats.freed_blocks);
+        assert_eq!(17, heap.num_used_blocks());
+        assert!(heap.used_blocks().all(|b| !b.is_marked()));
+        assert!(dead.iter().all(|n| heap.block_of(n.0).is_none()));
+        assert_eq!(
+            "[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]",
+            format!("{:?}", head)
+        );
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_gc_and_compaction_with_header_checksums() {
+        let mut heap = ManagedHeap::builder()
+            .initial(200 * WORD_SIZE)
+            .header_checksums(true)
+            .poisoning(true)
+            .build()
+            .unwrap();
+        assert!(heap.header_checksums());
+
+        // fills region 0 together with its header and checksum
+        let mut filler = heap.alloc(98).unwrap();
+        filler.write(0);
+        let live = list![&mut heap; 1, 2, 3];
+        let _dead = list![&mut heap; 4, 5];
+        let copy = heap.duplicate(live.0).unwrap();
+
+        let mut root = MockGcRoot::new(vec![live, LinkedList(copy)]);
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        assert_eq!(3, heap.gc(&mut roots[..]).freed_blocks);
+        assert_eq!(4, heap.compact_region(2, 1, &mut roots[..]));
+
+        assert_eq!("[1, 2, 3]", format!("{:?}", root.used_elems[0]));
+        assert_eq!("[1, 2, 3]", format!("{:?}", root.used_elems[1]));
+        assert_eq!(Ok(()), heap.verify());
+    }
+}
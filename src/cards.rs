@@ -0,0 +1,358 @@
+use crate::address::Address;
+use crate::block::Block;
+use crate::managed::ManagedHeap;
+use crate::stats::{GcKind, GcStats};
+use crate::trace::{GcRoot, Traceable};
+use crate::types::WORD_SIZE;
+
+use std::mem;
+
+/// Remembers which parts of the heap contain references from old objects to
+/// young objects.
+/// The heap is divided into cards of CARD_WORDS words. Every write of a
+/// reference into an old object has to dirty the card of the written field
+/// (write_ref does this while the write barrier is enabled).
+#[derive(Clone)]
+pub(crate) struct CardTable {
+    dirty: Vec<bool>,
+}
+
+impl CardTable {
+    pub(crate) const CARD_WORDS: usize = 64;
+
+    pub(crate) fn new(heap_words: usize) -> Self {
+        CardTable {
+            dirty: vec![false; heap_words.div_ceil(CardTable::CARD_WORDS)],
+        }
+    }
+
+    fn dirty_indices(&self) -> impl Iterator<Item = usize> + '_ {
+        self.dirty
+            .iter()
+            .enumerate()
+            .filter(|(_, &dirty)| dirty)
+            .map(|(i, _)| i)
+    }
+
+    fn clean(&mut self) {
+        self.dirty.iter_mut().for_each(|d| *d = false);
+    }
+}
+
+/// A simple generational collector.
+/// Every object starts out young and becomes old when it survives a minor
+/// or full collection. A minor collection only frees young objects. Old objects are
+/// assumed to be live and are only traced if their card is dirty, so every
+/// reference from an old object to a young one has to be recorded with
+/// dirty_card.
+/// Large objects and additional segments are not covered by cards, so old
+/// objects outside of the reserved memory are traced by every minor
+/// collection.
+impl ManagedHeap {
+    /// Marks the card containing address as dirty.
+    /// Addresses outside of the heap are ignored.
+    pub fn dirty_card(&mut self, address: Address) {
+        let start = self.heap.start();
+        let end = start + self.heap.size() * WORD_SIZE;
+        let ptr = usize::from(address);
+
+        if (start..end).contains(&ptr) {
+            let card = (ptr - start) / WORD_SIZE / CardTable::CARD_WORDS;
+            self.cards.dirty[card] = true;
+        }
+    }
+
+    /// The number of cards, which are currently dirty
+    pub fn num_dirty_cards(&self) -> usize {
+        self.cards.dirty_indices().count()
+    }
+
+    /// Checks if the object at address survived a minor collection
+    pub fn is_old(&self, address: Address) -> bool {
+        self.block_of(address).is_some_and(Block::is_old)
+    }
+
+    /// Frees all young objects, which are neither reachable from roots nor
+    /// from an old object in a dirty card, and promotes the remaining young
+    /// objects. Afterwards all cards are clean.
+    /// trace has the same meaning as in gc_addresses.
+    pub fn minor_gc<F>(&mut self, roots: &[Address], mut trace: F) -> GcStats
+    where
+        F: FnMut(Address, &mut dyn FnMut(Address)),
+    {
+        self.gc_abort();
+        self.release_reservations();
+        self.begin_epoch();
+
+        let mut stats = GcStats::default();
+        let mut worklist = mem::take(&mut self.mark_buffer);
+        self.mark_young(roots.iter().copied(), &mut worklist);
+        self.mark_young(self.region_objects(), &mut worklist);
+
+        let start = self.heap.start();
+        let dirty: Vec<usize> = self.cards.dirty_indices().collect();
+        for card in dirty {
+            let card_start = start + card * CardTable::CARD_WORDS * WORD_SIZE;
+            let card_end = card_start + CardTable::CARD_WORDS * WORD_SIZE;
+            stats.scanned_cards += 1;
+
+            let old = self
+                .heap
+                .used_overlapping(card_start, card_end)
+                .filter(|b| b.is_old());
+            for &block in old {
+                trace(Address::from(block), &mut |child| {
+                    self.mark_young(Some(child).into_iter(), &mut worklist)
+                });
+            }
+        }
+        self.cards.clean();
+
+        let uncovered = self
+            .used_blocks()
+            .filter(|&b| b.is_old() && self.checked_offset_of(Address::from(b)).is_none());
+        for block in uncovered {
+            trace(Address::from(block), &mut |child| {
+                self.mark_young(Some(child).into_iter(), &mut worklist)
+            });
+        }
+
+        while let Some(address) = worklist.pop() {
+            trace(address, &mut |child| {
+                self.mark_young(Some(child).into_iter(), &mut worklist)
+            });
+        }
+        self.mark_buffer = worklist;
+
+        let mut freeable = mem::take(&mut self.sweep_buffer);
+        {
+            let reservations = self.reservations();
+            freeable.extend(
+                self.used_blocks()
+                    .filter(|b| !b.is_old() && !b.is_marked_in(self.epoch))
+                    .map(Address::from)
+                    .filter(|&a| !reservations.is_held(a)),
+            );
+        }
+
+        let freed = self.free_all(&mut freeable);
+        self.sweep_buffer = freeable;
+        stats.freed_blocks = freed.freed_blocks;
+        stats.freed_words = freed.freed_words;
+
+        for mut block in self.used_blocks() {
+            stats.live_blocks += 1;
+            stats.live_words += block.size() as usize;
+
+            if block.is_marked_in(self.epoch) {
+                block.set_old(true);
+            }
+            self.clear_stale_mark(block);
+        }
+
+        self.end_epoch(&mut stats, GcKind::Minor);
+        stats
+    }
+
+    /// Like minor_gc, but for Traceable objects. Traceable::trace is used to
+    /// find the children of an object, so it has to return every reference.
+    /// Without the write barrier (see enable_write_barrier), the cards can't
+    /// tell which old objects reference young ones, so every old object is
+    /// traced instead.
+    pub fn gc_young<T>(&mut self, roots: &mut [&mut dyn GcRoot<T>]) -> GcStats
+    where
+        T: Traceable + From<Address> + Into<Address> + Copy,
+    {
+        if !self.write_barrier {
+            self.cards.dirty.iter_mut().for_each(|d| *d = true);
+        }
+
+        let root_addresses: Vec<Address> = roots
+            .iter_mut()
+            .flat_map(|r| r.children())
+            .map(|&mut t| t.into())
+            .collect();
+
+        self.minor_gc(&root_addresses, |address, children| {
+            T::from(address).trace_with(&mut |child| children(*child));
+        })
+    }
+
+    /// Like mark_addresses, but ignores old objects
+    fn mark_young<I>(&self, addresses: I, worklist: &mut Vec<Address>)
+    where
+        I: Iterator<Item = Address>,
+    {
+        let young = addresses.filter(|&a| self.block_of(a).is_some_and(|b| !b.is_old()));
+        self.mark_addresses(young, worklist);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Objects are [reference or 0, value]
+    fn trace(address: Address, children: &mut dyn FnMut(Address)) {
+        if *address != 0 {
+            children(Address::from(*address));
+        }
+    }
+
+    fn new_object(heap: &mut ManagedHeap) -> Address {
+        let mut address = heap.alloc(2).unwrap();
+        address.write(0);
+        address
+    }
+
+    /// Returns an old object, which is not reachable from any root
+    fn promoted_object(heap: &mut ManagedHeap) -> Address {
+        let old = new_object(heap);
+        heap.minor_gc(&[old], trace);
+        assert!(heap.is_old(old));
+        old
+    }
+
+    #[test]
+    fn test_minor_gc_frees_young_garbage_and_promotes_survivors() {
+        let mut heap = ManagedHeap::new(1000);
+        let young = new_object(&mut heap);
+        let garbage = new_object(&mut heap);
+        assert!(!heap.is_old(young));
+
+        let stats = heap.minor_gc(&[young], trace);
+        assert_eq!(1, stats.freed_blocks);
+        assert_eq!(1, stats.live_blocks);
+        assert!(heap.is_old(young));
+        assert!(heap.block_of(garbage).is_none());
+
+        // old objects survive minor collections without roots
+        let stats = heap.minor_gc(&[], trace);
+        assert_eq!(0, stats.freed_blocks);
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_dirty_card_keeps_young_object_alive() {
+        let mut heap = ManagedHeap::new(1000);
+        let old = promoted_object(&mut heap);
+        let young = new_object(&mut heap);
+
+        heap.enable_write_barrier();
+        heap.write_ref(old, 0, young);
+        assert_eq!(1, heap.num_dirty_cards());
+
+        let stats = heap.minor_gc(&[], trace);
+        assert_eq!(1, stats.scanned_cards);
+        assert_eq!(0, stats.freed_blocks);
+        assert!(heap.is_old(young));
+        assert_eq!(0, heap.num_dirty_cards());
+    }
+
+    #[test]
+    fn test_young_object_is_freed_without_dirty_card() {
+        let mut heap = ManagedHeap::new(1000);
+        let mut old = promoted_object(&mut heap);
+        let young = new_object(&mut heap);
+
+        // the write bypasses the barrier
+        old.write(young.into());
+
+        let stats = heap.minor_gc(&[], trace);
+        assert_eq!(0, stats.scanned_cards);
+        assert_eq!(1, stats.freed_blocks);
+        assert!(heap.block_of(young).is_none());
+    }
+
+    #[test]
+    fn test_clean_cards_are_not_scanned_again() {
+        let mut heap = ManagedHeap::new(4000);
+        let old = promoted_object(&mut heap);
+        let young = new_object(&mut heap);
+
+        heap.dirty_card(old);
+        heap.dirty_card(old + 1);
+        heap.dirty_card(Address::from(0));
+        (old + 0).write(young.into());
+
+        let stats = heap.minor_gc(&[], trace);
+        assert_eq!(1, stats.scanned_cards);
+
+        let stats = heap.minor_gc(&[], trace);
+        assert_eq!(0, stats.scanned_cards);
+        assert_eq!(2, stats.live_blocks);
+    }
+
+    #[test]
+    fn test_freed_blocks_are_young_when_reused() {
+        let mut heap = ManagedHeap::new(1000);
+        let old = promoted_object(&mut heap);
+        heap.free(old);
+
+        let reused = new_object(&mut heap);
+        assert_eq!(old, reused);
+        assert!(!heap.is_old(reused));
+    }
+
+    mod gc_young {
+        use super::*;
+        use crate::testing::*;
+
+        fn full_gc(heap: &mut ManagedHeap, live: Vec<LinkedList>) -> GcStats {
+            let mut root = MockGcRoot::new(live);
+            let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+            heap.gc(&mut roots[..])
+        }
+
+        fn young_gc(heap: &mut ManagedHeap, live: Vec<LinkedList>) -> GcStats {
+            let mut root = MockGcRoot::new(live);
+            let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+            heap.gc_young(&mut roots[..])
+        }
+
+        #[test]
+        fn test_old_garbage_survives_until_full_gc() {
+            let mut heap = ManagedHeap::new(1000);
+            let old_garbage = list![&mut heap; 1];
+            full_gc(&mut heap, vec![old_garbage]);
+            assert!(heap.is_old(old_garbage.0));
+
+            let young_garbage = list![&mut heap; 2];
+            let live = list![&mut heap; 3, 4];
+            let stats = young_gc(&mut heap, vec![live]);
+
+            assert_eq!(1, stats.freed_blocks);
+            assert_eq!(GcKind::Minor, stats.kind);
+            assert!(heap.block_of(young_garbage.0).is_none());
+            assert!(heap.block_of(old_garbage.0).is_some());
+
+            assert_eq!(1, full_gc(&mut heap, vec![live]).freed_blocks);
+            assert!(heap.block_of(old_garbage.0).is_none());
+            assert_eq!("[3, 4]", format!("{:?}", live));
+        }
+
+        #[test]
+        fn test_survivors_of_young_gc_become_old() {
+            let mut heap = ManagedHeap::new(1000);
+            let list = list![&mut heap; 1, 2];
+            assert!(list.iter().all(|n| !heap.is_old(n.0)));
+
+            young_gc(&mut heap, vec![list]);
+            assert!(list.iter().all(|n| heap.is_old(n.0)));
+        }
+
+        #[test]
+        fn test_young_objects_referenced_by_old_ones_survive() {
+            let mut heap = ManagedHeap::new(1000);
+            let old = list![&mut heap; 1];
+            young_gc(&mut heap, vec![old]);
+
+            // no write barrier, so the old object is traced anyway
+            let young = list![&mut heap; 2];
+            old.set_next(Some(young));
+            assert_eq!(0, young_gc(&mut heap, vec![old]).freed_blocks);
+            assert!(heap.is_old(young.0));
+            assert_eq!("[1, 2]", format!("{:?}", old));
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use crate::address::Address;
+use crate::managed::ManagedHeap;
+use crate::stats::Census;
+use crate::types::HalfWord;
+
+/// Gets called with the heap, the address and the block size (in words,
+/// including the header) of every live object and returns its class, e.g.
+/// by reading a type id with ManagedHeap::read (see
+/// ManagedHeap::set_gc_census).
+pub type CensusClassifier = Box<dyn Fn(&ManagedHeap, Address, HalfWord) -> u32 + Send>;
+
+impl ManagedHeap {
+    /// Counts the used blocks (including large objects) and their words per
+    /// class. classify gets called with the address and the block size (in
+    /// words, including the header) of every object. It can read the
+    /// payload with read, which fails instead of reading outside of the
+    /// object.
+    /// The chunks of a huge allocation are counted as a single object.
+    pub fn census(&self, classify: impl Fn(Address, HalfWord) -> u32) -> Census {
+        let mut census = Census {
+            epoch: self.epoch,
+            ..Census::default()
+        };
+
+        for block in self.used_blocks() {
+            let address = Address::from(block);
+            if self.is_huge_tail(address) {
+                continue;
+            }
+
+            let class = census
+                .classes
+                .entry(classify(address, block.size()))
+                .or_default();
+            class.objects += 1;
+            class.words += block.size() as usize + self.huge_tail_words(address);
+        }
+
+        census
+    }
+
+    /// Takes a census with classify at the end of every collection (see
+    /// last_census), e.g. to see which kinds of objects survive. The census
+    /// allocates, so collections are slower while this is enabled.
+    pub fn set_gc_census(&mut self, classify: CensusClassifier) {
+        self.census_classifier = Some(classify);
+    }
+
+    /// Stops taking a census after every collection and returns the
+    /// classifier
+    pub fn take_gc_census(&mut self) -> Option<CensusClassifier> {
+        self.census_classifier.take()
+    }
+
+    /// The census of the last collection. Census::epoch is the epoch of the
+    /// collection (see GcStats::epoch).
+    pub fn last_census(&self) -> Option<&Census> {
+        self.last_census.as_ref()
+    }
+
+    /// Takes the census of a finished collection, if it is enabled
+    pub(crate) fn record_census(&mut self) {
+        if let Some(classify) = self.census_classifier.take() {
+            let census = self.census(|address, size| classify(self, address, size));
+            self.last_census = Some(census);
+            self.census_classifier = Some(classify);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::ClassStats;
+
+    const STRING: usize = 1;
+    const ARRAY: usize = 2;
+
+    /// Allocates an object with size words, whose first word is its type id
+    fn object(heap: &mut ManagedHeap, type_id: usize, size: HalfWord) -> Address {
+        let address = heap.alloc(size).unwrap();
+        heap.write(address, 0, type_id).unwrap();
+        address
+    }
+
+    fn type_id(heap: &ManagedHeap, address: Address) -> u32 {
+        heap.read(address, 0).map_or(u32::MAX, |id| id as u32)
+    }
+
+    #[test]
+    fn test_census_counts_live_objects_per_class() {
+        let mut heap = ManagedHeap::new(1000);
+        let string = object(&mut heap, STRING, 2);
+        object(&mut heap, STRING, 4);
+        let small_array = object(&mut heap, ARRAY, 3);
+        let big_array = object(&mut heap, ARRAY, 5);
+        object(&mut heap, ARRAY, 7);
+
+        heap.gc_addresses(&[string, small_array, big_array], |_, _| {});
+        let census = heap.census(|address, _| type_id(&heap, address));
+
+        assert_eq!(
+            ClassStats {
+                objects: 1,
+                words: 3
+            },
+            census.get(STRING as u32)
+        );
+        assert_eq!(
+            ClassStats {
+                objects: 2,
+                words: 10
+            },
+            census.get(ARRAY as u32)
+        );
+        assert_eq!(ClassStats::default(), census.get(3));
+        assert_eq!(heap.num_used_blocks(), census.total_objects());
+        assert_eq!(heap.used_size(), census.total_words());
+    }
+
+    #[test]
+    fn test_census_after_every_collection() {
+        let mut heap = ManagedHeap::new(1000);
+        heap.set_large_object_threshold(10);
+        let string = object(&mut heap, STRING, 2);
+        let large = object(&mut heap, ARRAY, 20);
+        object(&mut heap, ARRAY, 3);
+        assert_eq!(None, heap.last_census());
+
+        heap.set_gc_census(Box::new(|heap, address, _| type_id(heap, address)));
+        let stats = heap.gc_addresses(&[string, large], |_, _| {});
+
+        let census = heap.last_census().unwrap();
+        assert_eq!(stats.epoch, census.epoch);
+        assert_eq!(1, census.get(STRING as u32).objects);
+        assert_eq!(
+            ClassStats {
+                objects: 1,
+                words: 21
+            },
+            census.get(ARRAY as u32)
+        );
+
+        heap.free(string);
+        assert!(heap.take_gc_census().is_some());
+        heap.gc_addresses(&[], |_, _| {});
+        // the census of the last collection with a classifier stays
+        assert_eq!(stats.epoch, heap.last_census().unwrap().epoch);
+    }
+}
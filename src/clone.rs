@@ -0,0 +1,192 @@
+use crate::address::Address;
+use crate::block::Block;
+use crate::managed::ManagedHeap;
+
+impl ManagedHeap {
+    /// Copies the heap into new memory, e.g. to run code speculatively
+    /// against the copy and drop it afterwards.
+    /// Returns the copy and its distance in bytes from this heap. The objects
+    /// of the copy keep their offsets, but the references stored in them
+    /// still point into this heap and have to be moved by the distance (see
+    /// clone_heap_with).
+    /// The settings, reference counts, tags, contexts, weak table entries,
+    /// dirty cards and huge allocations are copied as well. Large objects,
+    /// HeapBoxes, callbacks, registered roots, open regions, the leak check
+    /// and a running incremental collection are not.
+    /// Panics if the heap has additional segments.
+    pub fn clone_heap(&self) -> (ManagedHeap, isize) {
+        let (heap, delta) = self.heap.duplicate();
+        let relocate = |address: usize| address.wrapping_add_signed(delta);
+
+        let mut clone = ManagedHeap::from_heap(heap);
+        clone.set_large_object_threshold(self.large_object_threshold());
+        clone.growth_factor = self.growth_factor;
+        clone.max_segments = self.max_segments;
+        clone.gc_threshold = self.gc_threshold;
+        clone.soft_limit = self.soft_limit;
+        clone.hard_limit = self.hard_limit;
+        clone.write_barrier = self.write_barrier;
+        clone.cards = self.cards.clone();
+        clone.epoch = self.epoch;
+        clone.marking = self.marking;
+        clone.gc_history = self.gc_history.clone();
+        clone.gc_history_len = self.gc_history_len;
+        clone.words_since_gc = self.words_since_gc;
+        clone.trace_timing = self.trace_timing;
+        clone.skip_unclassifiable = self.skip_unclassifiable;
+        clone.strict = self.strict;
+        clone.defrag_policy = self.defrag_policy;
+
+        clone.rc_objects = self.rc_objects.iter().map(|&a| relocate(a)).collect();
+        clone.tags = self.tags.as_ref().map(|tags| {
+            tags.iter()
+                .map(|(&address, &tag)| (relocate(address), tag))
+                .collect()
+        });
+        clone.alloc_context = self.alloc_context;
+        clone.contexts = self.contexts.as_ref().map(|contexts| {
+            contexts
+                .iter()
+                .map(|(&address, &context)| (relocate(address), context))
+                .collect()
+        });
+
+        for (key, value) in self.weak_table.iter() {
+            if self.heap.block_of(key).is_some() {
+                let key = Address::from(relocate(key.into()));
+                clone.weak_table.insert(key, value);
+            }
+        }
+
+        // chains with chunks in the large object space lose those chunks
+        for (&head, tails) in self.huge_chains.iter() {
+            let in_heap = |address: usize| self.heap.block_of(Address::from(address)).is_some();
+            if in_heap(head) && tails.iter().all(|&t| in_heap(t.into())) {
+                let tails: Vec<Address> = tails
+                    .iter()
+                    .map(|&t| Address::from(relocate(t.into())))
+                    .collect();
+                clone
+                    .huge_tails
+                    .extend(tails.iter().map(|&t| usize::from(t)));
+                clone.huge_chains.insert(relocate(head), tails);
+            }
+        }
+        clone.huge_chunk_words = self.huge_chunk_words;
+
+        // the children of dirty pairs may be large objects
+        let relocate_address = |address: Address| {
+            if self.block_of(address).is_some() {
+                Address::from(relocate(address.into()))
+            } else {
+                address
+            }
+        };
+        clone.dirty = self
+            .dirty
+            .iter()
+            .map(|&(parent, child)| (relocate_address(parent), relocate_address(child)))
+            .collect();
+
+        (clone, delta)
+    }
+
+    /// Like clone_heap, but also moves the references of the copied objects.
+    /// trace_all has the same meaning as in save_image and gets called with
+    /// the objects of the copy. References, which don't point into a used
+    /// block of this heap (e.g. to large objects), are left unchanged.
+    pub fn clone_heap_with<F>(&self, mut trace_all: F) -> ManagedHeap
+    where
+        F: FnMut(Address, &mut dyn FnMut(&mut usize)),
+    {
+        let (clone, delta) = self.clone_heap();
+        let blocks: Vec<Block> = clone.heap.used().collect();
+
+        for block in blocks {
+            trace_all(Address::from(block), &mut |field| {
+                if *field != 0 && self.block_of(Address::from(*field)).is_some() {
+                    *field = field.wrapping_add_signed(delta);
+                }
+            });
+        }
+
+        clone
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::types::WORD_SIZE;
+
+    #[test]
+    fn test_clone_is_independent_of_original() {
+        let mut heap = ManagedHeap::new(1000);
+        let mut a = heap.alloc(2).unwrap();
+        a.write(1);
+        (a + 1).write(2);
+        heap.alloc(3).unwrap();
+
+        let (mut clone, delta) = heap.clone_heap();
+        let copy = Address::from(usize::from(a).wrapping_add_signed(delta));
+        assert_eq!(2, *(copy + 1));
+        assert_eq!(heap.num_used_blocks(), clone.num_used_blocks());
+        assert_eq!(heap.used_size(), clone.used_size());
+        assert_eq!(Ok(()), clone.verify());
+
+        (copy + 1).write(42);
+        clone.free(copy);
+        clone.alloc(10).unwrap();
+
+        assert_eq!(2, *(a + 1));
+        assert_eq!(2, heap.num_used_blocks());
+        assert!(heap.block_of(a).is_some());
+        assert_eq!(Ok(()), heap.verify());
+        assert_eq!(Ok(()), clone.verify());
+        assert_eq!(1000 / WORD_SIZE, clone.total_size());
+    }
+
+    fn relocated(list: LinkedList, delta: isize) -> LinkedList {
+        LinkedList(Address::from(
+            usize::from(list.0).wrapping_add_signed(delta),
+        ))
+    }
+
+    #[test]
+    fn test_linked_list_is_usable_after_applying_delta() {
+        let mut heap = ManagedHeap::new(1000);
+        let _garbage = list![&mut heap; 7];
+        let list = list![&mut heap; 1, 2, 3];
+
+        let (clone, delta) = heap.clone_heap();
+        let head = relocated(list, delta);
+        let mut node = head;
+        while let Some(next) = node.next() {
+            let next = relocated(next, delta);
+            node.set_next(Some(next));
+            node = next;
+        }
+        drop(heap);
+
+        assert_eq!("[1, 2, 3]", format!("{:?}", head));
+        assert!(head.iter().all(|n| clone.block_of(n.0).is_some()));
+    }
+
+    #[test]
+    fn test_clone_heap_with_moves_references() {
+        let mut heap = ManagedHeap::new(1000);
+        let list = list![&mut heap; 1, 2, 3];
+        let offset = heap.offset_of(list.0);
+
+        let clone =
+            heap.clone_heap_with(|address, fields| fields(unsafe { &mut *(address + 2).as_mut() }));
+        list.set_next(None);
+        drop(heap);
+
+        let head = LinkedList(clone.address_at(offset).unwrap());
+        assert_eq!("[1, 2, 3]", format!("{:?}", head));
+        assert!(head.iter().all(|n| clone.block_of(n.0).is_some()));
+        assert_eq!(Ok(()), clone.verify());
+    }
+}
@@ -0,0 +1,522 @@
+use crate::address::Address;
+use crate::block::Block;
+use crate::managed::ManagedHeap;
+use crate::stats::{CellState, RegionStats};
+use crate::trace::{GcRoot, Traceable};
+use crate::types::{HalfWord, WORD_SIZE};
+
+use std::ops::Range;
+
+/// A moved object
+struct Forward {
+    old: Address,
+    words: usize,
+    new: Address,
+}
+
+/// An object moved by ManagedHeap::relocate
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Relocation {
+    pub old: Address,
+    pub new: Address,
+}
+
+/// Replaces every reference, which is the old address of one of the
+/// relocations, with the new one. Pointers into the middle of a relocated
+/// object are not changed.
+/// Returns the number of changed references.
+pub fn apply_relocations<'a, I>(relocations: &[Relocation], references: I) -> usize
+where
+    I: IntoIterator<Item = &'a mut Address>,
+{
+    let mut changed = 0;
+    for reference in references {
+        if let Some(r) = relocations.iter().find(|r| r.old == *reference) {
+            *reference = r.new;
+            changed += 1;
+        }
+    }
+    changed
+}
+
+/// Maps the addresses of moved objects to their new location.
+/// Pointers into the middle of a moved object are moved as well.
+struct ForwardingTable(Vec<Forward>);
+
+impl ForwardingTable {
+    fn forward(&self, address: Address) -> Option<Address> {
+        let index = self
+            .0
+            .partition_point(|f| f.old <= address)
+            .checked_sub(1)?;
+        let forward = &self.0[index];
+        let offset = (usize::from(address) - usize::from(forward.old)) / WORD_SIZE;
+
+        if offset < forward.words {
+            Some(forward.new + offset)
+        } else {
+            None
+        }
+    }
+}
+
+/// Fragmentation analysis and compaction of parts of the heap.
+/// The heap is divided into a number of regions of equal size. Large objects
+/// are not part of any region.
+impl ManagedHeap {
+    /// Divides the heap into buckets regions and reports their usage
+    pub fn fragmentation_map(&self, buckets: usize) -> Vec<RegionStats> {
+        let mut regions: Vec<RegionStats> = (0..buckets)
+            .map(|i| {
+                let words = self.region_words(buckets, i);
+                RegionStats {
+                    start: words.start,
+                    words: words.len(),
+                    ..RegionStats::default()
+                }
+            })
+            .collect();
+
+        for block in self.heap.blocks() {
+            let start = self.heap.offset_of(block.as_ptr());
+            let end = start + block.size() as usize;
+            let used = self.heap.is_allocated(block);
+
+            for region in regions.iter_mut() {
+                let region_end = region.start + region.words;
+                let overlap = end.min(region_end).saturating_sub(start.max(region.start));
+                let starts_here = (region.start..region_end).contains(&start);
+
+                if used {
+                    region.used_words += overlap;
+                    region.used_blocks += starts_here as usize;
+                } else {
+                    region.free_words += overlap;
+                    region.free_blocks += starts_here as usize;
+                }
+            }
+        }
+
+        regions
+    }
+
+    /// Divides the heap into granules of granularity words and returns the
+    /// state of each of them, e.g. to draw the heap. The last granule may be
+    /// smaller than the others.
+    /// Panics if granularity_words is 0.
+    pub fn occupancy_bitmap(&self, granularity_words: usize) -> Vec<CellState> {
+        let mut cells = Vec::with_capacity(self.heap.size().div_ceil(granularity_words.max(1)));
+        self.occupancy_with(granularity_words, |cell| cells.push(cell));
+        cells
+    }
+
+    /// Like occupancy_bitmap, but calls f with the state of each granule in
+    /// address order instead of collecting them. This doesn't allocate.
+    pub fn occupancy_with<F: FnMut(CellState)>(&self, granularity_words: usize, mut f: F) {
+        assert!(granularity_words > 0, "The granularity must not be 0");
+        let size = self.heap.size();
+
+        let mut cell_start: usize = 0;
+        let mut cell: Option<CellState> = None;
+        for block in self.heap.blocks() {
+            let mut start = self.heap.offset_of(block.as_ptr());
+            // the blocks of additional segments start after the reserved memory
+            if start >= size {
+                break;
+            }
+            let end = (start + block.size() as usize).min(size);
+            let state = if self.heap.is_allocated(block) {
+                CellState::Used
+            } else {
+                CellState::Free
+            };
+
+            // a block can cover many granules and a granule many blocks
+            while start < end {
+                let cell_end = cell_start.saturating_add(granularity_words).min(size);
+                cell = Some(cell.map_or(state, |c| c.merge(state)));
+
+                if end >= cell_end {
+                    f(cell.take().unwrap());
+                    cell_start = cell_end;
+                    start = cell_end;
+                } else {
+                    start = end;
+                }
+            }
+        }
+    }
+
+    /// Moves all used blocks, which start in the region with region_index
+    /// (see fragmentation_map), into free blocks outside of that region.
+    /// All references to the moved objects are updated by tracing every
+    /// object and by updating the roots.
+    /// Blocks which don't fit anywhere else, reserved blocks, objects of
+    /// registered roots (see register_root), objects of open regions, huge
+    /// allocations and HeapBoxes of values with an alignment above WORD_SIZE
+    /// are not moved. Large objects never move.
+    /// Returns the number of moved objects.
+    pub fn compact_region<T>(
+        &mut self,
+        buckets: usize,
+        region_index: usize,
+        roots: &mut [&mut dyn GcRoot<T>],
+    ) -> usize
+    where
+        T: Traceable + From<Address> + Into<Address> + Copy,
+    {
+        if region_index >= buckets {
+            return 0;
+        }
+
+        self.gc_abort();
+        self.release_reservations();
+
+        let words = self.region_words(buckets, region_index);
+        let start = self.heap.start() + words.start * WORD_SIZE;
+        let end = self.heap.start() + words.end * WORD_SIZE;
+
+        let candidates: Vec<Block> = {
+            let reservations = self.reservations();
+            let rooted = self.registered_root_objects();
+            self.heap
+                .used()
+                .filter(|b| (start..end).contains(&(b.as_ptr() as usize)))
+                .filter(|&b| !reservations.is_held(Address::from(b)))
+                .filter(|&b| !rooted.contains(&Address::from(b)))
+                .filter(|&b| !self.in_region(Address::from(b)))
+                .filter(|&b| !self.is_huge_chunk(Address::from(b)))
+                .filter(|&b| self.is_movable(Address::from(b)))
+                .collect()
+        };
+
+        let mut forwards = Vec::with_capacity(candidates.len());
+        for block in candidates {
+            // the header (and the checksum) are not copied
+            let payload = self.heap.payload_words(block);
+            let mut new = match self.heap.alloc_outside(payload, start, end) {
+                Some(new) => new,
+                None => continue,
+            };
+
+            let old = Address::from(block);
+            old.copy_to(&mut new, payload as usize);
+            forwards.push(Forward {
+                old,
+                words: payload as usize,
+                new,
+            });
+        }
+
+        for forward in forwards.iter() {
+            self.move_side_tables(forward.old, forward.new);
+            self.heap.free(forward.old);
+        }
+
+        let table = ForwardingTable(forwards);
+        self.update_references::<T>(&table, roots);
+        table.0.len()
+    }
+
+    /// Moves the object at from into a new block with to_size_hint words (or
+    /// its current size) and frees the old block. Only min(old size, new
+    /// size) words are copied. The reference count, tag, context, weak table
+    /// entry, HeapBox destructor and generation of the object move with it.
+    /// References to the object are not updated, the caller has to fix them
+    /// (see apply_relocations) before the next collection.
+    /// Returns None without changing anything, if from is not the start of
+    /// an object, is a large object, is reserved, is an object of a registered
+    /// root, belongs to an open region or a huge allocation, holds a value
+    /// with an alignment above WORD_SIZE (see HeapBox), or if there is not
+    /// enough space.
+    pub fn relocate(
+        &mut self,
+        from: Address,
+        to_size_hint: Option<HalfWord>,
+    ) -> Option<Relocation> {
+        let block = self
+            .heap
+            .block_of(from)
+            .filter(|&block| Address::from(block) == from)?;
+        if self.reservations().is_held(from)
+            || self.registered_root_objects().contains(&from)
+            || self.in_region(from)
+            || self.is_huge_chunk(from)
+            || !self.is_movable(from)
+        {
+            return None;
+        }
+
+        let size = self.heap.payload_words(block) - self.heap.slack_of(from);
+        let new_size = to_size_hint.unwrap_or(size);
+        let mut new = self.alloc(new_size)?;
+        from.copy_to(&mut new, size.min(new_size) as usize);
+        if let Some(mut new_block) = self.block_of(new) {
+            new_block.set_old(block.is_old());
+        }
+
+        // an incremental collection would still know the old address
+        self.gc_abort();
+        self.move_side_tables(from, new);
+        self.heap.free(from);
+        Some(Relocation { old: from, new })
+    }
+
+    /// Moves the entries of the bookkeeping, which is keyed by address
+    fn move_side_tables(&mut self, old: Address, new: Address) {
+        // the count is not copied, if it lies in the slack or the new block
+        // has a different size
+        if let Ok(count) = self.rc_count(old) {
+            self.rc_objects.remove(&old.into());
+            self.rc_objects.insert(new.into());
+            self.set_rc_count(new, count);
+        }
+
+        if let Some(tags) = self.tags.as_mut() {
+            if let Some(tag) = tags.remove(&old.into()) {
+                tags.insert(new.into(), tag);
+            }
+        }
+
+        if let Some(contexts) = self.contexts.as_mut() {
+            if let Some(context) = contexts.remove(&old.into()) {
+                contexts.insert(new.into(), context);
+            }
+        }
+        self.weak_table.relocate(old, new);
+
+        if let Some(dropper) = self.droppers.remove(&old.into()) {
+            self.droppers.insert(new.into(), dropper);
+        }
+    }
+
+    fn update_references<T>(&mut self, table: &ForwardingTable, roots: &mut [&mut dyn GcRoot<T>])
+    where
+        T: Traceable + From<Address> + Into<Address> + Copy,
+    {
+        for root in roots.iter_mut().flat_map(|r| r.children()) {
+            if let Some(new) = table.forward((*root).into()) {
+                *root = T::from(new);
+            }
+        }
+
+        for block in self.objects() {
+            let mut object = T::from(Address::from(block));
+            object.trace_with(&mut |child| {
+                if let Some(new) = table.forward(*child) {
+                    *child = new;
+                }
+            });
+        }
+    }
+
+    /// The range of words covered by the region with index
+    fn region_words(&self, buckets: usize, index: usize) -> Range<usize> {
+        let size = self.heap.size();
+        (index * size / buckets)..((index + 1) * size / buckets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    // two regions of 100 words each
+    const HEAP_SIZE: usize = 200 * WORD_SIZE;
+
+    #[test]
+    fn test_fragmentation_map_of_empty_heap() {
+        let heap = ManagedHeap::new(HEAP_SIZE);
+        let map = heap.fragmentation_map(4);
+
+        assert_eq!(4, map.len());
+        assert_eq!(1, map[0].free_blocks);
+        assert_eq!(0, map[1].free_blocks);
+        assert!(map.iter().all(|r| r.free_words == 50 && r.used_words == 0));
+        assert_eq!(150, map[3].start);
+        assert!(heap.fragmentation_map(0).is_empty());
+    }
+
+    #[test]
+    fn test_occupancy_bitmap_of_known_layout() {
+        use CellState::*;
+
+        let mut heap = ManagedHeap::new(16 * WORD_SIZE);
+        // [a: 3 words][b: 3 words][c: 4 words][free: 6 words]
+        heap.alloc(2).unwrap();
+        let b = heap.alloc(2).unwrap();
+        heap.alloc(3).unwrap();
+        heap.free(b);
+
+        let mut words = vec![Used; 3];
+        words.extend([Free; 3]);
+        words.extend([Used; 4]);
+        words.extend([Free; 6]);
+        assert_eq!(words, heap.occupancy_bitmap(1));
+
+        assert_eq!(vec![Mixed, Mixed, Mixed, Free], heap.occupancy_bitmap(4));
+        assert_eq!(vec![Mixed], heap.occupancy_bitmap(16));
+        assert_eq!(vec![Mixed], heap.occupancy_bitmap(1000));
+        assert_eq!(vec![Mixed, Free], heap.occupancy_bitmap(10));
+
+        let mut cells = 0;
+        heap.occupancy_with(3, |cell| {
+            assert_eq!([Used, Free, Used, Mixed, Free, Free][cells], cell);
+            cells += 1;
+        });
+        assert_eq!(6, cells);
+    }
+
+    #[test]
+    fn test_occupancy_bitmap_of_empty_heap() {
+        let heap = ManagedHeap::new(HEAP_SIZE);
+        assert_eq!(vec![CellState::Free; 4], heap.occupancy_bitmap(50));
+        assert_eq!(vec![CellState::Free; 3], heap.occupancy_bitmap(70));
+    }
+
+    #[test]
+    fn test_compact_checkerboarded_region() {
+        let mut heap = ManagedHeap::new(HEAP_SIZE);
+
+        // 25 nodes of 4 words fill region 0, every second one is garbage
+        let mut head = None;
+        let mut garbage = Vec::new();
+        for i in 0..25 {
+            if i % 2 == 0 {
+                head = Some(LinkedList::new(&mut heap, i, head));
+            } else {
+                garbage.push(LinkedList::new(&mut heap, i, None));
+            }
+        }
+        let head = head.unwrap();
+
+        let clean = list![&mut heap; 100, 101, 102];
+        let clean_addresses: Vec<Address> = clean.iter().map(|n| n.0).collect();
+
+        for node in garbage {
+            heap.free(node.0);
+        }
+
+        let before = heap.fragmentation_map(2);
+        assert_eq!(13, before[0].used_blocks);
+        assert_eq!(12, before[0].free_blocks);
+        assert_eq!(3, before[1].used_blocks);
+
+        let mut root = MockGcRoot::new(vec![head, clean]);
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        assert_eq!(13, heap.compact_region(2, 0, &mut roots[..]));
+
+        let after = heap.fragmentation_map(2);
+        assert_eq!(0, after[0].used_blocks);
+        assert_eq!(1, after[0].free_blocks);
+        assert_eq!(100, after[0].free_words);
+        assert_eq!(16, after[1].used_blocks);
+
+        let head = root.used_elems[0];
+        let values: Vec<isize> = head.iter().map(|n| n.value()).collect();
+        assert_eq!((0..25).step_by(2).rev().collect::<Vec<isize>>(), values);
+        assert!(head
+            .iter()
+            .all(|n| usize::from(n.0) >= heap.heap.start() + 100 * WORD_SIZE));
+
+        let clean = root.used_elems[1];
+        assert_eq!(
+            clean_addresses,
+            clean.iter().map(|n| n.0).collect::<Vec<_>>()
+        );
+        assert_eq!("[100, 101, 102]", format!("{:?}", clean));
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_compact_region_without_space_moves_nothing() {
+        let mut heap = ManagedHeap::new(HEAP_SIZE);
+        let node = LinkedList::new(&mut heap, 1, None);
+        // fills the rest of the heap
+        heap.alloc(195).unwrap();
+
+        let mut root = MockGcRoot::new(vec![node]);
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+
+        assert_eq!(0, heap.compact_region(2, 0, &mut roots[..]));
+        assert_eq!(0, heap.compact_region(2, 2, &mut roots[..]));
+        assert_eq!(node.0, root.used_elems[0].0);
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_relocate_middle_node_of_list() {
+        let mut heap = ManagedHeap::new(HEAP_SIZE);
+        let list = list![&mut heap; 1, 2, 3];
+        let middle = list.next().unwrap();
+
+        let relocation = heap.relocate(middle.0, None).unwrap();
+        assert_eq!(middle.0, relocation.old);
+        assert_ne!(relocation.old, relocation.new);
+        assert!(heap.block_of(relocation.old).is_none());
+
+        let mut changed = 0;
+        for block in heap.used_blocks().collect::<Vec<_>>() {
+            let mut node = LinkedList(Address::from(block));
+            node.trace_with(&mut |next| changed += apply_relocations(&[relocation], Some(next)));
+        }
+        assert_eq!(1, changed);
+        assert_eq!("[1, 2, 3]", format!("{:?}", list));
+
+        let _garbage = list![&mut heap; 4];
+        let mut root = MockGcRoot::new(vec![list]);
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        assert_eq!(1, heap.gc(&mut roots[..]).freed_blocks);
+        assert_eq!("[1, 2, 3]", format!("{:?}", list));
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_failed_relocation_changes_nothing() {
+        let mut heap = ManagedHeap::new(HEAP_SIZE);
+        let list = list![&mut heap; 1, 2, 3];
+        let middle = list.next().unwrap();
+        while heap.alloc(1).is_some() {}
+
+        let layout: Vec<_> = heap.blocks().collect();
+        assert_eq!(None, heap.relocate(middle.0, None));
+        assert_eq!(None, heap.relocate(middle.0 + 1, None));
+        assert_eq!(layout, heap.blocks().collect::<Vec<_>>());
+        assert_eq!("[1, 2, 3]", format!("{:?}", list));
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_relocate_to_bigger_block() {
+        let mut heap = ManagedHeap::new(HEAP_SIZE);
+        let node = list![&mut heap; 7];
+        let mut roots = [node.0];
+
+        let relocation = heap.relocate(node.0, Some(10)).unwrap();
+        assert_eq!(1, apply_relocations(&[relocation], roots.iter_mut()));
+        assert_eq!(relocation.new, roots[0]);
+        assert_eq!(7, LinkedList(roots[0]).value());
+        assert_eq!(11, heap.block_of(roots[0]).unwrap().size());
+    }
+
+    #[test]
+    fn test_relocate_moves_the_reference_count() {
+        let mut heap = ManagedHeap::new(HEAP_SIZE);
+        let hole = heap.alloc(4).unwrap();
+        heap.alloc(2).unwrap();
+        heap.free(hole);
+
+        // the count lies in the slack, which is not copied
+        let rc = heap.rc_alloc(1).unwrap();
+        assert_eq!(hole, rc);
+        assert_eq!(Ok(2), heap.rc_inc(rc));
+        let relocation = heap.relocate(rc, None).unwrap();
+        assert_eq!(Ok(2), heap.rc_count(relocation.new));
+
+        let relocation = heap.relocate(relocation.new, Some(10)).unwrap();
+        assert_eq!(Ok(2), heap.rc_count(relocation.new));
+        assert_eq!(Ok(1), heap.rc_dec(relocation.new));
+        assert_eq!(Ok(()), heap.verify());
+    }
+}
@@ -0,0 +1,222 @@
+use crate::address::Address;
+use crate::managed::ManagedHeap;
+use crate::stats::{AllocFailureReason, DefragReport};
+use crate::types::HalfWord;
+
+/// Moves objects to merge free blocks (e.g. by calling compact_region with
+/// the roots of the program) and returns the number of moved objects.
+/// See ManagedHeap::set_compactor.
+pub type Compactor = Box<dyn FnMut(&mut ManagedHeap) -> usize + Send>;
+
+/// What the heap does before an allocation fails, because there is no free
+/// block which is big enough (see ManagedHeap::set_defrag_policy).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum DefragPolicy {
+    /// Fails right away. Quarantined and cached blocks stay where they are,
+    /// so they are never merged with their neighbours by an allocation.
+    Never,
+    /// Merges the quarantined blocks and the blocks of the hot cache with
+    /// their free neighbours and retries. This only helps if those blocks
+    /// happen to lie next to each other or next to free blocks.
+    #[default]
+    CoalesceOnly,
+    /// Like CoalesceOnly, but if the allocation still fails because of
+    /// fragmentation (see AllocFailureReason::Fragmentation), the compactor
+    /// runs before the allocation is retried. threshold is the minimal
+    /// fragmentation (1 - largest / total free words) in [0, 1], below which
+    /// compacting is not worth it. Without a compactor this is the same as
+    /// CoalesceOnly.
+    CompactIfFragmented { threshold: f64 },
+}
+
+impl ManagedHeap {
+    /// Sets what an allocation tries before it fails. The heap grows (see
+    /// set_growth_factor) before it is compacted.
+    pub fn set_defrag_policy(&mut self, policy: DefragPolicy) {
+        self.defrag_policy = policy;
+        self.heap
+            .set_flush_on_failure(policy != DefragPolicy::Never);
+    }
+
+    pub fn defrag_policy(&self) -> DefragPolicy {
+        self.defrag_policy
+    }
+
+    /// Sets the compactor for DefragPolicy::CompactIfFragmented. The heap
+    /// doesn't know the roots and the layout of the objects, so it can't
+    /// move them on its own.
+    /// The compactor is not called recursively by its own allocations.
+    pub fn set_compactor(&mut self, compactor: Compactor) {
+        self.compactor = Some(compactor);
+    }
+
+    /// Removes the compactor and returns it
+    pub fn take_compactor(&mut self) -> Option<Compactor> {
+        self.compactor.take()
+    }
+
+    /// The last time an allocation ran the compactor, e.g. to find out if
+    /// the threshold is too low
+    pub fn last_defrag(&self) -> Option<DefragReport> {
+        self.last_defrag
+    }
+
+    /// Runs the compactor if the policy allows it and retries the
+    /// allocation
+    pub(crate) fn compact_for(&mut self, size: HalfWord) -> Option<Address> {
+        let threshold = match self.defrag_policy {
+            DefragPolicy::CompactIfFragmented { threshold } => threshold,
+            _ => return None,
+        };
+
+        let reason = self.explain_alloc_failure(size)?;
+        let fragmentation = match reason {
+            AllocFailureReason::Fragmentation { largest, total, .. } => {
+                1.0 - largest as f64 / total as f64
+            }
+            _ => return None,
+        };
+        if fragmentation < threshold {
+            return None;
+        }
+
+        let mut compactor = self.compactor.take()?;
+        let moved = compactor(self);
+        // the compactor may have set a new one
+        if self.compactor.is_none() {
+            self.compactor = Some(compactor);
+        }
+
+        let address = self.heap.alloc(size);
+        self.last_defrag = Some(DefragReport {
+            size,
+            reason,
+            moved,
+            succeeded: address.is_some(),
+        });
+        address
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::trace::GcRoot;
+    use crate::types::WORD_SIZE;
+    use std::sync::{Arc, Mutex};
+
+    /// A full heap with a hole of 4 words after every object
+    fn fragmented_heap() -> (ManagedHeap, Arc<Mutex<MockGcRoot<LinkedList>>>) {
+        fragmented_heap_with(false)
+    }
+
+    fn fragmented_heap_with(checksums: bool) -> (ManagedHeap, Arc<Mutex<MockGcRoot<LinkedList>>>) {
+        let node_words = 4 + checksums as usize;
+        let mut heap = ManagedHeap::new(60 * node_words * WORD_SIZE);
+        heap.set_header_checksums(checksums);
+        let nodes: Vec<LinkedList> = (0..60)
+            .map(|i| LinkedList::new(&mut heap, i, None))
+            .collect();
+        assert_eq!(0, heap.stats().free_blocks);
+
+        let mut live = Vec::new();
+        for (i, node) in nodes.into_iter().enumerate() {
+            if i % 2 == 0 {
+                live.push(node);
+            } else {
+                heap.free(node.0);
+            }
+        }
+        (heap, Arc::new(Mutex::new(MockGcRoot::new(live))))
+    }
+
+    fn compactor(root: Arc<Mutex<MockGcRoot<LinkedList>>>) -> Compactor {
+        Box::new(move |heap: &mut ManagedHeap| {
+            let mut root = root.lock().unwrap();
+            let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut *root];
+            // moves the objects of the upper half into the holes below
+            heap.compact_region(2, 1, &mut roots[..])
+        })
+    }
+
+    #[test]
+    fn test_fragmented_heap_is_compacted() {
+        let (mut heap, root) = fragmented_heap();
+        assert!(matches!(
+            heap.explain_alloc_failure(50),
+            Some(AllocFailureReason::Fragmentation { .. })
+        ));
+
+        heap.set_compactor(compactor(root.clone()));
+        heap.set_defrag_policy(DefragPolicy::CompactIfFragmented { threshold: 0.5 });
+        assert!(heap.alloc(50).is_some());
+
+        let report = heap.last_defrag().unwrap();
+        assert_eq!(15, report.moved);
+        assert!(report.succeeded);
+        let values: Vec<isize> = root
+            .lock()
+            .unwrap()
+            .used_elems
+            .iter()
+            .map(|n| n.value())
+            .collect();
+        assert_eq!((0..60).step_by(2).collect::<Vec<_>>(), values);
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_fragmented_heap_is_not_compacted_without_policy() {
+        for &policy in [DefragPolicy::Never, DefragPolicy::CoalesceOnly].iter() {
+            let (mut heap, root) = fragmented_heap();
+            heap.set_compactor(compactor(root));
+            heap.set_defrag_policy(policy);
+            assert_eq!(None, heap.alloc(50));
+            assert_eq!(None, heap.last_defrag());
+        }
+
+        // the fragmentation is only 0.97
+        let (mut heap, root) = fragmented_heap();
+        heap.set_compactor(compactor(root));
+        heap.set_defrag_policy(DefragPolicy::CompactIfFragmented { threshold: 0.99 });
+        assert_eq!(None, heap.alloc(50));
+    }
+
+    #[test]
+    fn test_checksum_words_are_counted_as_needed() {
+        let (mut heap, root) = fragmented_heap_with(true);
+        heap.set_compactor(compactor(root));
+        heap.set_defrag_policy(DefragPolicy::CompactIfFragmented { threshold: 0.5 });
+
+        // 4 words would fit into the holes of 5 words without the checksum
+        assert!(heap.alloc(4).is_some());
+        let report = heap.last_defrag().unwrap();
+        assert!(matches!(
+            report.reason,
+            AllocFailureReason::Fragmentation { needed: 6, .. }
+        ));
+        assert!(report.succeeded);
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_coalesce_only_merges_adjacent_cached_blocks() {
+        let mut heap = ManagedHeap::new(100 * WORD_SIZE);
+        heap.set_hot_size(3, 10);
+        let cached: Vec<Address> = (0..10).map(|_| heap.alloc(3).unwrap()).collect();
+        heap.alloc(59).unwrap();
+        for address in cached {
+            heap.free(address);
+        }
+        assert_eq!(10, heap.num_hot_cached_blocks());
+
+        heap.set_defrag_policy(DefragPolicy::Never);
+        assert_eq!(None, heap.alloc(30));
+        assert_eq!(10, heap.num_hot_cached_blocks());
+
+        heap.set_defrag_policy(DefragPolicy::CoalesceOnly);
+        assert!(heap.alloc(30).is_some());
+        assert_eq!(0, heap.num_hot_cached_blocks());
+    }
+}
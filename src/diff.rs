@@ -0,0 +1,212 @@
+use crate::address::Address;
+use crate::managed::ManagedHeap;
+use crate::stats::BlockInfo;
+
+/// A mismatch between two heaps (see ManagedHeap::diff)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Difference {
+    /// The n-th blocks of the heaps differ in offset, size or state.
+    /// None if one heap has fewer blocks than the other.
+    Block {
+        left: Option<BlockInfo>,
+        right: Option<BlockInfo>,
+    },
+    /// A payload word of two blocks with the same layout differs.
+    /// offset is the offset of the block header in words from the start of
+    /// the heap and index the index of the word inside the payload.
+    Word {
+        offset: usize,
+        index: usize,
+        left: usize,
+        right: usize,
+    },
+}
+
+/// The result of ManagedHeap::diff
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeapDiff {
+    /// The first differences in address order, at most MAX_DIFFERENCES
+    pub differences: Vec<Difference>,
+    /// The number of all differences, including the ones which were not
+    /// recorded
+    pub count: usize,
+}
+
+impl HeapDiff {
+    pub const MAX_DIFFERENCES: usize = 32;
+
+    /// True if no difference was found
+    pub fn is_equal(&self) -> bool {
+        self.count == 0
+    }
+
+    fn push(&mut self, difference: Difference) {
+        if self.differences.len() < Self::MAX_DIFFERENCES {
+            self.differences.push(difference);
+        }
+        self.count += 1;
+    }
+}
+
+impl ManagedHeap {
+    /// Compares the blocks of both heaps by offset, size and state and the
+    /// payloads of the used blocks, which have the same layout in both.
+    /// The addresses are not compared, so two heaps, which did the same
+    /// operations, are equal. Large objects are not included (see blocks).
+    pub fn diff(&self, other: &ManagedHeap) -> HeapDiff {
+        self.diff_ignoring(other, |_, _| false)
+    }
+
+    /// Like diff, but skips every payload word for which ignore returns true.
+    /// ignore gets called with the offset of the block header and the index
+    /// of the word inside the payload, e.g. to skip mark words, which differ
+    /// depending on the collector.
+    /// Ignoring all words only compares the layouts.
+    pub fn diff_ignoring<F>(&self, other: &ManagedHeap, mut ignore: F) -> HeapDiff
+    where
+        F: FnMut(usize, usize) -> bool,
+    {
+        let mut diff = HeapDiff::default();
+        let mut left = self.heap.blocks();
+        let mut right = other.heap.blocks();
+
+        loop {
+            let (l, r) = match (left.next(), right.next()) {
+                (None, None) => break,
+                (l, r) => (l, r),
+            };
+            let l_info = l.map(|b| self.block_info(b));
+            let r_info = r.map(|b| other.block_info(b));
+
+            let (l, r, offset) = match (l, r, l_info, r_info) {
+                (Some(l), Some(r), Some(li), Some(ri))
+                    if (li.offset, li.size, li.used) == (ri.offset, ri.size, ri.used) =>
+                {
+                    (l, r, li.offset)
+                }
+                _ => {
+                    diff.push(Difference::Block {
+                        left: l_info,
+                        right: r_info,
+                    });
+                    continue;
+                }
+            };
+
+            if !self.heap.is_allocated(l) {
+                continue;
+            }
+
+            let words = self.heap.payload_words(l).min(other.heap.payload_words(r));
+            for index in 0..words as usize {
+                let l_word = *(Address::from(l) + index);
+                let r_word = *(Address::from(r) + index);
+                if l_word != r_word && !ignore(offset, index) {
+                    diff.push(Difference::Word {
+                        offset,
+                        index,
+                        left: l_word,
+                        right: r_word,
+                    });
+                }
+            }
+        }
+
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build() -> (ManagedHeap, Vec<Address>) {
+        let mut heap = ManagedHeap::new(256);
+        let mut addresses = Vec::new();
+        for size in 1..6 {
+            let address = heap.alloc(size).unwrap();
+            for i in 0..size as usize {
+                (address + i).write(size as usize * 10 + i);
+            }
+            addresses.push(address);
+        }
+        heap.free(addresses.remove(1));
+        (heap, addresses)
+    }
+
+    #[test]
+    fn test_identical_operations_diff_as_equal() {
+        let (left, _) = build();
+        let (right, _) = build();
+
+        let diff = left.diff(&right);
+        assert!(diff.is_equal());
+        assert_eq!(HeapDiff::default(), diff);
+    }
+
+    #[test]
+    fn test_diff_pinpoints_changed_word() {
+        let (left, _) = build();
+        let (right, addresses) = build();
+        let mut word = addresses[2] + 3;
+        word.write(7);
+
+        let diff = left.diff(&right);
+        let offset = right
+            .blocks()
+            .find(|b| b.address == addresses[2])
+            .unwrap()
+            .offset;
+        assert_eq!(1, diff.count);
+        assert_eq!(
+            vec![Difference::Word {
+                offset,
+                index: 3,
+                left: 43,
+                right: 7
+            }],
+            diff.differences
+        );
+
+        // the mask hides the word
+        let diff = left.diff_ignoring(&right, |o, i| (o, i) == (offset, 3));
+        assert!(diff.is_equal());
+    }
+
+    #[test]
+    fn test_diff_reports_layout_mismatch() {
+        let (left, _) = build();
+        let (mut right, addresses) = build();
+        right.free(addresses[0]);
+
+        let diff = left.diff_ignoring(&right, |_, _| true);
+        assert!(!diff.is_equal());
+        match diff.differences[0] {
+            Difference::Block {
+                left: Some(l),
+                right: Some(r),
+            } => {
+                assert_eq!(l.offset, r.offset);
+                assert!(l.used);
+                assert!(!r.used);
+            }
+            other => panic!("Unexpected difference {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_diff_records_at_most_max_differences() {
+        let mut left = ManagedHeap::new(1000);
+        let mut right = ManagedHeap::new(1000);
+        let l = left.alloc(40).unwrap();
+        let r = right.alloc(40).unwrap();
+        for i in 0..40 {
+            (l + i).write(i);
+            (r + i).write(i + 1);
+        }
+
+        let diff = left.diff(&right);
+        assert_eq!(40, diff.count);
+        assert_eq!(HeapDiff::MAX_DIFFERENCES, diff.differences.len());
+    }
+}
@@ -0,0 +1,324 @@
+use crate::address::Address;
+use crate::types::HalfWord;
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// An invalid access to the memory of a ManagedHeap.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccessError {
+    /// The address doesn't point into the payload of a used block
+    Unallocated(Address),
+    /// The range of words starting at address extends past the end of its block
+    OutOfBounds { address: Address, words: usize },
+    /// The address doesn't point to an object allocated with rc_alloc
+    NotReferenceCounted(Address),
+    /// The index is not smaller than the length of the array
+    IndexOutOfBounds { index: usize, len: usize },
+    /// The address was rejected by the strict checks (see
+    /// ManagedHeap::enable_strict)
+    InvalidAddress(AddressError),
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccessError::Unallocated(address) => {
+                write!(f, "{:?} doesn't point into a used block", address)
+            }
+            AccessError::OutOfBounds { address, words } => write!(
+                f,
+                "{} words starting at {:?} extend past the end of the block",
+                words, address
+            ),
+            AccessError::NotReferenceCounted(address) => {
+                write!(f, "{:?} is not a reference counted object", address)
+            }
+            AccessError::IndexOutOfBounds { index, len } => {
+                write!(f, "Index {} is out of bounds for length {}", index, len)
+            }
+            AccessError::InvalidAddress(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for AccessError {}
+
+impl From<AddressError> for AccessError {
+    fn from(e: AddressError) -> Self {
+        AccessError::InvalidAddress(e)
+    }
+}
+
+/// An address, which is not the start of an object (see
+/// ManagedHeap::validate_address).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AddressError {
+    /// The address is not a multiple of WORD_SIZE
+    Misaligned(Address),
+    /// The address is neither inside the heap nor inside a large object
+    OutOfRange(Address),
+    /// The address points into a free block
+    Free(Address),
+    /// The address points into the object starting at object, but not to
+    /// its start (e.g. to one of its fields or to its header)
+    Interior { address: Address, object: Address },
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddressError::Misaligned(address) => {
+                write!(f, "{:?} is not aligned to a word", address)
+            }
+            AddressError::OutOfRange(address) => {
+                write!(f, "{:?} is outside of the heap", address)
+            }
+            AddressError::Free(address) => write!(f, "{:?} points into a free block", address),
+            AddressError::Interior { address, object } => write!(
+                f,
+                "{:?} points into the object at {:?}, but not to its start",
+                address, object
+            ),
+        }
+    }
+}
+
+impl Error for AddressError {}
+
+/// A write past the payload of a block.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WriteError {
+    /// The offset of the write in words from the start of the payload
+    pub offset: usize,
+    /// The number of words in the payload of the block
+    pub payload_len: usize,
+    /// The payload of the block (see ManagedHeap::debug_address for its
+    /// offset in the heap)
+    pub block: Address,
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Offset {} is out of bounds for the payload of {} words at {:?}",
+            self.offset, self.payload_len, self.block
+        )
+    }
+}
+
+impl Error for WriteError {}
+
+/// A failed allocation of size words.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AllocError {
+    /// The size (including the header) exceeds the maximum block size
+    TooLarge(HalfWord),
+    /// There is no free block which is big enough
+    OutOfMemory(HalfWord),
+    /// The allocation would exceed the hard limit (see
+    /// ManagedHeap::set_limits)
+    LimitExceeded(HalfWord),
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AllocError::TooLarge(size) => {
+                write!(f, "{} words exceed the maximum block size", size)
+            }
+            AllocError::OutOfMemory(size) => {
+                write!(f, "There is no free block for {} words", size)
+            }
+            AllocError::LimitExceeded(size) => {
+                write!(f, "{} words would exceed the hard memory limit", size)
+            }
+        }
+    }
+}
+
+impl Error for AllocError {}
+
+/// A collection which could not be continued.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GcError {
+    /// The roots changed between two calls of gc_with_budget in the same cycle
+    RootsChanged,
+    /// The collection of a MarkToken was aborted or finished by another
+    /// collection before sweep_phase
+    StaleMark,
+}
+
+impl fmt::Display for GcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GcError::RootsChanged => write!(f, "The roots changed during the collection"),
+            GcError::StaleMark => write!(f, "Another collection ran since the mark phase"),
+        }
+    }
+}
+
+impl Error for GcError {}
+
+/// The reason why a heap couldn't be shrunk (see
+/// ManagedHeap::shrink_capacity_to).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ShrinkError {
+    /// The block with the header at offset (in words) lies above the target
+    /// size and is not free. It is the highest block, which is used,
+    /// quarantined or cached.
+    BlockInUse { offset: usize },
+    /// The heap has additional segments, which would have to be released
+    /// first
+    HasSegments,
+    /// The size of a heap in a file is part of the file format
+    FileBacked,
+}
+
+impl fmt::Display for ShrinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShrinkError::BlockInUse { offset } => {
+                write!(f, "The block at offset {} is still in use", offset)
+            }
+            ShrinkError::HasSegments => write!(f, "A heap with segments can't be shrunk"),
+            ShrinkError::FileBacked => write!(f, "A heap in a file can't be shrunk"),
+        }
+    }
+}
+
+impl Error for ShrinkError {}
+
+/// An invalid size passed to the constructor of a heap.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NewHeapError {
+    /// The heap would not contain a single word
+    ZeroSize,
+    /// The size in bytes is bigger than the maximum heap size
+    TooLarge(usize),
+    /// The maximum size in bytes is smaller than the initial size
+    MaxBelowInitial { initial: usize, max: usize },
+}
+
+impl fmt::Display for NewHeapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NewHeapError::ZeroSize => write!(f, "A heap can't have a size of 0"),
+            NewHeapError::TooLarge(size) => write!(
+                f,
+                "Size too big: {} (MAX: {})",
+                size,
+                crate::heap::Heap::MAX_BYTES
+            ),
+            NewHeapError::MaxBelowInitial { initial, max } => write!(
+                f,
+                "The maximum size {} is smaller than the initial size {}",
+                max, initial
+            ),
+        }
+    }
+}
+
+impl Error for NewHeapError {}
+
+/// The kind of inconsistency found by a heap verification.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Corruption {
+    /// A block header contains a size of 0
+    ZeroSize,
+    /// A block extends past the end of the heap
+    SizeOutOfBounds,
+    /// The pred size of a block doesn't match the size of its predecessor
+    PredSizeMismatch,
+    /// The used flag of a block doesn't match the set it is stored in
+    UsedFlagMismatch,
+    /// Two free blocks next to each other weren't merged
+    AdjacentFreeBlocks,
+    /// The bookkeeping doesn't match the blocks found in the heap
+    CountMismatch,
+    /// The payload of a free block was written to while poisoning was enabled
+    PoisonOverwritten,
+    /// A block header doesn't match its checksum (see Heap::set_header_checksums)
+    ChecksumMismatch,
+    /// The bitmap of the free block headers doesn't match the free blocks
+    FreeBitmapMismatch,
+}
+
+/// An inconsistency in the block layout of a heap.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CorruptionError {
+    /// The offset of the corrupted block in words from the start of the heap
+    pub offset: usize,
+    pub kind: Corruption,
+}
+
+impl fmt::Display for CorruptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Corrupted block at offset {}: {:?}",
+            self.offset, self.kind
+        )
+    }
+}
+
+impl Error for CorruptionError {}
+
+/// A failure while saving or loading a heap image.
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    /// The data doesn't start with the image magic number
+    InvalidMagic,
+    UnsupportedVersion(u64),
+    /// The image was written on a platform with a different word size
+    WordSizeMismatch {
+        expected: usize,
+        found: usize,
+    },
+    /// The block table doesn't describe a valid heap
+    CorruptedLayout,
+    /// A reference doesn't point into a used block of the heap
+    DanglingReference(Address),
+    /// The heap consists of more than one segment
+    Segmented,
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Io(e) => write!(f, "{}", e),
+            ImageError::InvalidMagic => write!(f, "Not a heap image"),
+            ImageError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported image version {}", version)
+            }
+            ImageError::WordSizeMismatch { expected, found } => write!(
+                f,
+                "Image uses a word size of {} bytes, but this platform uses {}",
+                found, expected
+            ),
+            ImageError::CorruptedLayout => write!(f, "Corrupted block table"),
+            ImageError::DanglingReference(address) => {
+                write!(f, "{:?} doesn't point into a used block", address)
+            }
+            ImageError::Segmented => write!(f, "Only heaps with a single segment can be saved"),
+        }
+    }
+}
+
+impl Error for ImageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ImageError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(e: io::Error) -> Self {
+        ImageError::Io(e)
+    }
+}
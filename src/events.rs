@@ -0,0 +1,120 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+/// An operation on the heap, which was recorded by the event log (see
+/// ManagedHeap::enable_event_log).
+/// Offsets are the offsets of the block headers in words from the start of
+/// the heap, sizes are in words and include the header. Large objects live
+/// outside of the heap and are not recorded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HeapEvent {
+    Alloc {
+        offset: usize,
+        size: usize,
+    },
+    Free {
+        offset: usize,
+        size: usize,
+    },
+    /// A free block was split into a block of size words and the rest
+    Split {
+        offset: usize,
+        size: usize,
+        rest: usize,
+    },
+    /// A freed block was merged with its free neighbours into a block of
+    /// size words
+    Coalesce {
+        offset: usize,
+        size: usize,
+    },
+    GcStart {
+        epoch: u64,
+    },
+    GcEnd {
+        epoch: u64,
+        freed_blocks: usize,
+    },
+}
+
+impl fmt::Display for HeapEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeapEvent::Alloc { offset, size } => write!(f, "alloc {} size {}", offset, size),
+            HeapEvent::Free { offset, size } => write!(f, "free {} size {}", offset, size),
+            HeapEvent::Split { offset, size, rest } => {
+                write!(f, "split {} size {} rest {}", offset, size, rest)
+            }
+            HeapEvent::Coalesce { offset, size } => {
+                write!(f, "coalesce {} size {}", offset, size)
+            }
+            HeapEvent::GcStart { epoch } => write!(f, "gc start epoch {}", epoch),
+            HeapEvent::GcEnd {
+                epoch,
+                freed_blocks,
+            } => write!(f, "gc end epoch {} freed {}", epoch, freed_blocks),
+        }
+    }
+}
+
+/// A ring buffer of the last events, which drops the oldest event once it
+/// is full
+#[derive(Clone)]
+pub(crate) struct EventLog {
+    events: VecDeque<HeapEvent>,
+    capacity: usize,
+}
+
+impl EventLog {
+    /// The buffer is allocated up front, so that recording doesn't allocate
+    pub(crate) fn new(capacity: usize) -> Self {
+        EventLog {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub(crate) fn record(&mut self, event: HeapEvent) {
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// The recorded events, oldest first
+    pub(crate) fn iter(&self) -> impl Iterator<Item = HeapEvent> + '_ {
+        self.events.iter().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_buffer_drops_oldest_events() {
+        let mut log = EventLog::new(3);
+        for epoch in 0..5 {
+            log.record(HeapEvent::GcStart { epoch });
+        }
+
+        let epochs: Vec<_> = log
+            .iter()
+            .map(|e| match e {
+                HeapEvent::GcStart { epoch } => epoch,
+                other => panic!("Unexpected event {}", other),
+            })
+            .collect();
+        assert_eq!(vec![2, 3, 4], epochs);
+    }
+
+    #[test]
+    fn test_display() {
+        let event = HeapEvent::Split {
+            offset: 4,
+            size: 3,
+            rest: 10,
+        };
+        assert_eq!("split 4 size 3 rest 10", event.to_string());
+    }
+}
@@ -0,0 +1,233 @@
+//! A C interface for embedding the heap into runtimes written in other
+//! languages (see include/managed_heap.h).
+//!
+//! Addresses are passed as plain integers, 0 is never a valid address.
+//! Every function checks its pointers for null and catches panics, so no
+//! unwinding crosses the language boundary. Functions which can fail return
+//! one of the status codes below.
+
+use crate::address::Address;
+use crate::managed::ManagedHeap;
+use crate::types::{HalfWord, WORD_SIZE};
+
+use std::convert::TryFrom;
+use std::os::raw::c_void;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+/// The call succeeded
+pub const MANAGED_HEAP_OK: i32 = 0;
+/// A required pointer argument was null
+pub const MANAGED_HEAP_NULL: i32 = -1;
+/// The address doesn't belong to a used block or the word lies outside of it
+pub const MANAGED_HEAP_INVALID_ADDRESS: i32 = -2;
+/// The heap panicked, it should be destroyed
+pub const MANAGED_HEAP_PANIC: i32 = -3;
+
+/// Called with every child of an object during a collection
+pub type ManagedHeapMarkFn = extern "C" fn(context: *mut c_void, child: usize);
+
+/// Called once for every live object during a collection. It has to call
+/// mark with context and every address the object references.
+pub type ManagedHeapTraceFn = extern "C" fn(
+    address: usize,
+    mark: ManagedHeapMarkFn,
+    context: *mut c_void,
+    userdata: *mut c_void,
+);
+
+/// Runs f and turns a panic into on_panic
+fn guard<R, F: FnOnce() -> R>(on_panic: R, f: F) -> R {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(on_panic)
+}
+
+/// The address of the word at offset inside of the payload of a used block
+fn word_address(heap: &ManagedHeap, address: usize, offset: usize) -> Option<Address> {
+    let word = offset
+        .checked_mul(WORD_SIZE)
+        .and_then(|bytes| address.checked_add(bytes))
+        .map(Address::from)?;
+    heap.read(word, 0).ok().map(|_| word)
+}
+
+/// Creates a heap of size bytes. Returns null if the size is invalid.
+#[no_mangle]
+pub extern "C" fn managed_heap_new(size: usize) -> *mut ManagedHeap {
+    guard(ptr::null_mut(), || match ManagedHeap::try_new(size) {
+        Ok(heap) => Box::into_raw(Box::new(heap)),
+        Err(_) => ptr::null_mut(),
+    })
+}
+
+/// Destroys a heap created by managed_heap_new. Null is ignored.
+///
+/// # Safety
+/// heap has to be null or a pointer returned by managed_heap_new, which
+/// wasn't destroyed yet.
+#[no_mangle]
+pub unsafe extern "C" fn managed_heap_destroy(heap: *mut ManagedHeap) {
+    if !heap.is_null() {
+        guard((), || drop(Box::from_raw(heap)));
+    }
+}
+
+/// Allocates size words and returns their address or 0 on failure.
+///
+/// # Safety
+/// heap has to be null or a valid heap.
+#[no_mangle]
+pub unsafe extern "C" fn managed_heap_alloc(heap: *mut ManagedHeap, size: usize) -> usize {
+    let heap = match heap.as_mut() {
+        Some(heap) => heap,
+        None => return 0,
+    };
+
+    guard(0, || {
+        HalfWord::try_from(size)
+            .ok()
+            .and_then(|size| heap.alloc(size))
+            .map_or(0, usize::from)
+    })
+}
+
+/// Frees the object at address, which has to be returned by
+/// managed_heap_alloc.
+///
+/// # Safety
+/// heap has to be null or a valid heap.
+#[no_mangle]
+pub unsafe extern "C" fn managed_heap_free(heap: *mut ManagedHeap, address: usize) -> i32 {
+    let heap = match heap.as_mut() {
+        Some(heap) => heap,
+        None => return MANAGED_HEAP_NULL,
+    };
+
+    guard(MANAGED_HEAP_PANIC, || {
+        let address = Address::from(address);
+        let is_start = heap
+            .block_of(address)
+            .is_some_and(|block| Address::from(block) == address);
+
+        if is_start {
+            heap.free(address);
+            MANAGED_HEAP_OK
+        } else {
+            MANAGED_HEAP_INVALID_ADDRESS
+        }
+    })
+}
+
+/// Stores the word at offset (in words) inside the object at address in
+/// value.
+///
+/// # Safety
+/// heap has to be null or a valid heap and value has to be null or valid
+/// for writes.
+#[no_mangle]
+pub unsafe extern "C" fn managed_heap_read(
+    heap: *const ManagedHeap,
+    address: usize,
+    offset: usize,
+    value: *mut usize,
+) -> i32 {
+    let (heap, value) = match (heap.as_ref(), value.as_mut()) {
+        (Some(heap), Some(value)) => (heap, value),
+        _ => return MANAGED_HEAP_NULL,
+    };
+
+    guard(MANAGED_HEAP_PANIC, || {
+        match word_address(heap, address, offset) {
+            Some(word) => {
+                *value = *word;
+                MANAGED_HEAP_OK
+            }
+            None => MANAGED_HEAP_INVALID_ADDRESS,
+        }
+    })
+}
+
+/// Writes value to offset (in words) inside the object at address.
+///
+/// # Safety
+/// heap has to be null or a valid heap.
+#[no_mangle]
+pub unsafe extern "C" fn managed_heap_write(
+    heap: *mut ManagedHeap,
+    address: usize,
+    offset: usize,
+    value: usize,
+) -> i32 {
+    let heap = match heap.as_mut() {
+        Some(heap) => heap,
+        None => return MANAGED_HEAP_NULL,
+    };
+
+    guard(MANAGED_HEAP_PANIC, || {
+        match word_address(heap, address, offset) {
+            Some(mut word) => {
+                word.write(value);
+                MANAGED_HEAP_OK
+            }
+            None => MANAGED_HEAP_INVALID_ADDRESS,
+        }
+    })
+}
+
+extern "C" fn mark_child(context: *mut c_void, child: usize) {
+    // context is the mark closure of the running collection
+    let mark = unsafe { &mut *(context as *mut &mut dyn FnMut(Address)) };
+    // a panic must not unwind into the C caller
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| mark(Address::from(child))));
+}
+
+/// Collects every object, which is not reachable from the roots_len
+/// addresses at roots (see ManagedHeap::gc_addresses). Null roots are
+/// allowed if roots_len is 0. trace may be null for objects without
+/// references. The number of freed blocks is stored in freed_blocks, unless
+/// it is null.
+///
+/// # Safety
+/// heap has to be null or a valid heap, roots has to point to roots_len
+/// words and freed_blocks has to be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn managed_heap_gc_addresses(
+    heap: *mut ManagedHeap,
+    roots: *const usize,
+    roots_len: usize,
+    trace: Option<ManagedHeapTraceFn>,
+    userdata: *mut c_void,
+    freed_blocks: *mut usize,
+) -> i32 {
+    let heap = match heap.as_mut() {
+        Some(heap) => heap,
+        None => return MANAGED_HEAP_NULL,
+    };
+    if roots.is_null() && roots_len > 0 {
+        return MANAGED_HEAP_NULL;
+    }
+
+    guard(MANAGED_HEAP_PANIC, || {
+        let roots: Vec<Address> = if roots_len == 0 {
+            Vec::new()
+        } else {
+            slice::from_raw_parts(roots, roots_len)
+                .iter()
+                .map(|&root| Address::from(root))
+                .collect()
+        };
+
+        let stats = heap.gc_addresses(&roots, |address, mark| {
+            if let Some(trace) = trace {
+                let mut mark: &mut dyn FnMut(Address) = mark;
+                let context = &mut mark as *mut &mut dyn FnMut(Address) as *mut c_void;
+                trace(address.into(), mark_child, context, userdata);
+            }
+        });
+
+        if let Some(freed_blocks) = freed_blocks.as_mut() {
+            *freed_blocks = stats.freed_blocks;
+        }
+        MANAGED_HEAP_OK
+    })
+}
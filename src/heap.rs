@@ -1,64 +1,698 @@
 use crate::address::Address;
+use crate::block::header::BlockHeader;
 use crate::block::set::BlockSet;
-use crate::block::Block;
+use crate::block::{Block, ByAddress};
+use crate::error::{Corruption, CorruptionError, NewHeapError, ShrinkError};
+use crate::events::{EventLog, HeapEvent};
+use crate::stats::CoalesceStats;
+use crate::store::{self, BlockStore};
 use crate::types::*;
 
 use core::ptr::NonNull;
 use std::alloc::{alloc, dealloc, Layout};
-use std::iter::Iterator;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::iter::{self, FusedIterator};
 use std::mem;
+use std::slice;
+
+/// The memory the heap lives in.
+pub enum Backing {
+    Allocated(Layout),
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::MmapMut),
+}
+
+/// The strategy for choosing a free block for an allocation.
+/// The allocation always uses the lower part of the chosen block.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum FitPolicy {
+    /// The free block with the lowest address, which is big enough
+    #[default]
+    FirstFitLowAddress,
+    /// The smallest free block, which is big enough
+    BestFit,
+    /// Like BestFit, but the wilderness (the free block at the end of the
+    /// reserved memory) is only used if no other block is big enough, so
+    /// that it stays available for large allocations and grows with the heap
+    PreserveWilderness,
+}
+
+/// How the requested sizes are rounded up before a free block is chosen.
+/// Rounding quantizes the sizes into fewer classes, so that freed blocks
+/// fit later allocations more often. The rounded up words belong to the
+/// object (unlike the slack).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SizeRounding {
+    #[default]
+    None,
+    /// The next power of two
+    PowerOfTwo,
+    /// The next multiple of the given number of words, which must not be 0
+    MultipleOf(HalfWord),
+}
+
+impl SizeRounding {
+    /// The rounded size or None if it would overflow
+    fn round(self, size: HalfWord) -> Option<HalfWord> {
+        match self {
+            SizeRounding::None => Some(size),
+            SizeRounding::PowerOfTwo if size == 0 => Some(0),
+            SizeRounding::PowerOfTwo => size.checked_next_power_of_two(),
+            SizeRounding::MultipleOf(n) => Some(size.checked_add(n - 1)? / n * n),
+        }
+    }
+}
+
+/// An additional region of memory, which is chained to the heap once the
+/// reserved memory is exhausted.
+struct Segment {
+    data: *mut usize,
+    size: usize,
+    // the offset of the first word, which continues after the reserved
+    // memory and the previous segments
+    offset: usize,
+    layout: Layout,
+}
 
 pub struct Heap {
     size: usize,
+    // the number of words reserved for growing
+    max_size: usize,
     used_size: usize,
     data: *mut usize,
     heap_end: usize,
-    layout: Layout,
-    free_blocks: BlockSet,
+    backing: Backing,
+    segments: Vec<Segment>,
+    free_blocks: Box<dyn BlockStore>,
+    // one bit per word (by offset), which is set for the headers of the
+    // blocks in free_blocks, so is_free doesn't have to search them
+    free_headers: Vec<u64>,
     used_blocks: BlockSet,
+    fit_policy: FitPolicy,
+    size_rounding: SizeRounding,
+    // the free block with the lowest address, from which first fit
+    // allocations are cut without searching the free blocks
+    active: Option<Block>,
+    // the smallest remainder (in words) which is split off a free block
+    split_threshold: HalfWord,
+    // freed blocks, which are neither used nor free yet (oldest first)
+    quarantine: VecDeque<Block>,
+    quarantine_len: usize,
+    // freed blocks with a payload of hot_size words, which are reused before
+    // the free blocks are searched (most recently freed last)
+    hot_cache: Vec<Block>,
+    hot_size: HalfWord,
+    hot_capacity: usize,
+    // freed blocks, whose headers still say used, until flush_deferred
+    // merges them all at once (sorted, so verify can look them up)
+    deferred: BTreeSet<ByAddress>,
+    // merge the quarantined, cached and deferred blocks before an
+    // allocation fails
+    flush_on_failure: bool,
+    // the unrequested words at the end of blocks, which weren't split
+    slack: HashMap<usize, HalfWord>,
+    slack_words: usize,
+    // the words added to used blocks by the size rounding
+    rounding: HashMap<usize, HalfWord>,
+    rounding_words: usize,
+    check_allocs: bool,
+    poison: bool,
+    // the last word of every block holds a checksum of its header
+    checksums: bool,
+    events: Option<EventLog>,
+    coalesce_stats: CoalesceStats,
+    // the most recently allocated block and the free remainder it was split
+    // from, as long as no block was freed or merged since
+    last_split: Option<(Block, Block)>,
+    undo_splits: bool,
+    undone_splits: usize,
 }
 
 impl Heap {
-    const H_SIZE: HalfWord = mem::size_of::<usize>() as HalfWord;
+    /// The pattern written into the payload of free blocks while poisoning
+    /// is enabled
+    pub const POISON: usize = usize::MAX / 0xFF * 0xAB;
+
+    // mixed into every checksum, so that zeroed memory doesn't match
+    const CHECKSUM_SALT: usize = usize::MAX / 0xFF * 0x5C;
+
+    /// Free blocks are only split if at least this many words (including the
+    /// header of the remainder) would be left over
+    pub const DEFAULT_SPLIT_THRESHOLD: HalfWord = 3;
+
+    /// The maximum size of a heap in bytes.
+    /// The whole heap has to fit into a single block.
+    pub const MAX_BYTES: usize = {
+        let max_block = BlockHeader::MAX_SIZE as usize * WORD_SIZE;
+        if max_block < HALF_WORD_MAX as usize {
+            max_block
+        } else {
+            HALF_WORD_MAX as usize
+        }
+    };
 
-    /// Expects the heap size in bytes.
+    /// Expects the heap size in bytes, which is rounded up to a multiple of
+    /// WORD_SIZE.
+    /// Panics if the size is invalid (see try_new).
     pub unsafe fn new(size: usize) -> Self {
+        match Heap::try_new(size) {
+            Ok(heap) => heap,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Expects the heap size in bytes, which is rounded up to a multiple of
+    /// WORD_SIZE.
+    pub fn try_new(size: usize) -> Result<Self, NewHeapError> {
+        Heap::try_with_max(size, size)
+    }
+
+    /// Like try_new, but reserves max bytes, so that the heap can grow
+    /// without moving.
+    pub fn try_with_max(size: usize, max: usize) -> Result<Self, NewHeapError> {
+        let words = Heap::words_for(size)?;
+        let max_words = Heap::words_for(max)?;
+        if max_words < words {
+            return Err(NewHeapError::MaxBelowInitial { initial: size, max });
+        }
+
         let align = mem::align_of::<usize>();
-        let layout = Layout::from_size_align(size, align).unwrap();
+        let layout = Layout::from_size_align(max_words * WORD_SIZE, align).unwrap();
 
-        if size > HALF_WORD_MAX as usize {
-            panic!("Size too big (MAX: {})", HALF_WORD_MAX);
+        unsafe {
+            let data = NonNull::new(alloc(layout))
+                .unwrap()
+                .cast::<usize>()
+                .as_ptr();
+
+            let mut heap = Heap::from_raw(data, words, Backing::Allocated(layout));
+            heap.max_size = max_words;
+            Ok(heap)
         }
+    }
 
-        let data = NonNull::new(alloc(layout))
-            .unwrap()
-            .cast::<usize>()
-            .as_ptr();
+    /// The number of words needed for size bytes
+    fn words_for(size: usize) -> Result<usize, NewHeapError> {
+        if size == 0 {
+            Err(NewHeapError::ZeroSize)
+        } else if size > Heap::MAX_BYTES {
+            Err(NewHeapError::TooLarge(size))
+        } else {
+            Ok(size.div_ceil(WORD_SIZE))
+        }
+    }
 
-        let size = size / Heap::H_SIZE as usize;
+    /// Creates a heap consisting of a single free block of size words at data.
+    pub unsafe fn from_raw(data: *mut usize, size: usize, backing: Backing) -> Self {
         let heap_end = data.add(size) as usize;
 
-        Heap {
+        let mut heap = Heap {
             size,
+            max_size: size,
             used_size: 0,
             data,
             heap_end,
-            layout,
-            free_blocks: BlockSet::from_raw(data, size as HalfWord),
+            backing,
+            segments: Vec::new(),
+            free_blocks: store::default_store(),
+            free_headers: vec![0; Heap::bitmap_words(size)],
             used_blocks: BlockSet::default(),
+            fit_policy: FitPolicy::default(),
+            size_rounding: SizeRounding::default(),
+            active: None,
+            split_threshold: Heap::DEFAULT_SPLIT_THRESHOLD,
+            quarantine: VecDeque::new(),
+            quarantine_len: 0,
+            hot_cache: Vec::new(),
+            hot_size: 0,
+            hot_capacity: 0,
+            deferred: BTreeSet::new(),
+            flush_on_failure: true,
+            slack: HashMap::new(),
+            slack_words: 0,
+            rounding: HashMap::new(),
+            rounding_words: 0,
+            check_allocs: false,
+            poison: false,
+            checksums: false,
+            events: None,
+            coalesce_stats: CoalesceStats::default(),
+            last_split: None,
+            undo_splits: true,
+            undone_splits: 0,
+        };
+        heap.add_free(Block::new(data, size as HalfWord, 0));
+        #[cfg(test)]
+        if tests::CHECKSUMS_BY_DEFAULT.with(|enabled| enabled.get()) {
+            heap.set_header_checksums(true);
         }
+        heap
+    }
+
+    /// Creates a heap from size words at data, which already contain a valid
+    /// block layout (e.g. a heap which was written to a file).
+    /// Returns None if the headers are inconsistent.
+    pub unsafe fn adopt(data: *mut usize, size: usize, backing: Backing) -> Option<Self> {
+        let heap_end = data.add(size) as usize;
+        let mut heap = Heap {
+            size,
+            max_size: size,
+            used_size: 0,
+            data,
+            heap_end,
+            backing,
+            segments: Vec::new(),
+            free_blocks: store::default_store(),
+            free_headers: vec![0; Heap::bitmap_words(size)],
+            used_blocks: BlockSet::default(),
+            fit_policy: FitPolicy::default(),
+            size_rounding: SizeRounding::default(),
+            active: None,
+            split_threshold: Heap::DEFAULT_SPLIT_THRESHOLD,
+            quarantine: VecDeque::new(),
+            quarantine_len: 0,
+            hot_cache: Vec::new(),
+            hot_size: 0,
+            hot_capacity: 0,
+            deferred: BTreeSet::new(),
+            flush_on_failure: true,
+            slack: HashMap::new(),
+            slack_words: 0,
+            rounding: HashMap::new(),
+            rounding_words: 0,
+            check_allocs: false,
+            poison: false,
+            checksums: false,
+            events: None,
+            coalesce_stats: CoalesceStats::default(),
+            last_split: None,
+            undo_splits: true,
+            undone_splits: 0,
+        };
+
+        let mut ptr = data;
+        let mut pred_size = 0;
+        while (ptr as usize) < heap_end {
+            let block = Block::from(ptr as *mut BlockHeader);
+            let block_size = block.size() as usize;
+
+            if block_size == 0 || block.pred_size() != pred_size {
+                return None;
+            }
+
+            let remaining = (heap_end - ptr as usize) / WORD_SIZE;
+            if remaining < block_size {
+                return None;
+            }
+
+            if block.is_used() {
+                heap.used_size += block_size;
+                heap.used_blocks.add_block(block);
+            } else {
+                heap.add_free(block);
+            }
+
+            pred_size = block.size();
+            ptr = ptr.add(block_size);
+        }
+
+        Some(heap)
     }
 }
 
 impl Heap {
+    /// Copies all words into newly allocated memory with the same reserved
+    /// size and returns the copy and its distance in bytes from this heap.
+    /// The copy has the same block layout and settings, but the references
+    /// stored in its objects still point into this heap.
+    /// Panics if the heap has additional segments.
+    pub fn duplicate(&self) -> (Heap, isize) {
+        assert!(
+            self.segments.is_empty(),
+            "Heaps with additional segments can't be duplicated"
+        );
+
+        let align = mem::align_of::<usize>();
+        let layout = Layout::from_size_align(self.max_size * WORD_SIZE, align).unwrap();
+
+        unsafe {
+            let data = NonNull::new(alloc(layout))
+                .expect("Could not allocate the heap memory")
+                .cast::<usize>()
+                .as_ptr();
+            self.data.copy_to_nonoverlapping(data, self.size);
+
+            let delta = (data as isize).wrapping_sub(self.data as isize);
+            let mut free_blocks = self.free_blocks.new_empty();
+            for block in self.free_blocks.iter() {
+                free_blocks.add(block.relocated(delta));
+            }
+            let heap = Heap {
+                size: self.size,
+                max_size: self.max_size,
+                used_size: self.used_size,
+                data,
+                heap_end: data.add(self.size) as usize,
+                backing: Backing::Allocated(layout),
+                segments: Vec::new(),
+                free_blocks,
+                free_headers: self.free_headers.clone(),
+                used_blocks: self.used_blocks.relocated(delta),
+                fit_policy: self.fit_policy,
+                size_rounding: self.size_rounding,
+                active: self.active.map(|b| b.relocated(delta)),
+                split_threshold: self.split_threshold,
+                quarantine: self.quarantine.iter().map(|b| b.relocated(delta)).collect(),
+                quarantine_len: self.quarantine_len,
+                hot_cache: self.hot_cache.iter().map(|b| b.relocated(delta)).collect(),
+                hot_size: self.hot_size,
+                hot_capacity: self.hot_capacity,
+                deferred: self
+                    .deferred
+                    .iter()
+                    .map(|b| ByAddress(b.0.relocated(delta)))
+                    .collect(),
+                flush_on_failure: self.flush_on_failure,
+                slack: self
+                    .slack
+                    .iter()
+                    .map(|(&a, &s)| (a.wrapping_add_signed(delta), s))
+                    .collect(),
+                slack_words: self.slack_words,
+                rounding: self
+                    .rounding
+                    .iter()
+                    .map(|(&a, &r)| (a.wrapping_add_signed(delta), r))
+                    .collect(),
+                rounding_words: self.rounding_words,
+                check_allocs: self.check_allocs,
+                poison: self.poison,
+                checksums: self.checksums,
+                events: self.events.clone(),
+                coalesce_stats: self.coalesce_stats,
+                last_split: None,
+                undo_splits: self.undo_splits,
+                undone_splits: self.undone_splits,
+            };
+
+            (heap, delta)
+        }
+    }
+}
+
+impl Heap {
+    fn bitmap_words(heap_words: usize) -> usize {
+        heap_words.div_ceil(u64::BITS as usize)
+    }
+
+    /// The word and the bit of the header of block in free_headers
+    fn free_bit(&self, block: Block) -> (usize, u64) {
+        let offset = self.offset_of(block.as_ptr());
+        let bits = u64::BITS as usize;
+        (offset / bits, 1 << (offset % bits))
+    }
+
+    /// Grows free_headers, so that it has a bit for every offset below end.
+    /// The segments have higher offsets than the reserved memory, so it
+    /// never shrinks.
+    fn cover_offsets(&mut self, end: usize) {
+        let words = Heap::bitmap_words(end);
+        if words > self.free_headers.len() {
+            self.free_headers.resize(words, 0);
+        }
+    }
+
+    fn set_free_bit(&mut self, block: Block, free: bool) {
+        let (word, bit) = self.free_bit(block);
+        if free {
+            self.free_headers[word] |= bit;
+        } else {
+            self.free_headers[word] &= !bit;
+        }
+    }
+
+    /// Checks if block is in free_blocks in constant time
     fn is_free(&self, block: Block) -> bool {
-        self.free_blocks.contains(block)
+        let (word, bit) = self.free_bit(block);
+        self.free_headers.get(word).is_some_and(|&w| w & bit != 0)
+    }
+
+    fn add_free(&mut self, block: Block) -> bool {
+        let added = self.free_blocks.add(block);
+        if added {
+            self.set_free_bit(block, true);
+        }
+        added
+    }
+
+    fn remove_free(&mut self, block: Block) {
+        if self.free_blocks.remove(block) {
+            self.set_free_bit(block, false);
+        }
+    }
+
+    fn replace_free(&mut self, old: Block, block: Block) {
+        self.free_blocks.replace(old, block);
+        self.set_free_bit(old, false);
+        self.set_free_bit(block, true);
+    }
+
+    fn take_free_fit(
+        &mut self,
+        min_size: HalfWord,
+        policy: FitPolicy,
+        predicate: &dyn Fn(&Block) -> bool,
+    ) -> Option<Block> {
+        let block = self.free_blocks.take_fit(min_size, policy, predicate)?;
+        self.set_free_bit(block, false);
+        Some(block)
     }
 
     pub fn size(&self) -> usize {
         self.size
     }
 
+    /// The number of words the heap can grow to
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// The size of the heap including all additional segments
+    pub fn total_size(&self) -> usize {
+        self.size + self.segments.iter().map(|s| s.size).sum::<usize>()
+    }
+
+    /// The number of memory regions, including the reserved one
+    pub fn num_segments(&self) -> usize {
+        1 + self.segments.len()
+    }
+
+    /// Changes the smallest remainder (in words, including its header),
+    /// which is split off a free block during an allocation.
+    /// Smaller remainders are handed out as part of the allocation.
+    pub fn set_split_threshold(&mut self, threshold: HalfWord) {
+        assert!(threshold > 0, "A block needs at least one word");
+        self.split_threshold = threshold;
+    }
+
+    pub fn fit_policy(&self) -> FitPolicy {
+        self.fit_policy
+    }
+
+    pub fn set_fit_policy(&mut self, policy: FitPolicy) {
+        self.fit_policy = policy;
+    }
+
+    pub fn size_rounding(&self) -> SizeRounding {
+        self.size_rounding
+    }
+
+    pub fn set_size_rounding(&mut self, rounding: SizeRounding) {
+        assert!(
+            rounding != SizeRounding::MultipleOf(0),
+            "Sizes can't be rounded to a multiple of 0"
+        );
+        self.size_rounding = rounding;
+    }
+
+    /// Checks the header of every free block before it is handed out, even
+    /// in release builds (debug builds always check).
+    pub fn set_alloc_checks(&mut self, enabled: bool) {
+        self.check_allocs = enabled;
+    }
+
+    /// Fills the payload of every free (or quarantined or cached) block with
+    /// POISON, so that writes through stale addresses are detected by the
+    /// allocation checks.
+    pub fn set_poisoning(&mut self, enabled: bool) {
+        if enabled && !self.poison {
+            let cached = self.quarantine.iter().chain(self.hot_cache.iter());
+            for block in self.free_blocks.iter().chain(cached.copied()) {
+                unsafe { Heap::poison_words(block.as_ptr().add(1), block.size() as usize - 1) };
+                self.seal(block);
+            }
+        }
+        self.poison = enabled;
+    }
+
+    unsafe fn poison_words(ptr: *mut usize, words: usize) {
+        Address::from(ptr as usize).range(words).fill(Heap::POISON);
+    }
+
+    /// Reserves the last word of every block for a checksum of the sizes in
+    /// its header, which is checked before the header is trusted by free,
+    /// alloc, coalescing and verify, even in release builds. A corrupted
+    /// header panics with the offset of its block.
+    /// Every allocation needs one more word and blocks are at least two
+    /// words long. The flags of the header are not covered, because they
+    /// change during every collection.
+    /// Panics if the heap contains used or quarantined blocks.
+    pub fn set_header_checksums(&mut self, enabled: bool) {
+        self.flush_hot_cache();
+        self.flush_deferred();
+        assert!(
+            self.used_blocks.is_empty() && self.quarantine.is_empty(),
+            "Header checksums can only be changed while the heap is empty"
+        );
+        self.checksums = enabled;
+        for block in self.free_blocks.iter() {
+            self.seal(block);
+        }
+    }
+
+    pub fn header_checksums(&self) -> bool {
+        self.checksums
+    }
+
+    /// The number of words a block needs in addition to its payload
+    pub(crate) fn overhead(&self) -> HalfWord {
+        1 + self.checksums as HalfWord
+    }
+
+    /// The size of the smallest block, which can hold size words, including
+    /// the rounding, the header and the checksum
+    pub fn block_words(&self, size: HalfWord) -> usize {
+        self.rounded(size) as usize + self.overhead() as usize
+    }
+
+    fn rounded(&self, size: HalfWord) -> HalfWord {
+        // sizes which can't be rounded are allocated as requested
+        self.size_rounding
+            .round(size)
+            .filter(|&size| size < BlockHeader::MAX_SIZE)
+            .unwrap_or(size)
+    }
+
+    /// The number of payload words of block, without the checksum
+    pub fn payload_words(&self, block: Block) -> HalfWord {
+        block.size() - self.overhead()
+    }
+
+    fn checksum(block: Block) -> usize {
+        let pred = (block.pred_size() as usize).rotate_left(usize::BITS / 2);
+        (pred ^ block.size() as usize).wrapping_mul(0x9E37_79B9) ^ Heap::CHECKSUM_SALT
+    }
+
+    /// Writes the checksum of the header of block into its last word
+    fn seal(&self, block: Block) {
+        if self.checksums {
+            unsafe { *block.as_ptr().add(block.size() as usize - 1) = Heap::checksum(block) };
+        }
+    }
+
+    /// Checks the header of block against its checksum
+    fn check_header(&self, block: Block) -> Result<(), CorruptionError> {
+        if !self.checksums {
+            return Ok(());
+        }
+
+        let ptr = block.as_ptr();
+        let offset = self.offset_of(ptr);
+        let corrupted = |kind| Err(CorruptionError { offset, kind });
+
+        let size = block.size() as usize;
+        let remaining = (self.end_of(block) - ptr as usize) / WORD_SIZE;
+        if size < 2 {
+            return corrupted(Corruption::ZeroSize);
+        }
+        if size > remaining {
+            return corrupted(Corruption::SizeOutOfBounds);
+        }
+        if unsafe { *ptr.add(size - 1) } != Heap::checksum(block) {
+            return corrupted(Corruption::ChecksumMismatch);
+        }
+        Ok(())
+    }
+
+    fn assert_header(&self, block: Block) {
+        if let Err(e) = self.check_header(block) {
+            panic!("{}", e);
+        }
+    }
+
+    /// Splits block after size words and updates the checksums of both parts
+    /// and of the successor
+    fn split(&mut self, block: Block, size: HalfWord) -> (Block, Block) {
+        let end = self.end_of(block);
+        let (first, second) = unsafe { block.split_after(size, end) };
+        self.seal(first);
+        self.seal(second);
+        if let Some(next) = second.next_block(end) {
+            self.seal(next);
+        }
+
+        self.log(first, |offset| HeapEvent::Split {
+            offset,
+            size: first.size() as usize,
+            rest: second.size() as usize,
+        });
+        (first, second)
+    }
+
+    /// Records the event of block in the event log, if it is enabled.
+    /// event gets called with the offset of block.
+    fn log<F>(&mut self, block: Block, event: F)
+    where
+        F: FnOnce(usize) -> HeapEvent,
+    {
+        if self.events.is_none() {
+            return;
+        }
+
+        let event = event(self.offset_of(block.as_ptr()));
+        self.record(event);
+    }
+
+    /// Records event in the event log, if it is enabled
+    pub fn record(&mut self, event: HeapEvent) {
+        if let Some(events) = self.events.as_mut() {
+            events.record(event);
+        }
+    }
+
+    /// Keeps the last capacity events (see HeapEvent). A capacity of 0
+    /// disables the log.
+    pub fn set_event_log(&mut self, capacity: usize) {
+        self.events = Some(capacity).filter(|&c| c > 0).map(EventLog::new);
+    }
+
+    /// The recorded events, oldest first
+    pub fn events(&self) -> impl Iterator<Item = HeapEvent> + '_ {
+        self.events.iter().flat_map(EventLog::iter)
+    }
+
+    /// All words of the heap (including the block headers), e.g. for offline
+    /// analysis with BlockHeader::decode.
+    /// Additional segments are not included.
+    pub fn raw_words(&self) -> &[usize] {
+        unsafe { slice::from_raw_parts(self.data, self.size) }
+    }
+
+    /// The address of the first word of the heap
+    pub fn start(&self) -> usize {
+        self.data as usize
+    }
+
     pub fn num_used_blocks(&self) -> usize {
         self.used_blocks.len()
     }
@@ -66,6 +700,180 @@ impl Heap {
     pub fn num_free_blocks(&self) -> usize {
         self.free_blocks.len()
     }
+
+    /// The number of freed blocks, which can't be allocated yet
+    pub fn num_quarantined_blocks(&self) -> usize {
+        self.quarantine.len()
+    }
+
+    /// Keeps the last blocks freed blocks out of the free blocks, so they
+    /// can't be reused immediately. A quarantine of 0 disables it.
+    pub fn set_quarantine(&mut self, blocks: usize) {
+        self.quarantine_len = blocks;
+        while self.quarantine.len() > blocks {
+            self.release_oldest();
+        }
+    }
+
+    /// Makes all quarantined blocks allocatable
+    pub fn flush_quarantine(&mut self) {
+        while !self.quarantine.is_empty() {
+            self.release_oldest();
+        }
+    }
+
+    fn release_oldest(&mut self) {
+        if let Some(block) = self.quarantine.pop_front() {
+            self.assert_intact(block);
+            self.coalesce(block);
+        }
+    }
+
+    /// Keeps up to capacity freed blocks with a payload of exactly size
+    /// words out of the free blocks and hands them out again (most recently
+    /// freed first) before the free blocks are searched. A size or capacity
+    /// of 0 disables the cache. The cached blocks are flushed.
+    pub fn set_hot_size(&mut self, size: HalfWord, capacity: usize) {
+        self.flush_hot_cache();
+        self.hot_size = size;
+        self.hot_capacity = if size == 0 { 0 } else { capacity };
+        self.hot_cache = Vec::with_capacity(self.hot_capacity);
+    }
+
+    /// The number of freed blocks, which are kept for allocations of the hot
+    /// size
+    pub fn num_hot_cached_blocks(&self) -> usize {
+        self.hot_cache.len()
+    }
+
+    /// Returns all cached blocks to the free blocks, where they get merged
+    /// with their free neighbours
+    pub fn flush_hot_cache(&mut self) {
+        while let Some(block) = self.hot_cache.pop() {
+            self.assert_intact(block);
+            self.coalesce(block);
+        }
+    }
+
+    /// Takes the most recently cached block for an allocation of size words
+    fn pop_hot<P>(&mut self, size: HalfWord, predicate: P) -> Option<Block>
+    where
+        P: Fn(&Block) -> bool,
+    {
+        let block = *self.hot_cache.last().filter(|_| size == self.hot_size)?;
+        if !predicate(&block) {
+            return None;
+        }
+
+        self.hot_cache.pop();
+        self.assert_intact(block);
+        self.used_size += block.size() as usize;
+        Some(block)
+    }
+
+    /// Caches the freed block, if it has the hot size and the cache isn't
+    /// full
+    fn push_hot(&mut self, block: Block) -> bool {
+        let hot_block_size = self.hot_size as usize + self.overhead() as usize;
+        if self.hot_cache.len() >= self.hot_capacity || block.size() as usize != hot_block_size {
+            return false;
+        }
+
+        if self.poison {
+            unsafe { Heap::poison_words(block.as_ptr().add(1), block.size() as usize - 1) };
+            self.seal(block);
+        }
+        self.hot_cache.push(block);
+        true
+    }
+
+    fn is_hot_cached(&self, block: Block) -> bool {
+        self.hot_cache.contains(&block)
+    }
+
+    /// Moves the free blocks into store, which is used from now on.
+    /// Panics if store is not empty.
+    pub fn set_block_store(&mut self, mut store: Box<dyn BlockStore>) {
+        assert!(store.is_empty(), "The new block store is not empty");
+        for block in self.free_blocks.iter() {
+            store.add(block);
+        }
+        self.free_blocks = store;
+    }
+
+    /// Enables (the default) or disables flushing the quarantine and the hot
+    /// cache, when there is no free block for an allocation
+    pub fn set_flush_on_failure(&mut self, enabled: bool) {
+        self.flush_on_failure = enabled;
+    }
+
+    /// Enables (the default) or disables the fast path of free for the most
+    /// recently allocated block (see undo_split). The resulting layout is
+    /// the same either way.
+    pub fn set_split_undo(&mut self, enabled: bool) {
+        self.undo_splits = enabled;
+        self.last_split = None;
+    }
+
+    /// How often a freed block was merged back into the remainder it was
+    /// split from by the fast path of free
+    pub fn num_undone_splits(&self) -> usize {
+        self.undone_splits
+    }
+
+    /// Merges the most recently allocated block back into the free remainder
+    /// it was split from, e.g. for a temporary which is freed right after its
+    /// allocation. Unlike coalesce, this only rewrites the two headers behind
+    /// the block and replaces the remainder in place. Returns false without
+    /// doing anything, if block is not the most recent split or if it has a
+    /// free predecessor, which coalesce would have merged as well.
+    fn undo_split(&mut self, mut block: Block, last_split: Option<(Block, Block)>) -> bool {
+        let rest = match last_split {
+            Some((split, rest)) if self.undo_splits && split == block => rest,
+            _ => return false,
+        };
+
+        let adjacent = unsafe { block.as_ptr().add(block.size() as usize) } == rest.as_ptr();
+        let free_pred = block.pred_block(0).is_some_and(|pred| !pred.is_used());
+        if !adjacent || free_pred || rest.is_used() {
+            return false;
+        }
+        self.assert_header(rest);
+        debug_assert!(self.is_free(rest), "Remainder of the split is not free");
+
+        let size = block
+            .size()
+            .checked_add(rest.size())
+            .expect("Block size overflow while coalescing");
+        block.set_size(size);
+        self.replace_free(rest, block);
+        // like in coalesce, so that the active block is the same either way
+        if self.active.is_some_and(|a| block.as_ptr() <= a.as_ptr()) {
+            self.active = None;
+        }
+
+        let successor = rest.next_block(self.end_of(block)).map(|mut next| {
+            next.set_pred_size(size);
+            next
+        });
+        if self.poison {
+            // the old checksum, the header of the remainder and its checksum
+            // are now part of the payload
+            let payload = block.size() as usize - rest.size() as usize;
+            unsafe { Heap::poison_words(block.as_ptr().add(1), payload) };
+        }
+        for &block in iter::once(&block).chain(successor.iter()) {
+            self.seal(block);
+        }
+
+        self.coalesce_stats.with_next += 1;
+        self.undone_splits += 1;
+        self.log(block, |offset| HeapEvent::Coalesce {
+            offset,
+            size: block.size() as usize,
+        });
+        true
+    }
 }
 
 impl Heap {
@@ -73,94 +881,887 @@ impl Heap {
     /// The size in bytes of the block is therefore size * mem::size_of::<usize>()
     /// (technically + one more usize to store information about the block)
     pub fn alloc(&mut self, size: HalfWord) -> Option<Address> {
-        let block = self.alloc_block(size)?;
-        self.used_blocks.add_block(block);
-        Some(Address::from(block))
+        self.alloc_where(size, |_| true)
+    }
+
+    /// Allocates a block, which lies completely outside of the byte range
+    /// [start, end)
+    pub fn alloc_outside(&mut self, size: HalfWord, start: usize, end: usize) -> Option<Address> {
+        self.alloc_where(size, |b| {
+            let block_start = b.as_ptr() as usize;
+            let block_end = block_start + b.size() as usize * WORD_SIZE;
+            block_end <= start || block_start >= end
+        })
+    }
+
+    fn alloc_where<P>(&mut self, requested: HalfWord, predicate: P) -> Option<Address>
+    where
+        P: Fn(&Block) -> bool,
+    {
+        let size = self.rounded(requested);
+
+        self.last_split = None;
+        let block = self
+            .pop_hot(size, &predicate)
+            .or_else(|| self.alloc_block_where(size, &predicate));
+        let mut block = match block {
+            Some(block) => block,
+            None if self.flush_on_failure
+                && (!self.quarantine.is_empty()
+                    || !self.hot_cache.is_empty()
+                    || !self.deferred.is_empty()) =>
+            {
+                self.flush_deferred();
+                self.flush_quarantine();
+                self.flush_hot_cache();
+                self.alloc_block_where(size, predicate)?
+            }
+            None => return None,
+        };
+        block.set_used(true);
+        // a block which wasn't split may still carry the flags of its last use
+        block.set_old(false);
+        block.set_marked(false);
+        let inserted = self.used_blocks.add_block(block);
+        debug_assert!(inserted, "Allocated block was already in use");
+
+        let address = Address::from(block);
+        let slack = block.size() - (size + self.overhead());
+        if slack > 0 {
+            self.slack.insert(address.into(), slack);
+            self.slack_words += slack as usize;
+        }
+        if size > requested {
+            self.rounding.insert(address.into(), size - requested);
+            self.rounding_words += (size - requested) as usize;
+        }
+
+        self.log(block, |offset| HeapEvent::Alloc {
+            offset,
+            size: block.size() as usize,
+        });
+
+        Some(address)
+    }
+
+    /// The number of words, which are part of used blocks without being
+    /// requested, because the sizes were rounded up (see SizeRounding)
+    pub fn rounding_words(&self) -> usize {
+        self.rounding_words
+    }
+
+    /// How often freed blocks were merged with their neighbours
+    pub fn coalesce_stats(&self) -> CoalesceStats {
+        self.coalesce_stats
     }
 
+    /// The number of words, which are part of used blocks without being
+    /// requested, because the remainder was too small to be split off
+    pub fn slack_words(&self) -> usize {
+        self.slack_words
+    }
+
+    /// The number of unrequested words at the end of the used block at
+    /// address
+    pub fn slack_of(&self, address: Address) -> HalfWord {
+        self.slack.get(&address.into()).copied().unwrap_or(0)
+    }
+
+    /// Turns the slack of the used block at address into requested words
+    /// (e.g. because the caller wants to use them) and returns their number
+    pub fn claim_slack(&mut self, address: Address) -> HalfWord {
+        let slack = self.slack.remove(&address.into()).unwrap_or(0);
+        self.slack_words -= slack as usize;
+        slack
+    }
+
+    /// Splits the slack off the used block at address and returns it to the
+    /// free blocks. Slack of a single word can't form a block of its own and
+    /// is kept. Returns the number of recovered words.
+    pub fn trim(&mut self, address: Address) -> HalfWord {
+        let slack = match self.slack.get(&address.into()) {
+            Some(&slack) if slack > self.overhead() => slack,
+            _ => return 0,
+        };
+
+        self.slack.remove(&address.into());
+        self.slack_words -= slack as usize;
+
+        let block = Block::from(address);
+        let (_, _, flags) = BlockHeader::decode(unsafe { *block.as_ptr() });
+        // splitting writes a fresh header without flags
+        let (block, mut rest) = self.split(block, block.size() - slack);
+        unsafe { *block.as_ptr() = BlockHeader::encode(block.pred_size(), block.size(), flags) };
+        rest.set_used(false);
+        self.used_size -= slack as usize;
+        self.coalesce(rest);
+        slack
+    }
+
+    #[cfg(test)]
     fn alloc_block(&mut self, size: HalfWord) -> Option<Block> {
-        let total_size = size + 1;
-        let mut block = self.free_blocks.get_block(total_size)?;
-        self.used_size += total_size as usize;
+        self.alloc_block_where(size, |_| true)
+    }
 
-        if block.size() > (total_size + 2) {
-            unsafe {
-                let (first, second) = block.split_after(total_size);
-                block = first;
-                self.free_blocks.add_block(second);
+    fn alloc_block_where<P>(&mut self, size: HalfWord, predicate: P) -> Option<Block>
+    where
+        P: Fn(&Block) -> bool,
+    {
+        // the header (and the checksum) need additional words
+        let total_size = size
+            .checked_add(self.overhead())
+            .filter(|&size| size <= BlockHeader::MAX_SIZE)?;
+        let is_first_fit = self.fit_policy == FitPolicy::FirstFitLowAddress;
+
+        // fast path: cut the allocation from the front of the active block,
+        // the remainder keeps its place in free_blocks
+        if let Some(active) = self
+            .active
+            .filter(|&b| is_first_fit && self.can_split(b, total_size))
+        {
+            if predicate(&active) {
+                self.assert_intact(active);
+                let (block, rest) = self.split(active, total_size);
+                self.replace_free(active, rest);
+                self.active = Some(rest);
+                self.last_split = Some((block, rest));
+
+                self.used_size += block.size() as usize;
+                return Some(block);
             }
         }
 
+        let mut block = match self.fit_policy {
+            FitPolicy::PreserveWilderness => {
+                let wilderness = self.wilderness();
+                let policy = FitPolicy::BestFit;
+                self.take_free_fit(total_size, policy, &|b| {
+                    Some(*b) != wilderness && predicate(b)
+                })
+                .or_else(|| self.take_free_fit(total_size, policy, &predicate))
+            }
+            policy => self.take_free_fit(total_size, policy, &predicate),
+        }?;
+        self.assert_intact(block);
+
+        if self.active == Some(block) {
+            self.active = None;
+        }
+
+        if self.can_split(block, total_size) {
+            let (first, second) = self.split(block, total_size);
+            block = first;
+            let inserted = self.add_free(second);
+            debug_assert!(inserted, "Split block was already free");
+            self.last_split = Some((first, second));
+
+            if is_first_fit && self.free_blocks.first() == Some(second) {
+                self.active = Some(second);
+            }
+        }
+
+        // if the block wasn't split, the caller gets a few more words
+        self.used_size += block.size() as usize;
         Some(block)
     }
 
+    fn can_split(&self, block: Block, size: HalfWord) -> bool {
+        let threshold = self.split_threshold.max(self.overhead());
+        block.size() >= size && block.size() - size >= threshold
+    }
+
+    fn assert_intact(&self, block: Block) {
+        if cfg!(debug_assertions) || self.check_allocs {
+            if let Err(e) = self.check_free_block(block) {
+                panic!("{}", e);
+            }
+        }
+    }
+
+    /// Checks that the header of a free block is consistent with the heap
+    /// bounds and its successor, and that its payload is still poisoned.
+    fn check_free_block(&self, block: Block) -> Result<(), CorruptionError> {
+        let ptr = block.as_ptr();
+        let offset = self.offset_of(ptr);
+        let corrupted = |kind| Err(CorruptionError { offset, kind });
+
+        let size = block.size() as usize;
+        let end = self.end_of(block);
+        let remaining = (end - ptr as usize) / WORD_SIZE;
+
+        if size == 0 {
+            return corrupted(Corruption::ZeroSize);
+        }
+
+        if size > remaining {
+            return corrupted(Corruption::SizeOutOfBounds);
+        }
+
+        self.check_header(block)?;
+
+        if let Some(next) = block.next_block(end) {
+            if next.pred_size() != block.size() {
+                return corrupted(Corruption::PredSizeMismatch);
+            }
+        }
+
+        if self.poison {
+            let len = self.payload_words(block) as usize;
+            let payload = unsafe { slice::from_raw_parts(ptr.add(1), len) };
+            if payload.iter().any(|&word| word != Heap::POISON) {
+                return corrupted(Corruption::PoisonOverwritten);
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn free(&mut self, address: Address) {
-        // TODO clean up
-        let mut block: Block = address.into();
+        let last_split = self.last_split.take();
+        let mut block = self.forget_used(address);
+        block.set_used(false);
+        self.give_back(block, last_split);
+    }
+
+    /// Takes back the block of the latest allocation, which the caller
+    /// didn't use (e.g. because it exceeds a limit). Unlike free, this
+    /// bypasses the quarantine and the hot cache, so the split is undone.
+    pub fn undo_alloc(&mut self, address: Address) {
+        let last_split = self.last_split.take();
+        let mut block = self.forget_used(address);
+        block.set_used(false);
+        if !self.undo_split(block, last_split) {
+            self.coalesce(block);
+        }
+    }
+
+    /// Like free, but the block keeps its header and is neither used nor
+    /// free until flush_deferred merges all deferred blocks at once
+    pub fn free_deferred(&mut self, address: Address) {
+        self.last_split = None;
+        let block = self.forget_used(address);
+        self.deferred.insert(ByAddress(block));
+    }
+
+    /// The number of blocks freed by free_deferred, which weren't flushed
+    /// yet
+    pub fn num_deferred_blocks(&self) -> usize {
+        self.deferred.len()
+    }
+
+    /// Merges the deferred blocks with each other and with their free
+    /// neighbours in a single pass in address order, so every run of
+    /// adjacent blocks is coalesced only once. Returns the number of
+    /// flushed blocks.
+    pub fn flush_deferred(&mut self) -> usize {
+        let deferred = mem::take(&mut self.deferred);
+        let flushed = deferred.len();
+        let deferred = deferred.into_iter().map(|b| b.0);
+
+        // the quarantine and the hot cache take single blocks
+        if self.quarantine_len > 0 || self.hot_capacity > 0 {
+            for mut block in deferred {
+                block.set_used(false);
+                self.give_back(block, None);
+            }
+            return flushed;
+        }
+
+        let mut blocks = deferred.peekable();
+        while let Some(mut run) = blocks.next() {
+            let end = self.end_of(run);
+            let mut last = run;
+            while let Some(next) = blocks.next_if(|&b| last.next_block(end) == Some(b)) {
+                run.checked_inc_size(next.size())
+                    .expect("Block size overflow while coalescing");
+                last = next;
+            }
+            run.set_used(false);
+            self.coalesce(run);
+        }
+
+        flushed
+    }
+
+    /// Removes a block, which is freed, from the used blocks
+    fn forget_used(&mut self, address: Address) -> Block {
+        if let Some(slack) = self.slack.remove(&address.into()) {
+            self.slack_words -= slack as usize;
+        }
+        if let Some(rounding) = self.rounding.remove(&address.into()) {
+            self.rounding_words -= rounding as usize;
+        }
+
+        let block: Block = address.into();
+        self.assert_header(block);
+        self.log(block, |offset| HeapEvent::Free {
+            offset,
+            size: block.size() as usize,
+        });
         self.used_blocks.remove_block(block);
+        self.used_size -= block.size() as usize;
+        block
+    }
+
+    /// Puts an unused block into the quarantine, the hot cache or the free
+    /// blocks
+    fn give_back(&mut self, block: Block, last_split: Option<(Block, Block)>) {
+        // the quarantine must not be bypassed by the hot cache
+        if self.quarantine_len == 0 {
+            if !self.push_hot(block) && !self.undo_split(block, last_split) {
+                self.coalesce(block);
+            }
+            return;
+        }
+
+        if self.poison {
+            unsafe { Heap::poison_words(block.as_ptr().add(1), block.size() as usize - 1) };
+            self.seal(block);
+        }
+
+        self.quarantine.push_back(block);
+        if self.quarantine.len() > self.quarantine_len {
+            self.release_oldest();
+        }
+    }
+
+    /// Adds up to words words at the end of the heap, without exceeding the
+    /// reserved maximum. Returns the number of added words.
+    pub fn grow(&mut self, words: usize) -> usize {
+        let words = words.min(self.max_size - self.size);
+        if words == 0 {
+            return 0;
+        }
+
+        let last = self.last_block();
+        let ptr = self.heap_end as *mut usize;
+        let block = Block::new(ptr, words as HalfWord, last.size());
+
+        self.size += words;
+        self.heap_end = unsafe { self.data.add(self.size) } as usize;
+        self.cover_offsets(self.size);
+        self.coalesce(block);
+        words
+    }
+
+    /// Removes the words above words from the end of the reserved memory, if
+    /// they belong to the free block at its end, and returns the number of
+    /// removed words. A remainder of the free block, which would be too
+    /// small for a block, is kept as well.
+    /// Nothing is given back to the allocator: the objects can't move, so
+    /// the memory stays reserved and the heap can grow into it again.
+    /// Fails if a block, which is not free (used, quarantined or cached),
+    /// lies above words, and for heaps with segments or a file as backing.
+    pub fn shrink_capacity_to(&mut self, words: usize) -> Result<usize, ShrinkError> {
+        if !self.segments.is_empty() {
+            return Err(ShrinkError::HasSegments);
+        }
+        #[cfg(feature = "mmap")]
+        if let Backing::Mapped(_) = self.backing {
+            return Err(ShrinkError::FileBacked);
+        }
+
+        let last = self.last_block();
+        let start = self.offset_of(last.as_ptr());
+
+        // a heap always contains a block and the rest of the last block has
+        // to hold at least a header (and a checksum)
+        let minimum = self.overhead() as usize;
+        let words = if words > start || start == 0 {
+            words.max(start + minimum)
+        } else {
+            words
+        };
+        if words >= self.size {
+            return Ok(0);
+        }
+
+        if !self.is_free(last) {
+            return Err(ShrinkError::BlockInUse { offset: start });
+        }
+        if start > words {
+            let pred = start - last.pred_size() as usize;
+            return Err(ShrinkError::BlockInUse { offset: pred });
+        }
+
+        self.remove_free(last);
+        self.last_split = None;
+        if self.active == Some(last) {
+            self.active = None;
+        }
+        if start < words {
+            let mut block = last;
+            block.set_size((words - start) as HalfWord);
+            self.seal(block);
+            self.add_free(block);
+        }
+
+        let removed = self.size - words;
+        self.size = words;
+        self.heap_end = unsafe { self.data.add(self.size) } as usize;
+        Ok(removed)
+    }
+
+    /// Chains a new segment of words words to the heap, which consists of a
+    /// single free block. Unlike grow, this is not limited by the reserved
+    /// maximum. Returns the number of added words (0 if the memory couldn't
+    /// be allocated).
+    pub fn add_segment(&mut self, words: usize) -> usize {
+        let words = match Heap::words_for(words * WORD_SIZE) {
+            Ok(words) => words,
+            Err(_) => return 0,
+        };
+
+        let align = mem::align_of::<usize>();
+        let layout = Layout::from_size_align(words * WORD_SIZE, align).unwrap();
+        let data = match NonNull::new(unsafe { alloc(layout) }) {
+            Some(data) => data.cast::<usize>().as_ptr(),
+            None => return 0,
+        };
+
+        let offset = self
+            .segments
+            .last()
+            .map_or(self.max_size, |s| s.offset + s.size);
+        self.segments.push(Segment {
+            data,
+            size: words,
+            offset,
+            layout,
+        });
+        self.cover_offsets(offset + words);
+
+        let block = Block::new(data, words as HalfWord, 0);
+        if self.poison {
+            unsafe { Heap::poison_words(data.add(1), words - 1) };
+        }
+        self.seal(block);
+        // the segment may lie below the active block
+        self.active = None;
+        let inserted = self.add_free(block);
+        debug_assert!(inserted, "New segment was already free");
+        words
+    }
 
+    /// The last block of the reserved memory, if it is free. It is merged
+    /// with the new words when the heap grows.
+    pub fn wilderness(&self) -> Option<Block> {
+        self.free_blocks.find_containing(self.heap_end - WORD_SIZE)
+    }
+
+    /// The last block of the reserved memory
+    fn last_block(&self) -> Block {
+        let ptr = self.heap_end - WORD_SIZE;
+        self.used_blocks
+            .find_containing(ptr)
+            .or_else(|| self.free_blocks.find_containing(ptr))
+            .or_else(|| self.quarantine.iter().copied().find(|b| b.contains(ptr)))
+            .or_else(|| self.hot_cache.iter().copied().find(|b| b.contains(ptr)))
+            .expect("A heap always contains a block")
+    }
+
+    /// Checks if ptr lies inside one of the regions of the heap
+    pub fn contains(&self, ptr: usize) -> bool {
+        self.regions()
+            .any(|(start, end, _)| start <= ptr && ptr < end)
+    }
+
+    /// The start, end and offset of all regions of the heap in address order
+    fn regions(&self) -> impl Iterator<Item = (usize, usize, usize)> + '_ {
+        let reserved = (self.data as usize, self.heap_end, 0);
+        let segments = self.segments.iter().map(|s| {
+            let start = s.data as usize;
+            (start, start + s.size * WORD_SIZE, s.offset)
+        });
+        iter::once(reserved).chain(segments)
+    }
+
+    /// The region containing ptr. Pointers outside of the heap belong to the
+    /// reserved memory.
+    fn region_of(&self, ptr: usize) -> (usize, usize, usize) {
+        self.segments
+            .iter()
+            .map(|s| {
+                (
+                    s.data as usize,
+                    s.data as usize + s.size * WORD_SIZE,
+                    s.offset,
+                )
+            })
+            .find(|&(start, end, _)| start <= ptr && ptr < end)
+            .unwrap_or((self.data as usize, self.heap_end, 0))
+    }
+
+    /// The end of the region containing block. Blocks never cross it.
+    fn end_of(&self, block: Block) -> usize {
+        self.region_of(block.as_ptr() as usize).1
+    }
+
+    fn is_quarantined(&self, block: Block) -> bool {
+        self.quarantine.contains(&block)
+    }
+
+    fn is_deferred(&self, block: Block) -> bool {
+        self.deferred.contains(&ByAddress(block))
+    }
+
+    /// Checks if block belongs to an object. Freed blocks, which were
+    /// deferred (see free_deferred), still have the used flag.
+    pub fn is_allocated(&self, block: Block) -> bool {
+        block.is_used() && !self.is_deferred(block)
+    }
+
+    /// Merges an unused block with its free neighbours and adds the result
+    /// to the free blocks
+    fn coalesce(&mut self, mut block: Block) {
+        // TODO clean up
+        // the remainder of the last split may get merged
+        self.last_split = None;
         let mut size = block.size();
-        self.used_size -= size as usize;
 
-        let next_block = block.next_block(self.heap_end);
+        let (start, end, _) = self.region_of(block.as_ptr() as usize);
+        let next_block = block.next_block(end);
         let mut freed_next = false;
+        let pred_block = block.pred_block(start);
+        for &neighbour in next_block.iter().chain(pred_block.iter()) {
+            self.assert_header(neighbour);
+        }
 
         if let Some(next) = next_block {
             if self.is_free(next) {
-                self.free_blocks.remove_block(next);
+                self.remove_free(next);
                 size += next.size();
                 freed_next = true;
             }
         }
 
-        let pred_block = block.pred_block(self.data as usize);
+        let freed_ptr = block.as_ptr();
+        let freed_size = size as usize;
+        let mut merged_into_pred = false;
+
+        // the freed memory may now be lower than the active block or may
+        // have swallowed it
+        if self.active.is_some_and(|a| freed_ptr <= a.as_ptr()) {
+            self.active = None;
+        }
+
         if let Some(mut pred) = pred_block {
             if self.is_free(pred) {
-                pred.inc_size(size);
-                size = pred.size();
+                merged_into_pred = true;
+                size = pred
+                    .checked_inc_size(size)
+                    .expect("Block size overflow while coalescing");
+                // the store may sort its blocks by size
+                self.replace_free(pred, pred);
             } else {
                 block.set_size(size);
-                self.free_blocks.add_block(block);
+                let inserted = self.add_free(block);
+                debug_assert!(inserted, "Freed block was already free");
             }
         } else {
             block.set_size(size);
-            self.free_blocks.add_block(block);
+            let inserted = self.add_free(block);
+            debug_assert!(inserted, "Freed block was already free");
         }
 
+        let mut successor = None;
         if freed_next {
-            let after_next = next_block.map(|next| next.next_block(self.heap_end));
+            let after_next = next_block.map(|next| next.next_block(end));
             if let Some(Some(mut after)) = after_next {
                 after.set_pred_size(size);
+                successor = Some(after);
             }
         } else if let Some(mut next) = next_block {
             if !self.is_free(next) {
                 next.set_pred_size(size);
+                successor = Some(next);
+            }
+        }
+
+        if self.poison {
+            // the header of the freed block is only part of a payload if it
+            // was merged into its predecessor
+            unsafe {
+                if merged_into_pred {
+                    // so is the checksum of the predecessor
+                    let checksum = self.checksums as usize;
+                    Heap::poison_words(freed_ptr.sub(checksum), freed_size + checksum);
+                } else {
+                    Heap::poison_words(freed_ptr.add(1), freed_size - 1);
+                }
             }
         }
+
+        let merged = if merged_into_pred {
+            pred_block
+        } else {
+            Some(block)
+        };
+        for &block in merged.iter().chain(successor.iter()) {
+            self.seal(block);
+        }
+
+        match (merged_into_pred, freed_next) {
+            (true, true) => self.coalesce_stats.three_way += 1,
+            (true, false) => self.coalesce_stats.with_pred += 1,
+            (false, true) => self.coalesce_stats.with_next += 1,
+            (false, false) => {}
+        }
+
+        if let Some(merged) = merged.filter(|_| freed_next || merged_into_pred) {
+            self.log(merged, |offset| HeapEvent::Coalesce {
+                offset,
+                size: merged.size() as usize,
+            });
+        }
     }
 }
 
 impl Heap {
-    pub fn used<'a>(&'a self) -> Box<Iterator<Item = &Block> + 'a> {
-        self.used_blocks.iter()
+    /// The free blocks in address order (without quarantined blocks)
+    pub fn free_blocks(&self) -> impl Iterator<Item = Block> + '_ {
+        self.free_blocks.iter()
+    }
+
+    /// The used blocks in ascending address order
+    pub fn used(&self) -> UsedBlocks<'_> {
+        UsedBlocks(self.used_blocks.iter())
     }
 
     pub fn used_size(&self) -> usize {
         self.used_size
     }
+
+    /// Walks over all blocks and checks that their headers are consistent
+    /// with each other and with the free and used sets.
+    pub fn verify(&self) -> Result<(), CorruptionError> {
+        let (mut used_blocks, mut free_blocks, mut used_size) = (0, 0, 0);
+
+        for (start, end, _) in self.regions() {
+            let counts = self.verify_region(start, end)?;
+            used_blocks += counts.0;
+            free_blocks += counts.1;
+            used_size += counts.2;
+        }
+
+        let free_bits: u32 = self.free_headers.iter().map(|w| w.count_ones()).sum();
+        let counts_match = used_blocks == self.used_blocks.len()
+            && free_blocks == self.free_blocks.len()
+            && free_bits as usize == free_blocks
+            && used_size == self.used_size;
+
+        if counts_match {
+            Ok(())
+        } else {
+            Err(CorruptionError {
+                offset: 0,
+                kind: Corruption::CountMismatch,
+            })
+        }
+    }
+
+    /// Verifies the blocks between start and end and returns the number of
+    /// used blocks, free blocks and used words
+    fn verify_region(
+        &self,
+        start: usize,
+        end: usize,
+    ) -> Result<(usize, usize, usize), CorruptionError> {
+        let mut ptr = start as *mut usize;
+        let mut pred: Option<Block> = None;
+        let (mut used_blocks, mut free_blocks, mut used_size) = (0, 0, 0);
+
+        while (ptr as usize) < end {
+            let offset = self.offset_of(ptr);
+            let corrupted = |kind| Err(CorruptionError { offset, kind });
+
+            let block = Block::from(ptr as *mut BlockHeader);
+            let size = block.size() as usize;
+            let remaining = (end - ptr as usize) / WORD_SIZE;
+
+            if size == 0 {
+                return corrupted(Corruption::ZeroSize);
+            }
+
+            if size > remaining {
+                return corrupted(Corruption::SizeOutOfBounds);
+            }
+
+            if block.pred_size() != pred.map_or(0, |p| p.size()) {
+                return corrupted(Corruption::PredSizeMismatch);
+            }
+
+            self.check_header(block)?;
+
+            if self.is_free(block) != self.free_blocks.contains(block) {
+                return corrupted(Corruption::FreeBitmapMismatch);
+            }
+
+            if self.is_allocated(block) {
+                if !self.used_blocks.contains(block) || self.is_free(block) {
+                    return corrupted(Corruption::UsedFlagMismatch);
+                }
+                used_blocks += 1;
+                used_size += size;
+            } else if self.is_quarantined(block)
+                || self.is_hot_cached(block)
+                || self.is_deferred(block)
+            {
+                if self.is_free(block) || self.used_blocks.contains(block) {
+                    return corrupted(Corruption::UsedFlagMismatch);
+                }
+            } else {
+                if !self.is_free(block) || self.used_blocks.contains(block) {
+                    return corrupted(Corruption::UsedFlagMismatch);
+                }
+                if pred.is_some_and(|p| self.is_free(p)) {
+                    return corrupted(Corruption::AdjacentFreeBlocks);
+                }
+                free_blocks += 1;
+            }
+
+            pred = Some(block);
+            ptr = unsafe { ptr.add(size) };
+        }
+
+        Ok((used_blocks, free_blocks, used_size))
+    }
+
+    /// Iterates over all blocks (used and free) in address order.
+    /// The blocks of additional segments follow the reserved memory.
+    pub fn blocks(&self) -> impl Iterator<Item = Block> + '_ {
+        self.regions().flat_map(|(start, end, _)| {
+            let first = Block::from(start as *mut BlockHeader);
+            iter::successors(Some(first), move |b| b.next_block(end))
+        })
+    }
+
+    /// The offset of ptr in words from the start of the heap.
+    /// The offsets of additional segments continue after the reserved
+    /// memory (max_size) in the order the segments were added.
+    pub fn offset_of(&self, ptr: *const usize) -> usize {
+        let (start, _, offset) = self.region_of(ptr as usize);
+        offset + (ptr as usize - start) / WORD_SIZE
+    }
+
+    /// Same as offset_of, but returns None if ptr doesn't point into the heap
+    /// or one of its segments
+    pub fn checked_offset_of(&self, ptr: *const usize) -> Option<usize> {
+        let (start, end, offset) = self.region_of(ptr as usize);
+        let ptr = ptr as usize;
+        if start <= ptr && ptr < end {
+            Some(offset + (ptr - start) / WORD_SIZE)
+        } else {
+            None
+        }
+    }
+
+    /// The inverse of offset_of. Returns None if offset is neither inside of
+    /// the heap nor one of its segments.
+    pub fn address_at(&self, offset: usize) -> Option<*mut usize> {
+        if offset < self.size {
+            return Some(unsafe { self.data.add(offset) });
+        }
+        self.segments
+            .iter()
+            .find(|s| s.offset <= offset && offset < s.offset + s.size)
+            .map(|s| unsafe { s.data.add(offset - s.offset) })
+    }
+
+    /// All used blocks whose memory overlaps the byte range [start, end)
+    pub fn used_overlapping(&self, start: usize, end: usize) -> slice::Iter<'_, Block> {
+        self.used_blocks.overlapping(start, end)
+    }
+
+    /// Finds the used block containing address
+    pub fn block_of(&self, address: Address) -> Option<Block> {
+        self.used_blocks.find_containing(address.into())
+    }
+}
+
+/// The used blocks of a heap in ascending address order (see Heap::used).
+/// Blocks of additional segments are ordered by their address as well, not
+/// by their offset.
+pub struct UsedBlocks<'a>(slice::Iter<'a, Block>);
+
+impl Iterator for UsedBlocks<'_> {
+    type Item = Block;
+
+    fn next(&mut self) -> Option<Block> {
+        self.0.next().copied()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for UsedBlocks<'_> {
+    fn next_back(&mut self) -> Option<Block> {
+        self.0.next_back().copied()
+    }
 }
 
+impl ExactSizeIterator for UsedBlocks<'_> {}
+
+impl FusedIterator for UsedBlocks<'_> {}
+
 impl Drop for Heap {
     fn drop(&mut self) {
-        unsafe {
-            dealloc(self.data as *mut u8, self.layout);
+        for segment in &self.segments {
+            unsafe { dealloc(segment.data as *mut u8, segment.layout) };
+        }
+
+        match &self.backing {
+            Backing::Allocated(layout) => unsafe {
+                dealloc(self.data as *mut u8, *layout);
+            },
+            // the mapping itself gets unmapped when it is dropped
+            #[cfg(feature = "mmap")]
+            Backing::Mapped(mapping) => {
+                let _ = mapping.flush();
+            }
         }
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use std::cell::Cell;
+
+    thread_local! {
+        /// Enables the header checksums of every new heap of the thread
+        pub(crate) static CHECKSUMS_BY_DEFAULT: Cell<bool> = const { Cell::new(false) };
+    }
+
+    /// Runs tests with header checksums in every heap they create.
+    /// Tests which expect exact block sizes can't be run this way.
+    pub(crate) fn run_with_checksums(tests: &[fn()]) {
+        CHECKSUMS_BY_DEFAULT.with(|enabled| enabled.set(true));
+        for test in tests {
+            test();
+        }
+        CHECKSUMS_BY_DEFAULT.with(|enabled| enabled.set(false));
+    }
+
+    #[test]
+    fn test_heap_tests_pass_with_checksums() {
+        run_with_checksums(&[
+            test_free_single_block,
+            test_free_adjacent_blocks_list,
+            test_verify_after_alloc_and_free,
+            test_alloc_and_free_set_used_flag,
+            test_alloc_half_word_max_returns_none,
+            test_poisoning_survives_coalescing,
+            test_free_invalidates_active_block,
+            test_quarantined_blocks_are_not_reused_immediately,
+            test_without_quarantine_blocks_are_reused,
+            test_undo_split_needs_the_last_allocation,
+            test_free_bitmap_matches_free_blocks,
+            test_verify_detects_wrong_free_bit,
+        ]);
+    }
 
     #[test]
     fn test_alloc_block_returns_correct_size_when_not_aligned() {
@@ -221,6 +1822,86 @@ mod tests {
         }
     }
 
+    /// Walks from the last block to the start of the heap with pred_block and
+    /// returns the sizes of the visited blocks
+    fn walk_back(heap: &Heap) -> Vec<HalfWord> {
+        let start = heap.data as usize;
+        let mut block = heap.last_block();
+        let mut sizes = vec![block.size()];
+
+        while let Some(pred) = block.pred_block(start) {
+            assert_eq!(pred.size(), block.pred_size());
+            assert_eq!(Some(block), pred.next_block(heap.heap_end));
+            sizes.push(pred.size());
+            block = pred;
+        }
+
+        assert_eq!(start, block.as_ptr() as usize);
+        sizes.reverse();
+        sizes
+    }
+
+    #[test]
+    fn test_split_keeps_pred_sizes_consistent() {
+        unsafe {
+            let mut heap = Heap::new(100 * WORD_SIZE);
+            let a = heap.alloc(10).unwrap();
+            let b = heap.alloc(20).unwrap();
+            assert_eq!(vec![11, 21, 68], walk_back(&heap));
+
+            heap.free(a);
+            heap.free(b);
+            assert_eq!(vec![100], walk_back(&heap));
+            assert_eq!(Ok(()), heap.verify());
+        }
+    }
+
+    #[test]
+    fn test_split_of_reused_block_updates_successor() {
+        unsafe {
+            let mut heap = Heap::new(100 * WORD_SIZE);
+            let a = heap.alloc(10).unwrap();
+            let b = heap.alloc(20).unwrap();
+            heap.free(a);
+
+            // splits a, which is followed by b
+            let c = heap.alloc(4).unwrap();
+            assert_eq!(vec![5, 6, 21, 68], walk_back(&heap));
+
+            heap.free(b);
+            assert_eq!(vec![5, 95], walk_back(&heap));
+            heap.free(c);
+            assert_eq!(vec![100], walk_back(&heap));
+            assert_eq!(Ok(()), heap.verify());
+        }
+    }
+
+    #[test]
+    fn test_used_blocks_are_in_address_order() {
+        unsafe {
+            let mut heap = Heap::new(100 * WORD_SIZE);
+            let sizes = [4, 1, 6, 2];
+            let addresses: Vec<Address> = sizes.iter().map(|&s| heap.alloc(s).unwrap()).collect();
+            heap.free(addresses[1]);
+            // reuses the gap of the freed block
+            let reused = heap.alloc(0).unwrap();
+
+            let used = heap.used();
+            assert_eq!(4, used.len());
+            let used: Vec<Address> = used.map(Address::from).collect();
+            assert_eq!(vec![addresses[0], reused, addresses[2], addresses[3]], used);
+
+            let mut used = heap.used();
+            assert_eq!(Some(3), used.next_back().map(|b| b.size()));
+            assert_eq!(Some(5), used.next().map(|b| b.size()));
+            assert_eq!(2, used.len());
+
+            let reversed: Vec<HalfWord> = heap.used().rev().map(|b| b.size()).collect();
+            // the reused block keeps its slack word
+            assert_eq!(vec![3, 7, 2, 5], reversed);
+        }
+    }
+
     #[test]
     fn test_free_single_block() {
         unsafe {
@@ -257,14 +1938,14 @@ mod tests {
 
             assert_eq!(None, first_block.pred_block(heap.data as usize));
             assert_eq!(Some(second_block), first_block.next_block(heap.heap_end));
-            assert_eq!(false, heap.is_free(first_block));
+            assert!(!heap.is_free(first_block));
 
             assert_eq!(
                 Some(first_block),
                 second_block.pred_block(heap.data as usize)
             );
             assert_eq!(Some(third_block), second_block.next_block(heap.heap_end));
-            assert_eq!(false, heap.is_free(second_block));
+            assert!(!heap.is_free(second_block));
 
             assert_eq!(
                 Some(second_block),
@@ -272,7 +1953,7 @@ mod tests {
             );
             assert!(third_block.next_block(heap.heap_end).is_some());
             assert!(heap.is_free(third_block.next_block(heap.heap_end).unwrap()));
-            assert_eq!(false, heap.is_free(third_block));
+            assert!(!heap.is_free(third_block));
 
             heap.free(Address::from(first_block));
 
@@ -397,7 +2078,7 @@ mod tests {
             assert_eq!(42, *Address::from(block));
 
             let next = block.next_block(heap.heap_end).unwrap();
-            let n_size = 4096 / Heap::H_SIZE - 2;
+            let n_size = 4096 / WORD_SIZE as HalfWord - 2;
 
             assert_eq!(n_size, next.size());
             assert_eq!(2, next.pred_size());
@@ -418,4 +2099,620 @@ mod tests {
             assert_eq!(None, address);
         }
     }
+
+    #[test]
+    fn test_verify_after_alloc_and_free() {
+        unsafe {
+            let mut heap = Heap::new(4096);
+            assert_eq!(Ok(()), heap.verify());
+
+            let addresses: Vec<Address> = (0..10).map(|i| heap.alloc(i).unwrap()).collect();
+            assert_eq!(Ok(()), heap.verify());
+
+            for address in addresses.iter().step_by(2) {
+                heap.free(*address);
+                assert_eq!(Ok(()), heap.verify());
+            }
+
+            // reuse the holes, which splits blocks in the middle of the heap
+            heap.alloc(0).unwrap();
+            heap.alloc(1).unwrap();
+            assert_eq!(Ok(()), heap.verify());
+
+            for address in addresses.iter().skip(1).step_by(2) {
+                heap.free(*address);
+                assert_eq!(Ok(()), heap.verify());
+            }
+        }
+    }
+
+    #[test]
+    fn test_verify_detects_corrupted_header() {
+        unsafe {
+            let mut heap = Heap::new(4096);
+            heap.alloc(4).unwrap();
+            let mut second: Block = heap.alloc(4).unwrap().into();
+
+            second.set_pred_size(3);
+            assert_eq!(
+                Err(CorruptionError {
+                    offset: 5,
+                    kind: Corruption::PredSizeMismatch
+                }),
+                heap.verify()
+            );
+        }
+    }
+
+    #[test]
+    fn test_alloc_and_free_set_used_flag() {
+        unsafe {
+            let mut heap = Heap::new(4096);
+
+            let first: Block = heap.alloc(10).unwrap().into();
+            let second: Block = heap.alloc(10).unwrap().into();
+            assert!(first.is_used());
+            assert!(second.is_used());
+            assert!(!second.next_block(heap.heap_end).unwrap().is_used());
+
+            heap.free(Address::from(first));
+            assert!(!first.is_used());
+            assert!(second.is_used());
+        }
+    }
+
+    #[test]
+    fn test_alloc_half_word_max_returns_none() {
+        unsafe {
+            let mut heap = Heap::new(4096);
+
+            assert_eq!(None, heap.alloc(HALF_WORD_MAX));
+            assert_eq!(None, heap.alloc(HALF_WORD_MAX - 1));
+            assert_eq!(None, heap.alloc(BlockHeader::MAX_SIZE));
+            assert_eq!(None, heap.alloc(BlockHeader::MAX_SIZE - 1));
+            assert_eq!(0, heap.used_blocks.len());
+            assert_eq!(1, heap.free_blocks.len());
+            assert_eq!(0, heap.used_size());
+        }
+    }
+
+    #[test]
+    fn test_words_for_rejects_zero_size() {
+        assert_eq!(Err(NewHeapError::ZeroSize), Heap::words_for(0));
+        assert!(Heap::try_new(0).is_err());
+    }
+
+    #[test]
+    fn test_words_for_rounds_up() {
+        assert_eq!(Ok(1), Heap::words_for(1));
+        assert_eq!(Ok(1), Heap::words_for(WORD_SIZE));
+        assert_eq!(Ok(2), Heap::words_for(WORD_SIZE + 1));
+
+        let heap = Heap::try_new(4 * WORD_SIZE + 1).unwrap();
+        assert_eq!(5, heap.size());
+        assert_eq!(1, heap.num_free_blocks());
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_max_bytes_on_64_bit() {
+        assert_eq!(HALF_WORD_MAX as usize, Heap::MAX_BYTES);
+        assert_eq!(Ok(0x2000_0000), Heap::words_for(Heap::MAX_BYTES));
+        assert_eq!(
+            Err(NewHeapError::TooLarge(0x1_0000_0000)),
+            Heap::words_for(HALF_WORD_MAX as usize + 1)
+        );
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "32")]
+    fn test_max_bytes_on_32_bit() {
+        // rounding HALF_WORD_MAX up would exceed the maximum block size
+        assert_eq!(0xFFFC, Heap::MAX_BYTES);
+        assert_eq!(Ok(0x3FFF), Heap::words_for(Heap::MAX_BYTES));
+        assert_eq!(
+            Err(NewHeapError::TooLarge(0xFFFF)),
+            Heap::words_for(HALF_WORD_MAX as usize)
+        );
+    }
+
+    /// Allocates two blocks of 4 words and frees the first one
+    fn heap_with_free_block(poison: bool) -> (Heap, Address) {
+        let mut heap = Heap::try_new(4096).unwrap();
+        heap.set_poisoning(poison);
+
+        let stale = heap.alloc(3).unwrap();
+        heap.alloc(3).unwrap();
+        heap.free(stale);
+        (heap, stale)
+    }
+
+    fn header_of(mut address: Address) -> *mut usize {
+        unsafe { address.as_mut().sub(1) }
+    }
+
+    #[test]
+    #[should_panic(expected = "Corrupted block at offset 0: PoisonOverwritten")]
+    fn test_alloc_detects_write_to_poisoned_block() {
+        let (mut heap, mut stale) = heap_with_free_block(true);
+        stale.write(42);
+        heap.alloc(3);
+    }
+
+    #[test]
+    #[should_panic(expected = "Corrupted block at offset 0: SizeOutOfBounds")]
+    fn test_alloc_detects_size_out_of_bounds() {
+        let (mut heap, stale) = heap_with_free_block(false);
+        unsafe { *header_of(stale) = BlockHeader::new(0, 1000).into() };
+        heap.alloc(3);
+    }
+
+    #[test]
+    #[should_panic(expected = "Corrupted block at offset 0: PredSizeMismatch")]
+    fn test_alloc_detects_pred_size_mismatch() {
+        let (mut heap, stale) = heap_with_free_block(false);
+        unsafe {
+            // the block now seems to end in the middle of its old payload
+            *header_of(stale) = BlockHeader::new(0, 2).into();
+            (stale + 1).write(0);
+        }
+        heap.alloc(0);
+    }
+
+    fn heap_with_checksums() -> (Heap, [Address; 3]) {
+        let mut heap = Heap::try_new(4096).unwrap();
+        heap.set_header_checksums(true);
+        // every block is 4 words long, the last one holds the checksum
+        let blocks = [(); 3].map(|_| heap.alloc(2).unwrap());
+        (heap, blocks)
+    }
+
+    #[test]
+    fn test_checksums_reserve_the_last_word() {
+        let (mut heap, [a, b, c]) = heap_with_checksums();
+        assert_eq!(4, Block::from(b).size());
+        assert_eq!(2, heap.payload_words(Block::from(b)));
+        assert_eq!(0, heap.slack_words());
+        assert_eq!(Ok(()), heap.verify());
+
+        heap.set_poisoning(true);
+        heap.set_quarantine(1);
+        heap.free(b);
+        heap.free(a);
+        let d = heap.alloc(5).unwrap();
+        assert_eq!(Ok(()), heap.verify());
+
+        heap.free(c);
+        heap.free(d);
+        heap.flush_quarantine();
+        assert_eq!(1, heap.num_free_blocks());
+        assert_eq!(0, heap.used_size());
+        assert_eq!(Ok(()), heap.verify());
+
+        heap.add_segment(10);
+        let e = heap.alloc(heap.size() as HalfWord - 2).unwrap();
+        let f = heap.alloc(5).unwrap();
+        (e + 1).write(42);
+        assert_eq!(Ok(()), heap.verify());
+        heap.free(f);
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_verify_detects_checksum_mismatch() {
+        let (heap, [_, b, _]) = heap_with_checksums();
+        unsafe { *header_of(b) ^= 1 << HalfWord::BITS };
+
+        let error = heap.verify().unwrap_err();
+        assert_eq!(4, error.offset);
+    }
+
+    #[test]
+    #[should_panic(expected = "Corrupted block at offset 4: ChecksumMismatch")]
+    fn test_free_detects_flipped_pred_size() {
+        let (mut heap, [_, b, _]) = heap_with_checksums();
+        unsafe { *header_of(b) ^= 1 << HalfWord::BITS };
+        heap.free(b);
+    }
+
+    #[test]
+    #[should_panic(expected = "Corrupted block at offset 4: ChecksumMismatch")]
+    fn test_free_detects_flipped_size_of_next_block() {
+        let (mut heap, [a, b, _]) = heap_with_checksums();
+        unsafe { *header_of(b) ^= 1 };
+        heap.free(a);
+    }
+
+    #[test]
+    fn test_poisoning_survives_coalescing() {
+        let mut heap = Heap::try_new(4096).unwrap();
+        heap.set_poisoning(true);
+
+        let blocks: Vec<_> = (0..10).map(|i| heap.alloc(i).unwrap()).collect();
+        for &i in &[1, 3, 2, 7, 9, 8, 0] {
+            heap.free(blocks[i]);
+        }
+
+        for block in heap.free_blocks.iter() {
+            assert!(heap.check_free_block(block).is_ok());
+        }
+
+        for i in 0..10 {
+            heap.alloc(i).unwrap();
+        }
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_small_allocations_are_cut_from_active_block() {
+        let mut heap = Heap::try_new(4096 * WORD_SIZE).unwrap();
+
+        for _ in 0..1000 {
+            heap.alloc(2).unwrap();
+            assert_eq!(1, heap.free_blocks.len());
+            assert_eq!(heap.free_blocks.first(), heap.active);
+        }
+
+        assert_eq!(3000, heap.used_size());
+        assert_eq!(1001, heap.blocks().count());
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_free_invalidates_active_block() {
+        let mut heap = Heap::try_new(100 * WORD_SIZE).unwrap();
+        let first = heap.alloc(2).unwrap();
+        assert!(heap.active.is_some());
+
+        // the hole is lower than the active block, so first fit has to use it
+        heap.free(first);
+        assert!(heap.active.is_none());
+        assert_eq!(first, heap.alloc(2).unwrap());
+
+        // second gets merged with the active block
+        let second = heap.alloc(2).unwrap();
+        assert!(heap.active.is_some());
+        heap.free(second);
+        assert!(heap.active.is_none());
+        assert_eq!(second, heap.alloc(2).unwrap());
+        assert_eq!(heap.free_blocks.first(), heap.active);
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_quarantined_blocks_are_not_reused_immediately() {
+        let mut heap = Heap::try_new(100 * WORD_SIZE).unwrap();
+        heap.set_quarantine(2);
+
+        let a = heap.alloc(2).unwrap();
+        heap.free(a);
+        let b = heap.alloc(2).unwrap();
+        assert_ne!(a, b);
+        heap.free(b);
+        assert_eq!(2, heap.num_quarantined_blocks());
+        assert_eq!(Ok(()), heap.verify());
+
+        // a leaves the quarantine and gets merged with its free neighbours
+        // (b is still quarantined)
+        let c = heap.alloc(2).unwrap();
+        heap.free(c);
+        assert_eq!(
+            vec![b, c],
+            heap.quarantine
+                .iter()
+                .map(|&b| Address::from(b))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(2, heap.num_free_blocks());
+        assert_eq!(a, heap.alloc(2).unwrap());
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_without_quarantine_blocks_are_reused() {
+        let mut heap = Heap::try_new(100 * WORD_SIZE).unwrap();
+        let a = heap.alloc(2).unwrap();
+        heap.free(a);
+        assert_eq!(0, heap.num_quarantined_blocks());
+        assert_eq!(a, heap.alloc(2).unwrap());
+    }
+
+    #[test]
+    fn test_failing_alloc_flushes_quarantine() {
+        let mut heap = Heap::try_new(12 * WORD_SIZE).unwrap();
+        heap.set_quarantine(10);
+
+        let blocks: Vec<_> = (0..4).map(|_| heap.alloc(2).unwrap()).collect();
+        assert!(heap.alloc(2).is_none());
+        for &block in &blocks {
+            heap.free(block);
+        }
+        assert_eq!(4, heap.num_quarantined_blocks());
+        assert_eq!(0, heap.num_free_blocks());
+
+        // the whole heap is needed, so everything has to be merged again
+        heap.alloc(11).unwrap();
+        assert_eq!(0, heap.num_quarantined_blocks());
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    #[should_panic(expected = "PoisonOverwritten")]
+    fn test_write_to_quarantined_block_is_detected() {
+        let mut heap = Heap::try_new(100 * WORD_SIZE).unwrap();
+        heap.set_poisoning(true);
+        heap.set_quarantine(1);
+
+        let mut a = heap.alloc(2).unwrap();
+        heap.free(a);
+        a.write(42);
+
+        // a leaves the quarantine
+        let b = heap.alloc(2).unwrap();
+        heap.free(b);
+    }
+
+    #[test]
+    fn test_grow_extends_last_block() {
+        let mut heap = Heap::try_with_max(10 * WORD_SIZE, 30 * WORD_SIZE).unwrap();
+        let a = heap.alloc(9).unwrap();
+        assert_eq!(0, heap.num_free_blocks());
+
+        // the last block is used, so a new free block is added
+        assert_eq!(10, heap.grow(10));
+        assert_eq!(20, heap.size());
+        assert_eq!(1, heap.num_free_blocks());
+        assert_eq!(Ok(()), heap.verify());
+
+        // the new words are merged with the free last block
+        assert_eq!(10, heap.grow(100));
+        assert_eq!(0, heap.grow(1));
+        assert_eq!(1, heap.num_free_blocks());
+        assert_eq!(30, heap.raw_words().len());
+        assert_eq!(Ok(()), heap.verify());
+
+        heap.free(a);
+        assert!(heap.alloc(29).is_some());
+    }
+
+    #[test]
+    fn test_shrink_cuts_off_free_end() {
+        let mut heap = Heap::try_with_max(100 * WORD_SIZE, 200 * WORD_SIZE).unwrap();
+        heap.set_poisoning(true);
+        let low: Vec<_> = (0..4).map(|_| heap.alloc(9).unwrap()).collect();
+        let high: Vec<_> = (0..4).map(|_| heap.alloc(9).unwrap()).collect();
+
+        // everything above the watermark of 40 words is freed
+        for a in high {
+            heap.free(a);
+        }
+        assert_eq!(Ok(50), heap.shrink_capacity_to(50));
+        assert_eq!(50, heap.size());
+        assert_eq!(200, heap.max_size());
+        assert_eq!(Ok(()), heap.verify());
+        assert_eq!(
+            vec![10, 10, 10, 10, 10],
+            heap.blocks().map(|b| b.size()).collect::<Vec<_>>()
+        );
+
+        // the rest of the free block can be removed as well
+        assert_eq!(Ok(10), heap.shrink_capacity_to(40));
+        assert_eq!(0, heap.num_free_blocks());
+        assert_eq!(None, heap.alloc(1));
+        assert_eq!(Ok(0), heap.shrink_capacity_to(60));
+
+        heap.free(low[3]);
+        assert!(heap.alloc(9).is_some());
+        assert_eq!(20, heap.grow(20));
+        assert!(heap.alloc(19).is_some());
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_shrink_keeps_room_for_the_checksum() {
+        let mut heap = Heap::try_new(100 * WORD_SIZE).unwrap();
+        heap.set_header_checksums(true);
+        heap.alloc(8).unwrap();
+
+        // a single word can't hold a header and a checksum
+        assert_eq!(Ok(88), heap.shrink_capacity_to(11));
+        assert_eq!(Ok(()), heap.verify());
+        assert_eq!(
+            vec![10, 2],
+            heap.blocks().map(|b| b.size()).collect::<Vec<_>>()
+        );
+        assert_eq!(Ok(2), heap.shrink_capacity_to(10));
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_shrink_fails_above_used_blocks() {
+        let mut heap = Heap::try_new(100 * WORD_SIZE).unwrap();
+        heap.alloc(9).unwrap();
+        let a = heap.alloc(9).unwrap();
+
+        assert_eq!(
+            Err(ShrinkError::BlockInUse { offset: 10 }),
+            heap.shrink_capacity_to(15)
+        );
+        assert_eq!(Ok(80), heap.shrink_capacity_to(20));
+        assert_eq!(
+            Err(ShrinkError::BlockInUse { offset: 10 }),
+            heap.shrink_capacity_to(15)
+        );
+
+        // a quarantined block is not free yet
+        heap.set_quarantine(1);
+        heap.free(a);
+        assert_eq!(
+            Err(ShrinkError::BlockInUse { offset: 10 }),
+            heap.shrink_capacity_to(15)
+        );
+        heap.flush_quarantine();
+        assert_eq!(Ok(5), heap.shrink_capacity_to(15));
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_alloc_uses_new_segment_when_heap_is_full() {
+        let mut heap = Heap::try_new(10 * WORD_SIZE).unwrap();
+        let a = heap.alloc(9).unwrap();
+        assert_eq!(None, heap.alloc(4));
+
+        assert_eq!(10, heap.add_segment(10));
+        assert_eq!(2, heap.num_segments());
+        assert_eq!(20, heap.total_size());
+        assert_eq!(10, heap.size());
+
+        let b = heap.alloc(4).unwrap();
+        assert_eq!(0, heap.offset_of(Block::from(a).as_ptr()));
+        // segment offsets continue after the reserved memory
+        assert_eq!(10, heap.offset_of(Block::from(b).as_ptr()));
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_blocks_are_not_merged_across_segments() {
+        let mut heap = Heap::try_new(10 * WORD_SIZE).unwrap();
+        let a = heap.alloc(9).unwrap();
+        heap.add_segment(10);
+        let b = heap.alloc(9).unwrap();
+
+        // neither block has a neighbour inside of its segment
+        for block in [Block::from(a), Block::from(b)] {
+            assert_eq!(None, block.next_block(heap.end_of(block)));
+            assert_eq!(0, block.pred_size());
+        }
+
+        heap.free(a);
+        heap.free(b);
+        assert_eq!(2, heap.num_free_blocks());
+        assert_eq!(
+            vec![10, 10],
+            heap.blocks().map(|b| b.size()).collect::<Vec<_>>()
+        );
+        assert_eq!(Ok(()), heap.verify());
+
+        // a block spanning both segments can't be allocated
+        assert_eq!(None, heap.alloc(15));
+        assert!(heap.alloc(9).is_some());
+        assert!(heap.alloc(9).is_some());
+    }
+
+    #[test]
+    fn test_verify_detects_corruption_in_segment() {
+        let mut heap = Heap::try_new(10 * WORD_SIZE).unwrap();
+        heap.alloc(9).unwrap();
+        heap.add_segment(10);
+        let b = heap.alloc(4).unwrap();
+
+        unsafe { Block::from(b).as_ptr().add(5).write(0) };
+        let error = heap.verify().unwrap_err();
+        assert_eq!(Corruption::ZeroSize, error.kind);
+        assert_eq!(15, error.offset);
+    }
+
+    #[test]
+    fn test_free_of_last_allocation_undoes_split() {
+        let mut heap = Heap::try_new(100 * WORD_SIZE).unwrap();
+        heap.set_poisoning(true);
+        let a = heap.alloc(10).unwrap();
+        let tmp = heap.alloc(5).unwrap();
+        heap.free(tmp);
+        assert_eq!(1, heap.num_undone_splits());
+        assert_eq!(vec![11, 89], walk_back(&heap));
+        assert_eq!(Ok(()), heap.verify());
+
+        // the remainder isn't the active block with best fit
+        heap.set_fit_policy(FitPolicy::BestFit);
+        let b = heap.alloc(20).unwrap();
+        heap.free(a);
+        let tmp = heap.alloc(5).unwrap();
+        assert_eq!(Block::from(a), Block::from(tmp));
+        heap.free(tmp);
+        assert_eq!(2, heap.num_undone_splits());
+        assert_eq!(vec![11, 21, 68], walk_back(&heap));
+        assert_eq!(Ok(()), heap.verify());
+        assert!(heap.alloc(4).is_some() && heap.alloc(5).is_some());
+        heap.free(b);
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_undo_split_needs_the_last_allocation() {
+        let mut heap = Heap::try_new(100 * WORD_SIZE).unwrap();
+        let a = heap.alloc(10).unwrap();
+        let b = heap.alloc(10).unwrap();
+        heap.free(a);
+        // the remainder of b may have changed by freeing a
+        heap.free(b);
+        assert_eq!(0, heap.num_undone_splits());
+        assert_eq!(vec![100], walk_back(&heap));
+
+        heap.set_split_undo(false);
+        let tmp = heap.alloc(5).unwrap();
+        heap.free(tmp);
+        assert_eq!(0, heap.num_undone_splits());
+        assert_eq!(1, heap.coalesce_stats().with_next);
+    }
+
+    #[test]
+    fn test_free_bitmap_matches_free_blocks() {
+        let mut heap = Heap::try_with_max(200 * WORD_SIZE, 300 * WORD_SIZE).unwrap();
+        let mut live = Vec::new();
+        let mut state: u64 = 0xb17;
+        for i in 0..600 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let n = state as usize;
+
+            match n % 5 {
+                0..=2 => match heap.alloc((n / 5 % 12) as HalfWord) {
+                    Some(address) => live.push(address),
+                    None if i % 2 == 0 => {
+                        heap.grow(50);
+                    }
+                    None => {
+                        heap.add_segment(64);
+                    }
+                },
+                _ if !live.is_empty() => {
+                    let address = live.swap_remove(n / 5 % live.len());
+                    heap.free(address);
+                }
+                _ => {}
+            }
+            assert_eq!(Ok(()), heap.verify(), "after operation {}", i);
+        }
+        assert!(heap.num_segments() > 1);
+    }
+
+    #[test]
+    fn test_verify_detects_wrong_free_bit() {
+        let mut heap = Heap::try_new(100 * WORD_SIZE).unwrap();
+        let a = heap.alloc(4).unwrap();
+        heap.alloc(4).unwrap();
+        heap.set_free_bit(Block::from(a), true);
+
+        let error = heap.verify().unwrap_err();
+        assert_eq!(Corruption::FreeBitmapMismatch, error.kind);
+        assert_eq!(0, error.offset);
+    }
+
+    #[test]
+    fn test_freeing_many_blocks_is_not_quadratic() {
+        let n = 20_000;
+        let mut heap = Heap::try_new(n * 3 * WORD_SIZE).unwrap();
+        let blocks: Vec<Address> = (0..n).map(|_| heap.alloc(2).unwrap()).collect();
+
+        // every other block, so that none of them can be merged
+        let start = std::time::Instant::now();
+        for &address in blocks.iter().step_by(2) {
+            heap.free(address);
+        }
+        assert!(start.elapsed() < std::time::Duration::from_secs(2));
+        assert_eq!(n / 2, heap.num_free_blocks());
+        assert_eq!(Ok(()), heap.verify());
+    }
 }
@@ -0,0 +1,252 @@
+use crate::address::Address;
+use crate::block::header::BlockHeader;
+use crate::block::Block;
+use crate::error::AccessError;
+use crate::managed::ManagedHeap;
+use crate::types::HalfWord;
+
+/// An object, which is bigger than a single block, stored as a chain of
+/// blocks (chunks). The words are indexed from 0 across all chunks.
+/// The first chunk is the head of the object: it is what roots have to
+/// reference and the other chunks live and die with it (see
+/// ManagedHeap::alloc_huge).
+/// Like an Address, the allocation must not be used after it was freed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HugeAllocation {
+    chunks: Vec<Address>,
+    len: usize,
+    chunk_words: usize,
+}
+
+impl HugeAllocation {
+    /// The address of the first chunk
+    pub fn head(&self) -> Address {
+        self.chunks[0]
+    }
+
+    /// The number of words
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of chunks
+    pub fn num_chunks(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn read(&self, index: usize) -> Result<usize, AccessError> {
+        Ok(*self.word(index)?)
+    }
+
+    pub fn write(&mut self, index: usize, value: usize) -> Result<(), AccessError> {
+        self.word(index)?.write(value);
+        Ok(())
+    }
+
+    fn word(&self, index: usize) -> Result<Address, AccessError> {
+        if index < self.len {
+            Ok(self.chunks[index / self.chunk_words] + index % self.chunk_words)
+        } else {
+            Err(AccessError::IndexOutOfBounds {
+                index,
+                len: self.len,
+            })
+        }
+    }
+}
+
+impl ManagedHeap {
+    /// The default size of a chunk, the biggest payload a block can have
+    /// (including the checksum of header_checksums)
+    pub const DEFAULT_HUGE_CHUNK_WORDS: HalfWord = BlockHeader::MAX_SIZE - 2;
+
+    /// Allocates total_words words as a chain of chunks with at most
+    /// huge_chunk_words words each, e.g. for objects, which don't fit into
+    /// a single block on 32 bit targets.
+    /// The chain is registered with the heap: collections only decide about
+    /// the head and free the other chunks together with it, so only the
+    /// head needs a mark word (if T::mark is used). Freeing the head frees
+    /// the whole chain.
+    /// The chunks are never moved by compact_region or relocate, and migrate
+    /// doesn't copy them.
+    /// Returns None (and frees the chunks allocated so far) if one of the
+    /// chunks can't be allocated.
+    pub fn alloc_huge(&mut self, total_words: usize) -> Option<HugeAllocation> {
+        let chunk_words = self.huge_chunk_words as usize;
+        let num_chunks = total_words.div_ceil(chunk_words).max(1);
+
+        let mut chunks = Vec::with_capacity(num_chunks);
+        for i in 0..num_chunks {
+            let words = (total_words - i * chunk_words).min(chunk_words);
+            match self.alloc(words as HalfWord) {
+                Some(chunk) => chunks.push(chunk),
+                None => {
+                    for chunk in chunks {
+                        self.free(chunk);
+                    }
+                    return None;
+                }
+            }
+        }
+
+        let tails = chunks[1..].to_vec();
+        self.huge_tails
+            .extend(tails.iter().map(|&t| usize::from(t)));
+        self.huge_chains.insert(chunks[0].into(), tails);
+
+        Some(HugeAllocation {
+            chunks,
+            len: total_words,
+            chunk_words,
+        })
+    }
+
+    /// Frees all chunks of allocation
+    pub fn free_huge(&mut self, allocation: HugeAllocation) {
+        self.free(allocation.head());
+    }
+
+    /// Changes the biggest chunk of future huge allocations.
+    /// Panics if words is 0 or bigger than DEFAULT_HUGE_CHUNK_WORDS.
+    pub fn set_huge_chunk_words(&mut self, words: HalfWord) {
+        assert!(
+            words > 0 && words <= Self::DEFAULT_HUGE_CHUNK_WORDS,
+            "Invalid chunk size {}",
+            words
+        );
+        self.huge_chunk_words = words;
+    }
+
+    /// Checks if address is the head or another chunk of a huge allocation
+    pub(crate) fn is_huge_chunk(&self, address: Address) -> bool {
+        self.huge_chains.contains_key(&address.into()) || self.is_huge_tail(address)
+    }
+
+    /// Checks if address is a chunk of a huge allocation, but not its head
+    pub(crate) fn is_huge_tail(&self, address: Address) -> bool {
+        self.huge_tails.contains(&address.into())
+    }
+
+    /// The size in words (including the headers) of the chunks after the
+    /// head, if address is the head of a huge allocation
+    pub(crate) fn huge_tail_words(&self, address: Address) -> usize {
+        self.huge_chains.get(&address.into()).map_or(0, |tails| {
+            tails.iter().map(|&t| Block::from(t).size() as usize).sum()
+        })
+    }
+
+    /// Frees the chunks after the head, if address is the head of a huge
+    /// allocation
+    pub(crate) fn free_huge_tails(&mut self, address: Address) {
+        if let Some(tails) = self.huge_chains.remove(&address.into()) {
+            for tail in tails {
+                self.huge_tails.remove(&tail.into());
+                self.free(tail);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::trace::GcRoot;
+
+    fn chunked_heap() -> ManagedHeap {
+        let mut heap = ManagedHeap::new(1000);
+        heap.set_huge_chunk_words(4);
+        heap
+    }
+
+    #[test]
+    fn test_round_trip_across_chunks() {
+        let mut heap = chunked_heap();
+        let mut huge = heap.alloc_huge(10).unwrap();
+        assert_eq!(3, huge.num_chunks());
+        assert_eq!(10, huge.len());
+        assert_eq!(3, heap.num_used_blocks());
+
+        for i in 0..10 {
+            huge.write(i, i * i).unwrap();
+        }
+        for i in 0..10 {
+            assert_eq!(Ok(i * i), huge.read(i));
+        }
+
+        // the last chunk only holds the rest
+        assert_eq!(Some(2), heap.capacity_of(huge.chunks[2]));
+        assert_eq!(
+            Err(AccessError::IndexOutOfBounds { index: 10, len: 10 }),
+            huge.read(10)
+        );
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_free_huge_frees_every_chunk() {
+        let mut heap = chunked_heap();
+        let small = heap.alloc(1).unwrap();
+        let huge = heap.alloc_huge(9).unwrap();
+        assert_eq!(4, heap.num_used_blocks());
+
+        heap.free_huge(huge);
+        assert_eq!(1, heap.num_used_blocks());
+        heap.free(small);
+        assert_eq!(1, heap.num_free_blocks());
+        assert!(heap.huge_chains.is_empty());
+        assert!(heap.huge_tails.is_empty());
+    }
+
+    #[test]
+    fn test_failed_huge_allocation_frees_its_chunks() {
+        let mut heap = chunked_heap();
+        assert_eq!(None, heap.alloc_huge(1000));
+        assert_eq!(0, heap.num_used_blocks());
+    }
+
+    #[test]
+    fn test_gc_collects_unrooted_huge_allocation() {
+        let mut heap = chunked_heap();
+        let rooted = heap.alloc_huge(12).unwrap();
+        heap.alloc_huge(12).unwrap();
+
+        let stats = heap.gc_addresses(&[rooted.head()], |_, _| {});
+        assert_eq!(1, stats.freed_blocks);
+        assert_eq!(15, stats.freed_words);
+        assert_eq!(3, heap.num_used_blocks());
+
+        heap.gc_addresses(&[], |_, _| {});
+        assert_eq!(0, heap.num_used_blocks());
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_gc_doesnt_unmark_other_chunks() {
+        let mut heap = chunked_heap();
+        let mut huge = heap.alloc_huge(8).unwrap();
+        // the first word of the head is the mark word of the IntegerObject
+        huge.write(0, 0).unwrap();
+        for i in 1..8 {
+            huge.write(i, 100 + i).unwrap();
+        }
+
+        let mut root = MockGcRoot::new(vec![IntegerObject(huge.head())]);
+        let mut roots: Vec<&mut dyn GcRoot<IntegerObject>> = vec![&mut root];
+        let stats = heap.gc(&mut roots[..]);
+
+        assert_eq!(0, stats.freed_blocks);
+        assert_eq!(Ok(104), huge.read(4));
+        assert_eq!(2, heap.num_used_blocks());
+
+        root.clear();
+        let mut roots: Vec<&mut dyn GcRoot<IntegerObject>> = vec![&mut root];
+        let stats = heap.gc(&mut roots[..]);
+        assert_eq!(1, stats.freed_blocks);
+        assert_eq!(0, heap.num_used_blocks());
+    }
+}
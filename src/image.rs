@@ -0,0 +1,343 @@
+use crate::address::Address;
+use crate::block::header::BlockHeader;
+use crate::block::Block;
+use crate::error::ImageError;
+use crate::heap::{Backing, Heap};
+use crate::managed::ManagedHeap;
+use crate::types::{HalfWord, WORD_SIZE};
+
+use std::alloc::{alloc, dealloc, Layout};
+use std::io::{Read, Write};
+use std::mem;
+
+/// "MHIMG" in ascii
+const MAGIC: u64 = 0x4D_48_49_4D_47;
+const VERSION: u64 = 1;
+
+// The image layout:
+// - magic, version and word size as little endian u64
+// - heap size in words and number of used blocks
+// - block table: offset and size in words of every used block
+// - the payload words of every used block
+// Everything after the word size uses little endian words of the word size.
+// References inside of the payload are stored as word offsets from the start
+// of the heap, so 0 stays 0.
+
+impl ManagedHeap {
+    /// Writes all used blocks into w, so that they can be loaded at a
+    /// different address with load_image.
+    /// trace_all gets called once for every used block and has to call its
+    /// second argument with every field of the object, which contains a
+    /// reference (null references are allowed).
+    /// The references are temporarily replaced by offsets while writing.
+    /// Large objects, reference counts, tags and HeapBoxes are not part of
+    /// the image, so references to large objects are rejected.
+    /// Heaps with additional segments can't be saved.
+    pub fn save_image<W, F>(&mut self, mut w: W, mut trace_all: F) -> Result<(), ImageError>
+    where
+        W: Write,
+        F: FnMut(Address, &mut dyn FnMut(&mut usize)),
+    {
+        if self.heap.num_segments() > 1 {
+            return Err(ImageError::Segmented);
+        }
+
+        let blocks: Vec<Block> = self.heap.used().collect();
+
+        let mut dangling = None;
+        for &block in &blocks {
+            trace_all(Address::from(block), &mut |field| {
+                let reference = Address::from(*field);
+                if *field != 0 && self.heap.block_of(reference).is_none() {
+                    dangling.get_or_insert(reference);
+                }
+            });
+        }
+
+        if let Some(address) = dangling {
+            return Err(ImageError::DanglingReference(address));
+        }
+
+        let start = self.heap.start();
+        self.convert_references(&blocks, &mut trace_all, |r| (r - start) / WORD_SIZE);
+        let result = self.write_image(&mut w, &blocks);
+        self.convert_references(&blocks, &mut trace_all, |r| start + r * WORD_SIZE);
+
+        result
+    }
+
+    fn write_image<W: Write>(&self, w: &mut W, blocks: &[Block]) -> Result<(), ImageError> {
+        w.write_all(&MAGIC.to_le_bytes())?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&(WORD_SIZE as u64).to_le_bytes())?;
+
+        w.write_all(&self.heap.size().to_le_bytes())?;
+        w.write_all(&blocks.len().to_le_bytes())?;
+
+        for block in blocks {
+            w.write_all(&self.heap.offset_of(block.as_ptr()).to_le_bytes())?;
+            w.write_all(&(block.size() as usize).to_le_bytes())?;
+        }
+
+        for block in blocks {
+            let payload = Address::from(*block);
+            for i in 0..block.size() as usize - 1 {
+                w.write_all(&(*(payload + i)).to_le_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces every non null reference in blocks with convert(reference)
+    fn convert_references<F, C>(&mut self, blocks: &[Block], trace_all: &mut F, mut convert: C)
+    where
+        F: FnMut(Address, &mut dyn FnMut(&mut usize)),
+        C: FnMut(usize) -> usize,
+    {
+        for &block in blocks {
+            trace_all(Address::from(block), &mut |field| {
+                if *field != 0 {
+                    *field = convert(*field);
+                }
+            });
+        }
+    }
+
+    /// Reads a heap, which was written with save_image.
+    /// trace_all has the same meaning as in save_image and is used to turn
+    /// the stored offsets back into addresses.
+    /// The objects keep their offsets from the start of the heap.
+    pub fn load_image<R, F>(mut r: R, mut trace_all: F) -> Result<ManagedHeap, ImageError>
+    where
+        R: Read,
+        F: FnMut(Address, &mut dyn FnMut(&mut usize)),
+    {
+        if read_u64(&mut r)? != MAGIC {
+            return Err(ImageError::InvalidMagic);
+        }
+
+        let version = read_u64(&mut r)?;
+        if version != VERSION {
+            return Err(ImageError::UnsupportedVersion(version));
+        }
+
+        let word_size = read_u64(&mut r)? as usize;
+        if word_size != WORD_SIZE {
+            return Err(ImageError::WordSizeMismatch {
+                expected: WORD_SIZE,
+                found: word_size,
+            });
+        }
+
+        let words = read_word(&mut r)?;
+        if words == 0 || words > Heap::MAX_BYTES / WORD_SIZE {
+            return Err(ImageError::CorruptedLayout);
+        }
+
+        let num_blocks = read_word(&mut r)?;
+        let mut table = Vec::new();
+        let mut end = 0;
+        for _ in 0..num_blocks {
+            let (offset, size) = (read_word(&mut r)?, read_word(&mut r)?);
+            if offset < end || size == 0 || offset.checked_add(size).is_none_or(|e| e > words) {
+                return Err(ImageError::CorruptedLayout);
+            }
+
+            end = offset + size;
+            table.push((offset, size));
+        }
+
+        let heap = unsafe {
+            let layout = Layout::from_size_align(words * WORD_SIZE, mem::align_of::<usize>())
+                .map_err(|_| ImageError::CorruptedLayout)?;
+            let data = alloc(layout) as *mut usize;
+            assert!(!data.is_null(), "Could not allocate the heap memory");
+
+            if let Err(e) = write_layout(&mut r, data, words, &table) {
+                dealloc(data as *mut u8, layout);
+                return Err(e);
+            }
+
+            Heap::adopt(data, words, Backing::Allocated(layout))
+                .ok_or(ImageError::CorruptedLayout)?
+        };
+
+        let mut heap = ManagedHeap::from_heap(heap);
+        let blocks: Vec<Block> = heap.heap.used().collect();
+
+        let mut valid = true;
+        heap.convert_references(&blocks, &mut trace_all, |offset| {
+            valid &= offset < words;
+            offset
+        });
+
+        if !valid {
+            return Err(ImageError::CorruptedLayout);
+        }
+
+        let start = heap.heap.start();
+        heap.convert_references(&blocks, &mut trace_all, |r| start + r * WORD_SIZE);
+        Ok(heap)
+    }
+}
+
+/// Writes the used blocks of table into data and fills the gaps between them
+/// with free blocks
+unsafe fn write_layout<R: Read>(
+    r: &mut R,
+    data: *mut usize,
+    words: usize,
+    table: &[(usize, usize)],
+) -> Result<(), ImageError> {
+    let mut end = 0;
+    let mut pred_size = 0;
+
+    let mut write_block = |offset: usize, size: usize, used: bool| {
+        let mut block = Block::from(data.add(offset) as *mut BlockHeader);
+        *block.as_ptr() = BlockHeader::new(pred_size, size as HalfWord).into();
+        block.set_used(used);
+        pred_size = size as HalfWord;
+    };
+
+    for &(offset, size) in table {
+        if offset > end {
+            write_block(end, offset - end, false);
+        }
+        write_block(offset, size, true);
+        end = offset + size;
+    }
+
+    if end < words {
+        write_block(end, words - end, false);
+    }
+
+    for &(offset, size) in table {
+        for i in offset + 1..offset + size {
+            *data.add(i) = read_word(r)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64, ImageError> {
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_word<R: Read>(r: &mut R) -> Result<usize, ImageError> {
+    let mut bytes = [0; WORD_SIZE];
+    r.read_exact(&mut bytes)?;
+    Ok(usize::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ImageError;
+
+    /// Objects are [next or 0, value]
+    fn trace_all(mut address: Address, fields: &mut dyn FnMut(&mut usize)) {
+        fields(unsafe { &mut *address.as_mut() })
+    }
+
+    fn new_list(heap: &mut ManagedHeap, values: &[usize]) -> Address {
+        let mut next = 0;
+        for &value in values.iter().rev() {
+            let mut node = heap.alloc(2).unwrap();
+            node.write(next);
+            (node + 1).write(value);
+            next = node.into();
+        }
+        Address::from(next)
+    }
+
+    fn values(mut node: Address) -> Vec<usize> {
+        let mut values = Vec::new();
+        while usize::from(node) != 0 {
+            values.push(*(node + 1));
+            node = Address::from(*node);
+        }
+        values
+    }
+
+    fn saved_list() -> (Vec<u8>, usize) {
+        let mut heap = ManagedHeap::new(1024);
+        let garbage = heap.alloc(5).unwrap();
+        let head = new_list(&mut heap, &[1, 2, 3, 4]);
+        heap.free(garbage);
+
+        let mut image = Vec::new();
+        heap.save_image(&mut image, trace_all).unwrap();
+        assert_eq!(vec![1, 2, 3, 4], values(head));
+
+        let offset = usize::from(head) - heap.heap.start();
+        (image, offset)
+    }
+
+    #[test]
+    fn test_load_image_preserves_list_at_new_base() {
+        let (image, offset) = saved_list();
+
+        // keep another heap alive, so the loaded heap lives somewhere else
+        let _other = ManagedHeap::new(1024);
+        let heap = ManagedHeap::load_image(&image[..], trace_all).unwrap();
+        assert_eq!(Ok(()), heap.verify());
+        assert_eq!(4, heap.num_used_blocks());
+        assert_eq!(2, heap.num_free_blocks());
+
+        let head = Address::from(heap.heap.start() + offset);
+        assert_eq!(vec![1, 2, 3, 4], values(head));
+    }
+
+    #[test]
+    fn test_load_image_rejects_other_word_size() {
+        let (mut image, _) = saved_list();
+        image[16..24].copy_from_slice(&(WORD_SIZE as u64 / 2).to_le_bytes());
+
+        match ManagedHeap::load_image(&image[..], trace_all) {
+            Err(ImageError::WordSizeMismatch { expected, found }) => {
+                assert_eq!(WORD_SIZE, expected);
+                assert_eq!(WORD_SIZE / 2, found);
+            }
+            _ => panic!("expected a word size mismatch"),
+        }
+    }
+
+    #[test]
+    fn test_load_image_rejects_invalid_data() {
+        let (image, _) = saved_list();
+
+        let invalid_magic = ManagedHeap::load_image(&image[1..], trace_all);
+        assert!(matches!(invalid_magic, Err(ImageError::InvalidMagic)));
+
+        let truncated = ManagedHeap::load_image(&image[..image.len() - 1], trace_all);
+        assert!(matches!(truncated, Err(ImageError::Io(_))));
+
+        // the first block now overlaps the heap end
+        let mut overlapping = image.clone();
+        let table = 24 + 2 * WORD_SIZE;
+        overlapping[table..table + WORD_SIZE].copy_from_slice(&1000usize.to_le_bytes());
+        let overlapping = ManagedHeap::load_image(&overlapping[..], trace_all);
+        assert!(matches!(overlapping, Err(ImageError::CorruptedLayout)));
+    }
+
+    #[test]
+    fn test_save_image_rejects_dangling_references() {
+        let mut heap = ManagedHeap::new(1024);
+        let mut head = new_list(&mut heap, &[1, 2]);
+        let second = Address::from(*head);
+        heap.free(second);
+
+        let result = heap.save_image(Vec::new(), trace_all);
+        assert!(matches!(result, Err(ImageError::DanglingReference(a)) if a == second));
+
+        // the heap was left untouched
+        assert_eq!(usize::from(second), *head);
+        head.write(0);
+        heap.save_image(Vec::new(), trace_all).unwrap();
+        assert_eq!(vec![1], values(head));
+    }
+}
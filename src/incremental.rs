@@ -0,0 +1,663 @@
+use crate::address::Address;
+use crate::block::Block;
+use crate::error::GcError;
+use crate::managed::ManagedHeap;
+use crate::stats::{GcKind, GcStats};
+use crate::trace::{GcRoot, Traceable};
+
+use std::time::{Duration, Instant};
+
+/// The state of an incremental collection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GcPhase {
+    /// There are still objects left, which need to be traced
+    Marking,
+    /// All reachable objects are marked, gc_finish can sweep the heap
+    ReadyToSweep,
+    /// There is no active collection
+    Done,
+}
+
+/// The result of gc_with_budget.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GcOutcome {
+    /// The budget expired before the marking was finished.
+    /// marked is the number of objects traced so far in this cycle and
+    /// remaining the number of objects waiting to be traced.
+    Partial { marked: usize, remaining: usize },
+    /// The cycle was finished and the heap was swept
+    Complete(GcStats),
+}
+
+/// Proves that mark_phase marked the heap and can only be swept once (see
+/// sweep_phase).
+#[derive(Debug, PartialEq, Eq)]
+#[must_use = "the heap isn't swept until the token is passed to sweep_phase"]
+pub struct MarkToken {
+    epoch: u64,
+    marked: usize,
+    duration: Duration,
+}
+
+impl MarkToken {
+    /// The epoch of the collection (see ManagedHeap::current_epoch)
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+}
+
+type TraceFn = Box<dyn FnMut(Address, &mut Vec<Address>)>;
+
+/// The bookkeeping of an active incremental collection.
+pub(crate) struct IncrementalGc {
+    worklist: Vec<Address>,
+    trace: TraceFn,
+    marked: usize,
+    // the roots of a cycle started by gc_with_budget
+    roots: Option<Vec<Address>>,
+}
+
+/// The number of objects traced between two checks of the budget
+const BUDGET_SLICE: usize = 8;
+
+/// An incremental mark & sweep collector.
+/// Instead of Traceable::mark, the incremental collector stores the mark state
+/// in the block headers and uses Traceable::trace to find the children of an
+/// object, so trace has to return every reference of an object.
+///
+/// Because the program keeps running between the steps, the following rules
+/// have to be followed during an active collection:
+/// - every reference stored into an object has to be reported with
+///   record_write
+/// - every reference stored into a root has to be reported with record_root,
+///   because the roots are only scanned by gc_begin
+///
+/// Objects allocated during a collection are always considered live.
+impl ManagedHeap {
+    /// Starts an incremental collection by marking the children of all roots.
+    /// An already active collection is aborted.
+    pub fn gc_begin<T>(&mut self, roots: &mut [&mut dyn GcRoot<T>])
+    where
+        T: Traceable + From<Address> + Into<Address> + Copy + 'static,
+    {
+        self.gc_abort();
+        self.begin_epoch();
+
+        let mut worklist = Vec::new();
+        let root_addresses = roots
+            .iter_mut()
+            .flat_map(|r| r.children())
+            .map(|t| (*t).into());
+        self.mark_addresses(root_addresses, &mut worklist);
+        self.mark_addresses(self.region_objects(), &mut worklist);
+        self.mark_addresses(self.registered_root_addresses().into_iter(), &mut worklist);
+
+        let trace = |address: Address, children: &mut Vec<Address>| {
+            let mut object = T::from(address);
+            object.trace_with(&mut |a| children.push(*a));
+        };
+
+        self.incremental = Some(IncrementalGc {
+            worklist,
+            trace: Box::new(trace),
+            marked: 0,
+            roots: None,
+        });
+    }
+
+    /// Runs the current collection (or starts a new one) until it is either
+    /// finished or budget has expired. The budget is checked after every
+    /// slice of a few objects, so it may be exceeded by one slice. The sweep
+    /// is not sliced. If force is true, the collection is always finished.
+    ///
+    /// The roots have to be the same for every call of a cycle. If they
+    /// changed, the cycle is aborted and RootsChanged is returned, so the
+    /// next call starts a new cycle with the new roots. A cycle started with
+    /// gc_begin is continued without checking the roots.
+    /// The rules of the incremental collector apply between the calls.
+    pub fn gc_with_budget<T>(
+        &mut self,
+        roots: &mut [&mut dyn GcRoot<T>],
+        budget: Duration,
+        force: bool,
+    ) -> Result<GcOutcome, GcError>
+    where
+        T: Traceable + From<Address> + Into<Address> + Copy + 'static,
+    {
+        let start = Instant::now();
+        let root_addresses: Vec<Address> = roots
+            .iter_mut()
+            .flat_map(|r| r.children())
+            .map(|t| (*t).into())
+            .collect();
+
+        match self.incremental.as_mut().map(|gc| &gc.roots) {
+            None => {
+                self.gc_begin(roots);
+                if let Some(gc) = self.incremental.as_mut() {
+                    gc.roots = Some(root_addresses);
+                }
+            }
+            Some(Some(stable)) if *stable != root_addresses => {
+                self.gc_abort();
+                return Err(GcError::RootsChanged);
+            }
+            Some(_) => {}
+        }
+
+        loop {
+            if self.gc_step(BUDGET_SLICE) == GcPhase::ReadyToSweep {
+                return Ok(GcOutcome::Complete(self.gc_finish()));
+            }
+
+            if !force && start.elapsed() >= budget {
+                let gc = self.incremental.as_ref().expect("No active collection");
+                return Ok(GcOutcome::Partial {
+                    marked: gc.marked,
+                    remaining: gc.worklist.len(),
+                });
+            }
+        }
+    }
+
+    /// Traces at most max_objects objects.
+    pub fn gc_step(&mut self, max_objects: usize) -> GcPhase {
+        let mut gc = match self.incremental.take() {
+            Some(gc) => gc,
+            None => return GcPhase::Done,
+        };
+
+        let mut children = Vec::new();
+        for _ in 0..max_objects {
+            let address = match gc.worklist.pop() {
+                Some(address) => address,
+                None => break,
+            };
+
+            (gc.trace)(address, &mut children);
+            gc.marked += 1;
+            self.mark_addresses(children.drain(..), &mut gc.worklist);
+        }
+
+        let phase = gc.phase();
+        self.incremental = Some(gc);
+        phase
+    }
+
+    /// Finishes the marking (if necessary) and frees all unmarked objects.
+    /// Returns default stats if there is no active collection.
+    pub fn gc_finish(&mut self) -> GcStats {
+        while let GcPhase::Marking = self.gc_step(usize::MAX) {}
+
+        if self.incremental.take().is_none() {
+            return GcStats::default();
+        }
+
+        // the marking was spread over many steps, only the sweep is timed
+        let start = Instant::now();
+        self.sweep_unmarked_headers(GcKind::Incremental, start, GcStats::default())
+    }
+
+    /// Marks all objects reachable from roots, but doesn't free anything
+    /// until the token is passed to sweep_phase, e.g. to split a stop the
+    /// world collection over two safepoints. Like gc_begin, the mark state is
+    /// stored in the block headers and Traceable::trace is used to find the
+    /// children of an object.
+    /// The marking is finished before this returns, so the rules of the
+    /// incremental collector don't apply between the phases. Objects
+    /// allocated between the phases are always considered live.
+    /// An active collection is aborted.
+    pub fn mark_phase<T>(&mut self, roots: &mut [&mut dyn GcRoot<T>]) -> MarkToken
+    where
+        T: Traceable + From<Address> + Into<Address> + Copy + 'static,
+    {
+        let start = Instant::now();
+        self.gc_begin(roots);
+        while let GcPhase::Marking = self.gc_step(usize::MAX) {}
+
+        let gc = self.incremental.as_ref().expect("No active collection");
+        MarkToken {
+            epoch: self.epoch,
+            marked: gc.marked,
+            duration: start.elapsed(),
+        }
+    }
+
+    /// Frees all objects, which weren't marked by the mark_phase of token.
+    /// Fails if its collection was aborted or finished in the meantime, e.g.
+    /// by another collection.
+    /// The duration of the stats doesn't include the time between the
+    /// phases.
+    pub fn sweep_phase(&mut self, token: MarkToken) -> Result<GcStats, GcError> {
+        if token.epoch != self.epoch || self.incremental.is_none() {
+            return Err(GcError::StaleMark);
+        }
+
+        // objects reported by record_root between the phases
+        while let GcPhase::Marking = self.gc_step(usize::MAX) {}
+        self.incremental = None;
+
+        let marked = GcStats {
+            mark_visited: token.marked,
+            mark_duration: token.duration,
+            ..GcStats::default()
+        };
+        // as if the sweep had followed the marking without a pause
+        let now = Instant::now();
+        let start = now.checked_sub(token.duration).unwrap_or(now);
+        Ok(self.sweep_unmarked_headers(GcKind::Full, start, marked))
+    }
+
+    /// The phase of the current incremental collection
+    pub fn gc_phase(&self) -> GcPhase {
+        self.incremental
+            .as_ref()
+            .map_or(GcPhase::Done, IncrementalGc::phase)
+    }
+
+    /// The write barrier, which has to be called whenever a reference to
+    /// child is stored inside of parent during an active collection.
+    /// If parent was already marked, child gets marked as well, so it can't
+    /// be freed by accident.
+    pub fn record_write(&mut self, parent: Address, child: Address) {
+        let parent_marked = self
+            .block_of(parent)
+            .is_some_and(|b| b.is_marked_in(self.epoch));
+        if parent_marked {
+            self.record_root(child);
+        }
+    }
+
+    /// Has to be called whenever a reference to child is stored in a root
+    /// during an active collection.
+    pub fn record_root(&mut self, child: Address) {
+        if let Some(mut gc) = self.incremental.take() {
+            self.mark_addresses(Some(child).into_iter(), &mut gc.worklist);
+            self.incremental = Some(gc);
+        }
+    }
+
+    /// Stops the current collection without freeing anything
+    pub(crate) fn gc_abort(&mut self) {
+        if self.incremental.take().is_some() {
+            let marked: Vec<Block> = self.used_blocks().filter(|b| b.is_marked()).collect();
+            for mut block in marked {
+                block.set_marked(false);
+            }
+            self.marking = false;
+        }
+    }
+}
+
+impl IncrementalGc {
+    fn phase(&self) -> GcPhase {
+        if self.worklist.is_empty() {
+            GcPhase::ReadyToSweep
+        } else {
+            GcPhase::Marking
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::trace::GcRootRef;
+
+    fn begin(heap: &mut ManagedHeap, root: &mut MockGcRoot<LinkedList>) {
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![root];
+        heap.gc_begin(&mut roots[..]);
+    }
+
+    #[test]
+    fn test_step_budget_is_respected() {
+        let mut heap = ManagedHeap::new(1000);
+        let list = list![&mut heap; 1, 2, 3, 4];
+        let _garbage = list![&mut heap; 5, 6];
+
+        let mut root = MockGcRoot::new(vec![list]);
+        begin(&mut heap, &mut root);
+        assert_eq!(GcPhase::Marking, heap.gc_phase());
+
+        // every step traces one node and finds the next one
+        for _ in 0..3 {
+            assert_eq!(GcPhase::Marking, heap.gc_step(1));
+        }
+        assert_eq!(GcPhase::ReadyToSweep, heap.gc_step(1));
+
+        let stats = heap.gc_finish();
+        assert_eq!(2, stats.freed_blocks);
+        assert_eq!(4, stats.live_blocks);
+        assert_eq!(GcPhase::Done, heap.gc_phase());
+        assert_eq!(GcPhase::Done, heap.gc_step(1));
+        assert_eq!("[1, 2, 3, 4]", format!("{:?}", list));
+        let next = heap.current_epoch() + 1;
+        assert!(heap.used_blocks().all(|b| !b.is_marked_in(next)));
+    }
+
+    #[test]
+    fn test_marks_of_interrupted_cycle_are_ignored() {
+        let mut heap = ManagedHeap::new(1000);
+        let list = list![&mut heap; 1, 2, 3, 4];
+
+        // the mark phase completes, but the cycle never reaches its sweep
+        let mut root = MockGcRoot::new(vec![list]);
+        begin(&mut heap, &mut root);
+        assert_eq!(GcPhase::ReadyToSweep, heap.gc_step(usize::MAX));
+        heap.incremental.take();
+        assert!(heap.used_blocks().all(Block::is_marked));
+
+        // the tail is garbage now, although it still carries its mark
+        list.next().unwrap().set_next(None);
+        let root = MockGcRoot::new(vec![list]);
+        let roots: Vec<&dyn GcRootRef<LinkedList>> = vec![&root];
+        let stats = heap.gc_shared(&roots[..]);
+
+        assert_eq!(2, stats.freed_blocks);
+        assert_eq!(2, stats.epoch);
+        assert_eq!("[1, 2]", format!("{:?}", list));
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_objects_allocated_during_collection_survive() {
+        let mut heap = ManagedHeap::new(1000);
+        let list = list![&mut heap; 1, 2];
+
+        let mut root = MockGcRoot::new(vec![list]);
+        begin(&mut heap, &mut root);
+        heap.gc_step(1);
+
+        // prepend a new node to the tail, which is still unmarked
+        let tail = list.next().unwrap();
+        let new = LinkedList::new(&mut heap, 3, Some(tail));
+        list.set_next(Some(new));
+        heap.record_write(list.0, new.0);
+
+        while heap.gc_step(1) == GcPhase::Marking {}
+        let stats = heap.gc_finish();
+
+        assert_eq!(0, stats.freed_blocks);
+        assert_eq!("[1, 3, 2]", format!("{:?}", list));
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_sweep_phase_keeps_objects_allocated_after_marking() {
+        let mut heap = ManagedHeap::new(1000);
+        let list = list![&mut heap; 1, 2];
+        let _garbage = list![&mut heap; 3, 4, 5];
+
+        let mut root = MockGcRoot::new(vec![list]);
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        let token = heap.mark_phase(&mut roots[..]);
+        assert_eq!(heap.current_epoch(), token.epoch());
+        assert_eq!(5, heap.num_used_blocks());
+
+        // neither rooted nor reachable, but allocated between the phases
+        let new = list![&mut heap; 6, 7];
+        list.next().unwrap().set_next(Some(new));
+
+        let stats = heap.sweep_phase(token).unwrap();
+        assert_eq!(3, stats.freed_blocks);
+        assert_eq!(4, stats.live_blocks);
+        assert_eq!(2, stats.mark_visited);
+        assert_eq!(GcKind::Full, stats.kind);
+        assert_eq!(Some(&stats), heap.last_gc());
+        assert_eq!(GcPhase::Done, heap.gc_phase());
+        assert_eq!("[1, 2, 6, 7]", format!("{:?}", list));
+        assert_eq!(Ok(()), heap.verify());
+
+        // the next collection frees them like any other object
+        list.set_next(None);
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        let token = heap.mark_phase(&mut roots[..]);
+        assert_eq!(3, heap.sweep_phase(token).unwrap().freed_blocks);
+    }
+
+    #[test]
+    fn test_sweep_phase_rejects_stale_token() {
+        let mut heap = ManagedHeap::new(1000);
+        let list = list![&mut heap; 1, 2];
+        let mut root = MockGcRoot::new(vec![list]);
+
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        let stale = heap.mark_phase(&mut roots[..]);
+        let token = heap.mark_phase(&mut roots[..]);
+        assert_eq!(Err(GcError::StaleMark), heap.sweep_phase(stale));
+        assert!(heap.sweep_phase(token).is_ok());
+
+        // a full collection finishes the marked one
+        let token = heap.mark_phase(&mut roots[..]);
+        heap.gc(&mut roots[..]);
+        assert_eq!(Err(GcError::StaleMark), heap.sweep_phase(token));
+        assert_eq!("[1, 2]", format!("{:?}", list));
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_write_barrier_keeps_moved_reference_alive() {
+        let mut heap = ManagedHeap::new(1000);
+        let a = list![&mut heap; 1, 2, 3];
+        let b = a.next().unwrap();
+        let c = b.next().unwrap();
+
+        let mut root = MockGcRoot::new(vec![a]);
+        begin(&mut heap, &mut root);
+
+        // a is traced, so b is marked but not traced yet
+        assert_eq!(GcPhase::Marking, heap.gc_step(1));
+
+        // move c from b into the already traced a
+        a.set_next(Some(c));
+        heap.record_write(a.0, c.0);
+        b.set_next(None);
+
+        assert_eq!(GcPhase::ReadyToSweep, heap.gc_step(10));
+        let stats = heap.gc_finish();
+
+        // b is still marked from before the mutation and c was saved by the
+        // write barrier
+        assert_eq!(0, stats.freed_blocks);
+        assert_eq!("[1, 3]", format!("{:?}", a));
+    }
+
+    #[test]
+    fn test_interleaved_mutation_never_frees_live_objects() {
+        let mut heap = ManagedHeap::new(4096);
+        let head = list![&mut heap; 0];
+        let mut root = MockGcRoot::new(vec![head]);
+
+        for cycle in 0..5 {
+            let _garbage = list![&mut heap; 100, 101];
+            begin(&mut heap, &mut root);
+
+            while heap.gc_step(1) == GcPhase::Marking {
+                // insert a new node after the head while marking
+                let new = LinkedList::new(&mut heap, cycle + 1, head.next());
+                head.set_next(Some(new));
+                heap.record_write(head.0, new.0);
+            }
+
+            let stats = heap.gc_finish();
+            assert_eq!(2, stats.freed_blocks);
+
+            let values: Vec<isize> = head.iter().map(|l| l.value()).collect();
+            assert_eq!(values.len(), stats.live_blocks);
+            assert_eq!(0, values[0]);
+            assert_eq!(Ok(()), heap.verify());
+        }
+    }
+
+    #[test]
+    fn test_record_root_keeps_object_alive() {
+        let mut heap = ManagedHeap::new(1000);
+        let list = list![&mut heap; 1];
+        let old = list![&mut heap; 3];
+        let unrecorded = list![&mut heap; 4];
+
+        let mut root = MockGcRoot::new(vec![list]);
+        begin(&mut heap, &mut root);
+
+        let detached = list![&mut heap; 2];
+        // detached was allocated during the collection, so it's live anyway.
+        // An object from before the collection needs record_root.
+        heap.record_root(old.0);
+        root.used_elems.push(detached);
+        root.used_elems.push(old);
+        // without record_root, a new root from before the collection is freed
+        root.used_elems.push(unrecorded);
+
+        let stats = heap.gc_finish();
+        assert_eq!(1, stats.freed_blocks);
+        assert_eq!(3, stats.live_blocks);
+        assert!(heap.block_of(old.0).is_some());
+        assert!(heap.block_of(unrecorded.0).is_none());
+    }
+
+    #[test]
+    fn test_gc_aborts_incremental_collection() {
+        let mut heap = ManagedHeap::new(1000);
+        let list = list![&mut heap; 1, 2];
+
+        let mut root = MockGcRoot::new(vec![list]);
+        begin(&mut heap, &mut root);
+        heap.gc_step(1);
+
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        heap.gc(&mut roots[..]);
+
+        assert_eq!(GcPhase::Done, heap.gc_phase());
+        assert!(heap.used_blocks().all(|b| !b.is_marked()));
+        assert_eq!(GcStats::default(), heap.gc_finish());
+    }
+
+    /// A list, which takes a millisecond to trace
+    #[derive(Copy, Clone)]
+    struct SlowList(LinkedList);
+
+    impl From<Address> for SlowList {
+        fn from(address: Address) -> Self {
+            SlowList(LinkedList(address))
+        }
+    }
+
+    impl From<SlowList> for Address {
+        fn from(list: SlowList) -> Address {
+            list.0 .0
+        }
+    }
+
+    unsafe impl Traceable for SlowList {
+        fn mark(&mut self) {
+            self.0.mark();
+        }
+
+        fn unmark(&mut self) {
+            self.0.unmark();
+        }
+
+        fn trace_with(&mut self, visitor: &mut dyn FnMut(&mut Address)) {
+            std::thread::sleep(Duration::from_millis(1));
+            self.0.trace_with(visitor)
+        }
+
+        fn is_marked(&self) -> bool {
+            self.0.is_marked()
+        }
+    }
+
+    fn long_list(heap: &mut ManagedHeap, len: isize) -> LinkedList {
+        let _garbage = list![heap; 1001, 1002, 1003];
+        (0..len).fold(LinkedList::new(heap, len, None), |next, i| {
+            LinkedList::new(heap, i, Some(next))
+        })
+    }
+
+    #[test]
+    fn test_gc_with_budget_honors_budget() {
+        let mut heap = ManagedHeap::new(8192);
+        let list = long_list(&mut heap, 200);
+        let mut root = MockGcRoot::new(vec![SlowList(list)]);
+        let budget = Duration::from_millis(20);
+
+        let start = Instant::now();
+        let mut roots: Vec<&mut dyn GcRoot<SlowList>> = vec![&mut root];
+        let outcome = heap.gc_with_budget(&mut roots[..], budget, false);
+        let elapsed = start.elapsed();
+
+        // tracing everything would take at least 200ms
+        assert!(elapsed < Duration::from_millis(150), "took {:?}", elapsed);
+        match outcome {
+            Ok(GcOutcome::Partial { marked, remaining }) => {
+                // every object takes at least a millisecond
+                assert!((1..=20 + BUDGET_SLICE).contains(&marked), "{}", marked);
+                assert_eq!(1, remaining);
+            }
+            _ => panic!("expected a partial collection"),
+        }
+        assert_eq!(GcPhase::Marking, heap.gc_phase());
+    }
+
+    #[test]
+    fn test_gc_with_budget_matches_monolithic_gc() {
+        let mut heap = ManagedHeap::new(8192);
+        let list = long_list(&mut heap, 100);
+        let mut root = MockGcRoot::new(vec![SlowList(list)]);
+
+        let mut expected_heap = ManagedHeap::new(8192);
+        let expected_list = long_list(&mut expected_heap, 100);
+        let mut expected_root = MockGcRoot::new(vec![expected_list]);
+        let mut expected_roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut expected_root];
+        let expected = expected_heap.gc(&mut expected_roots[..]);
+
+        let mut calls = 0;
+        let stats = loop {
+            calls += 1;
+            let mut roots: Vec<&mut dyn GcRoot<SlowList>> = vec![&mut root];
+            match heap.gc_with_budget(&mut roots[..], Duration::from_millis(5), false) {
+                Ok(GcOutcome::Complete(stats)) => break stats,
+                Ok(GcOutcome::Partial { .. }) => {}
+                Err(e) => panic!("{}", e),
+            }
+        };
+
+        assert!(calls > 1);
+        assert_eq!(expected.freed_blocks, stats.freed_blocks);
+        assert_eq!(expected.live_blocks, stats.live_blocks);
+        assert_eq!(
+            expected_list.iter().map(|l| l.value()).collect::<Vec<_>>(),
+            list.iter().map(|l| l.value()).collect::<Vec<_>>()
+        );
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_gc_with_budget_rejects_changed_roots() {
+        let mut heap = ManagedHeap::new(8192);
+        let list = long_list(&mut heap, 50);
+        let mut root = MockGcRoot::new(vec![SlowList(list)]);
+
+        let mut roots: Vec<&mut dyn GcRoot<SlowList>> = vec![&mut root];
+        let outcome = heap.gc_with_budget(&mut roots[..], Duration::ZERO, false);
+        assert!(matches!(outcome, Ok(GcOutcome::Partial { .. })));
+
+        let other = SlowList(LinkedList::new(&mut heap, 42, None));
+        root.used_elems.push(other);
+        let mut roots: Vec<&mut dyn GcRoot<SlowList>> = vec![&mut root];
+        let outcome = heap.gc_with_budget(&mut roots[..], Duration::ZERO, false);
+        assert_eq!(Err(GcError::RootsChanged), outcome);
+        assert_eq!(GcPhase::Done, heap.gc_phase());
+
+        // the next call starts a new cycle with the new roots
+        let outcome = heap.gc_with_budget(&mut roots[..], Duration::ZERO, true);
+        match outcome {
+            Ok(GcOutcome::Complete(stats)) => {
+                assert_eq!(3, stats.freed_blocks);
+                assert_eq!(52, stats.live_blocks);
+            }
+            _ => panic!("expected a complete collection"),
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use crate::address::Address;
+use crate::block::header::BlockHeader;
+use crate::block::{Block, ByAddress};
+use crate::types::{HalfWord, WORD_SIZE};
+
+use std::alloc::{alloc, dealloc, Layout};
+use std::mem;
+
+/// Objects which are too big for the normal heap.
+/// Every object gets its own allocation, which starts with a normal
+/// BlockHeader, so a large object can be used like any other block.
+#[derive(Default)]
+pub struct LargeObjectSpace {
+    // sorted by address
+    objects: Vec<(Block, Layout)>,
+    size: usize,
+}
+
+impl LargeObjectSpace {
+    /// Takes the blocksize as a number of usize values (without the header).
+    pub fn alloc(&mut self, size: HalfWord) -> Option<Address> {
+        let total_size = size
+            .checked_add(1)
+            .filter(|&size| size <= BlockHeader::MAX_SIZE)?;
+        let layout =
+            Layout::from_size_align(total_size as usize * WORD_SIZE, mem::align_of::<usize>())
+                .ok()?;
+
+        let ptr = unsafe { alloc(layout) } as *mut usize;
+        if ptr.is_null() {
+            return None;
+        }
+
+        let mut block = Block::new(ptr, total_size, 0);
+        block.set_used(true);
+        let index = match self
+            .objects
+            .binary_search_by_key(&ByAddress(block), |&(b, _)| ByAddress(b))
+        {
+            Ok(index) => index,
+            Err(index) => index,
+        };
+        self.objects.insert(index, (block, layout));
+        self.size += total_size as usize;
+
+        Some(Address::from(block))
+    }
+
+    /// Frees the object at address.
+    /// Returns false if address is not a large object.
+    pub fn free(&mut self, address: Address) -> bool {
+        let block = Block::from(address);
+        match self
+            .objects
+            .binary_search_by_key(&ByAddress(block), |&(b, _)| ByAddress(b))
+        {
+            Ok(index) => {
+                let (block, layout) = self.objects.remove(index);
+                self.size -= block.size() as usize;
+                unsafe {
+                    dealloc(block.as_ptr() as *mut u8, layout);
+                }
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+impl LargeObjectSpace {
+    pub fn contains(&self, address: Address) -> bool {
+        let block = Block::from(address);
+        self.objects
+            .binary_search_by_key(&ByAddress(block), |&(b, _)| ByAddress(b))
+            .is_ok()
+    }
+
+    /// Finds the large object containing address
+    pub fn block_of(&self, address: Address) -> Option<Block> {
+        let ptr: usize = address.into();
+        let index = self
+            .objects
+            .partition_point(|(b, _)| b.as_ptr() as usize <= ptr);
+        let (block, _) = *self.objects.get(index.checked_sub(1)?)?;
+
+        if block.contains(ptr) {
+            Some(block)
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Block> {
+        self.objects.iter().map(|(b, _)| b)
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// The size of all large objects in words, including their headers.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for LargeObjectSpace {
+    fn drop(&mut self) {
+        for (block, layout) in self.objects.drain(..) {
+            unsafe {
+                dealloc(block.as_ptr() as *mut u8, layout);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alloc_and_free_large_object() {
+        let mut space = LargeObjectSpace::default();
+
+        let address = space.alloc(1000).unwrap();
+        assert!(space.contains(address));
+        assert_eq!(1, space.len());
+        assert_eq!(1001, space.size());
+        assert_eq!(1001, Block::from(address).size());
+
+        (address + 999).write(42);
+        assert_eq!(42, *(address + 999));
+
+        assert!(space.free(address));
+        assert!(!space.contains(address));
+        assert_eq!(0, space.len());
+        assert_eq!(0, space.size());
+    }
+
+    #[test]
+    fn test_free_unknown_address_returns_false() {
+        let mut space = LargeObjectSpace::default();
+        let mut other = LargeObjectSpace::default();
+
+        let address = other.alloc(300).unwrap();
+        assert!(!space.free(address));
+        assert!(other.free(address));
+    }
+}
@@ -0,0 +1,221 @@
+use crate::address::{Address, RelativeAddress};
+use crate::managed::ManagedHeap;
+
+use std::fmt;
+use std::thread;
+
+/// Gets called with the objects, which are still in use when the heap is
+/// dropped (see ManagedHeap::set_leak_handler).
+pub type LeakHandler = Box<dyn FnMut(&LeakSummary) + Send>;
+
+/// The objects, which are still in use (see ManagedHeap::leak_summary).
+/// All sizes are in words and include the block headers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LeakSummary {
+    pub objects: usize,
+    pub words: usize,
+    /// The first objects in address order (heap before large objects)
+    pub first: Vec<LeakedObject>,
+}
+
+/// A single object of a LeakSummary
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LeakedObject {
+    pub address: RelativeAddress,
+    pub words: usize,
+    /// The tag of the object, if it was allocated with alloc_tagged
+    pub tag: Option<&'static str>,
+    /// The allocation context of the object (see set_alloc_context)
+    pub context: Option<u64>,
+}
+
+impl LeakSummary {
+    /// The maximum number of objects in first
+    pub const MAX_LISTED: usize = 8;
+
+    pub fn is_empty(&self) -> bool {
+        self.objects == 0
+    }
+}
+
+impl fmt::Display for LeakSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} objects with {} words are still in use:",
+            self.objects, self.words
+        )?;
+        for object in self.first.iter() {
+            write!(f, " {} ({} words", object.address, object.words)?;
+            if let Some(tag) = object.tag {
+                write!(f, ", {:?}", tag)?;
+            }
+            if let Some(context) = object.context {
+                write!(f, ", context {:#x}", context)?;
+            }
+            write!(f, ")")?;
+        }
+        if self.objects > self.first.len() {
+            write!(f, " ...")?;
+        }
+        Ok(())
+    }
+}
+
+impl ManagedHeap {
+    /// Makes dropping the heap panic while it still contains objects, e.g.
+    /// to catch a forgotten collection or a root holding on to objects in
+    /// tests. Doesn't panic if the thread is already panicking.
+    /// Disabled by default.
+    pub fn panic_on_leak(&mut self, enabled: bool) {
+        self.panic_on_leak = enabled;
+    }
+
+    /// Calls handler when the heap is dropped while it still contains
+    /// objects, e.g. to log them. Called before panic_on_leak panics.
+    pub fn set_leak_handler(&mut self, handler: LeakHandler) {
+        self.leak_handler = Some(handler);
+    }
+
+    pub fn take_leak_handler(&mut self) -> Option<LeakHandler> {
+        self.leak_handler.take()
+    }
+
+    /// All objects, which are in use, including large objects
+    pub fn leak_summary(&self) -> LeakSummary {
+        let mut summary = LeakSummary::default();
+        for block in self.used_blocks() {
+            let address = Address::from(block);
+            summary.objects += 1;
+            summary.words += block.size() as usize;
+            if summary.first.len() < LeakSummary::MAX_LISTED {
+                summary.first.push(LeakedObject {
+                    address: self.debug_address(address),
+                    words: block.size() as usize,
+                    tag: self.tag_of(address),
+                    context: self.context_of(address),
+                });
+            }
+        }
+        summary
+    }
+
+    /// Reports the leaked objects of a heap, which is dropped, to the leak
+    /// handler and returns them, if the drop has to panic. The caller panics
+    /// after it released everything else, e.g. the values of HeapBoxes.
+    pub(crate) fn check_leaks(&mut self) -> Option<LeakSummary> {
+        // a second panic would abort the process
+        if thread::panicking() || !self.panic_on_leak && self.leak_handler.is_none() {
+            return None;
+        }
+
+        let summary = self.leak_summary();
+        if summary.is_empty() {
+            return None;
+        }
+
+        if let Some(handler) = self.leak_handler.as_mut() {
+            handler(&summary);
+        }
+        Some(summary).filter(|_| self.panic_on_leak)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    #[should_panic(expected = "2 objects with 7 words are still in use: heap+1 (3 words) heap+4")]
+    fn test_drop_panics_on_leak() {
+        let mut heap = ManagedHeap::new(1000);
+        heap.panic_on_leak(true);
+        heap.alloc(2).unwrap();
+        heap.set_alloc_context(0x2a);
+        heap.alloc_tagged(3, "parser").unwrap();
+        let freed = heap.alloc(4).unwrap();
+        heap.free(freed);
+
+        let summary = heap.leak_summary();
+        assert_eq!(Some("parser"), summary.first[1].tag);
+        assert!(summary
+            .to_string()
+            .ends_with("heap+4 (4 words, \"parser\", context 0x2a)"));
+    }
+
+    #[test]
+    fn test_drop_is_silent_without_leak_check() {
+        let mut heap = ManagedHeap::new(1000);
+        heap.alloc(2).unwrap();
+        drop(heap);
+
+        let mut empty = ManagedHeap::new(1000);
+        empty.panic_on_leak(true);
+        let a = empty.alloc(2).unwrap();
+        empty.free(a);
+        drop(empty);
+    }
+
+    #[test]
+    fn test_leak_handler_sees_summary() {
+        let leaks = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&leaks);
+
+        let mut heap = ManagedHeap::new(1000);
+        heap.set_large_object_threshold(10);
+        for _ in 0..LeakSummary::MAX_LISTED {
+            heap.alloc(1).unwrap();
+        }
+        heap.alloc(20).unwrap();
+        heap.set_leak_handler(Box::new(move |summary| {
+            log.lock().unwrap().push(summary.clone())
+        }));
+        drop(heap);
+
+        let leaks = leaks.lock().unwrap();
+        assert_eq!(1, leaks.len());
+        assert_eq!(9, leaks[0].objects);
+        assert_eq!(2 * 8 + 21, leaks[0].words);
+        assert_eq!(LeakSummary::MAX_LISTED, leaks[0].first.len());
+        assert!(leaks[0].to_string().ends_with(" ..."));
+    }
+
+    #[test]
+    fn test_leak_panic_drops_boxed_values_first() {
+        struct Flag(Arc<Mutex<bool>>);
+
+        impl Drop for Flag {
+            fn drop(&mut self) {
+                *self.0.lock().unwrap() = true;
+            }
+        }
+
+        let dropped = Arc::new(Mutex::new(false));
+        let flag = Flag(Arc::clone(&dropped));
+        let result = panic::catch_unwind(AssertUnwindSafe(move || {
+            let mut heap = ManagedHeap::new(1000);
+            heap.panic_on_leak(true);
+            heap.alloc_rust(flag).unwrap();
+        }));
+
+        assert!(result.is_err());
+        assert!(*dropped.lock().unwrap());
+    }
+
+    #[test]
+    fn test_no_panic_while_unwinding() {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut heap = ManagedHeap::new(1000);
+            heap.panic_on_leak(true);
+            heap.alloc(2).unwrap();
+            panic!("first panic");
+        }));
+
+        // a panic during unwinding would have aborted the test
+        let message = result.unwrap_err();
+        assert_eq!(Some(&"first panic"), message.downcast_ref::<&str>());
+    }
+}
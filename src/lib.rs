@@ -2,6 +2,7 @@
 //! It can be used while creating your own virtual machine in Rust.
 //!
 //! To use it, you have to implement the traits in the trace module.
+//! Objects without references to other objects can simply implement Leaf.
 //!
 //! # Example
 //! ```
@@ -26,53 +27,47 @@
 //! }
 //!
 //! unsafe impl GcRoot<IntegerObject> for MockGcRoot {
-//!     fn children<'a>(&'a mut self) -> Box<Iterator<Item = &'a mut IntegerObject> + 'a> {
+//!     fn children<'a>(&'a mut self) -> Box<dyn Iterator<Item = &'a mut IntegerObject> + 'a> {
 //!         Box::new(self.used_elems.iter_mut())
 //!     }
 //! }
 //!
 //! #[derive(Debug)]
-//! struct IntegerObject(Address);
+//! struct IntegerObject(ManagedPtr);
 //!
 //! impl IntegerObject {
 //!     pub fn new(heap: &mut ManagedHeap, value: isize) -> Self {
-//!         // reserve one usize for mark byte
-//!         let mut address = heap.alloc(2).unwrap();
+//!         // the heap reserves the mark word in front of the field
+//!         let mut ptr = heap.alloc_managed(1).unwrap();
+//!         ptr.set_field(0, value as usize);
 //!
-//!         address.write(false as usize);
-//!         (address + 1).write(value as usize);
-//!
-//!         IntegerObject(address)
+//!         IntegerObject(ptr)
 //!     }
 //!
 //!     pub fn get(&self) -> isize {
-//!         *(self.0 + 1) as isize
+//!         self.0.field(0) as isize
 //!     }
 //! }
 //!
 //! impl From<Address> for IntegerObject {
 //!     fn from(address: Address) -> Self {
-//!         IntegerObject(address)
-//!     }
-//! }
-//!
-//! impl Into<Address> for IntegerObject {
-//!     fn into(self) -> Address {
-//!         self.0
+//!         IntegerObject(ManagedPtr::from(address))
 //!     }
 //! }
 //!
+//! // IntegerObject doesn't reference other objects, so the mark word of the
+//! // ManagedPtr is all it needs
 //! unsafe impl Traceable for IntegerObject {
 //!     fn mark(&mut self) {
-//!         self.0.write(true as usize);
+//!         self.0.mark()
 //!     }
 //!
 //!     fn unmark(&mut self) {
-//!         self.0.write(false as usize);
+//!         self.0.unmark()
 //!     }
 //!
 //!     fn is_marked(&self) -> bool {
-//!         (*self.0) != 0
+//!         self.0.is_marked()
 //!     }
 //! }
 //!
@@ -80,15 +75,63 @@
 //! let mut i = IntegerObject::new(&mut heap, -42);
 //!
 //! assert_eq!(-42, i.get());
-//! assert_eq!(false, i.is_marked());
+//! assert!(!i.is_marked());
 //!
 //! i.mark();
-//! assert_eq!(true, i.is_marked());
+//! assert!(i.is_marked());
+//! i.unmark();
+//!
+//! // the collector knows the address of every object, so IntegerObject
+//! // only has to be constructible from one
+//! IntegerObject::new(&mut heap, 7);
+//! let mut root = MockGcRoot::new(vec![i]);
+//! assert_eq!(1, heap.gc(&mut [&mut root]).freed_blocks);
+//! assert_eq!(-42, root.used_elems[0].get());
 //! ```
 
 pub mod address;
+mod barrier;
+mod bitmap;
 mod block;
+pub mod boxed;
+pub mod builder;
+mod cards;
+mod census;
+mod clone;
+mod compact;
+mod defrag;
+mod diff;
+pub mod error;
+mod events;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 mod heap;
+mod huge;
+mod image;
+pub mod incremental;
+mod large;
+mod leak;
 pub mod managed;
+mod migrate;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod pressure;
+pub mod raw;
+mod rc;
+pub mod region;
+pub mod reservation;
+mod roots;
+pub mod shared;
+pub mod stats;
+pub mod store;
+pub mod stress;
+mod strict;
+pub mod string;
+mod tags;
+#[cfg(test)]
+mod testing;
 pub mod trace;
 pub mod types;
+mod weak;
+
+pub use block::header::{BlockHeader, HeaderFlags};
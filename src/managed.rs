@@ -1,19 +1,204 @@
-use super::address::Address;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe};
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use super::address::{Address, AddressRange, RelativeAddress};
+use super::bitmap::MarkBitmap;
+use super::block::header::BlockHeader;
+use super::block::Block;
+use super::boxed::Dropper;
+use super::cards::CardTable;
+pub use super::census::CensusClassifier;
+pub use super::compact::{apply_relocations, Relocation};
+pub use super::defrag::{Compactor, DefragPolicy};
+pub use super::diff::{Difference, HeapDiff};
+use super::error::{AccessError, AllocError, CorruptionError, NewHeapError, ShrinkError};
+pub use super::events::HeapEvent;
 use super::heap::Heap;
-use super::trace::{GcRoot, Traceable};
+pub use super::heap::{FitPolicy, SizeRounding};
+pub use super::huge::HugeAllocation;
+use super::incremental::IncrementalGc;
+use super::large::LargeObjectSpace;
+pub use super::leak::{LeakHandler, LeakSummary, LeakedObject};
+pub use super::pressure::{GcPressure, PressureLevel};
+use super::rc::Finalizer;
+use super::reservation::ReservationTable;
+pub use super::roots::{HeapSliceRoot, RootId, SharedRoot};
+use super::stats::{
+    AllocFailureReason, BlockInfo, Census, DefragReport, FreeBlockInfo, FreeListSummary,
+    GcEstimate, GcKind, GcStats, HeapStats,
+};
+use super::store::BlockStore;
+use super::trace::{GcRoot, GcRootRef, Leaf, Traceable};
 use super::types::HalfWord;
+pub use super::types::WORD_SIZE;
+pub use super::weak::{WeakDropCallback, WeakTable};
+
+/// Gets called with the address and block size (in words, including the
+/// header) of every object freed by a collection.
+pub type SweepCallback = Box<dyn FnMut(Address, HalfWord) + Send>;
 
 /// A virtual Heap which can be garbage collected by calling gc().
 pub struct ManagedHeap {
-    heap: Heap,
+    pub(crate) heap: Heap,
+    pub(crate) large_objects: LargeObjectSpace,
+    large_object_threshold: HalfWord,
+    pub(crate) growth_factor: f64,
+    pub(crate) max_segments: usize,
+    pub(crate) gc_threshold: f64,
+    pub(crate) incremental: Option<IncrementalGc>,
+    pub(crate) rc_objects: HashSet<usize>,
+    pub(crate) rc_finalizer: Option<Finalizer>,
+    pub(crate) on_sweep: Option<SweepCallback>,
+    pub(crate) reservations: Arc<Mutex<ReservationTable>>,
+    pub(crate) tags: Option<HashMap<usize, &'static str>>,
+    pub(crate) alloc_context: Option<u64>,
+    pub(crate) contexts: Option<HashMap<usize, u64>>,
+    pub(crate) write_barrier: bool,
+    pub(crate) dirty: Vec<(Address, Address)>,
+    pub(crate) droppers: HashMap<usize, Dropper>,
+    pub(crate) cards: CardTable,
+    pub(crate) mark_bitmap: Option<MarkBitmap>,
+    // the objects of the open regions, innermost last
+    pub(crate) regions: Vec<Vec<Address>>,
+    // in words, see set_limits
+    pub(crate) soft_limit: usize,
+    pub(crate) hard_limit: usize,
+    // the number of started collections
+    pub(crate) epoch: u64,
+    // the current epoch was started, but its sweep didn't run yet
+    pub(crate) marking: bool,
+    // the stats of the last collections, oldest first
+    pub(crate) gc_history: VecDeque<GcStats>,
+    pub(crate) gc_history_len: usize,
+    // time every call of a trace function, see set_trace_timing
+    pub(crate) trace_timing: bool,
+    // catch panics while classifying objects, see set_skip_unclassifiable
+    pub(crate) skip_unclassifiable: bool,
+    // reused between collections to avoid allocations
+    pub(crate) sweep_buffer: Vec<Address>,
+    pub(crate) mark_buffer: Vec<Address>,
+    // the chunks after the head of every huge allocation, see alloc_huge
+    pub(crate) huge_chains: HashMap<usize, Vec<Address>>,
+    pub(crate) huge_tails: HashSet<usize>,
+    pub(crate) huge_chunk_words: HalfWord,
+    // validate the addresses passed to the public methods, see enable_strict
+    pub(crate) strict: bool,
+    pub(crate) registered_roots: Vec<(RootId, SharedRoot)>,
+    pub(crate) next_root_id: u64,
+    pub(crate) defrag_policy: DefragPolicy,
+    pub(crate) compactor: Option<Compactor>,
+    pub(crate) last_defrag: Option<DefragReport>,
+    pub(crate) weak_table: WeakTable,
+    pub(crate) census_classifier: Option<CensusClassifier>,
+    pub(crate) last_census: Option<Census>,
+    pub(crate) panic_on_leak: bool,
+    pub(crate) leak_handler: Option<LeakHandler>,
+    // reset at the end of every collection, see gc_pressure
+    pub(crate) words_since_gc: usize,
 }
 
+// The heap exclusively owns its memory, so it can be moved between threads
+unsafe impl Send for ManagedHeap {}
+
 impl ManagedHeap {
-    /// Expects the heap size in bytes.
+    /// The default size in words above which allocations are placed in the
+    /// large object space.
+    pub const DEFAULT_LARGE_OBJECT_THRESHOLD: HalfWord = 256;
+
+    /// The default fraction of the capacity, which has to be in use before
+    /// needs_gc returns true.
+    pub const DEFAULT_GC_THRESHOLD: f64 = 0.75;
+
+    /// The default number of collections, whose stats are kept in the
+    /// history (see gc_history).
+    pub const DEFAULT_GC_HISTORY_LEN: usize = 16;
+
+    /// Expects the heap size in bytes, which is rounded up to a multiple of
+    /// WORD_SIZE. All other sizes of the heap are in words, so new code
+    /// should prefer the builder, which names the unit (see
+    /// ManagedHeapBuilder::initial_words).
+    /// Panics if size is 0 or too large (see try_new).
     pub fn new(size: usize) -> Self {
         let heap = unsafe { Heap::new(size) };
+        ManagedHeap::from_heap(heap)
+    }
+
+    /// Expects the heap size in bytes, which is rounded up to a multiple of
+    /// WORD_SIZE (see new).
+    pub fn try_new(size: usize) -> Result<Self, NewHeapError> {
+        Heap::try_new(size).map(ManagedHeap::from_heap)
+    }
+
+    /// Like new, but the free blocks are kept in store instead of a
+    /// SizeAddressIndex, e.g. to try another allocation strategy (see
+    /// BlockStore).
+    /// Panics if store is not empty.
+    pub fn with_block_store(size: usize, store: Box<dyn BlockStore>) -> Self {
+        let mut heap = ManagedHeap::new(size);
+        heap.heap.set_block_store(store);
+        heap
+    }
 
-        ManagedHeap { heap }
+    pub(crate) fn from_heap(heap: Heap) -> Self {
+        // the card table has to cover the heap after growing as well
+        let heap_words = heap.max_size();
+        ManagedHeap {
+            heap,
+            large_objects: LargeObjectSpace::default(),
+            large_object_threshold: Self::DEFAULT_LARGE_OBJECT_THRESHOLD,
+            growth_factor: 1.0,
+            max_segments: 1,
+            gc_threshold: Self::DEFAULT_GC_THRESHOLD,
+            incremental: None,
+            rc_objects: HashSet::new(),
+            rc_finalizer: None,
+            on_sweep: None,
+            reservations: Arc::default(),
+            tags: None,
+            alloc_context: None,
+            contexts: None,
+            write_barrier: false,
+            dirty: Vec::new(),
+            droppers: HashMap::new(),
+            cards: CardTable::new(heap_words),
+            mark_bitmap: None,
+            regions: Vec::new(),
+            soft_limit: usize::MAX,
+            hard_limit: usize::MAX,
+            epoch: 0,
+            marking: false,
+            // allocated up front, so that collections don't allocate
+            gc_history: VecDeque::with_capacity(Self::DEFAULT_GC_HISTORY_LEN),
+            gc_history_len: Self::DEFAULT_GC_HISTORY_LEN,
+            trace_timing: false,
+            skip_unclassifiable: false,
+            sweep_buffer: Vec::new(),
+            mark_buffer: Vec::new(),
+            huge_chains: HashMap::new(),
+            huge_tails: HashSet::new(),
+            huge_chunk_words: Self::DEFAULT_HUGE_CHUNK_WORDS,
+            strict: false,
+            registered_roots: Vec::new(),
+            next_root_id: 0,
+            defrag_policy: DefragPolicy::default(),
+            compactor: None,
+            last_defrag: None,
+            weak_table: WeakTable::default(),
+            census_classifier: None,
+            last_census: None,
+            panic_on_leak: false,
+            leak_handler: None,
+            words_since_gc: 0,
+        }
     }
 }
 
@@ -26,289 +211,3950 @@ impl ManagedHeap {
         self.heap.num_free_blocks()
     }
 
+    /// The size of the heap in bytes after rounding, including all
+    /// additional segments
+    pub fn capacity_bytes(&self) -> usize {
+        self.heap.total_size() * WORD_SIZE
+    }
+
+    /// The size of the heap in words, including all additional segments.
+    /// Always capacity_bytes / WORD_SIZE.
+    pub fn capacity_words(&self) -> usize {
+        self.heap.total_size()
+    }
+
+    /// Same as capacity_words
     pub fn total_size(&self) -> usize {
-        self.heap.size()
+        self.heap.total_size()
+    }
+
+    /// The size of a word in bytes. Object sizes and offsets are in words,
+    /// the sizes passed to new and grow in bytes.
+    pub const fn word_size() -> usize {
+        WORD_SIZE
+    }
+
+    /// The number of memory regions of the heap. The heap starts with a
+    /// single one and chains another one whenever it has to grow beyond its
+    /// maximum capacity.
+    pub fn num_segments(&self) -> usize {
+        self.heap.num_segments()
+    }
+
+    /// The offset in words of address from the start of the heap.
+    /// Unlike addresses, offsets are the same in every run, so they can be
+    /// used to describe the layout of a heap in tests. Offsets in additional
+    /// segments continue after the reserved memory, like the ones of blocks.
+    /// Panics if address doesn't point into the heap.
+    pub fn offset_of(&self, address: Address) -> usize {
+        self.checked_offset_of(address)
+            .expect("Address is outside of the heap")
+    }
+
+    pub(crate) fn checked_offset_of(&self, address: Address) -> Option<usize> {
+        self.heap
+            .checked_offset_of(usize::from(address) as *const usize)
+    }
+
+    /// The inverse of offset_of. Returns None if offset is outside of the heap.
+    pub fn address_at(&self, offset: usize) -> Option<Address> {
+        self.heap
+            .address_at(offset)
+            .map(|ptr| Address::from(ptr as usize))
+    }
+
+    /// Wraps address, so that it is printed relative to the start of the
+    /// heap (e.g. "heap+3").
+    pub fn debug_address(&self, address: Address) -> RelativeAddress {
+        RelativeAddress {
+            address,
+            offset: self.checked_offset_of(address),
+        }
+    }
+
+    /// The size in bytes the heap can grow to
+    pub fn max_capacity_bytes(&self) -> usize {
+        self.heap.max_size() * WORD_SIZE
+    }
+
+    /// Grows the heap by bytes (rounded up to a multiple of WORD_SIZE)
+    /// without exceeding the maximum capacity. The existing objects don't
+    /// move. Returns the number of added words.
+    pub fn grow(&mut self, bytes: usize) -> usize {
+        self.heap.grow(bytes.div_ceil(WORD_SIZE))
+    }
+
+    /// Shrinks the capacity of the heap to target bytes (rounded up to a
+    /// multiple of WORD_SIZE), e.g. after a burst of allocations, so that
+    /// needs_gc and the allocations only consider the remaining capacity.
+    /// Only the free block at the end of the heap can be cut off, the
+    /// objects never move.
+    /// This doesn't release any memory: the whole reserved memory (see
+    /// max_capacity_bytes) stays allocated, because it can't be returned
+    /// without moving the heap, so it can grow again.
+    /// Returns the number of bytes the capacity shrank by.
+    pub fn shrink_capacity_to(&mut self, target: usize) -> Result<usize, ShrinkError> {
+        let words = self.heap.shrink_capacity_to(target.div_ceil(WORD_SIZE))?;
+        Ok(words * WORD_SIZE)
+    }
+
+    /// Checks if the used part of the heap exceeds the gc threshold.
+    /// The heap can't collect itself, because the roots are owned by the
+    /// caller, so this should be checked regularly (e.g. before allocating).
+    pub fn needs_gc(&self) -> bool {
+        self.heap.used_size() as f64 >= self.heap.total_size() as f64 * self.gc_threshold
     }
 
     pub fn used_size(&self) -> usize {
         self.heap.used_size()
     }
+
+    /// The number of words which count against the limits: the used words
+    /// of the heap and the size of all large objects, including their
+    /// headers
+    pub fn allocated_words(&self) -> usize {
+        self.heap.used_size() + self.large_objects.size()
+    }
+
+    /// Limits the allocated words (see allocated_words). Exceeding the soft
+    /// limit only sets over_soft_limit, e.g. so the caller can schedule a
+    /// collection. Allocations which would exceed the hard limit fail, even
+    /// if there is enough free space.
+    /// Panics if soft is above hard.
+    pub fn set_limits(&mut self, soft: usize, hard: usize) {
+        assert!(soft <= hard, "The soft limit is above the hard limit");
+        self.soft_limit = soft;
+        self.hard_limit = hard;
+    }
+
+    /// Checks if the allocated words exceed the soft limit
+    pub fn over_soft_limit(&self) -> bool {
+        self.allocated_words() > self.soft_limit
+    }
+
+    /// All words of the heap (including the block headers).
+    /// The headers can be decoded with BlockHeader::decode.
+    /// Large objects are not included.
+    pub fn raw_words(&self) -> &[usize] {
+        self.heap.raw_words()
+    }
+
+    pub fn num_large_objects(&self) -> usize {
+        self.large_objects.len()
+    }
+
+    /// Checks the consistency of all block headers in the heap.
+    pub fn verify(&self) -> Result<(), CorruptionError> {
+        self.heap.verify()
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            total_words: self.heap.total_size(),
+            max_words: self.heap.max_size(),
+            segments: self.heap.num_segments(),
+            used_words: self.heap.used_size(),
+            slack_words: self.heap.slack_words(),
+            rounding_words: self.heap.rounding_words(),
+            used_blocks: self.heap.num_used_blocks(),
+            free_blocks: self.heap.num_free_blocks(),
+            large_objects: self.large_objects.len(),
+            large_object_bytes: self.large_objects.size() * WORD_SIZE,
+            coalesce: self.heap.coalesce_stats(),
+        }
+    }
+
+    /// Records the last capacity allocations, frees, splits, merges and
+    /// collections in a ring buffer (see HeapEvent), e.g. to find out which
+    /// operations led to a corrupted heap. A capacity of 0 disables the log
+    /// (the default). Enabling the log again clears it.
+    pub fn enable_event_log(&mut self, capacity: usize) {
+        self.heap.set_event_log(capacity);
+    }
+
+    /// The recorded events, oldest first
+    pub fn event_log(&self) -> impl Iterator<Item = HeapEvent> + '_ {
+        self.heap.events()
+    }
+
+    /// Writes the recorded events to writer, one line per event
+    pub fn dump_event_log_to<W: io::Write>(&self, mut writer: W) -> io::Result<()> {
+        for event in self.event_log() {
+            writeln!(writer, "{}", event)?;
+        }
+        Ok(())
+    }
+
+    /// Walks over all blocks of the heap (used and free) in address order.
+    /// Blocks freed by free_deferred are not used anymore, even though they
+    /// weren't merged yet. Large objects are not included.
+    pub fn blocks(&self) -> impl Iterator<Item = BlockInfo> + '_ {
+        self.heap.blocks().map(move |block| self.block_info(block))
+    }
+
+    pub(crate) fn block_info(&self, block: Block) -> BlockInfo {
+        BlockInfo {
+            address: Address::from(block),
+            offset: self.heap.offset_of(block.as_ptr()),
+            size: block.size() as usize,
+            used: self.heap.is_allocated(block),
+        }
+    }
+
+    /// Lists all free blocks of the heap, e.g. to find out why an
+    /// allocation failed. Quarantined blocks are not included.
+    pub fn free_list_summary(&self) -> FreeListSummary {
+        let blocks: Vec<FreeBlockInfo> = self
+            .heap
+            .free_blocks()
+            .map(|block| FreeBlockInfo {
+                offset: self.heap.offset_of(block.as_ptr()),
+                size: block.size() as usize,
+            })
+            .collect();
+
+        let count = blocks.len();
+        let total_words = blocks.iter().map(|b| b.size).sum();
+        FreeListSummary {
+            count,
+            total_words,
+            largest: blocks.iter().map(|b| b.size).max().unwrap_or(0),
+            smallest: blocks.iter().map(|b| b.size).min().unwrap_or(0),
+            mean: if count == 0 {
+                0.0
+            } else {
+                total_words as f64 / count as f64
+            },
+            blocks,
+        }
+    }
+
+    /// Explains why the heap can't allocate size words right now or returns
+    /// None if it can. This doesn't allocate, so it can be called after
+    /// every failed alloc.
+    /// Growing the heap and the large object space are not taken into
+    /// account.
+    pub fn explain_alloc_failure(&self, size: HalfWord) -> Option<AllocFailureReason> {
+        let needed = self.heap.block_words(size);
+        let max = BlockHeader::MAX_SIZE as usize;
+        if needed > max {
+            return Some(AllocFailureReason::TooLarge { needed, max });
+        }
+
+        let (mut largest, mut total) = (0, 0);
+        for block in self.heap.free_blocks() {
+            let size = block.size() as usize;
+            largest = largest.max(size);
+            total += size;
+        }
+
+        if largest >= needed {
+            None
+        } else if total >= needed {
+            Some(AllocFailureReason::Fragmentation {
+                needed,
+                largest,
+                total,
+            })
+        } else {
+            Some(AllocFailureReason::InsufficientSpace { needed, total })
+        }
+    }
+
+    pub fn fit_policy(&self) -> FitPolicy {
+        self.heap.fit_policy()
+    }
+
+    /// Changes how free blocks are chosen for future allocations
+    pub fn set_fit_policy(&mut self, policy: FitPolicy) {
+        self.heap.set_fit_policy(policy);
+    }
+
+    pub fn size_rounding(&self) -> SizeRounding {
+        self.heap.size_rounding()
+    }
+
+    /// Changes how the sizes of future allocations are rounded up (see
+    /// SizeRounding). Large objects are never rounded.
+    /// Panics for SizeRounding::MultipleOf(0).
+    pub fn set_size_rounding(&mut self, rounding: SizeRounding) {
+        self.heap.set_size_rounding(rounding);
+    }
+
+    /// Checks the header of every free block before it is handed out, even
+    /// in release builds. A corrupted block causes a panic naming its offset.
+    pub fn set_alloc_checks(&mut self, enabled: bool) {
+        self.heap.set_alloc_checks(enabled);
+    }
+
+    /// Keeps the last blocks freed blocks from being reused, so that
+    /// use-after-free bugs don't silently read or corrupt new objects.
+    /// Quarantined blocks are only flushed early, if an allocation would
+    /// fail otherwise. A quarantine of 0 disables it.
+    pub fn set_quarantine(&mut self, blocks: usize) {
+        self.heap.set_quarantine(blocks);
+    }
+
+    /// The number of freed blocks, which are still in quarantine
+    pub fn num_quarantined_blocks(&self) -> usize {
+        self.heap.num_quarantined_blocks()
+    }
+
+    /// Keeps up to capacity freed objects of exactly size words in a stack
+    /// and hands them out again (most recently freed first) before the free
+    /// blocks are searched, e.g. for the cons cells of an interpreter.
+    /// Cached blocks are neither merged with their neighbours nor counted as
+    /// free blocks. The cache is flushed when a collection starts (so the
+    /// sweep refills it), by reset and whenever an allocation would fail
+    /// otherwise. A quarantine takes precedence over the cache.
+    /// A size or capacity of 0 disables the cache (the default).
+    pub fn set_hot_size(&mut self, size: HalfWord, capacity: usize) {
+        self.heap.set_hot_size(size, capacity);
+    }
+
+    /// The number of freed blocks in the cache of set_hot_size
+    pub fn num_hot_cached_blocks(&self) -> usize {
+        self.heap.num_hot_cached_blocks()
+    }
+
+    /// Returns the blocks of the cache of set_hot_size to the free blocks
+    pub fn flush_hot_cache(&mut self) {
+        self.heap.flush_hot_cache();
+    }
+
+    /// Enables (the default) or disables the fast path of free for an object,
+    /// which was the last allocation and split off a free block: it is merged
+    /// back into the remainder without searching the free blocks, e.g. for
+    /// the temporaries of an expression evaluator. The layout of the heap is
+    /// the same as without the fast path.
+    pub fn set_split_undo(&mut self, enabled: bool) {
+        self.heap.set_split_undo(enabled);
+    }
+
+    /// How often free took the fast path of set_split_undo
+    pub fn num_undone_splits(&self) -> usize {
+        self.heap.num_undone_splits()
+    }
+
+    /// Fills free memory with Heap::POISON, so that the allocation checks
+    /// also detect writes through stale addresses.
+    pub fn set_poisoning(&mut self, enabled: bool) {
+        self.heap.set_poisoning(enabled);
+    }
+
+    /// Stores a checksum of every block header in the last word of the
+    /// block, so that a corrupted header is detected by the next free,
+    /// allocation or collection touching it (see Heap::set_header_checksums).
+    /// Every object needs one more word, which is not part of its payload.
+    /// Panics unless the heap is empty.
+    pub fn set_header_checksums(&mut self, enabled: bool) {
+        self.heap.set_header_checksums(enabled);
+    }
+
+    pub fn header_checksums(&self) -> bool {
+        self.heap.header_checksums()
+    }
+
+    /// Checks if address points to an object in the large object space
+    pub fn is_large_object(&self, address: Address) -> bool {
+        self.large_objects.contains(address)
+    }
+
+    pub fn large_object_threshold(&self) -> HalfWord {
+        self.large_object_threshold
+    }
+
+    /// Allocations with a size (in words) above the threshold are not placed
+    /// inside the heap, but get their own memory region instead.
+    pub fn set_large_object_threshold(&mut self, threshold: HalfWord) {
+        self.large_object_threshold = threshold;
+    }
 }
 
 impl ManagedHeap {
     /// Takes the blocksize as a number of usize values.
     /// The size in bytes of the block is therefore size * mem::size_of::<usize>()
     /// (technically + one more usize to store information about the block)
+    /// Sizes above the large object threshold are allocated outside of the
+    /// heap.
     pub fn alloc(&mut self, size: HalfWord) -> Option<Address> {
+        self.try_alloc(size).ok()
+    }
+
+    /// Like alloc, but also returns the words, which the object can use.
+    /// The capacity can be bigger than size if the rest of the free block
+    /// was too small to be split off. Unlike with alloc, the additional words
+    /// belong to the object, so they are neither slack nor removed by trim.
+    pub fn alloc_exact_or_more(&mut self, size: HalfWord) -> Option<Allocation> {
+        let address = self.alloc(size)?;
+        self.heap.claim_slack(address);
+        let capacity = self.capacity_of(address)?;
+        Some(Allocation { address, capacity })
+    }
+
+    /// Like alloc, but tells why the allocation failed
+    pub fn try_alloc(&mut self, size: HalfWord) -> Result<Address, AllocError> {
+        self.release_reservations();
+
+        // the header needs one additional word
+        if size >= BlockHeader::MAX_SIZE {
+            return Err(AllocError::TooLarge(size));
+        }
+
+        if self.allocated_words() + self.block_words(size) > self.hard_limit {
+            return Err(AllocError::LimitExceeded(size));
+        }
+
+        let address = if size > self.large_object_threshold {
+            self.large_objects.alloc(size)
+        } else {
+            self.heap
+                .alloc(size)
+                .or_else(|| self.grow_for(size))
+                .or_else(|| self.compact_for(size))
+        }
+        .ok_or(AllocError::OutOfMemory(size))?;
+
+        // the block is bigger than estimated, if the rest of the free block
+        // was too small to be split off
+        if self.allocated_words() > self.hard_limit {
+            self.heap.undo_alloc(address);
+            return Err(AllocError::LimitExceeded(size));
+        }
+
+        self.record_context(address);
+        self.words_since_gc += Block::from(address).size() as usize;
+
+        // objects allocated during an incremental collection are always live
+        if self.incremental.is_some() {
+            Block::from(address).mark_in(self.epoch);
+        }
+
+        Ok(address)
+    }
+
+    /// The size of the smallest block, which can hold size words (see
+    /// Heap::block_words). Large objects are neither rounded nor checksummed.
+    fn block_words(&self, size: HalfWord) -> usize {
+        if size > self.large_object_threshold {
+            size as usize + 1
+        } else {
+            self.heap.block_words(size)
+        }
+    }
+
+    /// Allocates an object with field_count fields and an unmarked mark word
+    /// in front of them (see ManagedPtr).
+    /// The fields are not initialized.
+    pub fn alloc_managed(&mut self, field_count: HalfWord) -> Option<ManagedPtr> {
+        let mut address = self.alloc(field_count.checked_add(1)?)?;
+        // the capacity doesn't include the slack, which trim may remove
+        let fields = self.capacity_of(address)? as usize - 1;
+        address.write(fields << 1);
+        Some(ManagedPtr(address))
+    }
+
+    /// Like alloc, but the returned Ref borrows the heap, so it can't outlive
+    /// it (see Ref).
+    pub fn alloc_ref(&mut self, size: HalfWord) -> Option<Ref<'_>> {
+        let address = self.alloc(size)?;
+        Some(Ref {
+            address,
+            len: self.payload_range(address)?.len_words,
+            heap: PhantomData,
+        })
+    }
+
+    /// Grows the heap according to the growth factor, so that size words
+    /// can be allocated and retries the allocation.
+    fn grow_for(&mut self, size: HalfWord) -> Option<Address> {
+        if self.growth_factor <= 1.0 {
+            return None;
+        }
+
+        let current = self.heap.size();
+        let needed = current + self.heap.block_words(size);
+        let target = ((current as f64 * self.growth_factor).ceil() as usize).max(needed);
+
+        if self.heap.grow(target - current) > 0 {
+            if let Some(address) = self.heap.alloc(size) {
+                return Some(address);
+            }
+        }
+
+        // the reserved memory is exhausted, so the heap continues in a new
+        // segment instead
+        if self.heap.num_segments() >= self.max_segments {
+            return None;
+        }
+
+        let total = self.heap.total_size();
+        let growth = (total as f64 * (self.growth_factor - 1.0)).ceil() as usize;
+        if self
+            .heap
+            .add_segment(growth.max(self.heap.block_words(size)))
+            == 0
+        {
+            return None;
+        }
         self.heap.alloc(size)
     }
 
+    /// The number of words the object at address can use, which includes
+    /// the words added by the size rounding, but not the slack.
+    /// None if address is not the start of an object.
+    pub fn capacity_of(&self, address: Address) -> Option<HalfWord> {
+        if let Some(block) = self.large_objects.block_of(address) {
+            return Some(block.size() - 1).filter(|_| Address::from(block) == address);
+        }
+
+        let block = self
+            .heap
+            .block_of(address)
+            .filter(|&block| Address::from(block) == address)?;
+        Some(self.heap.payload_words(block) - self.heap.slack_of(address))
+    }
+
+    /// The payload of the object at address, including the words, which were
+    /// not requested (e.g. the slack).
+    /// None if address is not the start of an object.
+    pub fn payload_range(&self, address: Address) -> Option<AddressRange> {
+        if let Some(block) = self.large_objects.block_of(address) {
+            let words = block.size() as usize - 1;
+            return Some(address.range(words)).filter(|_| Address::from(block) == address);
+        }
+
+        let block = self
+            .heap
+            .block_of(address)
+            .filter(|&block| Address::from(block) == address)?;
+        Some(address.range(self.heap.payload_words(block) as usize))
+    }
+
+    /// The words of payload_range as a slice, which borrows the heap, so the
+    /// object can't be freed while the slice lives.
+    /// The slice includes the unrequested words at the end of the block, so
+    /// it can be longer than the requested size (see capacity_of).
+    /// None if address is not the start of an object.
+    pub fn payload(&self, address: Address) -> Option<&[usize]> {
+        let range = self.payload_range(address)?;
+        Some(unsafe {
+            slice::from_raw_parts(usize::from(range.start) as *const usize, range.len_words)
+        })
+    }
+
+    /// Like payload, but the words can be changed. The heap can't be used
+    /// while the slice lives:
+    /// ```compile_fail
+    /// use managed_heap::managed::ManagedHeap;
+    ///
+    /// let mut heap = ManagedHeap::new(100);
+    /// let address = heap.alloc(2).unwrap();
+    /// let words = heap.payload_mut(address).unwrap();
+    /// heap.alloc(1);
+    /// words[0] = 1;
+    /// ```
+    pub fn payload_mut(&mut self, address: Address) -> Option<&mut [usize]> {
+        let mut range = self.payload_range(address)?;
+        Some(unsafe { slice::from_raw_parts_mut(range.start.as_mut(), range.len_words) })
+    }
+
+    /// The len_words words starting at address.
+    /// Fails if they are not inside the payload of a used block.
+    pub fn checked_range(
+        &self,
+        address: Address,
+        len_words: usize,
+    ) -> Result<AddressRange, AccessError> {
+        self.check_range(address, len_words)?;
+        Ok(address.range(len_words))
+    }
+
+    /// Returns the unrequested words at the end of the object at address to
+    /// the free blocks (see HeapStats::slack_words) and returns their number.
+    /// Returns 0 if address is not the start of an object in the heap.
+    /// Panics if the address is invalid in strict mode.
+    pub fn trim(&mut self, address: Address) -> HalfWord {
+        self.assert_strict(address);
+        let is_object = self
+            .heap
+            .block_of(address)
+            .is_some_and(|block| Address::from(block) == address);
+
+        if !is_object {
+            return 0;
+        }
+
+        // the reference count lives in the last word of the payload, which
+        // is part of the slack
+        let count = self.rc_count(address).ok();
+        let trimmed = self.heap.trim(address);
+        if let Some(count) = count {
+            self.set_rc_count(address, count);
+        }
+        trimmed
+    }
+
+    /// Frees the object at address, regardless of it being reachable or not.
+    /// Freeing the head of a huge allocation frees all of its chunks.
+    /// Using address afterwards is undefined behaviour.
+    /// Panics if the address is invalid in strict mode (see try_free).
+    pub fn free(&mut self, address: Address) {
+        self.assert_strict(address);
+        self.free_unchecked(address);
+    }
+
+    /// Like free, but without the checks of strict mode
+    pub fn free_unchecked(&mut self, address: Address) {
+        if self.release_object(address) {
+            self.heap.free(address);
+        }
+    }
+
+    /// Like free, but the block of the object is only removed from the used
+    /// blocks. It is merged with its neighbours by flush_deferred_frees,
+    /// which merges all deferred blocks in a single pass, e.g. to tear down
+    /// thousands of objects without coalescing after every free.
+    /// Allocations don't reuse the deferred blocks, unless they would fail
+    /// otherwise: then the deferred frees are flushed first (like the hot
+    /// cache, see set_hot_size). Collections and reset flush them as well.
+    /// Large objects are freed immediately.
+    pub fn free_deferred(&mut self, address: Address) {
+        self.assert_strict(address);
+        if self.release_object(address) {
+            self.heap.free_deferred(address);
+        }
+    }
+
+    /// Merges the blocks of free_deferred with each other and with their
+    /// free neighbours. Returns the number of flushed blocks.
+    pub fn flush_deferred_frees(&mut self) -> usize {
+        self.heap.flush_deferred()
+    }
+
+    /// The number of objects freed by free_deferred, which weren't flushed
+    /// yet
+    pub fn num_deferred_frees(&self) -> usize {
+        self.heap.num_deferred_blocks()
+    }
+
+    /// Drops everything the heap keeps for an object, which is freed, and
+    /// frees it, if it is a large object. Returns false for large objects.
+    fn release_object(&mut self, address: Address) -> bool {
+        self.free_huge_tails(address);
+        self.rc_objects.remove(&address.into());
+        if let Some(tags) = self.tags.as_mut() {
+            tags.remove(&address.into());
+        }
+        if let Some(contexts) = self.contexts.as_mut() {
+            contexts.remove(&address.into());
+        }
+        self.weak_table.prune(address);
+        if let Some(dropper) = self.droppers.remove(&address.into()) {
+            unsafe { dropper.drop_value(address) };
+        }
+
+        !self.large_objects.free(address)
+    }
+
+    /// Reads the word at offset (in words) inside the object at address.
+    /// Fails if the word is not inside the payload of a used block.
+    pub fn read(&self, address: Address, offset: usize) -> Result<usize, AccessError> {
+        self.check_strict(address)?;
+        self.read_unchecked(address, offset)
+    }
+
+    /// Like read, but without the checks of strict mode. The word still has
+    /// to be inside the payload of a used block.
+    pub fn read_unchecked(&self, address: Address, offset: usize) -> Result<usize, AccessError> {
+        let address = address + offset;
+        self.check_range(address, 1)?;
+        Ok(*address)
+    }
+
+    /// Writes value to offset (in words) inside the object at address.
+    /// Fails if the word is not inside the payload of a used block.
+    pub fn write(
+        &mut self,
+        address: Address,
+        offset: usize,
+        value: usize,
+    ) -> Result<(), AccessError> {
+        self.check_strict(address)?;
+        self.write_unchecked(address, offset, value)
+    }
+
+    /// Like write, but without the checks of strict mode. The word still has
+    /// to be inside the payload of a used block.
+    pub fn write_unchecked(
+        &mut self,
+        address: Address,
+        offset: usize,
+        value: usize,
+    ) -> Result<(), AccessError> {
+        let mut address = address + offset;
+        self.check_range(address, 1)?;
+        address.write(value);
+        Ok(())
+    }
+
+    /// Copies words values from src to dst after checking that both ranges
+    /// lie inside the payload of a used block.
+    /// The ranges may overlap if they are inside the same block, in which case
+    /// the copy behaves as if the source was first copied into a temporary
+    /// buffer.
+    pub fn copy_words(
+        &mut self,
+        src: Address,
+        mut dst: Address,
+        words: usize,
+    ) -> Result<(), AccessError> {
+        let src_block = self.check_range(src, words)?;
+        let dst_block = self.check_range(dst, words)?;
+
+        if src_block == dst_block {
+            src.copy_to_overlapping(&mut dst, words);
+        } else {
+            src.copy_to(&mut dst, words);
+        }
+
+        Ok(())
+    }
+
+    /// Allocates an object with exactly words.len() words and copies words
+    /// into it.
+    pub fn alloc_from_slice(&mut self, words: &[usize]) -> Option<Address> {
+        let len = HalfWord::try_from(words.len()).ok()?;
+        self.alloc_from_iter(len, words.iter().copied())
+    }
+
+    /// Allocates an object with len words and fills it with the values of it.
+    /// If it yields fewer than len values, the rest of the object is filled
+    /// with zeros, values after the first len are not consumed.
+    pub fn alloc_from_iter(
+        &mut self,
+        len: HalfWord,
+        mut it: impl Iterator<Item = usize>,
+    ) -> Option<Address> {
+        let address = self.alloc(len)?;
+
+        for mut word in address.range(len as usize).iter() {
+            word.write(it.next().unwrap_or(0));
+        }
+
+        Some(address)
+    }
+
+    /// Reads len words starting at address into a new Vec.
+    /// Fails if the words are not inside the payload of a used block, reading
+    /// 0 words always succeeds (objects without payload have no words that
+    /// could be checked).
+    pub fn read_to_vec(&self, address: Address, len: usize) -> Result<Vec<usize>, AccessError> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+
+        self.check_range(address, len)?;
+        Ok(address.range(len).iter().map(|word| *word).collect())
+    }
+
+    /// Reads buffer.len() bytes starting at byte_offset (in bytes) inside the
+    /// object at address (see Address::read_u8 for the layout).
+    /// Fails if the bytes are not inside the payload of a used block.
+    pub fn read_bytes(
+        &self,
+        address: Address,
+        byte_offset: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), AccessError> {
+        self.check_strict(address)?;
+        self.check_bytes(address, byte_offset, buffer.len())?;
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = address.read_u8(byte_offset + i);
+        }
+        Ok(())
+    }
+
+    /// Writes bytes starting at byte_offset (in bytes) inside the object at
+    /// address (see Address::write_u8 for the layout).
+    /// Fails if the bytes are not inside the payload of a used block.
+    pub fn write_bytes(
+        &mut self,
+        mut address: Address,
+        byte_offset: usize,
+        bytes: &[u8],
+    ) -> Result<(), AccessError> {
+        self.check_strict(address)?;
+        self.check_bytes(address, byte_offset, bytes.len())?;
+        for (i, &byte) in bytes.iter().enumerate() {
+            address.write_u8(byte_offset + i, byte);
+        }
+        Ok(())
+    }
+
+    /// Allocates a new object with the same requested size as the object at
+    /// address and copies its payload. The copy starts out like every new
+    /// allocation, so it is unmarked, young and has no reference count, tag
+    /// or HeapBox destructor.
+    /// Returns None if address is not the start of an object or if there is
+    /// not enough space. Panics if the address is invalid in strict mode.
+    pub fn duplicate(&mut self, address: Address) -> Option<Address> {
+        self.assert_strict(address);
+        let block = self
+            .block_of(address)
+            .filter(|&block| Address::from(block) == address)?;
+
+        // the slack was never requested, so the copy doesn't need it
+        let size = self.heap.payload_words(block) - self.heap.slack_of(address);
+        let mut copy = self.alloc(size)?;
+        address.copy_to(&mut copy, size as usize);
+        Some(copy)
+    }
+
     /// Run the mark & sweep garbage collector.
     /// roots should return an iterator over all objects still in use.
     /// If an object is neither returned by one of the roots, nor from another
     /// object in the root.children(), it gets automatically freed.
-    pub fn gc<T>(&mut self, roots: &mut [&mut GcRoot<T>])
+    /// Unreachable objects are freed in ascending address order (see
+    /// set_on_sweep), so the collection is deterministic.
+    /// An active incremental collection is aborted.
+    /// If Traceable::mark (or the conversion from an address) panics, all
+    /// objects are unmarked before the panic continues, so the heap is left
+    /// as if the collection never started. A panic during the sweep only
+    /// leaves the objects, which were freed before it, freed.
+    pub fn gc<T>(&mut self, roots: &mut [&mut dyn GcRoot<T>]) -> GcStats
     where
-        T: Traceable + From<Address> + Into<Address>,
+        T: Traceable + From<Address>,
     {
-        for traceable in roots.iter_mut().flat_map(|r| r.children()) {
-            traceable.mark();
+        let start = Instant::now();
+        self.gc_abort();
+        self.release_reservations();
+        self.begin_epoch();
+        let mark_visited = {
+            let guard = UnmarkGuard::<T>::new(self);
+            let visited = Self::mark(roots) + self.mark_regions::<T>();
+            guard.disarm();
+            visited
+        };
+        let marked = GcStats {
+            mark_visited,
+            mark_duration: start.elapsed(),
+            ..GcStats::default()
+        };
+        self.sweep::<T>(start, marked)
+    }
+
+    /// Like gc, but additionally keeps every object alive, which is
+    /// referenced by one of the words (see scan_conservative).
+    pub fn gc_conservative<T>(
+        &mut self,
+        roots: &mut [&mut dyn GcRoot<T>],
+        words: &[usize],
+    ) -> GcStats
+    where
+        T: Traceable + From<Address>,
+    {
+        let start = Instant::now();
+        self.gc_abort();
+        self.release_reservations();
+        self.begin_epoch();
+        let mut marked = GcStats::default();
+        {
+            let guard = UnmarkGuard::<T>::new(self);
+            for address in self.scan_conservative(words) {
+                T::from(address).mark();
+                marked.mark_visited += 1;
+            }
+            marked.mark_visited += Self::mark(roots) + self.mark_regions::<T>();
+            guard.disarm();
         }
+        marked.mark_duration = start.elapsed();
+        self.sweep::<T>(start, marked)
+    }
 
-        let freeable: Vec<Address> = self
-            .heap
-            .used()
-            .map(|b| T::from(Address::from(*b)))
-            .filter(|t| !t.is_marked())
-            .map(|t| t.into())
+    /// Like gc, but the roots are only borrowed immutably.
+    /// Instead of Traceable::mark, the mark state is stored in the block
+    /// headers and Traceable::trace is used to find the children of an
+    /// object (like the incremental collector), so trace has to return every
+    /// reference of an object.
+    pub fn gc_shared<T>(&mut self, roots: &[&dyn GcRootRef<T>]) -> GcStats
+    where
+        T: Traceable + From<Address> + Into<Address> + Copy,
+    {
+        self.gc_abort();
+        let root_addresses: Vec<Address> = roots
+            .iter()
+            .flat_map(|r| r.children())
+            .map(|&t| t.into())
+            .collect();
+
+        self.gc_addresses(&root_addresses, |address, children| {
+            T::from(address).trace_with(&mut |child| children(*child));
+        })
+    }
+
+    /// Treats every word as a potential pointer into the heap and returns the
+    /// addresses of the used blocks they point into. Pointers into the middle
+    /// of an object are allowed. The result is sorted and contains every
+    /// address only once.
+    /// Integers which happen to look like a pointer into a used block are
+    /// returned as well.
+    pub fn scan_conservative(&self, words: &[usize]) -> Vec<Address> {
+        let mut candidates: Vec<Address> = words
+            .iter()
+            .filter_map(|&word| self.block_of(Address::from(word)))
+            .map(Address::from)
             .collect();
 
-        for a in freeable {
-            self.heap.free(a);
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+
+    /// Frees all objects, which are not marked, and unmarks the remaining ones.
+    /// The survivors become old (see minor_gc).
+    /// marked contains the stats of the mark phase of the collection, which
+    /// started at start.
+    fn sweep<T>(&mut self, start: Instant, marked: GcStats) -> GcStats
+    where
+        T: Traceable + From<Address>,
+    {
+        let sweep_start = Instant::now();
+        let mut freeable = mem::take(&mut self.sweep_buffer);
+        let unclassifiable = {
+            let guard = UnmarkGuard::<T>::new(self);
+            let unclassifiable = self.unmarked::<T>(&mut freeable);
+            guard.disarm();
+            unclassifiable
+        };
+        let freed = self.free_all(&mut freeable);
+        self.sweep_buffer = freeable;
+
+        let mut stats = GcStats {
+            freed_blocks: freed.freed_blocks,
+            freed_words: freed.freed_words,
+            unclassifiable,
+            ..marked
+        };
+        for mut block in self.used_blocks() {
+            stats.live_blocks += 1;
+            stats.live_words += block.size() as usize;
+            block.set_old(true);
+            self.clear_stale_mark(block);
         }
+        stats.sweep_visited = stats.freed_blocks + stats.live_blocks;
+        stats.sweep_duration = sweep_start.elapsed();
 
-        self.heap
-            .used()
-            .map(|b| Address::from(*b))
-            .map(T::from)
-            .for_each(|mut t| t.unmark());
+        let unmark_start = Instant::now();
+        stats.unmark_visited = self.unmark_all::<T>();
+        stats.unmark_duration = unmark_start.elapsed();
+
+        stats.duration = start.elapsed();
+        self.end_epoch(&mut stats, GcKind::Full);
+        stats
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// The number of collections started so far. Every collection starts a
+    /// new epoch and the header mark flags are only valid in the epoch they
+    /// were set in, so the marks don't have to be cleared after a
+    /// collection.
+    pub fn current_epoch(&self) -> u64 {
+        self.epoch
+    }
 
-    mod simple {
-        use super::*;
-        use std::ops::Add;
+    /// Starts the epoch of a new collection.
+    /// Only the parity of the epoch is stored next to the mark flag, which
+    /// can't tell apart marks from two epochs ago. If the last collection
+    /// never reached its sweep (e.g. because tracing panicked), its marks
+    /// are therefore cleared.
+    pub(crate) fn begin_epoch(&mut self) {
+        if self.marking {
+            for mut block in self.used_blocks() {
+                block.set_marked(false);
+            }
+        }
+
+        self.heap.flush_hot_cache();
+        self.heap.flush_deferred();
+        self.epoch += 1;
+        self.marking = true;
+        self.heap.record(HeapEvent::GcStart { epoch: self.epoch });
+    }
+
+    /// Has to be called for every block which survives a collection, so that
+    /// no mark outlives the next epoch
+    pub(crate) fn clear_stale_mark(&self, mut block: Block) {
+        if block.is_marked() && !block.is_marked_in(self.epoch) {
+            block.set_marked(false);
+        }
+    }
+
+    /// Finishes the current epoch and records stats in the history
+    pub(crate) fn end_epoch(&mut self, stats: &mut GcStats, kind: GcKind) {
+        self.marking = false;
+        self.words_since_gc = 0;
+        stats.epoch = self.epoch;
+        stats.kind = kind;
+        self.heap.record(HeapEvent::GcEnd {
+            epoch: self.epoch,
+            freed_blocks: stats.freed_blocks,
+        });
+
+        self.record_census();
+
+        if self.gc_history_len > 0 {
+            if self.gc_history.len() == self.gc_history_len {
+                self.gc_history.pop_front();
+            }
+            self.gc_history.push_back(*stats);
+        }
+    }
+
+    /// Measures every call of a trace function by gc_addresses and gc_shared
+    /// and reports the slowest one in GcStats::slowest_trace, e.g. to find
+    /// objects which are pathologically slow to trace.
+    /// gc and gc_conservative can't attribute the time to single objects,
+    /// because Traceable::mark marks the children as well.
+    /// Disabled by default, so that a collection doesn't read the clock for
+    /// every object.
+    pub fn set_trace_timing(&mut self, enabled: bool) {
+        self.trace_timing = enabled;
+    }
+
+    /// Makes gc and gc_conservative keep every object, for which T::from or
+    /// Traceable::is_marked panics, instead of aborting the collection, e.g.
+    /// if the heap contains objects of another kind. They are counted in
+    /// GcStats::unclassifiable.
+    /// The panics are still reported by the panic hook.
+    /// Disabled by default, because catching the panics has a cost for every
+    /// object.
+    pub fn set_skip_unclassifiable(&mut self, enabled: bool) {
+        self.skip_unclassifiable = enabled;
+    }
+
+    /// The stats of the last collections, oldest first
+    pub fn gc_history(&self) -> impl Iterator<Item = &GcStats> + '_ {
+        self.gc_history.iter()
+    }
+
+    /// The stats of the most recent collection
+    pub fn last_gc(&self) -> Option<&GcStats> {
+        self.gc_history.back()
+    }
+
+    /// The mean reclaim ratio (see GcStats::reclaim_ratio) of the collections
+    /// in the history, or None if there weren't any
+    pub fn average_reclaim_ratio(&self) -> Option<f64> {
+        if self.gc_history.is_empty() {
+            return None;
+        }
+
+        let sum: f64 = self.gc_history.iter().map(GcStats::reclaim_ratio).sum();
+        Some(sum / self.gc_history.len() as f64)
+    }
+
+    /// Changes the number of collections kept in the history and drops the
+    /// oldest ones if there are too many. A length of 0 disables the history.
+    pub fn set_gc_history_len(&mut self, len: usize) {
+        self.gc_history_len = len;
+        while self.gc_history.len() > len {
+            self.gc_history.pop_front();
+        }
+        self.gc_history.reserve(len - self.gc_history.len());
+    }
+
+    /// Frees every object except for reserved blocks.
+    /// Values allocated with alloc_rust are dropped and the rc finalizer is
+    /// called for reference counted objects.
+    pub fn reset(&mut self) {
+        self.gc_abort();
+        self.release_reservations();
+
+        let mut freeable = mem::take(&mut self.sweep_buffer);
+        {
+            let reservations = self.reservations();
+            freeable.extend(
+                self.used_blocks()
+                    .map(Address::from)
+                    .filter(|&a| !reservations.is_held(a)),
+            );
+        }
+
+        // reset is not a collection
+        self.free_batch(&mut freeable);
+        self.sweep_buffer = freeable;
+        self.heap.flush_hot_cache();
+        self.heap.flush_deferred();
+        self.clear_regions();
+    }
+
+    /// Sets the function, which gets called for every object freed by a
+    /// collection (but not by free or reset). It is called before the
+    /// finalizer, while the payload can still be read.
+    /// The objects are swept in ascending address order and each one is
+    /// finalized and freed before the callback sees the next one.
+    /// Keeping the address after the callback returns is a bug, because the
+    /// memory is reused.
+    pub fn set_on_sweep(&mut self, callback: SweepCallback) {
+        self.on_sweep = Some(callback);
+    }
+
+    /// Removes the sweep callback and returns it
+    pub fn take_on_sweep(&mut self) -> Option<SweepCallback> {
+        self.on_sweep.take()
+    }
+
+    /// Frees all addresses in ascending order and leaves the buffer empty.
+    /// The sweep callback and the finalizer of an object are called right
+    /// before it is freed, so they see the objects in the same order. The
+    /// weak table entries of all objects are removed before that.
+    /// The order doesn't depend on how the addresses were collected (the
+    /// large objects e.g. can lie below the heap), so two runs with the same
+    /// allocations and roots always end up with the same layout.
+    pub(crate) fn free_all(&mut self, addresses: &mut Vec<Address>) -> GcStats {
+        // the other chunks of huge allocations are freed with their head
+        addresses.retain(|&a| !self.is_huge_tail(a));
+        addresses.sort_unstable();
+
+        // the callbacks must not find entries of objects, which are freed
+        // by the same sweep
+        for &a in addresses.iter() {
+            self.weak_table.prune(a);
+        }
+
+        let mut stats = GcStats::default();
+        for a in addresses.drain(..) {
+            let size = Block::from(a).size();
+            stats.freed_blocks += 1;
+            stats.freed_words += size as usize + self.huge_tail_words(a);
+
+            if let Some(on_sweep) = self.on_sweep.as_mut() {
+                on_sweep(a, size);
+            }
+            self.finalize(a);
+            self.free(a);
+        }
+
+        stats
+    }
+
+    /// Run the mark & sweep garbage collector without Traceable objects.
+    /// Every address in roots is kept alive. trace gets called once for every
+    /// live object and has to call its second argument with every address
+    /// the object references.
+    /// The mark state is stored in the block headers, so the objects don't
+    /// need to reserve a mark word.
+    pub fn gc_addresses<F>(&mut self, roots: &[Address], mut trace: F) -> GcStats
+    where
+        F: FnMut(Address, &mut dyn FnMut(Address)),
+    {
+        let start = Instant::now();
+        self.begin_epoch();
+        let mut worklist = mem::take(&mut self.mark_buffer);
+        self.mark_addresses(roots.iter().copied(), &mut worklist);
+        self.mark_addresses(self.region_objects(), &mut worklist);
+
+        let mut marked = GcStats::default();
+        while let Some(address) = worklist.pop() {
+            let trace_start = self.trace_timing.then(Instant::now);
+            trace(address, &mut |child| {
+                self.mark_addresses(Some(child).into_iter(), &mut worklist)
+            });
+
+            marked.mark_visited += 1;
+            if let Some(trace_start) = trace_start {
+                marked.record_trace(address, trace_start.elapsed());
+            }
+        }
+
+        self.mark_buffer = worklist;
+        marked.mark_duration = start.elapsed();
+        self.sweep_unmarked_headers(GcKind::Full, start, marked)
+    }
+
+    /// Frees every used block, which wasn't marked in the current epoch.
+    /// The survivors of a full collection become old (see minor_gc).
+    /// marked contains the stats of the mark phase of the collection, which
+    /// started at start.
+    pub(crate) fn sweep_unmarked_headers(
+        &mut self,
+        kind: GcKind,
+        start: Instant,
+        marked: GcStats,
+    ) -> GcStats {
+        let sweep_start = Instant::now();
+        self.release_reservations();
+
+        let mut freeable = mem::take(&mut self.sweep_buffer);
+        {
+            let reservations = self.reservations();
+            freeable.extend(
+                self.used_blocks()
+                    .filter(|b| !b.is_marked_in(self.epoch))
+                    .map(Address::from)
+                    .filter(|&a| !reservations.is_held(a)),
+            );
+        }
+
+        let freed = self.free_all(&mut freeable);
+        self.sweep_buffer = freeable;
+
+        let mut stats = GcStats {
+            freed_blocks: freed.freed_blocks,
+            freed_words: freed.freed_words,
+            ..marked
+        };
+        for mut block in self.used_blocks() {
+            stats.live_blocks += 1;
+            stats.live_words += block.size() as usize;
+            if kind == GcKind::Full {
+                block.set_old(true);
+            }
+            self.clear_stale_mark(block);
+        }
+        stats.sweep_visited = stats.freed_blocks + stats.live_blocks;
+        stats.sweep_duration = sweep_start.elapsed();
+
+        stats.duration = start.elapsed();
+        self.end_epoch(&mut stats, kind);
+        stats
+    }
+
+    /// Marks every block referenced by addresses in the current epoch and
+    /// pushes the newly marked ones onto worklist.
+    pub(crate) fn mark_addresses<I>(&self, addresses: I, worklist: &mut Vec<Address>)
+    where
+        I: Iterator<Item = Address>,
+    {
+        for address in addresses {
+            if let Some(mut block) = self.block_of(address) {
+                if !block.is_marked_in(self.epoch) {
+                    block.mark_in(self.epoch);
+                    worklist.push(Address::from(block));
+                }
+            }
+        }
+    }
+
+    /// Performs a dry-run of the garbage collector.
+    /// The objects reachable from roots get marked, but nothing is freed.
+    /// Afterwards all objects are unmarked again, so a following gc() behaves
+    /// exactly as if the estimate never happened.
+    pub fn gc_estimate<T>(&mut self, roots: &mut [&mut dyn GcRoot<T>]) -> GcEstimate
+    where
+        T: Traceable + From<Address>,
+    {
+        {
+            let guard = UnmarkGuard::<T>::new(self);
+            Self::mark(roots);
+            guard.disarm();
+        }
+
+        let reservations = self.reservations();
+        let mut estimate = GcEstimate::default();
+        for block in self.objects() {
+            let size = block.size() as usize + self.huge_tail_words(block.into());
+            let address = Address::from(block);
+
+            if reservations.is_held(address) || T::from(address).is_marked() {
+                estimate.live_blocks += 1;
+                estimate.live_words += size;
+            } else {
+                estimate.reclaimable_blocks += 1;
+                estimate.reclaimable_words += size;
+            }
+        }
+
+        self.unmark_all::<T>();
+        estimate
+    }
+
+    /// Checks if the object containing target is reachable from roots without
+    /// running a collection. The objects are traversed with Traceable::trace
+    /// until target is found and the visited objects are remembered outside
+    /// of the heap, so neither the objects nor their headers are changed and
+    /// a following gc() behaves as if the query never happened.
+    /// Objects in open regions count as roots, because gc never frees them.
+    pub fn is_reachable<T>(&self, target: Address, roots: &mut [&mut dyn GcRoot<T>]) -> bool
+    where
+        T: Traceable + From<Address> + Into<Address> + Copy,
+    {
+        let target = match self.block_of(target) {
+            Some(block) => Address::from(block),
+            None => return false,
+        };
+
+        let mut found = false;
+        self.visit_reachable(roots, |object| {
+            found = object == target;
+            found
+        });
+        found
+    }
+
+    /// The addresses of all objects reachable from roots in ascending order
+    /// (see is_reachable), e.g. for snapshot tooling
+    pub fn reachable_set<T>(&self, roots: &mut [&mut dyn GcRoot<T>]) -> Vec<Address>
+    where
+        T: Traceable + From<Address> + Into<Address> + Copy,
+    {
+        let mut reachable = Vec::new();
+        self.visit_reachable(roots, |object| {
+            reachable.push(object);
+            false
+        });
+
+        reachable.sort_unstable();
+        reachable
+    }
+
+    /// Calls visit once for every object reachable from roots and stops as
+    /// soon as visit returns true
+    fn visit_reachable<T, F>(&self, roots: &mut [&mut dyn GcRoot<T>], mut visit: F)
+    where
+        T: Traceable + From<Address> + Into<Address> + Copy,
+        F: FnMut(Address) -> bool,
+    {
+        let mut visited = HashSet::new();
+        let mut worklist: Vec<Address> = roots
+            .iter_mut()
+            .flat_map(|r| r.children())
+            .map(|child| (*child).into())
+            .chain(self.region_objects())
+            .collect();
+
+        while let Some(address) = worklist.pop() {
+            let object = match self.block_of(address) {
+                Some(block) => Address::from(block),
+                None => continue,
+            };
+            if !visited.insert(usize::from(object)) {
+                continue;
+            }
+            if visit(object) {
+                return;
+            }
+
+            let mut traceable = T::from(object);
+            traceable.trace_with(&mut |child| worklist.push(*child));
+        }
+    }
+
+    /// Marks the children of roots and returns their number
+    fn mark<T>(roots: &mut [&mut dyn GcRoot<T>]) -> usize
+    where
+        T: Traceable + From<Address>,
+    {
+        let mut marked = 0;
+        for root in roots.iter_mut() {
+            root.visit_children(&mut |traceable| {
+                traceable.mark();
+                marked += 1;
+            });
+        }
+        marked
+    }
+
+    /// Marks the objects of the open regions and returns their number
+    fn mark_regions<T>(&self) -> usize
+    where
+        T: Traceable + From<Address>,
+    {
+        let mut marked = 0;
+        for address in self.region_objects() {
+            T::from(address).mark();
+            marked += 1;
+        }
+        marked
+    }
+
+    /// Finds the used block whose payload contains address
+    pub(crate) fn block_of(&self, address: Address) -> Option<Block> {
+        let block = self
+            .heap
+            .block_of(address)
+            .or_else(|| self.large_objects.block_of(address))?;
+
+        // the header is not part of the payload
+        if address < Address::from(block) {
+            None
+        } else {
+            Some(block)
+        }
+    }
+
+    /// Checks that words values starting at address are inside the payload of
+    /// a single used block
+    fn check_range(&self, address: Address, words: usize) -> Result<Block, AccessError> {
+        let block = self
+            .block_of(address)
+            .ok_or(AccessError::Unallocated(address))?;
+
+        let start: usize = address.into();
+        let block_end = block.as_ptr() as usize + block.size() as usize * WORD_SIZE;
+        let fits = words
+            .checked_mul(WORD_SIZE)
+            .and_then(|bytes| start.checked_add(bytes))
+            .is_some_and(|end| end <= block_end);
+
+        if fits {
+            Ok(block)
+        } else {
+            Err(AccessError::OutOfBounds { address, words })
+        }
+    }
+
+    /// Like check_range, but for len bytes starting at byte_offset
+    fn check_bytes(
+        &self,
+        address: Address,
+        byte_offset: usize,
+        len: usize,
+    ) -> Result<(), AccessError> {
+        if len == 0 {
+            return Ok(());
+        }
+
+        let last = byte_offset.saturating_add(len - 1) / WORD_SIZE;
+        self.check_range(address, last + 1).map(|_| ())
+    }
+
+    /// All blocks in use, including large objects
+    pub(crate) fn used_blocks<'a>(&'a self) -> impl Iterator<Item = Block> + 'a {
+        self.heap.used().chain(self.large_objects.iter().copied())
+    }
+
+    /// Like used_blocks, but without the chunks of huge allocations, which
+    /// follow their head and don't contain an object of their own
+    pub(crate) fn objects<'a>(&'a self) -> impl Iterator<Item = Block> + 'a {
+        self.used_blocks()
+            .filter(move |&b| !self.is_huge_tail(Address::from(b)))
+    }
+
+    /// Appends the addresses of all unmarked objects to buffer and returns
+    /// the number of objects, which couldn't be classified (see
+    /// set_skip_unclassifiable)
+    fn unmarked<T>(&self, buffer: &mut Vec<Address>) -> usize
+    where
+        T: Traceable + From<Address>,
+    {
+        let reservations = self.reservations();
+        let mut unclassifiable = 0;
+        for address in self.objects().map(Address::from) {
+            if reservations.is_held(address) {
+                continue;
+            }
+
+            // the wrapper is only needed to ask for the mark, the address is
+            // already known
+            match self.classify(|| T::from(address).is_marked()) {
+                Some(true) => {}
+                Some(false) => buffer.push(address),
+                None => unclassifiable += 1,
+            }
+        }
+
+        unclassifiable
+    }
+
+    /// Calls f and catches its panic if skip_unclassifiable is enabled
+    fn classify<R>(&self, f: impl FnOnce() -> R) -> Option<R> {
+        if self.skip_unclassifiable {
+            panic::catch_unwind(AssertUnwindSafe(f)).ok()
+        } else {
+            Some(f())
+        }
+    }
+
+    /// Unmarks all objects and returns their number
+    fn unmark_all<T>(&self) -> usize
+    where
+        T: Traceable + From<Address>,
+    {
+        let mut unmarked = 0;
+        for address in self.objects().map(Address::from) {
+            if self.classify(|| T::from(address).unmark()).is_some() {
+                unmarked += 1;
+            }
+        }
+        unmarked
+    }
+}
+
+/// Unmarks all objects if a collection of Traceable objects is aborted by a
+/// panic, so that the marks don't keep garbage alive in the next collection.
+/// The guarded code has to disarm the guard when it returns, because a
+/// collection may also run while the thread unwinds from an unrelated
+/// panic (e.g. inside of a Drop impl).
+/// The marks in the block headers don't need to be cleared, because they
+/// expire with their epoch (see begin_epoch).
+struct UnmarkGuard<'h, T>
+where
+    T: Traceable + From<Address>,
+{
+    heap: &'h ManagedHeap,
+    objects: PhantomData<T>,
+}
+
+impl<'h, T> UnmarkGuard<'h, T>
+where
+    T: Traceable + From<Address>,
+{
+    fn new(heap: &'h ManagedHeap) -> Self {
+        UnmarkGuard {
+            heap,
+            objects: PhantomData,
+        }
+    }
+
+    /// Keeps the marks, because the guarded code returned normally
+    fn disarm(self) {
+        mem::forget(self);
+    }
+}
+
+impl<T> Drop for UnmarkGuard<'_, T>
+where
+    T: Traceable + From<Address>,
+{
+    fn drop(&mut self) {
+        for address in self.heap.objects().map(Address::from) {
+            // the object, which caused the panic, probably panics again
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| T::from(address).unmark()));
+        }
+    }
+}
+
+impl Drop for ManagedHeap {
+    fn drop(&mut self) {
+        let leaked = self.check_leaks();
+
+        // the memory itself is released by the heap and the large objects
+        for (address, dropper) in self.droppers.drain() {
+            unsafe { dropper.drop_value(Address::from(address)) };
+        }
+
+        if let Some(summary) = leaked {
+            panic!("{}", summary);
+        }
+    }
+}
+
+/// The result of ManagedHeap::alloc_exact_or_more, which can be used like
+/// the address of the object.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Allocation {
+    pub address: Address,
+    /// The number of words the object can use, at least the requested size
+    pub capacity: HalfWord,
+}
+
+impl Deref for Allocation {
+    type Target = Address;
+
+    fn deref(&self) -> &Address {
+        &self.address
+    }
+}
+
+impl From<Allocation> for Address {
+    fn from(allocation: Allocation) -> Self {
+        allocation.address
+    }
+}
+
+/// An object with a mark word, which was allocated with alloc_managed.
+/// Layout: [mark word, fields...]
+/// The lowest bit of the mark word is the mark, the other bits hold the
+/// number of fields.
+/// The fields are indexed from 0 without the mark word, so a type which
+/// wraps a ManagedPtr can implement Traceable by delegating mark, unmark and
+/// is_marked to it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ManagedPtr(Address);
+
+impl ManagedPtr {
+    /// The number of fields, which can be larger than the requested number
+    /// if the size was rounded up (see SizeRounding)
+    pub fn field_count(&self) -> usize {
+        *self.0 >> 1
+    }
+
+    /// Panics if index is not below field_count
+    pub fn field(&self, index: usize) -> usize {
+        *self.field_address(index)
+    }
+
+    /// Panics if index is not below field_count
+    pub fn set_field(&mut self, index: usize, value: usize) {
+        self.field_address(index).write(value);
+    }
+
+    fn field_address(&self, index: usize) -> Address {
+        let count = self.field_count();
+        assert!(
+            index < count,
+            "Field {} is out of bounds for {} fields",
+            index,
+            count
+        );
+        self.0 + 1 + index
+    }
+
+    pub fn mark(&mut self) {
+        self.0.write(*self.0 | 1);
+    }
+
+    pub fn unmark(&mut self) {
+        self.0.write(*self.0 & !1);
+    }
+
+    pub fn is_marked(&self) -> bool {
+        *self.0 & 1 != 0
+    }
+}
+
+impl From<Address> for ManagedPtr {
+    fn from(address: Address) -> Self {
+        ManagedPtr(address)
+    }
+}
+
+impl From<ManagedPtr> for Address {
+    fn from(ptr: ManagedPtr) -> Address {
+        ptr.0
+    }
+}
+
+/// An allocated block, which borrows its heap, so the borrow checker
+/// prevents any use after the heap was dropped:
+/// ```compile_fail
+/// use managed_heap::managed::ManagedHeap;
+///
+/// let object = {
+///     let mut heap = ManagedHeap::new(100);
+///     heap.alloc_ref(2).unwrap()
+/// };
+/// object.get(0).unwrap();
+/// ```
+/// Code which manages the lifetime of its objects itself can turn a Ref into
+/// a plain Address with leak.
+#[derive(Debug)]
+pub struct Ref<'h> {
+    address: Address,
+    len: usize,
+    heap: PhantomData<&'h ManagedHeap>,
+}
+
+impl Ref<'_> {
+    /// The number of words in the block (without the header and the
+    /// checksum)
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, offset: usize) -> Result<usize, AccessError> {
+        Ok(*self.word(offset)?)
+    }
+
+    pub fn set(&mut self, offset: usize, value: usize) -> Result<(), AccessError> {
+        self.word(offset)?.write(value);
+        Ok(())
+    }
+
+    fn word(&self, offset: usize) -> Result<Address, AccessError> {
+        let len = self.len();
+        if offset < len {
+            Ok(self.address + offset)
+        } else {
+            Err(AccessError::IndexOutOfBounds { index: offset, len })
+        }
+    }
+
+    /// Gives up the borrow of the heap. The caller has to make sure, that
+    /// the address isn't used after the heap was dropped.
+    pub fn leak(self) -> Address {
+        self.address
+    }
+}
+
+/// A length prefixed array of words on a ManagedHeap.
+/// Layout: [mark word, length, elements...]
+/// Arrays created by new_traced treat their elements as addresses of T
+/// objects, which are kept alive by the garbage collector. Elements with the
+/// value 0 are ignored.
+pub struct HeapArray<T = Word>(Address, PhantomData<fn() -> T>);
+
+/// The elements of an array created by HeapArray::new, which are plain words
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Word(Address);
+
+unsafe impl Leaf for Word {
+    fn address(&self) -> Address {
+        self.0
+    }
+}
+
+impl From<Address> for Word {
+    fn from(address: Address) -> Self {
+        Word(address)
+    }
+}
+
+impl From<Word> for Address {
+    fn from(word: Word) -> Address {
+        word.0
+    }
+}
+
+impl HeapArray {
+    /// Allocates an array of len words, which are initialized with 0
+    pub fn new(heap: &mut ManagedHeap, len: usize) -> Option<Self> {
+        Self::with_flags(heap, len, 0)
+    }
+}
+
+impl<T> HeapArray<T> {
+    const HEADER_WORDS: usize = 2;
+    const TRACED_FLAG: usize = 1 << (usize::BITS - 1);
+
+    /// Allocates an array of len addresses of T objects, which are
+    /// initialized with 0
+    pub fn new_traced(heap: &mut ManagedHeap, len: usize) -> Option<Self> {
+        Self::with_flags(heap, len, Self::TRACED_FLAG)
+    }
+
+    fn with_flags(heap: &mut ManagedHeap, len: usize, flags: usize) -> Option<Self> {
+        if len & Self::TRACED_FLAG != 0 {
+            return None;
+        }
+
+        let size = len.checked_add(Self::HEADER_WORDS)?;
+        let mut address = heap.alloc(HalfWord::try_from(size).ok()?)?;
+
+        address.write(false as usize);
+        (address + 1).write(len | flags);
+        for i in 0..len {
+            (address + Self::HEADER_WORDS + i).write(0);
+        }
+
+        Some(HeapArray(address, PhantomData))
+    }
+
+    pub fn len(&self) -> usize {
+        *(self.0 + 1) & !Self::TRACED_FLAG
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Checks if the elements are traced by the garbage collector
+    pub fn is_traced(&self) -> bool {
+        *(self.0 + 1) & Self::TRACED_FLAG != 0
+    }
+
+    pub fn get(&self, index: usize) -> Result<usize, AccessError> {
+        Ok(*self.element(index)?)
+    }
+
+    pub fn set(&mut self, index: usize, value: usize) -> Result<(), AccessError> {
+        self.element(index)?.write(value);
+        Ok(())
+    }
+
+    fn element(&self, index: usize) -> Result<Address, AccessError> {
+        let len = self.len();
+        if index < len {
+            Ok(self.0 + Self::HEADER_WORDS + index)
+        } else {
+            Err(AccessError::IndexOutOfBounds { index, len })
+        }
+    }
+
+    fn children(&self) -> impl Iterator<Item = Address> {
+        let first = self.0 + Self::HEADER_WORDS;
+        let len = if self.is_traced() { self.len() } else { 0 };
+
+        (0..len)
+            .map(move |i| *(first + i))
+            .filter(|&a| a != 0)
+            .map(Address::from)
+    }
+}
+
+impl<T> Copy for HeapArray<T> {}
+
+impl<T> Clone for HeapArray<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> PartialEq for HeapArray<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<T> Eq for HeapArray<T> {}
+
+impl<T> fmt::Debug for HeapArray<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("HeapArray").field(&self.0).finish()
+    }
+}
+
+impl<T> From<Address> for HeapArray<T> {
+    fn from(address: Address) -> Self {
+        HeapArray(address, PhantomData)
+    }
+}
+
+impl<T> From<HeapArray<T>> for Address {
+    fn from(array: HeapArray<T>) -> Address {
+        array.0
+    }
+}
+
+unsafe impl<T> Traceable for HeapArray<T>
+where
+    T: Traceable + From<Address>,
+{
+    fn mark(&mut self) {
+        if self.is_marked() {
+            return;
+        }
+
+        self.0.write(true as usize);
+        for child in self.children() {
+            T::from(child).mark();
+        }
+    }
+
+    fn unmark(&mut self) {
+        self.0.write(false as usize);
+    }
+
+    fn trace<'a>(&'a mut self) -> Box<dyn Iterator<Item = &'a mut Address> + 'a> {
+        let len = if self.is_traced() { self.len() } else { 0 };
+        let first = self.0 + Self::HEADER_WORDS;
+
+        let elements = (0..len)
+            .map(move |i| unsafe { (first + i).as_address_mut() })
+            .filter(|a| usize::from(**a) != 0);
+        Box::new(elements)
+    }
+
+    fn is_marked(&self) -> bool {
+        *self.0 != 0
+    }
+}
+
+/// A hash map from words to objects on a ManagedHeap, which keeps its values
+/// alive while it is reachable. The values are marked as V.
+/// Layout: [mark word, capacity, length, (key, value)...]
+/// The pairs use linear probing and a value of 0 marks an empty slot.
+/// A map which has to grow moves to a new block, so insert updates the
+/// handle it is called on and all other copies of it become invalid.
+pub struct HeapMap<V>(Address, PhantomData<fn() -> V>);
+
+impl<V> HeapMap<V> {
+    const HEADER_WORDS: usize = 3;
+    const MIN_CAPACITY: usize = 8;
+
+    /// Allocates an empty map
+    pub fn new(heap: &mut ManagedHeap) -> Option<Self> {
+        Self::with_capacity(heap, Self::MIN_CAPACITY)
+    }
+
+    /// Allocates an empty map with at least capacity slots
+    pub fn with_capacity(heap: &mut ManagedHeap, capacity: usize) -> Option<Self> {
+        let capacity = capacity
+            .max(Self::MIN_CAPACITY)
+            .checked_next_power_of_two()?;
+        let size = Self::block_size(capacity).ok()?;
+        let mut address = heap.alloc(size)?;
+
+        address.write(false as usize);
+        (address + 1).write(capacity);
+        (address + 2).write(0);
+        for i in 0..2 * capacity {
+            (address + Self::HEADER_WORDS + i).write(0);
+        }
+
+        Some(HeapMap(address, PhantomData))
+    }
+
+    fn block_size(capacity: usize) -> Result<HalfWord, AllocError> {
+        capacity
+            .checked_mul(2)
+            .and_then(|words| words.checked_add(Self::HEADER_WORDS))
+            .and_then(|size| HalfWord::try_from(size).ok())
+            .ok_or(AllocError::TooLarge(HalfWord::MAX))
+    }
+
+    pub fn len(&self) -> usize {
+        *(self.0 + 2)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of slots, which grows once 3/4 of them are in use
+    pub fn capacity(&self) -> usize {
+        *(self.0 + 1)
+    }
+
+    pub fn get(&self, key: usize) -> Option<Address> {
+        self.find(key)
+            .map(|slot| *(self.slot(slot) + 1))
+            .map(Address::from)
+    }
+
+    /// Inserts value and returns the previous value of key.
+    /// If the map has to grow and there is not enough space, the map stays
+    /// unchanged.
+    /// Panics if value is null.
+    pub fn insert(
+        &mut self,
+        heap: &mut ManagedHeap,
+        key: usize,
+        value: Address,
+    ) -> Result<Option<Address>, AllocError> {
+        assert!(usize::from(value) != 0, "HeapMap values must not be null");
+
+        if let Some(slot) = self.find(key) {
+            let mut value_word = self.slot(slot) + 1;
+            let old = Address::from(*value_word);
+            value_word.write(value.into());
+            return Ok(Some(old));
+        }
+
+        if (self.len() + 1) * 4 > self.capacity() * 3 {
+            self.grow(heap)?;
+        }
+
+        self.insert_new(key, value);
+        Ok(None)
+    }
+
+    /// Removes key and returns its value
+    pub fn remove(&mut self, key: usize) -> Option<Address> {
+        let mut hole = self.find(key)?;
+        let value = Address::from(*(self.slot(hole) + 1));
+        self.clear_slot(hole);
+        (self.0 + 2).write(self.len() - 1);
+
+        // move the following pairs of the probe sequence into the hole, so
+        // that lookups don't stop early
+        let mask = self.capacity() - 1;
+        let mut slot = (hole + 1) & mask;
+        while let Some((key, value)) = self.pair(slot) {
+            let ideal = Self::hash(key) & mask;
+            if (slot.wrapping_sub(ideal) & mask) >= (slot.wrapping_sub(hole) & mask) {
+                self.write_pair(hole, key, value);
+                self.clear_slot(slot);
+                hole = slot;
+            }
+            slot = (slot + 1) & mask;
+        }
+
+        Some(value)
+    }
+
+    /// All keys and values in slot order
+    pub fn iter(&self) -> impl Iterator<Item = (usize, Address)> + '_ {
+        (0..self.capacity()).filter_map(move |slot| self.pair(slot))
+    }
+
+    /// Moves the pairs into a block with twice the capacity and frees the
+    /// old block
+    fn grow(&mut self, heap: &mut ManagedHeap) -> Result<(), AllocError> {
+        let capacity = self.capacity() * 2;
+        let size = Self::block_size(capacity)?;
+        let mut grown = Self::with_capacity(heap, capacity).ok_or(AllocError::OutOfMemory(size))?;
+
+        for (key, value) in self.iter() {
+            grown.insert_new(key, value);
+        }
+
+        heap.free(self.0);
+        self.0 = grown.0;
+        Ok(())
+    }
+
+    fn insert_new(&mut self, key: usize, value: Address) {
+        let mask = self.capacity() - 1;
+        let mut slot = Self::hash(key) & mask;
+        while self.pair(slot).is_some() {
+            slot = (slot + 1) & mask;
+        }
+
+        self.write_pair(slot, key, value);
+        (self.0 + 2).write(self.len() + 1);
+    }
+
+    fn find(&self, key: usize) -> Option<usize> {
+        let mask = self.capacity() - 1;
+        let mut slot = Self::hash(key) & mask;
+        while let Some((k, _)) = self.pair(slot) {
+            if k == key {
+                return Some(slot);
+            }
+            slot = (slot + 1) & mask;
+        }
+        None
+    }
+
+    fn hash(key: usize) -> usize {
+        // fibonacci hashing, the upper bits are the best mixed ones
+        let hash = key.wrapping_mul(0x9E37_79B9_7F4A_7C15_u64 as usize);
+        hash.rotate_left(usize::BITS / 2)
+    }
+
+    fn slot(&self, slot: usize) -> Address {
+        self.0 + Self::HEADER_WORDS + 2 * slot
+    }
+
+    fn pair(&self, slot: usize) -> Option<(usize, Address)> {
+        let address = self.slot(slot);
+        match *(address + 1) {
+            0 => None,
+            value => Some((*address, Address::from(value))),
+        }
+    }
+
+    fn write_pair(&mut self, slot: usize, key: usize, value: Address) {
+        let mut address = self.slot(slot);
+        address.write(key);
+        (address + 1).write(value.into());
+    }
+
+    fn clear_slot(&mut self, slot: usize) {
+        self.write_pair(slot, 0, Address::from(0));
+    }
+}
+
+impl<V> Copy for HeapMap<V> {}
+
+impl<V> Clone for HeapMap<V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<V> fmt::Debug for HeapMap<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<V> From<Address> for HeapMap<V> {
+    fn from(address: Address) -> Self {
+        HeapMap(address, PhantomData)
+    }
+}
+
+impl<V> From<HeapMap<V>> for Address {
+    fn from(map: HeapMap<V>) -> Address {
+        map.0
+    }
+}
+
+unsafe impl<V> Traceable for HeapMap<V>
+where
+    V: Traceable + From<Address>,
+{
+    fn mark(&mut self) {
+        if self.is_marked() {
+            return;
+        }
+
+        self.0.write(true as usize);
+        for (_, value) in self.iter() {
+            V::from(value).mark();
+        }
+    }
+
+    fn unmark(&mut self) {
+        self.0.write(false as usize);
+    }
+
+    fn trace<'a>(&'a mut self) -> Box<dyn Iterator<Item = &'a mut Address> + 'a> {
+        let first = self.0 + Self::HEADER_WORDS;
+        let values = (0..self.capacity())
+            .map(move |slot| unsafe { (first + 2 * slot + 1).as_address_mut() })
+            .filter(|a| usize::from(**a) != 0);
+        Box::new(values)
+    }
+
+    fn is_marked(&self) -> bool {
+        *self.0 != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod map {
+        use super::*;
+        use crate::testing::*;
+
+        fn leaf(heap: &mut ManagedHeap, value: usize) -> Address {
+            let mut array = HeapArray::new(heap, 1).unwrap();
+            array.set(0, value).unwrap();
+            array.0
+        }
+
+        #[test]
+        fn test_insert_get_and_remove() {
+            let mut heap = ManagedHeap::new(4000);
+            let mut map = HeapMap::<HeapArray>::new(&mut heap).unwrap();
+            let (a, b) = (leaf(&mut heap, 1), leaf(&mut heap, 2));
+
+            assert!(map.is_empty());
+            assert_eq!(Ok(None), map.insert(&mut heap, 0, a));
+            assert_eq!(Ok(None), map.insert(&mut heap, 42, b));
+            assert_eq!(Some(a), map.get(0));
+            assert_eq!(Some(b), map.get(42));
+            assert_eq!(None, map.get(7));
+            assert_eq!(2, map.len());
+
+            assert_eq!(Ok(Some(a)), map.insert(&mut heap, 0, b));
+            assert_eq!(Some(b), map.get(0));
+            assert_eq!(2, map.len());
+
+            assert_eq!(Some(b), map.remove(0));
+            assert_eq!(None, map.remove(0));
+            assert_eq!(None, map.get(0));
+            assert_eq!(Some(b), map.get(42));
+            assert_eq!(1, map.len());
+        }
+
+        #[test]
+        fn test_remove_keeps_colliding_keys_reachable() {
+            let mut heap = ManagedHeap::new(4000);
+            let mut map = HeapMap::<HeapArray>::new(&mut heap).unwrap();
+            let value = leaf(&mut heap, 1);
+
+            // the keys share the same slot
+            let mask = map.capacity() - 1;
+            let keys: Vec<usize> = (0..)
+                .filter(|&k| HeapMap::<HeapArray>::hash(k) & mask == 3)
+                .take(4)
+                .collect();
+            for &key in &keys {
+                map.insert(&mut heap, key, value).unwrap();
+            }
+
+            map.remove(keys[1]);
+            for &key in [keys[0], keys[2], keys[3]].iter() {
+                assert_eq!(Some(value), map.get(key));
+            }
+        }
+
+        #[test]
+        fn test_growth_preserves_entries() {
+            let mut heap = ManagedHeap::new(8000);
+            let mut map = HeapMap::<HeapArray>::new(&mut heap).unwrap();
+            let values: Vec<Address> = (0..20).map(|i| leaf(&mut heap, i)).collect();
+            let blocks = heap.num_used_blocks();
+
+            for (key, &value) in values.iter().enumerate() {
+                map.insert(&mut heap, key * 31, value).unwrap();
+            }
+
+            assert_eq!(20, map.len());
+            assert_eq!(32, map.capacity());
+            for (key, &value) in values.iter().enumerate() {
+                assert_eq!(Some(value), map.get(key * 31));
+            }
+            // the old blocks were freed
+            assert_eq!(blocks, heap.num_used_blocks());
+            assert_eq!(Ok(()), heap.verify());
+        }
+
+        #[test]
+        fn test_failed_growth_leaves_map_unchanged() {
+            let mut heap = ManagedHeap::new(30 * WORD_SIZE);
+            let mut map = HeapMap::<HeapArray>::new(&mut heap).unwrap();
+            let value = leaf(&mut heap, 1);
+
+            for key in 0..6 {
+                map.insert(&mut heap, key, value).unwrap();
+            }
+            let before = map;
+            assert!(map.insert(&mut heap, 6, value).is_err());
+
+            assert_eq!(before.0, map.0);
+            assert_eq!(6, map.len());
+            assert_eq!(Some(value), map.get(5));
+        }
+
+        #[test]
+        fn test_gc_keeps_values_while_map_is_rooted() {
+            let mut heap = ManagedHeap::new(4000);
+            let mut map = HeapMap::<HeapArray>::new(&mut heap).unwrap();
+            for key in 0..10 {
+                let value = leaf(&mut heap, key);
+                map.insert(&mut heap, key, value).unwrap();
+            }
+            let removed = map.remove(3).unwrap();
+
+            let mut root = MockGcRoot::new(vec![map]);
+            let mut roots: Vec<&mut dyn GcRoot<HeapMap<HeapArray>>> = vec![&mut root];
+            let stats = heap.gc(&mut roots[..]);
+
+            assert_eq!(1, stats.freed_blocks);
+            assert!(heap.block_of(removed).is_none());
+            for key in (0..10).filter(|&k| k != 3) {
+                let value: HeapArray = HeapArray::from(map.get(key).unwrap());
+                assert_eq!(Ok(key), value.get(0));
+            }
+
+            root.clear();
+            let mut roots: Vec<&mut dyn GcRoot<HeapMap<HeapArray>>> = vec![&mut root];
+            assert_eq!(10, heap.gc(&mut roots[..]).freed_blocks);
+            assert_eq!(0, heap.num_used_blocks());
+        }
+    }
+
+    mod alloc_ref {
+        use super::*;
+
+        #[test]
+        fn test_ref_accesses_the_memory_of_its_address() {
+            let mut heap = ManagedHeap::new(1000);
+            let mut object = heap.alloc_ref(3).unwrap();
+            assert_eq!(3, object.len());
+
+            object.set(0, 7).unwrap();
+            object.set(2, 42).unwrap();
+            assert_eq!(
+                Err(AccessError::IndexOutOfBounds { index: 3, len: 3 }),
+                object.set(3, 1)
+            );
+
+            let address = object.leak();
+            assert_eq!(7, *address);
+            assert_eq!(42, *(address + 2));
+            assert!(heap.block_of(address).is_some());
+        }
+
+        #[test]
+        fn test_ref_reads_raw_writes() {
+            let mut heap = ManagedHeap::new(1000);
+            let object = heap.alloc_ref(2).unwrap();
+            let mut address = Address::from(usize::from(object.address));
+
+            address.write(1);
+            (address + 1).write(2);
+            assert_eq!(Ok(1), object.get(0));
+            assert_eq!(Ok(2), object.get(1));
+            assert_eq!(
+                Err(AccessError::IndexOutOfBounds { index: 2, len: 2 }),
+                object.get(2)
+            );
+        }
+
+        #[test]
+        fn test_refs_exclude_the_checksum() {
+            crate::heap::tests::run_with_checksums(&[
+                test_ref_accesses_the_memory_of_its_address,
+                test_ref_reads_raw_writes,
+            ]);
+        }
+    }
+
+    mod managed_ptr {
+        use super::*;
+        use crate::testing::*;
+
+        #[derive(Copy, Clone)]
+        struct Pair(ManagedPtr);
+
+        impl From<Address> for Pair {
+            fn from(address: Address) -> Self {
+                Pair(ManagedPtr::from(address))
+            }
+        }
+
+        unsafe impl Traceable for Pair {
+            fn mark(&mut self) {
+                self.0.mark()
+            }
+
+            fn unmark(&mut self) {
+                self.0.unmark()
+            }
+
+            fn is_marked(&self) -> bool {
+                self.0.is_marked()
+            }
+        }
+
+        #[test]
+        fn test_fields_are_indexed_without_mark_word() {
+            let mut heap = ManagedHeap::new(1000);
+            let mut ptr = heap.alloc_managed(2).unwrap();
+
+            ptr.set_field(0, 17);
+            ptr.set_field(1, 42);
+
+            let address = Address::from(ptr);
+            // the unmarked mark word holds the number of fields
+            assert_eq!(2 << 1, *address);
+            assert_eq!(17, *(address + 1));
+            assert_eq!(42, *(address + 2));
+            assert_eq!(17, ptr.field(0));
+            assert_eq!(42, ptr.field(1));
+            assert_eq!(2, ptr.field_count());
+        }
+
+        #[test]
+        #[should_panic(expected = "Field 2 is out of bounds for 2 fields")]
+        fn test_field_out_of_bounds_panics() {
+            let mut heap = ManagedHeap::new(1000);
+            heap.alloc_managed(2).unwrap().field(2);
+        }
+
+        #[test]
+        fn test_gc_uses_the_mark_word() {
+            let mut heap = ManagedHeap::new(1000);
+            let mut pair = Pair(heap.alloc_managed(2).unwrap());
+            heap.alloc_managed(1).unwrap();
+
+            assert!(!pair.is_marked());
+            pair.mark();
+            assert!(pair.is_marked());
+            pair.unmark();
+
+            let mut root = MockGcRoot::new(vec![pair]);
+            let mut roots: Vec<&mut dyn GcRoot<Pair>> = vec![&mut root];
+            assert_eq!(1, heap.gc(&mut roots[..]).freed_blocks);
+            assert!(heap.block_of(Address::from(pair.0)).is_some());
+        }
+
+        #[test]
+        fn test_fields_exclude_the_checksum() {
+            crate::heap::tests::run_with_checksums(&[
+                test_fields_are_indexed_without_mark_word,
+                test_gc_uses_the_mark_word,
+            ]);
+        }
+
+        #[test]
+        fn test_slack_is_not_a_field() {
+            let mut heap = ManagedHeap::new(100 * WORD_SIZE);
+            let hole = heap.alloc(4).unwrap();
+            heap.alloc(2).unwrap();
+            heap.free(hole);
+
+            // the remainder of 2 words is too small to be split off
+            let ptr = heap.alloc_managed(1).unwrap();
+            assert_eq!(1, ptr.field_count());
+            assert_eq!(2, heap.trim(Address::from(ptr)));
+            assert_eq!(1, ptr.field_count());
+        }
+    }
+
+    mod array {
+        use super::*;
+        use crate::testing::*;
+
+        #[test]
+        fn test_get_and_set() {
+            let mut heap = ManagedHeap::new(1000);
+            let mut array = HeapArray::new(&mut heap, 3).unwrap();
+
+            assert_eq!(3, array.len());
+            assert!(!array.is_traced());
+            assert_eq!(Ok(0), array.get(2));
+            assert_eq!(Ok(()), array.set(2, 42));
+            assert_eq!(Ok(42), array.get(2));
+            assert_eq!(Ok(0), array.get(1));
+        }
+
+        #[test]
+        fn test_out_of_bounds_access_fails() {
+            let mut heap = ManagedHeap::new(1000);
+            let mut array = HeapArray::new(&mut heap, 3).unwrap();
+            let err = AccessError::IndexOutOfBounds { index: 3, len: 3 };
+
+            assert_eq!(Err(err), array.get(3));
+            assert_eq!(Err(err), array.set(3, 1));
+
+            let empty = HeapArray::new(&mut heap, 0).unwrap();
+            assert!(empty.is_empty());
+            assert!(empty.get(0).is_err());
+        }
+
+        #[test]
+        fn test_gc_keeps_elements_of_rooted_array() {
+            let mut heap = ManagedHeap::new(1000);
+            let mut array = HeapArray::<HeapArray>::new_traced(&mut heap, 3).unwrap();
+            let mut first = HeapArray::new(&mut heap, 1).unwrap();
+            let second = HeapArray::new(&mut heap, 2).unwrap();
+            first.set(0, 7).unwrap();
+            array.set(0, first.0.into()).unwrap();
+            array.set(2, second.0.into()).unwrap();
+
+            let unrooted = HeapArray::<HeapArray>::new_traced(&mut heap, 1).unwrap();
+            let mut unrooted_child = HeapArray::new(&mut heap, 1).unwrap();
+            unrooted_child.set(0, 1).unwrap();
+
+            let mut root = MockGcRoot::new(vec![array]);
+            let mut roots: Vec<&mut dyn GcRoot<HeapArray<HeapArray>>> = vec![&mut root];
+            let stats = heap.gc(&mut roots[..]);
+
+            // the value 1 in unrooted_child is not traced
+            assert_eq!(2, stats.freed_blocks);
+            assert_eq!(3, stats.live_blocks);
+            assert_eq!(Ok(7), first.get(0));
+            assert!(!heap.used_blocks().any(|b| Address::from(b) == unrooted.0));
+            assert_eq!(Ok(()), heap.verify());
+        }
+
+        #[test]
+        fn test_incremental_gc_traces_elements() {
+            let mut heap = ManagedHeap::new(1000);
+            let mut array = HeapArray::<HeapArray>::new_traced(&mut heap, 2).unwrap();
+            let child = HeapArray::new(&mut heap, 1).unwrap();
+            array.set(1, child.0.into()).unwrap();
+            HeapArray::new(&mut heap, 1).unwrap();
+
+            let mut root = MockGcRoot::new(vec![array]);
+            let mut roots: Vec<&mut dyn GcRoot<HeapArray<HeapArray>>> = vec![&mut root];
+            heap.gc_begin(&mut roots[..]);
+            let stats = heap.gc_finish();
+
+            assert_eq!(1, stats.freed_blocks);
+            assert_eq!(2, stats.live_blocks);
+        }
+
+        #[test]
+        fn test_gc_marks_elements_as_their_type() {
+            let mut heap = ManagedHeap::new(1000);
+            let mut array = HeapArray::<LinkedList>::new_traced(&mut heap, 2).unwrap();
+            let list = list![&mut heap; 1, 2, 3];
+            array.set(1, list.0.into()).unwrap();
+            list![&mut heap; 4];
+
+            let mut root = MockGcRoot::new(vec![array]);
+            let mut roots: Vec<&mut dyn GcRoot<HeapArray<LinkedList>>> = vec![&mut root];
+            let stats = heap.gc(&mut roots[..]);
+
+            assert_eq!(1, stats.freed_blocks);
+            assert_eq!(4, stats.live_blocks);
+            assert_eq!("[1, 2, 3]", format!("{:?}", list));
+        }
+    }
+
+    mod simple {
+        use super::*;
+        use crate::testing::*;
+        use crate::trace::Leaf;
+        use std::ops::Add;
+
+        #[test]
+        fn test_try_new_validates_size() {
+            assert_eq!(Some(NewHeapError::ZeroSize), ManagedHeap::try_new(0).err());
+            assert_eq!(
+                Some(NewHeapError::TooLarge(Heap::MAX_BYTES + 1)),
+                ManagedHeap::try_new(Heap::MAX_BYTES + 1).err()
+            );
+
+            let heap = ManagedHeap::try_new(8 * WORD_SIZE + 1).unwrap();
+            assert_eq!(9 * WORD_SIZE, heap.capacity_bytes());
+            assert_eq!(9, heap.total_size());
+        }
+
+        /// Allocates and frees blocks, so that there is a hole of 11 words
+        /// at offset 0 and a hole of 5 words at offset 14
+        fn alloc_with_holes(heap: &mut ManagedHeap) {
+            let a = heap.alloc(10).unwrap();
+            heap.alloc(2).unwrap();
+            let c = heap.alloc(4).unwrap();
+            heap.alloc(2).unwrap();
+            heap.free(a);
+            heap.free(c);
+        }
+
+        fn layout(heap: &ManagedHeap) -> Vec<(usize, usize, bool)> {
+            heap.blocks().map(|b| (b.offset, b.size, b.used)).collect()
+        }
+
+        #[test]
+        fn test_first_fit_uses_lowest_address() {
+            let mut heap = ManagedHeap::new(100 * WORD_SIZE);
+            assert_eq!(FitPolicy::FirstFitLowAddress, heap.fit_policy());
+            alloc_with_holes(&mut heap);
+
+            let a = heap.alloc(3).unwrap();
+            assert_eq!(0, heap.blocks().find(|b| b.address == a).unwrap().offset);
+
+            let expected = vec![
+                (0, 4, true),
+                (4, 7, false),
+                (11, 3, true),
+                (14, 5, false),
+                (19, 3, true),
+                (22, 78, false),
+            ];
+            assert_eq!(expected, layout(&heap));
+            assert_eq!(Ok(()), heap.verify());
+        }
+
+        #[test]
+        fn test_best_fit_uses_smallest_block() {
+            let mut heap = ManagedHeap::new(100 * WORD_SIZE);
+            heap.set_fit_policy(FitPolicy::BestFit);
+            alloc_with_holes(&mut heap);
+
+            // the hole is too small to be split, so the whole block is used
+            heap.alloc(3).unwrap();
+
+            let expected = vec![
+                (0, 11, false),
+                (11, 3, true),
+                (14, 5, true),
+                (19, 3, true),
+                (22, 78, false),
+            ];
+            assert_eq!(expected, layout(&heap));
+            assert_eq!(Ok(()), heap.verify());
+        }
+
+        /// Leaves two holes of 24 words in front of a wilderness of 12 words,
+        /// allocates two small objects and grows the heap by 32 words
+        fn alloc_small_and_grow(policy: FitPolicy) -> ManagedHeap {
+            let mut heap = ManagedHeap::builder()
+                .initial(64 * WORD_SIZE)
+                .max(96 * WORD_SIZE)
+                .fit_policy(policy)
+                .build()
+                .unwrap();
+
+            let a = heap.alloc(23).unwrap();
+            heap.alloc(1).unwrap();
+            let b = heap.alloc(23).unwrap();
+            heap.alloc(1).unwrap();
+            heap.free(a);
+            heap.free(b);
+            assert_eq!(12, heap.heap.wilderness().unwrap().size());
+
+            heap.alloc(5).unwrap();
+            heap.alloc(5).unwrap();
+            assert_eq!(32, heap.heap.grow(32));
+            heap
+        }
+
+        #[test]
+        fn test_preserve_wilderness_keeps_room_for_large_objects() {
+            // best fit used up the wilderness, because it was the smallest
+            // block, so the new words form a block of their own
+            let mut heap = alloc_small_and_grow(FitPolicy::BestFit);
+            assert_eq!(32, heap.heap.wilderness().unwrap().size());
+            assert_eq!(None, heap.heap.alloc(40));
+
+            let mut heap = alloc_small_and_grow(FitPolicy::PreserveWilderness);
+            let expected = vec![
+                (0, 6, true),
+                (6, 6, true),
+                (12, 12, false),
+                (24, 2, true),
+                (26, 24, false),
+                (50, 2, true),
+                (52, 44, false),
+            ];
+            assert_eq!(expected, layout(&heap));
+            assert!(heap.heap.alloc(40).is_some());
+            assert_eq!(3, heap.heap.wilderness().unwrap().size());
+            assert_eq!(Ok(()), heap.verify());
+        }
+
+        #[test]
+        fn test_preserve_wilderness_falls_back_to_wilderness() {
+            let mut heap = ManagedHeap::new(64 * WORD_SIZE);
+            heap.set_fit_policy(FitPolicy::PreserveWilderness);
+            heap.alloc(10).unwrap();
+            heap.alloc(10).unwrap();
+
+            assert_eq!(42, heap.heap.wilderness().unwrap().size());
+            assert_eq!(3, layout(&heap).len());
+            assert_eq!(Ok(()), heap.verify());
+        }
+
+        #[test]
+        fn test_integer_object_constructor() {
+            let mut heap = ManagedHeap::new(100);
+            let mut i = IntegerObject::new(&mut heap, -42);
+
+            assert_eq!(-42, i.get());
+            assert!(!i.is_marked());
+
+            i.mark();
+            assert!(i.is_marked());
+        }
+
+        #[test]
+        fn test_integer_gets_freed_when_not_marked() {
+            let mut heap = ManagedHeap::new(100);
+            let i = IntegerObject::new(&mut heap, 42);
+
+            let mut gc_root = MockGcRoot::new(vec![i]);
+            assert_eq!(1, heap.num_used_blocks());
+            assert_eq!(1, heap.num_free_blocks());
+
+            {
+                let mut roots: Vec<&mut dyn GcRoot<IntegerObject>> = vec![&mut gc_root];
+                heap.gc(&mut roots[..]);
+                assert_eq!(1, heap.num_used_blocks());
+                assert_eq!(1, heap.num_free_blocks());
+            }
+
+            {
+                let mut roots: Vec<&mut dyn GcRoot<IntegerObject>> = vec![&mut gc_root];
+                heap.gc(&mut roots[..]);
+                assert_eq!(1, heap.num_used_blocks());
+                assert_eq!(1, heap.num_free_blocks());
+            }
+
+            gc_root.clear();
+            let mut roots: Vec<&mut dyn GcRoot<IntegerObject>> = vec![&mut gc_root];
+            heap.gc(&mut roots[..]);
+            assert_eq!(0, heap.num_used_blocks());
+            assert_eq!(1, heap.num_free_blocks());
+        }
+
+        #[test]
+        fn test_alloc_above_threshold_uses_large_object_space() {
+            let mut heap = ManagedHeap::new(4096);
+            heap.set_large_object_threshold(100);
+
+            let small = heap.alloc(100).unwrap();
+            assert!(!heap.is_large_object(small));
+            assert_eq!(1, heap.num_used_blocks());
+            assert_eq!(0, heap.num_large_objects());
+
+            let stats_before = heap.stats();
+
+            let large = heap.alloc(101).unwrap();
+            assert!(heap.is_large_object(large));
+            assert_eq!(1, heap.num_large_objects());
+
+            // the main heap must not be touched by the large allocation
+            let stats = heap.stats();
+            assert_eq!(stats_before.used_words, stats.used_words);
+            assert_eq!(1, stats.used_blocks);
+            assert_eq!(1, stats.free_blocks);
+            assert_eq!(1, stats.large_objects);
+            assert_eq!(102 * WORD_SIZE, stats.large_object_bytes);
+
+            heap.free(large);
+            assert_eq!(0, heap.num_large_objects());
+            assert_eq!(0, heap.stats().large_object_bytes);
+
+            heap.free(small);
+            assert_eq!(0, heap.num_used_blocks());
+        }
+
+        #[test]
+        fn test_offsets_are_the_same_for_every_heap() {
+            let run = || {
+                let mut heap = ManagedHeap::new(100 * WORD_SIZE);
+                let a = heap.alloc(3).unwrap();
+                let b = heap.alloc(5).unwrap();
+                heap.free(a);
+                let c = heap.alloc(1).unwrap();
+
+                let offsets: Vec<_> = [a, b, c].iter().map(|&x| heap.offset_of(x)).collect();
+                let printed = format!("{:?} {}", heap.debug_address(b), heap.debug_address(c));
+                (offsets, printed)
+            };
+
+            assert_eq!(run(), run());
+            assert_eq!((vec![1, 5, 1], "heap+5 heap+1".to_owned()), run());
+        }
+
+        #[test]
+        fn test_address_at_is_inverse_of_offset_of() {
+            let mut heap = ManagedHeap::new(10 * WORD_SIZE);
+            let a = heap.alloc(2).unwrap();
+
+            assert_eq!(Some(a), heap.address_at(heap.offset_of(a)));
+            assert_eq!(Some(a + 1), heap.address_at(2));
+            assert!(heap.address_at(9).is_some());
+            assert_eq!(None, heap.address_at(10));
+
+            let outside = Address::from(8);
+            assert_eq!("0x8", format!("{:?}", heap.debug_address(outside)));
+        }
+
+        #[test]
+        fn test_trim_recovers_slack() {
+            let mut heap = ManagedHeap::new(100 * WORD_SIZE);
+            let hole = heap.alloc(3).unwrap();
+            heap.alloc(2).unwrap();
+            heap.free(hole);
+
+            // the remainder of 2 words is too small to be split off
+            let a = heap.alloc(1).unwrap();
+            assert_eq!(hole, a);
+            assert_eq!(2, heap.stats().slack_words);
+            assert_eq!(0, heap.trim(a + 1));
+
+            assert_eq!(2, heap.trim(a));
+            assert_eq!(0, heap.stats().slack_words);
+            assert_eq!(0, heap.trim(a));
+            assert_eq!(Ok(()), heap.verify());
+
+            let b = heap.alloc(1).unwrap();
+            let offsets: Vec<_> = heap.blocks().map(|b| (b.offset, b.size)).collect();
+            assert_eq!(a + 2, b);
+            assert_eq!(vec![(0, 2), (2, 2), (4, 3), (7, 93)], offsets);
+        }
+
+        #[test]
+        fn test_trim_moves_the_reference_count() {
+            let mut heap = ManagedHeap::new(100 * WORD_SIZE);
+            let hole = heap.alloc(4).unwrap();
+            heap.alloc(2).unwrap();
+            heap.free(hole);
+
+            // the count lives in the last word of the slack
+            let a = heap.rc_alloc(1).unwrap();
+            assert_eq!(hole, a);
+            assert_eq!(Ok(2), heap.rc_inc(a));
+            assert_eq!(2, heap.trim(a));
+            assert_eq!(Ok(2), heap.rc_count(a));
+
+            // the trimmed words are reused
+            let mut b = heap.alloc(1).unwrap();
+            assert_eq!(a + 3, b);
+            b.write(42);
+            assert_eq!(Ok(1), heap.rc_dec(a));
+            assert_eq!(Ok(()), heap.verify());
+        }
+
+        #[test]
+        fn test_free_forgets_slack() {
+            let mut heap = ManagedHeap::new(100 * WORD_SIZE);
+            let hole = heap.alloc(3).unwrap();
+            heap.alloc(2).unwrap();
+            heap.free(hole);
+
+            let a = heap.alloc(2).unwrap();
+            assert_eq!(1, heap.stats().slack_words);
+            // a single word can't be a block
+            assert_eq!(0, heap.trim(a));
+
+            heap.free(a);
+            assert_eq!(0, heap.stats().slack_words);
+        }
+
+        #[test]
+        fn test_scan_conservative_resolves_candidates() {
+            let mut heap = ManagedHeap::new(1000);
+            let real = IntegerObject::new(&mut heap, 1);
+            let interior = heap.alloc(4).unwrap();
+            let stale = IntegerObject::new(&mut heap, 3);
+            let _unreferenced = IntegerObject::new(&mut heap, 4);
+            let stale_address = stale.0;
+            heap.free(stale_address);
+
+            let words = [
+                usize::from(real.0),
+                usize::from(interior + 2),
+                42,
+                usize::from(stale_address),
+                usize::from(real.0 + 1),
+            ];
+
+            let mut expected = vec![real.0, interior];
+            expected.sort();
+            assert_eq!(expected, heap.scan_conservative(&words));
+
+            let mut root: MockGcRoot<IntegerObject> = MockGcRoot::new(vec![]);
+            let mut roots: Vec<&mut dyn GcRoot<IntegerObject>> = vec![&mut root];
+            let stats = heap.gc_conservative(&mut roots[..], &words);
+
+            // only the unreferenced object gets freed
+            assert_eq!(1, stats.freed_blocks);
+            assert_eq!(2, stats.live_blocks);
+            assert_eq!(1, real.get());
+            assert_eq!(Ok(()), heap.verify());
+        }
+
+        #[test]
+        fn test_gc_reclaims_unreachable_large_object() {
+            let mut heap = ManagedHeap::new(100);
+
+            let mut address = heap
+                .alloc(ManagedHeap::DEFAULT_LARGE_OBJECT_THRESHOLD + 1)
+                .unwrap();
+            address.write(false as usize);
+            let large = IntegerObject(address);
+            let small = IntegerObject::new(&mut heap, 1);
+
+            assert!(heap.is_large_object(address));
+
+            let mut gc_root = MockGcRoot::new(vec![large, small]);
+            {
+                let mut roots: Vec<&mut dyn GcRoot<IntegerObject>> = vec![&mut gc_root];
+                let stats = heap.gc(&mut roots[..]);
+                assert_eq!(0, stats.freed_blocks);
+                assert_eq!(2, stats.live_blocks);
+                assert_eq!(1, heap.num_large_objects());
+            }
+
+            gc_root.used_elems.remove(0);
+            let mut roots: Vec<&mut dyn GcRoot<IntegerObject>> = vec![&mut gc_root];
+            let stats = heap.gc(&mut roots[..]);
+            assert_eq!(1, stats.freed_blocks);
+            assert_eq!(
+                ManagedHeap::DEFAULT_LARGE_OBJECT_THRESHOLD as usize + 2,
+                stats.freed_words
+            );
+            assert_eq!(1, stats.live_blocks);
+            assert_eq!(0, heap.num_large_objects());
+            assert_eq!(1, heap.num_used_blocks());
+        }
+
+        #[test]
+        fn test_copy_words_between_allocations() {
+            let mut heap = ManagedHeap::new(256);
+            let src = heap.alloc(3).unwrap();
+            let dst = heap.alloc(4).unwrap();
+
+            for i in 0..3 {
+                src.add(i).write(i + 10);
+            }
+
+            heap.copy_words(src, dst.add(1), 3).unwrap();
+
+            for i in 0..3 {
+                assert_eq!(i + 10, *dst.add(i + 1));
+            }
+        }
+
+        #[test]
+        fn test_copy_words_overlapping_inside_block() {
+            let mut heap = ManagedHeap::new(256);
+            let address = heap.alloc(5).unwrap();
+
+            for i in 0..5 {
+                address.add(i).write(i);
+            }
+
+            heap.copy_words(address, address.add(1), 4).unwrap();
+            let values: Vec<usize> = (0..5).map(|i| *address.add(i)).collect();
+            assert_eq!(vec![0, 0, 1, 2, 3], values);
+
+            heap.copy_words(address.add(1), address, 4).unwrap();
+            let values: Vec<usize> = (0..5).map(|i| *address.add(i)).collect();
+            assert_eq!(vec![0, 1, 2, 3, 3], values);
+        }
+
+        #[test]
+        fn test_copy_words_fails_if_destination_exceeds_block() {
+            let mut heap = ManagedHeap::new(256);
+            let src = heap.alloc(4).unwrap();
+            let dst = heap.alloc(2).unwrap();
+            let _after = heap.alloc(2).unwrap();
+
+            assert_eq!(
+                Err(AccessError::OutOfBounds {
+                    address: dst,
+                    words: 3
+                }),
+                heap.copy_words(src, dst, 3)
+            );
+            assert_eq!(
+                Err(AccessError::OutOfBounds {
+                    address: dst.add(1),
+                    words: 2
+                }),
+                heap.copy_words(src, dst.add(1), 2)
+            );
+            assert_eq!(Ok(()), heap.copy_words(src, dst, 2));
+        }
+
+        #[test]
+        fn test_copy_words_fails_for_unallocated_address() {
+            let mut heap = ManagedHeap::new(256);
+            let src = heap.alloc(4).unwrap();
+            let dst = heap.alloc(4).unwrap();
+            heap.free(dst);
+
+            assert_eq!(
+                Err(AccessError::Unallocated(dst)),
+                heap.copy_words(src, dst, 1)
+            );
+        }
+
+        #[test]
+        fn test_payload_range_spans_the_payload() {
+            let mut heap = ManagedHeap::new(256);
+            let address = heap.alloc(4).unwrap();
+            heap.alloc(1).unwrap();
+
+            let mut payload = heap.payload_range(address).unwrap();
+            assert_eq!(address.range(4), payload);
+            payload.fill(3);
+            assert_eq!(Ok(vec![3; 4]), heap.read_to_vec(address, 4));
+            assert_eq!(None, heap.payload_range(address + 1));
+
+            assert_eq!(Ok(address.range(4)), heap.checked_range(address, 4));
+            assert_eq!(
+                Err(AccessError::OutOfBounds {
+                    address: address + 1,
+                    words: 4
+                }),
+                heap.checked_range(address + 1, 4)
+            );
+        }
+
+        #[test]
+        fn test_payload_slices() {
+            let mut heap = ManagedHeap::new(100 * WORD_SIZE);
+            let hole = heap.alloc(3).unwrap();
+            heap.alloc(2).unwrap();
+            heap.free(hole);
+
+            let address = heap.alloc(4).unwrap();
+            assert_eq!(Some(4), heap.payload(address).map(<[usize]>::len));
+
+            // the slice includes the slack
+            let small = heap.alloc(1).unwrap();
+            assert_eq!(hole, small);
+            assert_eq!(Some(3), heap.payload(small).map(<[usize]>::len));
+
+            let words = heap.payload_mut(address).unwrap();
+            words.copy_from_slice(&[1, 2, 3, 4]);
+            assert_eq!(3, *(address + 2));
+            assert_eq!(Some(&[1, 2, 3, 4][..]), heap.payload(address));
+
+            assert_eq!(None, heap.payload(address + 1));
+            heap.free(address);
+            assert_eq!(None, heap.payload_mut(address));
+        }
+
+        #[test]
+        fn test_alloc_from_slice_round_trips_through_read_to_vec() {
+            let mut heap = ManagedHeap::new(256);
+            let words = [1, 2, 3, usize::MAX];
+            let address = heap.alloc_from_slice(&words).unwrap();
+
+            assert_eq!(4, Block::from(address).size() - 1);
+            assert_eq!(Ok(words.to_vec()), heap.read_to_vec(address, 4));
+            assert_eq!(Ok(vec![2, 3]), heap.read_to_vec(address + 1, 2));
+            assert_eq!(
+                Err(AccessError::OutOfBounds { address, words: 5 }),
+                heap.read_to_vec(address, 5)
+            );
+        }
+
+        #[test]
+        fn test_alloc_from_iter_zero_fills_short_iterator() {
+            let mut heap = ManagedHeap::new(256);
+            let dirty = heap.alloc_from_slice(&[7; 5]).unwrap();
+            heap.free(dirty);
+
+            let address = heap.alloc_from_iter(5, 1..3).unwrap();
+            assert_eq!(Ok(vec![1, 2, 0, 0, 0]), heap.read_to_vec(address, 5));
+        }
+
+        #[test]
+        fn test_alloc_from_iter_takes_only_len_values() {
+            let mut heap = ManagedHeap::new(256);
+            let mut values = 1..10;
+
+            let address = heap.alloc_from_iter(3, &mut values).unwrap();
+            assert_eq!(Ok(vec![1, 2, 3]), heap.read_to_vec(address, 3));
+            assert_eq!(Some(4), values.next());
+        }
+
+        #[test]
+        fn test_alloc_from_empty_slice() {
+            let mut heap = ManagedHeap::new(256);
+            let address = heap.alloc_from_slice(&[]).unwrap();
+
+            let block = Block::from(address);
+            assert!(block.is_used());
+            assert_eq!(1, block.size());
+            assert_eq!(Ok(vec![]), heap.read_to_vec(address, 0));
+        }
+
+        #[test]
+        fn test_checked_bytes_stay_inside_the_payload() {
+            let mut heap = ManagedHeap::new(256);
+            let address = heap.alloc(2).unwrap();
+            let _after = heap.alloc(2).unwrap();
+            let last = 2 * WORD_SIZE - 1;
+
+            assert_eq!(Ok(()), heap.write_bytes(address, last - 2, &[1, 2, 3]));
+            let mut buffer = [0; 4];
+            assert_eq!(Ok(()), heap.read_bytes(address, last - 3, &mut buffer));
+            assert_eq!([0, 1, 2, 3], buffer);
+            assert_eq!(0x0302, address.read_u16(last - 1));
+
+            assert_eq!(
+                Err(AccessError::OutOfBounds { address, words: 3 }),
+                heap.write_bytes(address, last, &[1, 2])
+            );
+            assert_eq!(
+                Err(AccessError::OutOfBounds { address, words: 3 }),
+                heap.read_bytes(address, last + 1, &mut buffer[..1])
+            );
+            assert_eq!(Ok(()), heap.read_bytes(address, last + 1, &mut []));
+            assert_eq!(3, address.read_u8(last));
+        }
+
+        #[test]
+        fn test_multiple_of_rounding_reuses_blocks_of_the_same_class() {
+            let mut heap = ManagedHeap::new(1000);
+            heap.set_size_rounding(SizeRounding::MultipleOf(4));
+
+            let rounded = heap.alloc(7).unwrap();
+            let after = heap.alloc(1).unwrap();
+            assert_eq!(Some(8), heap.capacity_of(rounded));
+            assert_eq!(Some(4), heap.capacity_of(after));
+            assert_eq!(None, heap.capacity_of(rounded + 1));
+            assert_eq!(4, heap.stats().rounding_words);
+            assert_eq!(0, heap.stats().slack_words);
+
+            // 5 words fall into the same class, so the block fits exactly
+            heap.free(rounded);
+            assert_eq!(3, heap.stats().rounding_words);
+            let reused = heap.alloc(5).unwrap();
+            assert_eq!(rounded, reused);
+            assert_eq!(Some(8), heap.capacity_of(reused));
+            assert_eq!(6, heap.stats().rounding_words);
+            assert_eq!(Ok(()), heap.verify());
+        }
+
+        #[test]
+        fn test_power_of_two_rounding() {
+            let mut heap = ManagedHeap::new(1000);
+            heap.set_size_rounding(SizeRounding::PowerOfTwo);
+
+            let addresses: Vec<_> = [1, 3, 5, 16]
+                .iter()
+                .map(|&size| heap.alloc(size).unwrap())
+                .collect();
+            let capacities: Vec<_> = addresses
+                .iter()
+                .map(|&a| heap.capacity_of(a).unwrap())
+                .collect();
+            assert_eq!(vec![1, 4, 8, 16], capacities);
+            assert_eq!(4, heap.stats().rounding_words);
+        }
+
+        #[test]
+        fn test_no_rounding_keeps_the_requested_sizes() {
+            let mut plain = ManagedHeap::new(1000);
+            let mut unrounded = ManagedHeap::new(1000);
+            unrounded.set_size_rounding(SizeRounding::MultipleOf(4));
+            unrounded.set_size_rounding(SizeRounding::None);
+
+            for heap in [&mut plain, &mut unrounded] {
+                for size in [7, 9, 11, 13] {
+                    let address = heap.alloc(size).unwrap();
+                    assert_eq!(Some(size), heap.capacity_of(address));
+                }
+            }
+
+            assert_eq!(0, unrounded.stats().rounding_words);
+            assert!(plain.diff_ignoring(&unrounded, |_, _| true).is_equal());
+        }
+
+        #[test]
+        #[should_panic(expected = "multiple of 0")]
+        fn test_rounding_to_multiple_of_zero_panics() {
+            let mut heap = ManagedHeap::new(1000);
+            heap.set_size_rounding(SizeRounding::MultipleOf(0));
+        }
+
+        #[test]
+        fn test_event_log_records_operations_in_order() {
+            let mut heap = ManagedHeap::new(1000);
+            heap.alloc(1).unwrap();
+            assert_eq!(0, heap.event_log().count());
+
+            heap.enable_event_log(100);
+            let a = heap.alloc(2).unwrap();
+            let b = heap.alloc(3).unwrap();
+            heap.free(a);
+            heap.free(b);
+            heap.gc_addresses(&[], |_, _| {});
+
+            let events: Vec<_> = heap.event_log().collect();
+            assert_eq!(
+                vec![
+                    HeapEvent::Split {
+                        offset: 2,
+                        size: 3,
+                        rest: 120
+                    },
+                    HeapEvent::Alloc { offset: 2, size: 3 },
+                    HeapEvent::Split {
+                        offset: 5,
+                        size: 4,
+                        rest: 116
+                    },
+                    HeapEvent::Alloc { offset: 5, size: 4 },
+                    HeapEvent::Free { offset: 2, size: 3 },
+                    HeapEvent::Free { offset: 5, size: 4 },
+                    HeapEvent::Coalesce {
+                        offset: 2,
+                        size: 123
+                    },
+                    HeapEvent::GcStart { epoch: 1 },
+                    HeapEvent::Free { offset: 0, size: 2 },
+                    HeapEvent::Coalesce {
+                        offset: 0,
+                        size: 125
+                    },
+                    HeapEvent::GcEnd {
+                        epoch: 1,
+                        freed_blocks: 1
+                    },
+                ],
+                events
+            );
+
+            let mut dump = Vec::new();
+            heap.dump_event_log_to(&mut dump).unwrap();
+            let dump = String::from_utf8(dump).unwrap();
+            assert_eq!(events.len(), dump.lines().count());
+            assert_eq!(Some("split 2 size 3 rest 120"), dump.lines().next());
+            assert_eq!(Some("gc end epoch 1 freed 1"), dump.lines().last());
+
+            heap.enable_event_log(0);
+            heap.alloc(1).unwrap();
+            assert_eq!(0, heap.event_log().count());
+        }
+
+        #[test]
+        fn test_event_log_keeps_the_last_events() {
+            let mut heap = ManagedHeap::new(1000);
+            heap.enable_event_log(2);
+            for _ in 0..3 {
+                heap.alloc(1).unwrap();
+            }
+
+            let events: Vec<_> = heap.event_log().collect();
+            assert_eq!(
+                vec![
+                    HeapEvent::Split {
+                        offset: 4,
+                        size: 2,
+                        rest: 119
+                    },
+                    HeapEvent::Alloc { offset: 4, size: 2 },
+                ],
+                events
+            );
+        }
+
+        #[test]
+        fn test_event_log_is_readable_after_corruption() {
+            let mut heap = ManagedHeap::new(1000);
+            heap.enable_event_log(10);
+            heap.alloc(4).unwrap();
+            let second = heap.alloc(4).unwrap();
+
+            Block::from(second).set_pred_size(3);
+            assert!(heap.verify().is_err());
+            assert_eq!(
+                Some(HeapEvent::Alloc { offset: 5, size: 5 }),
+                heap.event_log().last()
+            );
+        }
+
+        #[test]
+        fn test_duplicate_copies_payload() {
+            let mut heap = ManagedHeap::new(256);
+            let original = heap.alloc(3).unwrap();
+            for i in 0..3 {
+                original.add(i).write(i + 10);
+            }
+            Block::from(original).set_marked(true);
+
+            let copy = heap.duplicate(original).unwrap();
+            assert_ne!(original, copy);
+            assert!(!Block::from(copy).is_marked());
+            assert_eq!(Block::from(original).size(), Block::from(copy).size());
+
+            copy.add(1).write(42);
+            let values: Vec<usize> = (0..3).map(|i| *original.add(i)).collect();
+            assert_eq!(vec![10, 11, 12], values);
+            let values: Vec<usize> = (0..3).map(|i| *copy.add(i)).collect();
+            assert_eq!(vec![10, 42, 12], values);
+
+            assert_eq!(None, heap.duplicate(original + 1));
+        }
+
+        #[test]
+        fn test_alloc_exact_or_more_grants_slack() {
+            let mut heap = ManagedHeap::new(100 * WORD_SIZE);
+            let hole = heap.alloc(3).unwrap();
+            heap.alloc(2).unwrap();
+            heap.free(hole);
+
+            // the remainder of 2 words is too small to be split off
+            let a = heap.alloc_exact_or_more(1).unwrap();
+            assert_eq!(hole, *a);
+            assert_eq!(3, a.capacity);
+            assert_eq!(0, heap.stats().slack_words);
+            assert_eq!(0, heap.trim(*a));
+
+            assert_eq!(Ok(()), heap.write(*a, 2, 42));
+            let copy = heap.duplicate(a.into()).unwrap();
+            assert_eq!(Ok(42), heap.read(copy, 2));
+
+            heap.free(a.into());
+            heap.free(copy);
+            let blocks: Vec<_> = heap.blocks().map(|b| (b.offset, b.size, b.used)).collect();
+            assert_eq!(vec![(0, 4, false), (4, 3, true), (7, 93, false)], blocks);
+            assert_eq!(Ok(()), heap.verify());
+
+            let b = heap.alloc_exact_or_more(5).unwrap();
+            assert_eq!(5, b.capacity);
+        }
+
+        #[test]
+        fn test_duplicate_ignores_slack() {
+            let mut heap = ManagedHeap::new(100 * WORD_SIZE);
+            let hole = heap.alloc(3).unwrap();
+            heap.alloc(2).unwrap();
+            heap.free(hole);
+
+            // the block of a has 2 words of slack
+            let a = heap.alloc(1).unwrap();
+            let copy = heap.duplicate(a).unwrap();
+            assert_eq!(2, Block::from(copy).size());
+            assert_eq!(Ok(()), heap.verify());
+        }
+
+        #[test]
+        fn test_hot_size_reuses_most_recently_freed_block() {
+            let mut heap = ManagedHeap::new(1000);
+            heap.set_hot_size(3, 4);
+            let x = heap.alloc(3).unwrap();
+            let a = heap.alloc(3).unwrap();
+            heap.alloc(1).unwrap();
+            let free_blocks = heap.num_free_blocks();
+
+            heap.free(x);
+            heap.free(a);
+            assert_eq!(2, heap.num_hot_cached_blocks());
+            assert_eq!(free_blocks, heap.num_free_blocks());
+
+            // first fit would hand out x
+            for _ in 0..10 {
+                let b = heap.alloc(3).unwrap();
+                assert_eq!(a, b);
+                heap.free(b);
+            }
+            assert_eq!(Ok(()), heap.verify());
+        }
+
+        #[test]
+        fn test_hot_cache_is_bounded() {
+            let mut heap = ManagedHeap::new(1000);
+            heap.set_hot_size(3, 2);
+            let hot: Vec<_> = (0..4)
+                .map(|_| {
+                    let address = heap.alloc(3).unwrap();
+                    heap.alloc(1).unwrap();
+                    address
+                })
+                .collect();
+            let free_blocks = heap.num_free_blocks();
+
+            for &address in hot.iter() {
+                heap.free(address);
+            }
+            assert_eq!(2, heap.num_hot_cached_blocks());
+            assert_eq!(free_blocks + 2, heap.num_free_blocks());
+            assert_eq!(Ok(()), heap.verify());
+
+            // other sizes are not cached
+            let other = heap.alloc(2).unwrap();
+            heap.free(other);
+            assert_eq!(2, heap.num_hot_cached_blocks());
+        }
+
+        #[test]
+        fn test_flushing_hot_cache_restores_coalescing() {
+            fn fill(heap: &mut ManagedHeap) {
+                // 25 blocks of 4 words fill the heap
+                let cells: Vec<_> = (0..25).map(|_| heap.alloc(3).unwrap()).collect();
+                for cell in cells {
+                    heap.free(cell);
+                }
+                assert_eq!(25, heap.num_hot_cached_blocks());
+                assert_eq!(0, heap.num_free_blocks());
+            }
+
+            let mut heap = ManagedHeap::new(100 * WORD_SIZE);
+            heap.set_hot_size(3, 100);
+
+            fill(&mut heap);
+            heap.flush_hot_cache();
+            assert_eq!(1, heap.num_free_blocks());
+            assert_eq!(Ok(()), heap.verify());
+
+            // a failing allocation flushes the cache itself
+            fill(&mut heap);
+            let large = heap.alloc(90).unwrap();
+            assert_eq!(0, heap.num_hot_cached_blocks());
+            heap.free(large);
+
+            // a collection flushes the cache before the sweep refills it
+            fill(&mut heap);
+            heap.gc_addresses(&[], |_, _| {});
+            assert_eq!(0, heap.num_hot_cached_blocks());
+            assert_eq!(1, heap.num_free_blocks());
+            assert_eq!(Ok(()), heap.verify());
+        }
+
+        #[test]
+        fn test_deferred_frees_match_eager_frees() {
+            let mut state: u64 = 0xdefe;
+            let mut next = move |bound: u64| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state % bound
+            };
+            let sizes: Vec<HalfWord> = (0..1200).map(|_| next(12) as HalfWord + 1).collect();
+            let keep: Vec<bool> = (0..1200).map(|_| next(10) == 0).collect();
+            let garbage_count = keep.iter().filter(|&&k| !k).count();
+            assert!(garbage_count >= 1000);
+
+            let run = |deferred: bool| {
+                let mut heap = ManagedHeap::new(20000 * WORD_SIZE);
+                let objects: Vec<_> = sizes.iter().map(|&s| heap.alloc(s).unwrap()).collect();
+                let garbage = objects.iter().zip(keep.iter()).filter(|(_, &k)| !k);
+                for (&object, _) in garbage {
+                    if deferred {
+                        heap.free_deferred(object);
+                    } else {
+                        heap.free(object);
+                    }
+                }
+                if deferred {
+                    assert_eq!(garbage_count, heap.num_deferred_frees());
+                    assert_eq!(Ok(()), heap.verify());
+                    assert_eq!(heap.num_deferred_frees(), heap.flush_deferred_frees());
+                }
+                assert_eq!(0, heap.num_deferred_frees());
+                assert_eq!(Ok(()), heap.verify());
+                heap.blocks()
+                    .map(|b| (b.offset, b.size, b.used))
+                    .collect::<Vec<_>>()
+            };
+
+            assert_eq!(run(false), run(true));
+        }
+
+        #[test]
+        fn test_alloc_reuses_deferred_blocks_only_when_full() {
+            let mut heap = ManagedHeap::new(20 * WORD_SIZE);
+            let a = heap.alloc(4).unwrap();
+            let b = heap.alloc(4).unwrap();
+            heap.free_deferred(a);
+            heap.free_deferred(b);
+            assert_eq!(0, heap.used_size());
+
+            // the deferred blocks are not free yet
+            let c = heap.alloc(4).unwrap();
+            assert!(c != a && c != b);
+            assert_eq!(2, heap.num_deferred_frees());
+
+            // but a failing allocation flushes them
+            let d = heap.alloc(8).unwrap();
+            assert_eq!(a, d);
+            assert_eq!(0, heap.num_deferred_frees());
+            assert_eq!(Ok(()), heap.verify());
+
+            heap.free_deferred(c);
+            heap.gc_addresses(&[d], |_, _| {});
+            assert_eq!(0, heap.num_deferred_frees());
+            assert_eq!(2, heap.num_free_blocks() + heap.num_used_blocks());
+        }
+
+        #[test]
+        fn test_deferred_blocks_are_not_reported_as_used() {
+            let mut heap = ManagedHeap::new(20 * WORD_SIZE);
+            let a = heap.alloc(4).unwrap();
+            heap.alloc(4).unwrap();
+            heap.free_deferred(a);
+
+            let used: Vec<_> = heap.blocks().map(|b| (b.offset, b.used)).collect();
+            assert_eq!(vec![(0, false), (5, true), (10, false)], used);
+            let regions = heap.fragmentation_map(1);
+            assert_eq!(1, regions[0].used_blocks);
+            assert_eq!(5, regions[0].used_words);
+            assert_eq!(15, regions[0].free_words);
+            assert_eq!(Ok(()), heap.verify());
+        }
+
+        #[test]
+        fn test_duplicate_without_space_fails() {
+            let mut heap = ManagedHeap::new(10 * WORD_SIZE);
+            let a = heap.alloc(5).unwrap();
+            let before = heap.stats();
+
+            assert_eq!(None, heap.duplicate(a));
+            assert_eq!(before, heap.stats());
+            assert_eq!(Ok(()), heap.verify());
+        }
+
+        #[test]
+        fn test_leaf_with_mark_offset_uses_that_word() {
+            struct Pair(Address);
+
+            unsafe impl Leaf for Pair {
+                const MARK_OFFSET: usize = 1;
+
+                fn address(&self) -> Address {
+                    self.0
+                }
+            }
+
+            let mut heap = ManagedHeap::new(100);
+            let mut address = heap.alloc(2).unwrap();
+            address.write(42);
+            address.add(1).write(false as usize);
+
+            let mut pair = Pair(address);
+            assert!(!pair.is_marked());
+
+            pair.mark();
+            assert!(pair.is_marked());
+            assert_eq!(42, *address);
+
+            pair.unmark();
+            assert!(!pair.is_marked());
+            assert_eq!(42, *address);
+        }
+
+        fn fragmented_heap() -> ManagedHeap {
+            // [a: 4, b: 3, c: 5, free: 8] with b freed afterwards
+            let mut heap = ManagedHeap::new(20 * WORD_SIZE);
+            heap.alloc(3).unwrap();
+            let b = heap.alloc(2).unwrap();
+            heap.alloc(4).unwrap();
+            heap.free(b);
+            heap
+        }
+
+        #[test]
+        fn test_free_list_summary() {
+            let summary = fragmented_heap().free_list_summary();
+
+            let blocks = vec![
+                FreeBlockInfo { offset: 4, size: 3 },
+                FreeBlockInfo {
+                    offset: 12,
+                    size: 8,
+                },
+            ];
+            assert_eq!(blocks, summary.blocks);
+            assert_eq!(2, summary.count);
+            assert_eq!(11, summary.total_words);
+            assert_eq!(8, summary.largest);
+            assert_eq!(3, summary.smallest);
+            assert_eq!(5.5, summary.mean);
+        }
+
+        #[test]
+        fn test_explain_alloc_failure_of_fragmented_heap() {
+            let mut heap = fragmented_heap();
+            assert_eq!(None, heap.explain_alloc_failure(7));
+
+            assert!(heap.alloc(9).is_none());
+            let reason = AllocFailureReason::Fragmentation {
+                needed: 10,
+                largest: 8,
+                total: 11,
+            };
+            assert_eq!(Some(reason), heap.explain_alloc_failure(9));
+        }
+
+        #[test]
+        fn test_explain_alloc_failure_of_full_heap() {
+            let mut heap = fragmented_heap();
+
+            assert!(heap.alloc(11).is_none());
+            let reason = AllocFailureReason::InsufficientSpace {
+                needed: 12,
+                total: 11,
+            };
+            assert_eq!(Some(reason), heap.explain_alloc_failure(11));
+        }
+
+        #[test]
+        fn test_explain_alloc_failure_counts_rounding_and_checksum() {
+            let mut heap = ManagedHeap::new(12 * WORD_SIZE);
+            heap.set_header_checksums(true);
+            heap.set_size_rounding(SizeRounding::MultipleOf(4));
+            assert_eq!(None, heap.explain_alloc_failure(5));
+
+            // 9 words are rounded up to 12 and need a header and a checksum
+            assert!(heap.alloc(9).is_none());
+            let reason = AllocFailureReason::InsufficientSpace {
+                needed: 14,
+                total: 12,
+            };
+            assert_eq!(Some(reason), heap.explain_alloc_failure(9));
+        }
 
-        struct MockGcRoot {
-            used_elems: Vec<IntegerObject>,
+        #[test]
+        fn test_explain_alloc_failure_of_unrepresentable_size() {
+            let heap = fragmented_heap();
+            let reason = AllocFailureReason::TooLarge {
+                needed: BlockHeader::MAX_SIZE as usize + 1,
+                max: BlockHeader::MAX_SIZE as usize,
+            };
+            assert_eq!(
+                Some(reason),
+                heap.explain_alloc_failure(BlockHeader::MAX_SIZE)
+            );
         }
+    }
 
-        impl MockGcRoot {
-            pub fn new(used_elems: Vec<IntegerObject>) -> Self {
-                MockGcRoot { used_elems }
-            }
+    mod complex {
+        use super::*;
+        use crate::testing::*;
+        use std::cell::Cell;
+        use std::thread;
+        use std::time::Duration;
 
-            pub fn clear(&mut self) {
-                self.used_elems.clear();
+        /// A list node, which is slow to trace if its value is 2
+        #[derive(Copy, Clone)]
+        struct SlowNode(LinkedList);
+
+        impl From<Address> for SlowNode {
+            fn from(address: Address) -> Self {
+                SlowNode(LinkedList(address))
             }
         }
 
-        unsafe impl GcRoot<IntegerObject> for MockGcRoot {
-            fn children<'a>(&'a mut self) -> Box<Iterator<Item = &'a mut IntegerObject> + 'a> {
-                Box::new(self.used_elems.iter_mut())
+        impl From<SlowNode> for Address {
+            fn from(node: SlowNode) -> Self {
+                node.0 .0
             }
         }
 
-        #[derive(Debug)]
-        struct IntegerObject(Address);
-
-        impl IntegerObject {
-            pub fn new(heap: &mut ManagedHeap, value: isize) -> Self {
-                // reserve one usize for mark byte
-                let mut address = heap.alloc(2).unwrap();
+        unsafe impl Traceable for SlowNode {
+            fn mark(&mut self) {
+                self.0.mark();
+            }
 
-                address.write(false as usize);
-                address.add(1).write(value as usize);
+            fn unmark(&mut self) {
+                self.0.unmark();
+            }
 
-                IntegerObject(address)
+            fn trace_with(&mut self, visitor: &mut dyn FnMut(&mut Address)) {
+                if self.0.value() == 2 {
+                    thread::sleep(Duration::from_millis(20));
+                }
+                self.0.trace_with(visitor)
             }
 
-            pub fn get(&self) -> isize {
-                *self.0.add(1) as isize
+            fn is_marked(&self) -> bool {
+                self.0.is_marked()
             }
         }
 
-        impl From<Address> for IntegerObject {
-            fn from(address: Address) -> Self {
-                IntegerObject(address)
-            }
+        thread_local! {
+            static UNCLASSIFIABLE: Cell<usize> = const { Cell::new(0) };
         }
 
-        impl Into<Address> for IntegerObject {
-            fn into(self) -> Address {
-                self.0
+        /// A list node, whose conversion panics for the address in
+        /// UNCLASSIFIABLE, like a heap with objects of another kind
+        #[derive(Copy, Clone)]
+        struct PickyNode(LinkedList);
+
+        impl From<Address> for PickyNode {
+            fn from(address: Address) -> Self {
+                if UNCLASSIFIABLE.with(Cell::get) == usize::from(address) {
+                    panic!("Not a list node");
+                }
+                PickyNode(LinkedList(address))
             }
         }
 
-        unsafe impl Traceable for IntegerObject {
+        unsafe impl Traceable for PickyNode {
             fn mark(&mut self) {
-                self.0.write(true as usize);
+                self.0.mark();
             }
 
             fn unmark(&mut self) {
-                self.0.write(false as usize);
+                self.0.unmark();
             }
 
             fn is_marked(&self) -> bool {
-                (*self.0) != 0
+                self.0.is_marked()
             }
         }
 
+        /// Allocates a live and a dead list and an object of another kind
+        /// between them
+        fn picky_heap() -> (ManagedHeap, LinkedList, Address) {
+            let mut heap = ManagedHeap::new(1000);
+            let live = list![&mut heap; 1, 2];
+            let mut foreign = heap.alloc(3).unwrap();
+            foreign.write(0);
+            list![&mut heap; 3, 4];
+
+            UNCLASSIFIABLE.with(|a| a.set(foreign.into()));
+            (heap, live, foreign)
+        }
+
         #[test]
-        fn test_integer_object_constructor() {
-            let mut heap = ManagedHeap::new(100);
-            let mut i = IntegerObject::new(&mut heap, -42);
+        fn test_gc_skips_unclassifiable_objects() {
+            let (mut heap, live, foreign) = picky_heap();
+            heap.set_skip_unclassifiable(true);
 
-            assert_eq!(-42, i.get());
-            assert_eq!(false, i.is_marked());
+            let mut root = MockGcRoot::new(vec![PickyNode(live)]);
+            let mut roots: Vec<&mut dyn GcRoot<PickyNode>> = vec![&mut root];
+            let stats = heap.gc(&mut roots[..]);
+            UNCLASSIFIABLE.with(|a| a.set(0));
 
-            i.mark();
-            assert_eq!(true, i.is_marked());
+            assert_eq!(2, stats.freed_blocks);
+            assert_eq!(1, stats.unclassifiable);
+            assert_eq!(3, stats.live_blocks);
+            assert!(heap.block_of(foreign).is_some());
+            assert!(live.iter().all(|l| !l.is_marked()));
         }
 
         #[test]
-        fn test_integer_gets_freed_when_not_marked() {
-            let mut heap = ManagedHeap::new(100);
-            let i = IntegerObject::new(&mut heap, 42);
-
-            let mut gc_root = MockGcRoot::new(vec![i]);
-            assert_eq!(1, heap.num_used_blocks());
-            assert_eq!(1, heap.num_free_blocks());
+        fn test_panicking_gc_leaves_no_marks() {
+            let (mut heap, live, foreign) = picky_heap();
 
-            {
-                let mut roots: Vec<&mut GcRoot<IntegerObject>> = vec![&mut gc_root];
-                heap.gc(&mut roots[..]);
-                assert_eq!(1, heap.num_used_blocks());
-                assert_eq!(1, heap.num_free_blocks());
-            }
+            let mut root = MockGcRoot::new(vec![PickyNode(live)]);
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut roots: Vec<&mut dyn GcRoot<PickyNode>> = vec![&mut root];
+                heap.gc(&mut roots[..])
+            }));
+            assert!(result.is_err());
+            assert_eq!(5, heap.num_used_blocks());
+            assert!(live.iter().all(|l| !l.is_marked()));
 
-            {
-                let mut roots: Vec<&mut GcRoot<IntegerObject>> = vec![&mut gc_root];
-                heap.gc(&mut roots[..]);
-                assert_eq!(1, heap.num_used_blocks());
-                assert_eq!(1, heap.num_free_blocks());
-            }
+            // the next collection is not affected by the aborted one
+            UNCLASSIFIABLE.with(|a| a.set(0));
+            let mut roots: Vec<&mut dyn GcRoot<PickyNode>> = vec![&mut root];
+            let stats = heap.gc(&mut roots[..]);
+            assert_eq!(3, stats.freed_blocks);
+            assert_eq!(0, stats.unclassifiable);
+            assert!(heap.block_of(foreign).is_none());
+            assert_eq!(Ok(()), heap.verify());
+        }
 
-            gc_root.clear();
-            let mut roots: Vec<&mut GcRoot<IntegerObject>> = vec![&mut gc_root];
-            heap.gc(&mut roots[..]);
-            assert_eq!(0, heap.num_used_blocks());
-            assert_eq!(1, heap.num_free_blocks());
+        thread_local! {
+            static FRAGILE: Cell<isize> = const { Cell::new(0) };
         }
-    }
 
-    mod complex {
-        use super::*;
-        use std::fmt;
-        use std::iter::Iterator;
-        use std::ops::Add;
+        /// A list node, whose mark panics for the node with the value in
+        /// FRAGILE, like a node with a corrupted next pointer
+        #[derive(Copy, Clone)]
+        struct FragileNode(LinkedList);
 
-        struct MockGcRoot {
-            used_elems: Vec<LinkedList>,
+        impl From<Address> for FragileNode {
+            fn from(address: Address) -> Self {
+                FragileNode(LinkedList(address))
+            }
         }
 
-        impl MockGcRoot {
-            pub fn new(used_elems: Vec<LinkedList>) -> Self {
-                MockGcRoot { used_elems }
+        unsafe impl Traceable for FragileNode {
+            fn mark(&mut self) {
+                if FRAGILE.with(Cell::get) == self.0.value() {
+                    panic!("Corrupted next pointer");
+                }
+                self.0 .0.write(true as usize);
+                if let Some(next) = self.0.next() {
+                    FragileNode(next).mark();
+                }
+            }
+
+            fn unmark(&mut self) {
+                self.0.unmark();
             }
 
-            pub fn clear(&mut self) {
-                self.used_elems.clear();
+            fn is_marked(&self) -> bool {
+                self.0.is_marked()
             }
         }
 
-        unsafe impl GcRoot<LinkedList> for MockGcRoot {
-            fn children<'a>(&'a mut self) -> Box<Iterator<Item = &'a mut LinkedList> + 'a> {
-                Box::new(self.used_elems.iter_mut())
+        #[test]
+        fn test_panicking_mark_is_undone() {
+            fn build() -> (ManagedHeap, LinkedList) {
+                let mut heap = ManagedHeap::new(1000);
+                let live = list![&mut heap; 1, 2, 3, 4];
+                list![&mut heap; 5, 6];
+                (heap, live)
             }
-        }
 
-        #[derive(Copy, Clone)]
-        struct LinkedList(Address);
+            let (mut heap, live) = build();
+            let mut root = MockGcRoot::new(vec![FragileNode(live)]);
+            FRAGILE.with(|v| v.set(3));
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut roots: Vec<&mut dyn GcRoot<FragileNode>> = vec![&mut root];
+                heap.gc(&mut roots[..])
+            }));
+            FRAGILE.with(|v| v.set(0));
+            assert!(result.is_err());
+            assert!(live.iter().all(|l| !l.is_marked()));
+            assert_eq!(6, heap.num_used_blocks());
 
-        impl LinkedList {
-            pub fn new(heap: &mut ManagedHeap, value: isize, next: Option<LinkedList>) -> Self {
-                // [mark byte, value, next], each 1 byte
-                let mut address = heap.alloc(3).unwrap();
+            let (mut fresh, fresh_live) = build();
+            let mut fresh_root = MockGcRoot::new(vec![FragileNode(fresh_live)]);
+            let mut roots: Vec<&mut dyn GcRoot<FragileNode>> = vec![&mut fresh_root];
+            let expected = outcome(fresh.gc(&mut roots[..]));
 
-                address.write(false as usize);
-                address.add(1).write(value as usize);
+            let mut roots: Vec<&mut dyn GcRoot<FragileNode>> = vec![&mut root];
+            let stats = outcome(heap.gc(&mut roots[..]));
+            // only the epoch of the failed attempt is left
+            assert_eq!(expected.epoch + 1, stats.epoch);
+            assert_eq!(
+                GcStats {
+                    epoch: 0,
+                    ..expected
+                },
+                GcStats { epoch: 0, ..stats }
+            );
+            assert_eq!(2, stats.freed_blocks);
+            // the next pointers are absolute, so they differ between the heaps
+            assert!(heap.diff_ignoring(&fresh, |_, index| index == 2).is_equal());
+            assert_eq!(1, heap.gc_history().count());
+        }
 
-                let next = next.map(|n| n.0.into()).unwrap_or(0);
-                address.add(2).write(next);
+        /// Collects when it is dropped, e.g. while the thread unwinds
+        struct CollectOnDrop<'a> {
+            heap: &'a mut ManagedHeap,
+            root: &'a mut MockGcRoot<LinkedList>,
+            freed: &'a mut usize,
+        }
 
-                LinkedList(address)
+        impl Drop for CollectOnDrop<'_> {
+            fn drop(&mut self) {
+                let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut *self.root];
+                *self.freed = self.heap.gc(&mut roots[..]).freed_blocks;
             }
+        }
 
-            pub fn next(self) -> Option<LinkedList> {
-                let next = *self.0.add(2);
+        #[test]
+        fn test_gc_during_unrelated_unwind_keeps_marks() {
+            let mut heap = ManagedHeap::new(1000);
+            let live = list![&mut heap; 1, 2];
+            list![&mut heap; 3];
 
-                if next != 0 {
-                    let address = Address::from(next);
-                    Some(LinkedList(address))
-                } else {
-                    None
-                }
-            }
+            let mut root = MockGcRoot::new(vec![live]);
+            let mut freed = 0;
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let _collect = CollectOnDrop {
+                    heap: &mut heap,
+                    root: &mut root,
+                    freed: &mut freed,
+                };
+                panic!("Unrelated panic");
+            }));
+            assert!(result.is_err());
+            assert_eq!(1, freed);
+            assert_eq!(2, heap.num_used_blocks());
+            assert_eq!("[1, 2]", format!("{:?}", live));
+        }
 
-            pub fn value(self) -> isize {
-                *self.0.add(1) as isize
-            }
+        #[test]
+        fn test_trace_timing_finds_slowest_object() {
+            let mut heap = ManagedHeap::new(1000);
+            let list = list![&mut heap; 1, 2, 3];
+            let slow = list.next().unwrap();
+            list![&mut heap; 4];
 
-            pub fn iter(self) -> Iter {
-                Iter {
-                    current: Some(self),
-                }
-            }
+            let roots = MockGcRoot::new(vec![SlowNode(list)]);
+            let stats = heap.gc_shared(&[&roots]);
+            assert_eq!(None, stats.slowest_trace);
+
+            heap.set_trace_timing(true);
+            let stats = heap.gc_shared(&[&roots]);
+            let slowest = stats.slowest_trace.unwrap();
+            assert_eq!(slow.0, slowest.address);
+            assert!(slowest.duration >= Duration::from_millis(20));
+            assert_eq!(3, stats.mark_visited);
+            assert_eq!(3, stats.sweep_visited);
         }
 
-        impl From<Address> for LinkedList {
-            fn from(address: Address) -> Self {
-                LinkedList(address)
-            }
+        #[test]
+        fn test_phase_durations_sum_to_total() {
+            let mut heap = ManagedHeap::new(1000);
+            let list = list![&mut heap; 1, 2, 3];
+            list![&mut heap; 4, 5];
+
+            let roots = MockGcRoot::new(vec![SlowNode(list)]);
+            let stats = heap.gc_shared(&[&roots]);
+            let phases = stats.mark_duration + stats.sweep_duration + stats.unmark_duration;
+            assert!(stats.mark_duration >= Duration::from_millis(20));
+            assert!(phases <= stats.duration);
+            assert!(stats.duration - phases < stats.duration / 2);
+
+            let mut root = MockGcRoot::new(vec![list]);
+            let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+            let stats = heap.gc(&mut roots[..]);
+            let phases = stats.mark_duration + stats.sweep_duration + stats.unmark_duration;
+            assert!(phases <= stats.duration);
+            assert_eq!(
+                (1, 3, 3),
+                (
+                    stats.mark_visited,
+                    stats.sweep_visited,
+                    stats.unmark_visited
+                )
+            );
+            assert_eq!(None, stats.slowest_trace);
         }
 
-        impl Into<Address> for LinkedList {
-            fn into(self) -> Address {
-                self.0
-            }
+        #[test]
+        fn test_hard_limit_fails_allocations() {
+            let mut heap = ManagedHeap::new(1000 * WORD_SIZE);
+            heap.set_limits(30, 40);
+
+            // 10 words per block
+            let blocks = alloc_blocks(&mut heap, 4);
+            assert_eq!(40, heap.allocated_words());
+            assert_eq!(Err(AllocError::LimitExceeded(0)), heap.try_alloc(0));
+            assert_eq!(None, heap.alloc(9));
+            assert!(heap.explain_alloc_failure(9).is_none());
+
+            heap.gc_addresses(&blocks[..2], |_, _| {});
+            assert_eq!(20, heap.allocated_words());
+            assert!(heap.try_alloc(9).is_ok());
+            assert_eq!(Err(AllocError::LimitExceeded(10)), heap.try_alloc(10));
         }
 
-        unsafe impl Traceable for LinkedList {
-            fn mark(&mut self) {
-                self.0.write(true as usize);
-                if let Some(mut next) = self.next() {
-                    next.mark();
-                }
-            }
+        #[test]
+        fn test_hard_limit_counts_slack() {
+            let mut heap = ManagedHeap::new(100 * WORD_SIZE);
+            let hole = heap.alloc(3).unwrap();
+            heap.alloc(2).unwrap();
+            heap.free(hole);
+            heap.set_limits(5, 5);
 
-            fn unmark(&mut self) {
-                self.0.write(false as usize);
-            }
+            // the remainder of 2 words is too small to be split off
+            assert_eq!(Err(AllocError::LimitExceeded(1)), heap.try_alloc(1));
+            assert_eq!(3, heap.allocated_words());
+            assert_eq!(Ok(()), heap.verify());
 
-            fn is_marked(&self) -> bool {
-                (*self.0) != 0
-            }
+            heap.set_limits(7, 7);
+            assert_eq!(Ok(hole), heap.try_alloc(1));
         }
 
-        impl fmt::Debug for LinkedList {
-            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-                let string_list: Vec<String> = self
-                    .iter()
-                    .map(|l| l.value())
-                    .map(|v| format!("{}", v))
-                    .collect();
+        #[test]
+        fn test_hard_limit_counts_rounding_and_checksum() {
+            let mut heap = ManagedHeap::new(1000 * WORD_SIZE);
+            heap.set_header_checksums(true);
+            heap.set_size_rounding(SizeRounding::MultipleOf(4));
+            heap.set_limits(12, 12);
 
-                write!(f, "[{}]", string_list.join(", "))
-            }
+            // 9 words are rounded up to 12 and need a header and a checksum
+            assert_eq!(Err(AllocError::LimitExceeded(9)), heap.try_alloc(9));
+            heap.alloc(5).unwrap();
+            assert_eq!(10, heap.allocated_words());
+        }
+
+        #[test]
+        fn test_soft_limit_toggles_with_usage() {
+            let mut heap = ManagedHeap::new(1000 * WORD_SIZE);
+            heap.set_large_object_threshold(20);
+            heap.set_limits(20, 100);
+
+            let small = alloc_blocks(&mut heap, 2);
+            assert!(!heap.over_soft_limit());
+
+            // large objects count as well
+            let large = heap.alloc(30).unwrap();
+            assert!(heap.is_large_object(large));
+            assert_eq!(51, heap.allocated_words());
+            assert!(heap.over_soft_limit());
+
+            heap.gc_addresses(&small, |_, _| {});
+            assert!(!heap.over_soft_limit());
         }
 
-        struct Iter {
-            current: Option<LinkedList>,
+        fn alloc_blocks(heap: &mut ManagedHeap, count: usize) -> Vec<Address> {
+            (0..count).map(|_| heap.alloc(9).unwrap()).collect()
         }
 
-        impl Iterator for Iter {
-            type Item = LinkedList;
+        #[test]
+        fn test_gc_history_records_collections_in_order() {
+            let mut heap = ManagedHeap::new(1000 * WORD_SIZE);
+            assert_eq!(None, heap.last_gc());
+            assert_eq!(None, heap.average_reclaim_ratio());
+
+            // 10 words per block
+            let roots = alloc_blocks(&mut heap, 2);
+            alloc_blocks(&mut heap, 2);
+            heap.gc_addresses(&roots, |_, _| {});
+            alloc_blocks(&mut heap, 6);
+            heap.gc_addresses(&roots, |_, _| {});
+            heap.gc_addresses(&roots, |_, _| {});
+
+            let freed: Vec<usize> = heap.gc_history().map(|s| s.freed_blocks).collect();
+            assert_eq!(vec![2, 6, 0], freed);
+            let epochs: Vec<u64> = heap.gc_history().map(|s| s.epoch).collect();
+            assert_eq!(vec![1, 2, 3], epochs);
+            assert!(heap.gc_history().all(|s| s.kind == GcKind::Full));
+            assert_eq!(Some(0.0), heap.last_gc().map(GcStats::reclaim_ratio));
 
-            fn next(&mut self) -> Option<LinkedList> {
-                let curr = self.current;
-                self.current = self.current.and_then(|c| c.next());
-                curr
+            // (20 / 40 + 60 / 80 + 0 / 20) / 3
+            let average = heap.average_reclaim_ratio().unwrap();
+            assert!((average - 1.25 / 3.0).abs() < 1e-9);
+        }
+
+        #[test]
+        fn test_gc_history_wraps_around() {
+            let mut heap = ManagedHeap::new(1000);
+            for _ in 0..20 {
+                heap.gc_addresses(&[], |_, _| {});
             }
+
+            let epochs: Vec<u64> = heap.gc_history().map(|s| s.epoch).collect();
+            assert_eq!((5..=20).collect::<Vec<u64>>(), epochs);
+
+            heap.set_gc_history_len(2);
+            heap.gc_addresses(&[], |_, _| {});
+            let epochs: Vec<u64> = heap.gc_history().map(|s| s.epoch).collect();
+            assert_eq!(vec![20, 21], epochs);
+        }
+
+        #[test]
+        fn test_gc_history_tells_kinds_apart() {
+            let mut heap = ManagedHeap::new(1000);
+            heap.minor_gc(&[], |_, _| {});
+            let mut root = MockGcRoot::new(Vec::<LinkedList>::new());
+            let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+            heap.gc_begin(&mut roots[..]);
+            heap.gc_finish();
+            heap.gc(&mut roots[..]);
+
+            let kinds: Vec<GcKind> = heap.gc_history().map(|s| s.kind).collect();
+            assert_eq!(
+                vec![GcKind::Minor, GcKind::Incremental, GcKind::Full],
+                kinds
+            );
         }
 
         #[test]
@@ -331,25 +4177,6 @@ mod tests {
             assert_eq!(1, heap.num_free_blocks());
         }
 
-        macro_rules! list {
-            ($heap:expr; $($elems:tt)+) => {
-                construct_list!($heap; [$($elems)*])
-            };
-        }
-
-        macro_rules! construct_list {
-            ($heap:expr; [] $head:expr, $($elem:expr),*) => {
-                {
-                    let mut list = LinkedList::new($heap, $head, None);
-                    $(list = LinkedList::new($heap, $elem, Some(list));)*
-                    list
-                }
-            };
-            ($heap:expr; [$first:tt $($rest:tt)*] $($reversed:tt)*) => {
-                construct_list!($heap; [$($rest)*] $first $($reversed)*)
-            };
-        }
-
         #[test]
         fn test_single_linked_list_gets_freed_when_not_marked() {
             let mut heap = ManagedHeap::new(100);
@@ -361,21 +4188,21 @@ mod tests {
             assert_eq!(1, heap.num_free_blocks());
 
             {
-                let mut roots: Vec<&mut GcRoot<LinkedList>> = vec![&mut gc_root];
+                let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut gc_root];
                 heap.gc(&mut roots[..]);
                 assert_eq!(1, heap.num_used_blocks());
                 assert_eq!(1, heap.num_free_blocks());
             }
 
             {
-                let mut roots: Vec<&mut GcRoot<LinkedList>> = vec![&mut gc_root];
+                let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut gc_root];
                 heap.gc(&mut roots[..]);
                 assert_eq!(1, heap.num_used_blocks());
                 assert_eq!(1, heap.num_free_blocks());
             }
 
             gc_root.clear();
-            let mut roots: Vec<&mut GcRoot<LinkedList>> = vec![&mut gc_root];
+            let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut gc_root];
             heap.gc(&mut roots[..]);
             assert_eq!(0, heap.num_used_blocks());
             assert_eq!(1, heap.num_free_blocks());
@@ -392,28 +4219,413 @@ mod tests {
             assert_eq!(1, heap.num_free_blocks());
 
             {
-                let mut roots: Vec<&mut GcRoot<LinkedList>> = vec![&mut gc_root];
+                let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut gc_root];
                 heap.gc(&mut roots[..]);
                 assert_eq!(2, heap.num_used_blocks());
                 assert_eq!(1, heap.num_free_blocks());
-                assert_eq!(false, list.is_marked());
+                assert!(!list.is_marked());
             }
 
             {
-                let mut roots: Vec<&mut GcRoot<LinkedList>> = vec![&mut gc_root];
+                let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut gc_root];
                 heap.gc(&mut roots[..]);
                 assert_eq!(2, heap.num_used_blocks());
                 assert_eq!(1, heap.num_free_blocks());
-                assert_eq!(false, list.is_marked());
+                assert!(!list.is_marked());
             }
 
             gc_root.clear();
-            let mut roots: Vec<&mut GcRoot<LinkedList>> = vec![&mut gc_root];
+            let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut gc_root];
             heap.gc(&mut roots[..]);
             assert_eq!(0, heap.num_used_blocks());
             assert_eq!(1, heap.num_free_blocks());
         }
 
+        #[test]
+        fn test_gc_estimate_matches_gc_stats() {
+            let mut heap = ManagedHeap::new(1000);
+            let live = list![&mut heap; 1, 2, 3];
+            let _dead = list![&mut heap; 4, 5];
+
+            let mut gc_root = MockGcRoot::new(vec![live]);
+
+            let estimate = {
+                let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut gc_root];
+                heap.gc_estimate(&mut roots[..])
+            };
+
+            assert_eq!(2, estimate.reclaimable_blocks);
+            assert_eq!(8, estimate.reclaimable_words);
+            assert_eq!(3, estimate.live_blocks);
+            assert_eq!(12, estimate.live_words);
+            assert_eq!(5, heap.num_used_blocks());
+            assert!(!live.is_marked());
+
+            let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut gc_root];
+            let stats = heap.gc(&mut roots[..]);
+
+            assert_eq!(estimate.reclaimable_blocks, stats.freed_blocks);
+            assert_eq!(estimate.reclaimable_words, stats.freed_words);
+            assert_eq!(estimate.live_blocks, stats.live_blocks);
+            assert_eq!(estimate.live_words, stats.live_words);
+        }
+
+        #[test]
+        fn test_gc_estimate_during_unrelated_unwind() {
+            /// Estimates when it is dropped, e.g. while the thread unwinds
+            struct EstimateOnDrop<'a> {
+                heap: &'a mut ManagedHeap,
+                root: &'a mut MockGcRoot<LinkedList>,
+                estimate: &'a mut GcEstimate,
+            }
+
+            impl Drop for EstimateOnDrop<'_> {
+                fn drop(&mut self) {
+                    let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut *self.root];
+                    *self.estimate = self.heap.gc_estimate(&mut roots[..]);
+                }
+            }
+
+            let mut heap = ManagedHeap::new(1000);
+            let live = list![&mut heap; 1, 2, 3];
+            list![&mut heap; 4, 5];
+
+            let mut root = MockGcRoot::new(vec![live]);
+            let mut estimate = GcEstimate::default();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let _estimate = EstimateOnDrop {
+                    heap: &mut heap,
+                    root: &mut root,
+                    estimate: &mut estimate,
+                };
+                panic!("Unrelated panic");
+            }));
+            assert!(result.is_err());
+            assert_eq!(3, estimate.live_blocks);
+            assert_eq!(2, estimate.reclaimable_blocks);
+            assert!(!live.is_marked());
+        }
+
+        #[test]
+        fn test_gc_estimate_does_not_change_gc_result() {
+            let mut estimated = ManagedHeap::new(1000);
+            let mut plain = ManagedHeap::new(1000);
+
+            let collect = |heap: &mut ManagedHeap, estimate: bool| {
+                let live = list![heap; 1, 2, 3];
+                let _dead = list![heap; 4, 5];
+                let mut gc_root = MockGcRoot::new(vec![live]);
+                let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut gc_root];
+
+                if estimate {
+                    heap.gc_estimate(&mut roots[..]);
+                }
+
+                let stats = heap.gc(&mut roots[..]);
+                (stats, live.iter().any(|l| l.is_marked()))
+            };
+
+            let (estimated_stats, estimated_marked) = collect(&mut estimated, true);
+            let (plain_stats, plain_marked) = collect(&mut plain, false);
+
+            assert_eq!(outcome(plain_stats), outcome(estimated_stats));
+            assert!(!estimated_marked);
+            assert!(!plain_marked);
+            assert_eq!(plain.num_used_blocks(), estimated.num_used_blocks());
+            assert_eq!(plain.num_free_blocks(), estimated.num_free_blocks());
+        }
+
+        #[test]
+        fn test_is_reachable_follows_traced_references() {
+            let mut heap = ManagedHeap::new(1000);
+            let live = list![&mut heap; 1, 2, 3];
+            let dead = list![&mut heap; 4, 5];
+            let tail = live.iter().last().unwrap();
+
+            let mut gc_root = MockGcRoot::new(vec![live]);
+            let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut gc_root];
+
+            assert!(heap.is_reachable(live.0, &mut roots[..]));
+            assert!(heap.is_reachable(tail.0 + 2, &mut roots[..]));
+            assert!(!heap.is_reachable(dead.0, &mut roots[..]));
+            assert!(!heap.is_reachable(dead.next().unwrap().0, &mut roots[..]));
+
+            let mut expected: Vec<_> = live.iter().map(|l| l.0).collect();
+            expected.sort_unstable();
+            assert_eq!(expected, heap.reachable_set(&mut roots[..]));
+            assert!(live.iter().chain(dead.iter()).all(|l| !l.is_marked()));
+        }
+
+        #[test]
+        fn test_reachability_query_does_not_change_gc_result() {
+            let mut queried = ManagedHeap::new(1000);
+            let mut plain = ManagedHeap::new(1000);
+
+            let collect = |heap: &mut ManagedHeap, query: bool| {
+                let live = list![heap; 1, 2, 3];
+                let dead = list![heap; 4, 5];
+                let mut gc_root = MockGcRoot::new(vec![live]);
+                let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut gc_root];
+
+                if query {
+                    assert!(!heap.is_reachable(dead.0, &mut roots[..]));
+                    assert_eq!(3, heap.reachable_set(&mut roots[..]).len());
+                }
+
+                heap.gc(&mut roots[..])
+            };
+
+            let queried_stats = collect(&mut queried, true);
+            let plain_stats = collect(&mut plain, false);
+
+            assert_eq!(outcome(plain_stats), outcome(queried_stats));
+            // the next pointers are absolute, so they differ between the heaps
+            assert!(queried.diff_ignoring(&plain, |_, i| i == 2).is_equal());
+        }
+
+        fn trace_list(address: Address, children: &mut dyn FnMut(Address)) {
+            if let Some(next) = LinkedList(address).next() {
+                children(next.0);
+            }
+        }
+
+        #[test]
+        fn test_gc_addresses_matches_trait_based_gc() {
+            let mut address_heap = ManagedHeap::new(1000);
+            let mut trait_heap = ManagedHeap::new(1000);
+
+            let address_live = list![&mut address_heap; 1, 2, 3];
+            let _address_dead = list![&mut address_heap; 4, 5];
+            let trait_live = list![&mut trait_heap; 1, 2, 3];
+            let _trait_dead = list![&mut trait_heap; 4, 5];
+
+            let address_stats = address_heap.gc_addresses(&[address_live.0], trace_list);
+
+            let mut gc_root = MockGcRoot::new(vec![trait_live]);
+            let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut gc_root];
+            let trait_stats = trait_heap.gc(&mut roots[..]);
+
+            assert_eq!(outcome(trait_stats), outcome(address_stats));
+            assert_eq!(trait_heap.num_used_blocks(), address_heap.num_used_blocks());
+            assert_eq!(trait_heap.num_free_blocks(), address_heap.num_free_blocks());
+            assert_eq!("[1, 2, 3]", format!("{:?}", address_live));
+        }
+
+        #[test]
+        fn test_gc_shared_matches_gc_while_roots_are_borrowed() {
+            let mut shared_heap = ManagedHeap::new(1000);
+            let mut heap = ManagedHeap::new(1000);
+
+            let shared_live = list![&mut shared_heap; 1, 2, 3];
+            let _shared_dead = list![&mut shared_heap; 4, 5];
+            let live = list![&mut heap; 1, 2, 3];
+            let _dead = list![&mut heap; 4, 5];
+
+            let stack = MockGcRoot::new(vec![shared_live]);
+            // e.g. the interpreter loop keeps looking at the stack
+            let interpreter_view = &stack;
+            let shared_stats = shared_heap.gc_shared(&[&stack]);
+            assert_eq!("[1, 2, 3]", format!("{:?}", interpreter_view.used_elems[0]));
+
+            let mut gc_root = MockGcRoot::new(vec![live]);
+            let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut gc_root];
+            let stats = heap.gc(&mut roots[..]);
+
+            assert_eq!(outcome(stats), outcome(shared_stats));
+            assert_eq!(heap.num_used_blocks(), shared_heap.num_used_blocks());
+            let next = shared_heap.current_epoch() + 1;
+            assert!(shared_heap.used_blocks().all(|b| !b.is_marked_in(next)));
+            assert_eq!(Ok(()), shared_heap.verify());
+        }
+
+        #[test]
+        fn test_on_sweep_reports_dropped_objects() {
+            let mut heap = ManagedHeap::new(1000);
+            let kept = list![&mut heap; 1, 2];
+            let dropped = list![&mut heap; 3, 4, 5];
+            let mut gc_root = MockGcRoot::new(vec![kept, dropped]);
+
+            let swept = Arc::new(Mutex::new(Vec::new()));
+            let inner = Arc::clone(&swept);
+            heap.set_on_sweep(Box::new(move |address, size| {
+                // the payload is still intact
+                let value = *(address + 1) as isize;
+                inner
+                    .lock()
+                    .unwrap()
+                    .push((usize::from(address), size, value));
+            }));
+
+            let manual = list![&mut heap; 6];
+            heap.free(manual.0);
+
+            let mut expected: Vec<_> = dropped
+                .iter()
+                .map(|l| (usize::from(l.0), Block::from(l.0).size(), l.value()))
+                .collect();
+
+            gc_root.used_elems.pop();
+            let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut gc_root];
+            let stats = heap.gc(&mut roots[..]);
+
+            let mut swept_now = swept.lock().unwrap().clone();
+            swept_now.sort_unstable();
+            expected.sort_unstable();
+            assert_eq!(expected, swept_now);
+            assert_eq!(3, stats.freed_blocks);
+
+            // reset is not a collection
+            heap.reset();
+            assert_eq!(3, swept.lock().unwrap().len());
+            assert!(heap.take_on_sweep().is_some());
+        }
+
+        #[test]
+        fn test_sweep_finalizes_in_ascending_address_order() {
+            let mut heap = ManagedHeap::new(1000);
+            heap.set_large_object_threshold(16);
+
+            // the large object lives outside of the heap, so it can't be
+            // visited in heap order
+            let mut addresses = vec![heap.alloc(32).unwrap()];
+            for size in 1..6 {
+                addresses.push(heap.rc_alloc(size).unwrap());
+                addresses.push(heap.alloc(size).unwrap());
+            }
+            let kept = addresses[3];
+
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let inner = Arc::clone(&events);
+            heap.set_on_sweep(Box::new(move |address, _| {
+                inner.lock().unwrap().push(("sweep", usize::from(address)));
+            }));
+            let inner = Arc::clone(&events);
+            heap.set_rc_finalizer(Box::new(move |address| {
+                inner
+                    .lock()
+                    .unwrap()
+                    .push(("finalize", usize::from(address)));
+            }));
+
+            let stats = heap.gc_addresses(&[kept], |_, _| {});
+            assert_eq!(10, stats.freed_blocks);
+
+            let events = events.lock().unwrap();
+            let swept: Vec<_> = events
+                .iter()
+                .filter(|(kind, _)| *kind == "sweep")
+                .map(|&(_, address)| address)
+                .collect();
+            let mut expected: Vec<_> = addresses
+                .iter()
+                .filter(|&&a| a != kept)
+                .map(|&a| usize::from(a))
+                .collect();
+            expected.sort_unstable();
+            assert_eq!(expected, swept);
+
+            // every finalizer runs right after the sweep callback of its object
+            for (i, &(kind, address)) in events.iter().enumerate() {
+                if kind == "finalize" {
+                    assert_eq!(("sweep", address), events[i - 1]);
+                }
+            }
+            assert_eq!(4, events.iter().filter(|(k, _)| *k == "finalize").count());
+        }
+
+        #[test]
+        fn test_same_operations_produce_same_layout() {
+            fn run(seed: u64) -> (Vec<(usize, usize, bool)>, Vec<GcStats>) {
+                let mut state = seed;
+                let mut next = move |bound: usize| {
+                    // xorshift, so the sequence only depends on the seed
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as usize % bound
+                };
+
+                let mut heap = ManagedHeap::new(512);
+                heap.set_large_object_threshold(48);
+                let mut live: Vec<Address> = Vec::new();
+                let mut collections = Vec::new();
+
+                for _ in 0..500 {
+                    match next(10) {
+                        0..=5 => {
+                            if let Some(address) = heap.alloc(next(64) as HalfWord + 1) {
+                                live.push(address);
+                            }
+                        }
+                        6 | 7 if !live.is_empty() => {
+                            let address = live.swap_remove(next(live.len()));
+                            heap.free(address);
+                        }
+                        _ => {
+                            let roots: Vec<_> =
+                                live.iter().copied().filter(|_| next(3) > 0).collect();
+                            collections.push(outcome(heap.gc_addresses(&roots, |_, _| {})));
+                            live = roots;
+                        }
+                    }
+                }
+
+                let layout = heap.blocks().map(|b| (b.offset, b.size, b.used)).collect();
+                (layout, collections)
+            }
+
+            let first = run(0x5eed);
+            assert!(first.1.iter().any(|stats| stats.freed_blocks > 0));
+            assert_eq!(first, run(0x5eed));
+        }
+
+        #[test]
+        fn test_marks_expire_with_their_epoch() {
+            let mut heap = ManagedHeap::new(1000);
+            let list = list![&mut heap; 1, 2, 3];
+            assert_eq!(0, heap.current_epoch());
+
+            let stats = heap.gc_addresses(&[list.0], trace_list);
+            assert_eq!(1, stats.epoch);
+            assert_eq!(1, heap.current_epoch());
+            // the survivors keep their marks, which are stale in the next epoch
+            assert!(heap.used_blocks().all(|b| b.is_marked_in(1)));
+
+            list.set_next(None);
+            let stats = heap.gc_addresses(&[list.0], trace_list);
+            assert_eq!(2, stats.freed_blocks);
+            assert_eq!(2, heap.current_epoch());
+
+            // a trait based collection is an epoch as well
+            let mut root = MockGcRoot::new(vec![list]);
+            let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+            assert_eq!(3, heap.gc(&mut roots[..]).epoch);
+            assert!(heap.used_blocks().all(|b| !b.is_marked()));
+        }
+
+        #[test]
+        fn test_gc_addresses_frees_lists_without_roots() {
+            let mut heap = ManagedHeap::new(1000);
+            for _i in 0..20 {
+                let list = list![&mut heap; 1, 2, 3];
+                assert_eq!(3, heap.num_used_blocks());
+
+                heap.gc_addresses(&[list.0], trace_list);
+                assert_eq!(3, heap.num_used_blocks());
+                assert_eq!(1, heap.num_free_blocks());
+                let next = heap.current_epoch() + 1;
+                assert!(heap.used_blocks().all(|b| !b.is_marked_in(next)));
+
+                // only the tail is still reachable, so the head gets freed
+                heap.gc_addresses(&[list.next().unwrap().0], trace_list);
+                assert_eq!(2, heap.num_used_blocks());
+
+                heap.gc_addresses(&[], trace_list);
+                assert_eq!(0, heap.num_used_blocks());
+                assert_eq!(1, heap.num_free_blocks());
+            }
+        }
+
         #[test]
         fn test_triple_linked_list_gets_freed_when_not_marked() {
             let mut heap = ManagedHeap::new(1000);
@@ -428,26 +4640,26 @@ mod tests {
                 assert_eq!(1, heap.num_free_blocks());
 
                 {
-                    let mut roots: Vec<&mut GcRoot<LinkedList>> = vec![&mut gc_root];
+                    let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut gc_root];
                     heap.gc(&mut roots[..]);
                     assert_eq!(3, heap.num_used_blocks());
                     assert_eq!(1, heap.num_free_blocks());
                 }
 
                 {
-                    let mut roots: Vec<&mut GcRoot<LinkedList>> = vec![&mut gc_root];
+                    let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut gc_root];
                     heap.gc(&mut roots[..]);
                     assert_eq!(3, heap.num_used_blocks());
                     assert_eq!(1, heap.num_free_blocks());
                 }
 
                 gc_root.clear();
-                let mut roots: Vec<&mut GcRoot<LinkedList>> = vec![&mut gc_root];
+                let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut gc_root];
                 heap.gc(&mut roots[..]);
                 assert_eq!(0, heap.num_used_blocks());
                 assert_eq!(1, heap.num_free_blocks());
 
-                let mut roots: Vec<&mut GcRoot<LinkedList>> = vec![&mut gc_root];
+                let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut gc_root];
                 heap.gc(&mut roots[..]);
                 assert_eq!(0, heap.num_used_blocks());
                 assert_eq!(1, heap.num_free_blocks());
@@ -0,0 +1,198 @@
+use crate::address::Address;
+use crate::managed::ManagedHeap;
+use crate::trace::{GcRoot, Traceable};
+use crate::types::WORD_SIZE;
+
+use std::collections::HashMap;
+use std::mem;
+
+/// Copies the reachable objects of one heap into another one.
+struct Migration<'a> {
+    from: &'a mut ManagedHeap,
+    to: ManagedHeap,
+    // maps the old addresses to the new ones
+    forwards: HashMap<usize, Address>,
+    // copied objects, whose references weren't updated yet
+    worklist: Vec<Address>,
+}
+
+impl Migration<'_> {
+    /// Returns the new location of address and copies the object if
+    /// necessary. Addresses which don't point into a used block are returned
+    /// unchanged.
+    fn forward(&mut self, address: Address) -> Address {
+        let block = match self.from.block_of(address) {
+            Some(block) => block,
+            None => return address,
+        };
+
+        let old = Address::from(block);
+        let offset = (usize::from(address) - usize::from(old)) / WORD_SIZE;
+
+        if let Some(&new) = self.forwards.get(&old.into()) {
+            return new + offset;
+        }
+
+        // the header (and the checksum) are not copied
+        let payload = self.from.heap.payload_words(block);
+        let mut new = self
+            .to
+            .alloc(payload)
+            .expect("The new heap is too small for all live objects");
+        old.copy_to(&mut new, payload as usize);
+
+        // the new block can be bigger than the old one, which moves the count
+        if let Ok(count) = self.from.rc_count(old) {
+            self.to.rc_objects.insert(new.into());
+            self.to.set_rc_count(new, count);
+        }
+        if let Some(tag) = self.from.tag_of(old) {
+            self.to
+                .tags
+                .get_or_insert_with(HashMap::new)
+                .insert(new.into(), tag);
+        }
+        if let Some(context) = self.from.context_of(old) {
+            self.to
+                .contexts
+                .get_or_insert_with(HashMap::new)
+                .insert(new.into(), context);
+        }
+        if let Some(value) = self.from.weak_table.get(old) {
+            self.to.weak_table.insert(new, value);
+        }
+        // the value now belongs to the new heap and must not be dropped twice
+        if let Some(dropper) = self.from.droppers.remove(&old.into()) {
+            unsafe { dropper.realign(old, new, payload as usize) };
+            self.to.droppers.insert(new.into(), dropper);
+        }
+
+        self.forwards.insert(old.into(), new);
+        self.worklist.push(new);
+        new + offset
+    }
+}
+
+impl ManagedHeap {
+    /// Copies all objects reachable from roots into a new heap with new_size
+    /// bytes and returns it. The copied objects are placed next to each
+    /// other.
+    /// All references are updated by tracing the copies and the children of
+    /// the roots are updated in place. Afterwards the old heap can be
+    /// dropped.
+    /// The settings of the heap (including the leak check), the rc
+    /// finalizer, the sweep callback, the census classifier and the weak
+    /// table entries of the live objects are moved as well.
+    /// Panics if new_size is invalid or too small for the live objects, or if
+    /// there are registered roots, whose objects can't be updated (see
+    /// register_root).
+    pub fn migrate<T>(&mut self, new_size: usize, roots: &mut [&mut dyn GcRoot<T>]) -> ManagedHeap
+    where
+        T: Traceable + From<Address> + Into<Address> + Copy,
+    {
+        assert!(
+            self.registered_roots.is_empty(),
+            "Can't migrate a heap with registered roots"
+        );
+        self.gc_abort();
+
+        let mut to = ManagedHeap::new(new_size);
+        to.set_large_object_threshold(self.large_object_threshold());
+        to.set_fit_policy(self.fit_policy());
+        to.rc_finalizer = self.rc_finalizer.take();
+        to.on_sweep = self.on_sweep.take();
+        to.census_classifier = self.census_classifier.take();
+        // the objects left behind are not leaks
+        to.panic_on_leak = mem::replace(&mut self.panic_on_leak, false);
+        to.leak_handler = self.leak_handler.take();
+        if let Some(on_drop) = self.weak_table.take_on_drop() {
+            to.weak_table.set_on_drop(on_drop);
+        }
+
+        let mut migration = Migration {
+            from: self,
+            to,
+            forwards: HashMap::new(),
+            worklist: Vec::new(),
+        };
+
+        for root in roots.iter_mut().flat_map(|r| r.children()) {
+            let new = migration.forward((*root).into());
+            *root = T::from(new);
+        }
+
+        while let Some(new) = migration.worklist.pop() {
+            let mut object = T::from(new);
+            object.trace_with(&mut |child| *child = migration.forward(*child));
+        }
+
+        // the copies keep the contexts of the originals
+        migration.to.alloc_context = migration.from.alloc_context;
+        // copying the live objects isn't allocation pressure
+        migration.to.words_since_gc = 0;
+        migration.to
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_migrate_linked_list() {
+        let mut heap = ManagedHeap::new(1000);
+        heap.panic_on_leak(true);
+        let _garbage = list![&mut heap; 7, 8, 9];
+        let list = list![&mut heap; 1, 2, 3, 4];
+        let _more_garbage = list![&mut heap; 10];
+
+        let mut root = MockGcRoot::new(vec![list]);
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        let mut new_heap = heap.migrate(2000, &mut roots[..]);
+        // the old objects are not reported as leaks
+        drop(heap);
+
+        let list = root.used_elems[0];
+        assert_eq!("[1, 2, 3, 4]", format!("{:?}", list));
+        assert!(list.iter().all(|n| new_heap.block_of(n.0).is_some()));
+
+        // 4 nodes with 3 words each + header
+        assert_eq!(4, new_heap.num_used_blocks());
+        assert_eq!(16, new_heap.used_size());
+        assert_eq!(2000 / WORD_SIZE, new_heap.total_size());
+        assert_eq!(Ok(()), new_heap.verify());
+
+        // the leak check moved with the objects
+        assert_eq!(4, new_heap.leak_summary().objects);
+        new_heap.panic_on_leak(false);
+    }
+
+    #[test]
+    fn test_migrate_keeps_shared_objects_shared() {
+        let mut heap = ManagedHeap::new(1000);
+        let tail = list![&mut heap; 3, 4];
+        let first = LinkedList::new(&mut heap, 1, Some(tail));
+        let second = LinkedList::new(&mut heap, 2, Some(tail));
+        let rc = heap.rc_alloc(2).unwrap();
+
+        let mut root = MockGcRoot::new(vec![first, second, LinkedList(rc)]);
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        let mut new_heap = heap.migrate(1000, &mut roots[..]);
+
+        let (first, second) = (root.used_elems[0], root.used_elems[1]);
+        assert_eq!("[1, 3, 4]", format!("{:?}", first));
+        assert_eq!("[2, 3, 4]", format!("{:?}", second));
+        assert_eq!(first.next().unwrap().0, second.next().unwrap().0);
+        assert_eq!(5, new_heap.num_used_blocks());
+
+        let rc = root.used_elems[2].0;
+        assert_eq!(Ok(1), new_heap.rc_count(rc));
+        assert_eq!(Ok(0), new_heap.rc_dec(rc));
+    }
+
+    #[test]
+    fn test_migrate_keeps_reference_counts_with_checksums() {
+        crate::heap::tests::run_with_checksums(&[test_migrate_keeps_shared_objects_shared]);
+    }
+}
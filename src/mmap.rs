@@ -0,0 +1,215 @@
+use crate::block::header::BlockHeader;
+use crate::heap::{Backing, Heap};
+use crate::managed::ManagedHeap;
+use crate::types::{HALF_WORD_MAX, WORD_SIZE};
+
+use memmap2::MmapMut;
+use std::fs::OpenOptions;
+use std::io::{self, Error, ErrorKind};
+use std::path::Path;
+
+/// "MHEAP" in ascii
+const MAGIC: usize = 0x4D_48_45_41_50;
+const VERSION: usize = 1;
+
+/// magic, version, word size and heap size in words
+const FILE_HEADER_WORDS: usize = 4;
+
+impl ManagedHeap {
+    /// Opens a heap which is backed by a memory mapped file.
+    /// If the file is empty, a new heap with size bytes is created, otherwise
+    /// the existing heap inside the file is used, which has to have the
+    /// same size.
+    ///
+    /// All changes are written back to the file when the heap is dropped.
+    /// Addresses stored inside the heap are absolute, so they are only valid
+    /// while the heap is mapped. Use offsets to refer to objects across runs.
+    /// Large objects would live outside of the file, so every object is
+    /// placed inside the heap (the large object threshold is MAX_SIZE).
+    pub fn open_file<P: AsRef<Path>>(path: P, size: usize) -> io::Result<ManagedHeap> {
+        if size > HALF_WORD_MAX as usize {
+            let msg = format!("Size too big (MAX: {})", HALF_WORD_MAX);
+            return Err(Error::new(ErrorKind::InvalidInput, msg));
+        }
+
+        let words = size / WORD_SIZE;
+        if words == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Size too small"));
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let file_size = ((FILE_HEADER_WORDS + words) * WORD_SIZE) as u64;
+        let is_new = file.metadata()?.len() == 0;
+
+        if is_new {
+            file.set_len(file_size)?;
+        } else if file.metadata()?.len() != file_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "File has the wrong size",
+            ));
+        }
+
+        let mut mapping = unsafe { MmapMut::map_mut(&file)? };
+        let header = mapping.as_mut_ptr() as *mut usize;
+
+        unsafe {
+            let data = header.add(FILE_HEADER_WORDS);
+            let backing = Backing::Mapped(mapping);
+
+            let heap = if is_new {
+                *header = MAGIC;
+                *header.add(1) = VERSION;
+                *header.add(2) = WORD_SIZE;
+                *header.add(3) = words;
+                Heap::from_raw(data, words, backing)
+            } else {
+                let expected = [MAGIC, VERSION, WORD_SIZE, words];
+                for (i, expected) in expected.iter().enumerate() {
+                    if *header.add(i) != *expected {
+                        let msg = "Invalid heap file header";
+                        return Err(Error::new(ErrorKind::InvalidData, msg));
+                    }
+                }
+
+                Heap::adopt(data, words, backing)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Corrupted block layout"))?
+            };
+
+            let mut heap = ManagedHeap::from_heap(heap);
+            heap.set_large_object_threshold(BlockHeader::MAX_SIZE);
+            Ok(heap)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+    use crate::types::HalfWord;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_file(name: &str) -> PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!("managed-heap-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn used_addresses(heap: &ManagedHeap) -> Vec<Address> {
+        heap.heap.used().map(Address::from).collect()
+    }
+
+    #[test]
+    fn test_reopen_file_keeps_values() {
+        let path = temp_file("reopen");
+
+        {
+            let mut heap = ManagedHeap::open_file(&path, 1024).unwrap();
+            assert_eq!(1, heap.num_free_blocks());
+
+            let mut first = heap.alloc(2).unwrap();
+            let second = heap.alloc(3).unwrap();
+            let third = heap.alloc(1).unwrap();
+            heap.free(third);
+
+            first.write(42);
+            (first + 1).write(43);
+            (second + 2).write(44);
+        }
+
+        {
+            let heap = ManagedHeap::open_file(&path, 1024).unwrap();
+            assert_eq!(2, heap.num_used_blocks());
+            assert_eq!(1, heap.num_free_blocks());
+            assert_eq!(7, heap.used_size());
+
+            let used = used_addresses(&heap);
+            assert_eq!(42, *used[0]);
+            assert_eq!(43, *(used[0] + 1));
+            assert_eq!(44, *(used[1] + 2));
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reopened_heap_can_allocate_and_free() {
+        let path = temp_file("alloc");
+
+        {
+            let mut heap = ManagedHeap::open_file(&path, 512).unwrap();
+            heap.alloc(10).unwrap();
+        }
+
+        {
+            let mut heap = ManagedHeap::open_file(&path, 512).unwrap();
+            let address = heap.alloc(5).unwrap();
+            assert_eq!(2, heap.num_used_blocks());
+
+            heap.free(address);
+            let first = used_addresses(&heap)[0];
+            heap.free(first);
+
+            assert_eq!(0, heap.num_used_blocks());
+            assert_eq!(1, heap.num_free_blocks());
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reopen_file_keeps_big_objects() {
+        let path = temp_file("big");
+        let size = ManagedHeap::DEFAULT_LARGE_OBJECT_THRESHOLD as usize + 1;
+
+        {
+            let mut heap = ManagedHeap::open_file(&path, 1024 * WORD_SIZE).unwrap();
+            let mut address = heap.alloc(size as HalfWord).unwrap();
+            assert!(!heap.is_large_object(address));
+            (address + (size - 1)).write(42);
+            address.write(43);
+        }
+
+        {
+            let heap = ManagedHeap::open_file(&path, 1024 * WORD_SIZE).unwrap();
+            assert_eq!(1, heap.num_used_blocks());
+
+            let address = used_addresses(&heap)[0];
+            assert_eq!(43, *address);
+            assert_eq!(42, *(address + (size - 1)));
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_file_with_wrong_size_fails() {
+        let path = temp_file("size");
+
+        ManagedHeap::open_file(&path, 512).unwrap();
+        let err = ManagedHeap::open_file(&path, 1024).err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_file_with_invalid_header_fails() {
+        let path = temp_file("magic");
+        fs::write(&path, vec![0xFF; (FILE_HEADER_WORDS + 64) * WORD_SIZE]).unwrap();
+
+        let err = ManagedHeap::open_file(&path, 64 * WORD_SIZE).err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
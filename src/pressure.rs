@@ -0,0 +1,133 @@
+use crate::managed::ManagedHeap;
+
+/// How worthwhile a collection would be (see ManagedHeap::gc_pressure)
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PressureLevel {
+    /// Nothing was allocated since the last collection or a collection is
+    /// expected to free almost nothing
+    None,
+    Low,
+    Medium,
+    High,
+}
+
+impl PressureLevel {
+    /// The lowest score of Low, Medium and High
+    pub const THRESHOLDS: [f64; 3] = [0.05, 0.25, 0.5];
+
+    pub fn from_score(score: f64) -> Self {
+        match Self::THRESHOLDS.iter().filter(|&&t| score >= t).count() {
+            0 => PressureLevel::None,
+            1 => PressureLevel::Low,
+            2 => PressureLevel::Medium,
+            _ => PressureLevel::High,
+        }
+    }
+}
+
+/// The numbers behind a PressureLevel.
+/// All sizes are in words and include the block headers.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GcPressure {
+    pub level: PressureLevel,
+    /// The estimated fraction of the capacity, which a collection would
+    /// free (between 0 and 1)
+    pub score: f64,
+    /// See ManagedHeap::words_allocated_since_gc
+    pub words_since_gc: usize,
+    /// The fraction of the capacity (including large objects), which is in
+    /// use
+    pub usage: f64,
+    /// The average reclaim ratio of the gc history, which is 1 without a
+    /// history
+    pub reclaim_ratio: f64,
+}
+
+impl ManagedHeap {
+    /// The words allocated since the end of the last collection of any
+    /// kind, including large objects and objects, which were freed again
+    pub fn words_allocated_since_gc(&self) -> usize {
+        self.words_since_gc
+    }
+
+    /// Estimates how worthwhile a collection would be, e.g. to collect
+    /// during idle time only if it pays off.
+    /// The objects, which survived the last collection, are expected to
+    /// survive the next one as well, so only the words allocated since then
+    /// (but at most the used words) can be freed. Of those the average
+    /// reclaim ratio of the history is expected to be garbage, which gives
+    /// the score as a fraction of the capacity.
+    pub fn gc_pressure(&self) -> GcPressure {
+        let used = self.allocated_words();
+        let capacity = self.heap.total_size() + self.large_objects.size();
+        let reclaim_ratio = self.average_reclaim_ratio().unwrap_or(1.0);
+
+        let (usage, score) = if capacity == 0 {
+            (0.0, 0.0)
+        } else {
+            let fresh = self.words_since_gc.min(used) as f64;
+            (
+                used as f64 / capacity as f64,
+                fresh * reclaim_ratio / capacity as f64,
+            )
+        };
+
+        GcPressure {
+            level: PressureLevel::from_score(score),
+            score,
+            words_since_gc: self.words_since_gc,
+            usage,
+            reclaim_ratio,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::WORD_SIZE;
+
+    #[test]
+    fn test_pressure_follows_allocations() {
+        let mut heap = ManagedHeap::new(250 * WORD_SIZE);
+        let live = heap.alloc(4).unwrap();
+        for _ in 0..4 {
+            heap.alloc(4).unwrap();
+        }
+        heap.gc_addresses(&[live], |_, _| {});
+
+        let pressure = heap.gc_pressure();
+        assert_eq!(0, heap.words_allocated_since_gc());
+        assert_eq!(PressureLevel::None, pressure.level);
+        assert_eq!(0.8, pressure.reclaim_ratio);
+        assert_eq!(5.0 / 250.0, pressure.usage);
+
+        for _ in 0..10 {
+            heap.alloc(4).unwrap();
+        }
+        assert_eq!(50, heap.words_allocated_since_gc());
+        assert_eq!(PressureLevel::Low, heap.gc_pressure().level);
+
+        heap.set_large_object_threshold(10);
+        heap.alloc(99).unwrap();
+        for _ in 0..10 {
+            heap.alloc(9).unwrap();
+        }
+        let pressure = heap.gc_pressure();
+        assert_eq!(250, pressure.words_since_gc);
+        assert_eq!(PressureLevel::High, pressure.level);
+
+        heap.gc_addresses(&[live], |_, _| {});
+        assert_eq!(0, heap.words_allocated_since_gc());
+        assert_eq!(PressureLevel::None, heap.gc_pressure().level);
+    }
+
+    #[test]
+    fn test_pressure_levels() {
+        assert_eq!(PressureLevel::None, PressureLevel::from_score(0.0));
+        assert_eq!(PressureLevel::Low, PressureLevel::from_score(0.05));
+        assert_eq!(PressureLevel::Medium, PressureLevel::from_score(0.3));
+        assert_eq!(PressureLevel::High, PressureLevel::from_score(1.0));
+        assert!(PressureLevel::High > PressureLevel::Low);
+    }
+}
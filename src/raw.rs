@@ -0,0 +1,141 @@
+use crate::address::Address;
+use crate::error::{CorruptionError, NewHeapError};
+use crate::heap::Heap;
+use crate::types::HalfWord;
+
+/// A heap without garbage collection, where every allocation has to be freed
+/// manually (e.g. for arenas).
+///
+/// # Example
+/// ```
+/// use managed_heap::raw::RawHeap;
+///
+/// let mut heap = RawHeap::new(1024).unwrap();
+/// let mut a = heap.alloc(4).unwrap();
+/// a.write(42);
+/// assert_eq!(42, *a);
+/// assert_eq!(1, heap.num_used_blocks());
+///
+/// assert!(heap.free(a));
+/// assert_eq!(0, heap.num_used_blocks());
+/// // freeing twice is detected
+/// assert!(!heap.free(a));
+/// ```
+pub struct RawHeap {
+    heap: Heap,
+}
+
+impl RawHeap {
+    /// Expects the heap size in bytes, which is rounded up to a multiple of
+    /// WORD_SIZE.
+    pub fn new(size: usize) -> Result<Self, NewHeapError> {
+        Heap::try_new(size).map(|heap| RawHeap { heap })
+    }
+
+    /// Allocates size words, which are not initialized.
+    /// Returns None if there is no free block which is big enough.
+    pub fn alloc(&mut self, size: HalfWord) -> Option<Address> {
+        self.heap.alloc(size)
+    }
+
+    /// Frees the allocation at address.
+    /// Returns false (and does nothing) if address was not returned by alloc
+    /// or was already freed.
+    pub fn free(&mut self, address: Address) -> bool {
+        let allocated = self
+            .heap
+            .block_of(address)
+            .is_some_and(|block| Address::from(block) == address);
+
+        if allocated {
+            self.heap.free(address);
+        }
+
+        allocated
+    }
+
+    /// The size of the heap in words
+    pub fn size(&self) -> usize {
+        self.heap.size()
+    }
+
+    /// All words of the heap (including the block headers).
+    /// The headers can be decoded with BlockHeader::decode.
+    pub fn raw_words(&self) -> &[usize] {
+        self.heap.raw_words()
+    }
+
+    /// The number of words in use, including the block headers
+    pub fn used_size(&self) -> usize {
+        self.heap.used_size()
+    }
+
+    /// The addresses of all allocations, which were not freed yet, in
+    /// ascending order
+    pub fn used(&self) -> impl ExactSizeIterator<Item = Address> + DoubleEndedIterator + '_ {
+        self.heap.used().map(Address::from)
+    }
+
+    pub fn num_used_blocks(&self) -> usize {
+        self.heap.num_used_blocks()
+    }
+
+    pub fn num_free_blocks(&self) -> usize {
+        self.heap.num_free_blocks()
+    }
+
+    /// Checks the block layout of the heap for corruption
+    pub fn verify(&self) -> Result<(), CorruptionError> {
+        self.heap.verify()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::block::header::BlockHeader;
+    use crate::types::WORD_SIZE;
+
+    #[test]
+    fn test_new_validates_size() {
+        assert_eq!(Some(NewHeapError::ZeroSize), RawHeap::new(0).err());
+        assert_eq!(
+            Some(NewHeapError::TooLarge(Heap::MAX_BYTES + 1)),
+            RawHeap::new(Heap::MAX_BYTES + 1).err()
+        );
+        assert_eq!(2, RawHeap::new(2 * WORD_SIZE).unwrap().size());
+    }
+
+    #[test]
+    fn test_free_only_accepts_allocations() {
+        let mut heap = RawHeap::new(1024).unwrap();
+        let a = heap.alloc(4).unwrap();
+
+        assert!(!heap.free(a + 1));
+        assert!(!heap.free(Address::from(0)));
+        assert_eq!(1, heap.num_used_blocks());
+        assert!(heap.free(a));
+        assert!(!heap.free(a));
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_raw_words_contain_decodable_headers() {
+        let mut heap = RawHeap::new(64 * WORD_SIZE).unwrap();
+        let mut a = heap.alloc(4).unwrap();
+        a.write(42);
+
+        let words = heap.raw_words();
+        assert_eq!(heap.size(), words.len());
+        assert_eq!(42, words[1]);
+
+        let (pred_size, size, flags) = BlockHeader::decode(words[0]);
+        assert_eq!((0, 5), (pred_size, size));
+        assert!(flags.used && !flags.marked && !flags.old);
+
+        let (pred_size, size, flags) = BlockHeader::decode(words[5]);
+        assert_eq!((5, 59), (pred_size, size));
+        assert!(!flags.used);
+    }
+}
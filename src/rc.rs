@@ -0,0 +1,238 @@
+use crate::address::Address;
+use crate::error::AccessError;
+use crate::managed::ManagedHeap;
+use crate::types::HalfWord;
+
+/// Gets called with the address of a reference counted object right before
+/// it is freed.
+pub(crate) type Finalizer = Box<dyn FnMut(Address) + Send>;
+
+/// Reference counted objects.
+/// The count is stored in the last word of the payload, after the words
+/// requested by rc_alloc (and before the checksum of the block, if any), so
+/// the layout of the object itself is not affected.
+/// Reference counted objects are still managed by the garbage collector,
+/// which frees them if they are unreachable, even if their count is not 0
+/// (e.g. because of a cycle).
+impl ManagedHeap {
+    /// Allocates an object with size words and a reference count of 1.
+    pub fn rc_alloc(&mut self, size: HalfWord) -> Option<Address> {
+        let address = self.alloc(size.checked_add(1)?)?;
+        self.rc_objects.insert(address.into());
+        self.count_address(address).write(1);
+        Some(address)
+    }
+
+    /// Increments the reference count of address and returns the new count.
+    pub fn rc_inc(&mut self, address: Address) -> Result<usize, AccessError> {
+        let mut count = self.rc_count_address(address)?;
+        let new = *count + 1;
+        count.write(new);
+        Ok(new)
+    }
+
+    /// Decrements the reference count of address and returns the new count.
+    /// If the count reaches 0, the finalizer is called and the object is
+    /// freed. Using address afterwards is undefined behaviour.
+    pub fn rc_dec(&mut self, address: Address) -> Result<usize, AccessError> {
+        let mut count = self.rc_count_address(address)?;
+        let new = *count - 1;
+
+        if new == 0 {
+            self.finalize(address);
+            self.free(address);
+        } else {
+            count.write(new);
+        }
+
+        Ok(new)
+    }
+
+    /// The current reference count of address
+    pub fn rc_count(&self, address: Address) -> Result<usize, AccessError> {
+        self.rc_count_address(address).map(|count| *count)
+    }
+
+    /// Writes count to the last word of the payload of the reference
+    /// counted object at address, e.g. after its block was trimmed
+    pub(crate) fn set_rc_count(&mut self, address: Address, count: usize) {
+        self.count_address(address).write(count);
+    }
+
+    /// Sets the function, which gets called before a reference counted object
+    /// is freed by either rc_dec or the garbage collector.
+    pub fn set_rc_finalizer(&mut self, finalizer: Finalizer) {
+        self.rc_finalizer = Some(finalizer);
+    }
+
+    /// Runs the finalizer if address is a reference counted object
+    pub(crate) fn finalize(&mut self, address: Address) {
+        if self.rc_objects.contains(&address.into()) {
+            if let Some(finalizer) = self.rc_finalizer.as_mut() {
+                finalizer(address);
+            }
+        }
+    }
+
+    fn rc_count_address(&self, address: Address) -> Result<Address, AccessError> {
+        self.check_strict(address)?;
+        if self.rc_objects.contains(&address.into()) {
+            Ok(self.count_address(address))
+        } else {
+            Err(AccessError::NotReferenceCounted(address))
+        }
+    }
+
+    fn count_address(&self, address: Address) -> Address {
+        let range = self
+            .payload_range(address)
+            .expect("Reference counted objects are always allocated");
+        address + (range.len_words - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::trace::GcRoot;
+
+    use std::sync::{Arc, Mutex};
+
+    fn record_finalized(heap: &mut ManagedHeap) -> Arc<Mutex<Vec<Address>>> {
+        let finalized = Arc::new(Mutex::new(Vec::new()));
+        let inner = Arc::clone(&finalized);
+        heap.set_rc_finalizer(Box::new(move |a| inner.lock().unwrap().push(a)));
+        finalized
+    }
+
+    /// Allocates a linked list node with a reference count
+    fn rc_node(heap: &mut ManagedHeap, value: isize, next: Option<LinkedList>) -> LinkedList {
+        let mut node = LinkedList(heap.rc_alloc(3).unwrap());
+        node.0.write(0);
+        (node.0 + 1).write(value as usize);
+        node.set_next(next);
+        node
+    }
+
+    #[test]
+    fn test_inc_dec_lifecycle() {
+        let mut heap = ManagedHeap::new(1000);
+        let finalized = record_finalized(&mut heap);
+
+        let a = heap.rc_alloc(2).unwrap();
+        assert_eq!(Ok(1), heap.rc_count(a));
+        assert_eq!(Ok(2), heap.rc_inc(a));
+        assert_eq!(Ok(1), heap.rc_dec(a));
+        assert_eq!(1, heap.num_used_blocks());
+        assert!(finalized.lock().unwrap().is_empty());
+
+        assert_eq!(Ok(0), heap.rc_dec(a));
+        assert_eq!(0, heap.num_used_blocks());
+        assert_eq!(vec![a], *finalized.lock().unwrap());
+        assert_eq!(Err(AccessError::NotReferenceCounted(a)), heap.rc_dec(a));
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_count_does_not_overlap_payload() {
+        let mut heap = ManagedHeap::new(1000);
+        let a = heap.rc_alloc(2).unwrap();
+
+        heap.write(a, 0, usize::MAX).unwrap();
+        heap.write(a, 1, usize::MAX).unwrap();
+        assert_eq!(Ok(1), heap.rc_count(a));
+    }
+
+    #[test]
+    fn test_ordinary_objects_are_not_reference_counted() {
+        let mut heap = ManagedHeap::new(1000);
+        let a = heap.alloc(2).unwrap();
+
+        assert_eq!(Err(AccessError::NotReferenceCounted(a)), heap.rc_inc(a));
+        assert_eq!(Err(AccessError::NotReferenceCounted(a)), heap.rc_dec(a));
+        assert_eq!(1, heap.num_used_blocks());
+    }
+
+    #[test]
+    fn test_gc_collects_cycle_with_nonzero_counts() {
+        let mut heap = ManagedHeap::new(1000);
+        let finalized = record_finalized(&mut heap);
+
+        let a = rc_node(&mut heap, 1, None);
+        let b = rc_node(&mut heap, 2, Some(a));
+        a.set_next(Some(b));
+        heap.rc_inc(a.0).unwrap();
+        heap.rc_inc(b.0).unwrap();
+
+        // dropping the external references leaves the cycle alive
+        assert_eq!(Ok(1), heap.rc_dec(a.0));
+        assert_eq!(Ok(1), heap.rc_dec(b.0));
+        assert_eq!(2, heap.num_used_blocks());
+
+        let mut root: MockGcRoot<IntegerObject> = MockGcRoot::new(vec![]);
+        let mut roots: Vec<&mut dyn GcRoot<IntegerObject>> = vec![&mut root];
+        let stats = heap.gc(&mut roots[..]);
+
+        assert_eq!(2, stats.freed_blocks);
+        assert_eq!(0, heap.num_used_blocks());
+        assert_eq!(2, finalized.lock().unwrap().len());
+        assert_eq!(
+            Err(AccessError::NotReferenceCounted(a.0)),
+            heap.rc_count(a.0)
+        );
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_gc_does_not_free_blocks_released_by_rc_dec() {
+        let mut heap = ManagedHeap::new(1000);
+        let finalized = record_finalized(&mut heap);
+
+        let a = rc_node(&mut heap, 1, None);
+        assert_eq!(Ok(0), heap.rc_dec(a.0));
+
+        let mut root: MockGcRoot<IntegerObject> = MockGcRoot::new(vec![]);
+        let mut roots: Vec<&mut dyn GcRoot<IntegerObject>> = vec![&mut root];
+        let stats = heap.gc(&mut roots[..]);
+
+        assert_eq!(0, stats.freed_blocks);
+        assert_eq!(1, finalized.lock().unwrap().len());
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_interleaving_with_ordinary_allocations() {
+        let mut heap = ManagedHeap::new(1000);
+        let finalized = record_finalized(&mut heap);
+
+        let rc = rc_node(&mut heap, 1, None);
+        let list = list![&mut heap; 2, 3];
+        let garbage = list![&mut heap; 4];
+        let rc_garbage = rc_node(&mut heap, 5, Some(garbage));
+
+        assert_eq!(Ok(0), heap.rc_dec(rc.0));
+        assert_eq!(vec![rc.0], *finalized.lock().unwrap());
+
+        let mut root = MockGcRoot::new(vec![list]);
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        let stats = heap.gc(&mut roots[..]);
+
+        // rc_garbage and the ordinary node it references
+        assert_eq!(2, stats.freed_blocks);
+        assert_eq!(2, stats.live_blocks);
+        assert_eq!(vec![rc.0, rc_garbage.0], *finalized.lock().unwrap());
+        assert_eq!("[2, 3]", format!("{:?}", list));
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_rc_tests_pass_with_checksums() {
+        crate::heap::tests::run_with_checksums(&[
+            test_inc_dec_lifecycle,
+            test_gc_collects_cycle_with_nonzero_counts,
+            test_gc_does_not_free_blocks_released_by_rc_dec,
+            test_interleaving_with_ordinary_allocations,
+        ]);
+    }
+}
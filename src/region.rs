@@ -0,0 +1,277 @@
+use crate::address::Address;
+use crate::managed::ManagedHeap;
+use crate::types::HalfWord;
+
+use std::ops::Deref;
+
+/// A scope for temporary objects, which are all freed at once when the region
+/// is released or dropped.
+/// Objects allocated in a region are roots of every collection while the
+/// region lives, so they and everything they reference stay alive.
+/// Regions can be nested, the borrow of the heap makes sure that they are
+/// released in LIFO order.
+/// Collections and allocations of long lived objects go through heap().
+/// The objects of a region must not be freed manually.
+pub struct Region<'h> {
+    heap: &'h mut ManagedHeap,
+    depth: usize,
+}
+
+impl Region<'_> {
+    /// Allocates size words, which are freed together with the region
+    pub fn alloc(&mut self, size: HalfWord) -> Option<Address> {
+        let address = self.heap.alloc(size)?;
+        self.objects().push(address);
+        Some(address)
+    }
+
+    /// Opens a nested region, which has to be released before this one can
+    /// be used again
+    pub fn region(&mut self) -> Region<'_> {
+        self.heap.region()
+    }
+
+    /// Moves address out of this region into the enclosing one. Objects kept
+    /// by the outermost region become normal objects, which are managed by
+    /// the garbage collector.
+    /// Returns false if address was not allocated in this region.
+    pub fn keep(&mut self, address: Address) -> bool {
+        let objects = self.objects();
+        let index = match objects.iter().position(|&a| a == address) {
+            Some(index) => index,
+            None => return false,
+        };
+        objects.swap_remove(index);
+
+        if self.depth > 1 {
+            self.heap.regions[self.depth - 2].push(address);
+        }
+        true
+    }
+
+    /// The number of objects, which are freed by the release of this region
+    pub fn num_objects(&self) -> usize {
+        self.heap.regions[self.depth - 1].len()
+    }
+
+    /// The heap the region allocates from
+    pub fn heap(&mut self) -> &mut ManagedHeap {
+        self.heap
+    }
+
+    /// Frees all objects of the region (like dropping it)
+    pub fn release(self) {}
+
+    fn objects(&mut self) -> &mut Vec<Address> {
+        &mut self.heap.regions[self.depth - 1]
+    }
+}
+
+impl Drop for Region<'_> {
+    fn drop(&mut self) {
+        debug_assert_eq!(
+            self.depth,
+            self.heap.regions.len(),
+            "Regions released out of order"
+        );
+        let mut objects = self.heap.regions.pop().unwrap_or_default();
+        self.heap.free_batch(&mut objects);
+    }
+}
+
+/// A temporary object, which is freed when the guard is dropped, even if the
+/// drop is caused by a panic (see ManagedHeap::alloc_scoped).
+/// It is a region with a single object, so the object is a root of every
+/// collection while it lives and the guard borrows the heap mutably. The
+/// heap stays usable through heap(), an explicit queue of deferred frees is
+/// not needed.
+pub struct Scoped<'h> {
+    region: Region<'h>,
+    address: Address,
+}
+
+impl Scoped<'_> {
+    /// The heap the object was allocated from
+    pub fn heap(&mut self) -> &mut ManagedHeap {
+        self.region.heap()
+    }
+}
+
+impl Deref for Scoped<'_> {
+    type Target = Address;
+
+    fn deref(&self) -> &Address {
+        &self.address
+    }
+}
+
+impl ManagedHeap {
+    /// Opens a new region for temporary objects (see Region)
+    pub fn region(&mut self) -> Region<'_> {
+        self.regions.push(Vec::new());
+        Region {
+            depth: self.regions.len(),
+            heap: self,
+        }
+    }
+
+    /// Allocates a scratch object with size words, which is freed when the
+    /// returned guard is dropped (see Scoped)
+    pub fn alloc_scoped(&mut self, size: HalfWord) -> Option<Scoped<'_>> {
+        let mut region = self.region();
+        let address = region.alloc(size)?;
+        Some(Scoped { region, address })
+    }
+
+    /// The objects of all open regions, which have to be treated as roots
+    pub(crate) fn region_objects(&self) -> impl Iterator<Item = Address> + '_ {
+        self.regions.iter().flatten().copied()
+    }
+
+    pub(crate) fn in_region(&self, address: Address) -> bool {
+        self.region_objects().any(|a| a == address)
+    }
+
+    /// Frees addresses without calling the sweep callback, because the
+    /// objects are not freed by a collection
+    pub(crate) fn free_batch(&mut self, addresses: &mut Vec<Address>) {
+        let on_sweep = self.on_sweep.take();
+        self.free_all(addresses);
+        self.on_sweep = on_sweep;
+    }
+
+    /// Forgets the objects of all open regions, e.g. because they were freed
+    pub(crate) fn clear_regions(&mut self) {
+        for objects in self.regions.iter_mut() {
+            objects.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::trace::GcRoot;
+
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[test]
+    fn test_release_frees_all_objects() {
+        let mut heap = ManagedHeap::new(1000);
+        let long_lived = heap.alloc(2).unwrap();
+        let free_blocks = heap.num_free_blocks();
+
+        let mut region = heap.region();
+        for size in 1..5 {
+            region.alloc(size).unwrap();
+        }
+        region.heap().alloc(3).unwrap();
+        assert_eq!(4, region.num_objects());
+        region.release();
+
+        assert_eq!(2, heap.num_used_blocks());
+        assert!(heap.block_of(long_lived).is_some());
+        assert_eq!(free_blocks + 1, heap.num_free_blocks());
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_nested_regions_release_in_lifo_order() {
+        let mut heap = ManagedHeap::new(1000);
+
+        let mut outer = heap.region();
+        let a = outer.alloc(2).unwrap();
+        {
+            let mut inner = outer.region();
+            let b = inner.alloc(2).unwrap();
+            let c = inner.alloc(2).unwrap();
+            assert!(inner.keep(c));
+            assert!(!inner.keep(a));
+            assert_eq!(3, inner.heap().num_used_blocks());
+
+            drop(inner);
+            assert!(outer.heap().block_of(b).is_none());
+        }
+
+        // c belongs to the outer region now
+        assert_eq!(2, outer.num_objects());
+        assert!(outer.keep(a));
+        drop(outer);
+
+        assert_eq!(1, heap.num_used_blocks());
+        assert!(heap.block_of(a).is_some());
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_region_objects_are_roots() {
+        let mut heap = ManagedHeap::new(1000);
+        let mut region = heap.region();
+
+        let tail = list![region.heap(); 2, 3];
+        let head = LinkedList::new(region.heap(), 1, Some(tail));
+        let mut node = region.alloc(3).unwrap();
+        node.write(false as usize);
+        (node + 1).write(0);
+        (node + 2).write(head.0.into());
+        let _garbage = list![region.heap(); 4];
+
+        let mut root = MockGcRoot::new(Vec::new());
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        assert_eq!(1, region.heap().gc(&mut roots[..]).freed_blocks);
+        assert_eq!(0, region.heap().gc_shared::<LinkedList>(&[]).freed_blocks);
+        assert_eq!("[0, 1, 2, 3]", format!("{:?}", LinkedList(node)));
+
+        region.release();
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        assert_eq!(3, heap.gc(&mut roots[..]).freed_blocks);
+        assert_eq!(0, heap.num_used_blocks());
+    }
+
+    #[test]
+    fn test_scoped_allocation_is_freed_on_scope_exit() {
+        let mut heap = ManagedHeap::new(1000);
+        heap.alloc(2).unwrap();
+        let free_blocks = heap.num_free_blocks();
+
+        {
+            let mut tmp = heap.alloc_scoped(16).unwrap();
+            let address = *tmp;
+            (address + 15).write(7);
+            assert_eq!(Ok(7), tmp.heap().read(address, 15));
+            assert_eq!(2, tmp.heap().num_used_blocks());
+        }
+
+        assert_eq!(1, heap.num_used_blocks());
+        assert_eq!(free_blocks, heap.num_free_blocks());
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_scoped_allocation_survives_gc() {
+        let mut heap = ManagedHeap::new(1000);
+        let mut tmp = heap.alloc_scoped(3).unwrap();
+        let address = *tmp;
+        let _garbage = list![tmp.heap(); 1];
+
+        assert_eq!(1, tmp.heap().gc_addresses(&[], |_, _| {}).freed_blocks);
+        assert!(tmp.heap().block_of(address).is_some());
+
+        drop(tmp);
+        assert_eq!(0, heap.num_used_blocks());
+    }
+
+    #[test]
+    fn test_scoped_allocation_is_freed_on_panic() {
+        let mut heap = ManagedHeap::new(1000);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _tmp = heap.alloc_scoped(4).unwrap();
+            panic!("instruction failed");
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(0, heap.num_used_blocks());
+        assert_eq!(Ok(()), heap.verify());
+    }
+}
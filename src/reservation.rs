@@ -0,0 +1,177 @@
+use crate::address::Address;
+use crate::error::AllocError;
+use crate::managed::ManagedHeap;
+use crate::types::HalfWord;
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// The reservations of a heap, which are shared with the Reservation
+/// handles, so that they can be released without access to the heap.
+#[derive(Default)]
+pub(crate) struct ReservationTable {
+    held: HashSet<usize>,
+    released: Vec<Address>,
+}
+
+impl ReservationTable {
+    /// Checks if address is reserved and not claimed yet
+    pub(crate) fn is_held(&self, address: Address) -> bool {
+        self.held.contains(&address.into())
+    }
+}
+
+/// A block which is held back for a later allocation.
+/// The block is already allocated, but it is ignored by the garbage collector
+/// until it gets claimed. Dropping the reservation returns the block to the
+/// heap during the next allocation or collection.
+/// The reservation must not outlive the heap it was created by.
+pub struct Reservation {
+    address: Address,
+    table: Arc<Mutex<ReservationTable>>,
+}
+
+impl Reservation {
+    /// The address of the reserved block
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Converts the reservation into a normal allocation, which is managed by
+    /// the garbage collector from now on.
+    pub fn claim(self) -> Address {
+        lock(&self.table).held.remove(&self.address.into());
+        let address = self.address;
+        std::mem::forget(self);
+        address
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut table = lock(&self.table);
+        table.held.remove(&self.address.into());
+        table.released.push(self.address);
+    }
+}
+
+impl ManagedHeap {
+    /// Reserves a block for size words, which can't be used by any other
+    /// allocation until the reservation is claimed or dropped.
+    /// The payload of the block is zeroed.
+    pub fn reserve(&mut self, size: HalfWord) -> Result<Reservation, AllocError> {
+        let address = self.try_alloc(size)?;
+        for i in 0..size as usize {
+            (address + i).write(0);
+        }
+
+        lock(&self.reservations).held.insert(address.into());
+
+        Ok(Reservation {
+            address,
+            table: Arc::clone(&self.reservations),
+        })
+    }
+
+    /// The number of reservations, which are neither claimed nor dropped
+    pub fn num_reservations(&self) -> usize {
+        lock(&self.reservations).held.len()
+    }
+
+    /// Frees the blocks of all dropped reservations
+    pub(crate) fn release_reservations(&mut self) {
+        let released = std::mem::take(&mut lock(&self.reservations).released);
+        for address in released {
+            self.free(address);
+        }
+    }
+
+    /// Gives access to the reservations until the guard is dropped
+    pub(crate) fn reservations(&self) -> MutexGuard<'_, ReservationTable> {
+        lock(&self.reservations)
+    }
+}
+
+/// The lock is held while a collection runs user code (e.g. T::from), so
+/// it gets poisoned if that code panics. The table itself is never left in
+/// an inconsistent state, so the poison is ignored.
+fn lock(table: &Mutex<ReservationTable>) -> MutexGuard<'_, ReservationTable> {
+    table.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::header::BlockHeader;
+    use crate::testing::*;
+    use crate::trace::GcRoot;
+
+    // enough for one block of 60 words
+    const HEAP_SIZE: usize = 64 * crate::types::WORD_SIZE;
+
+    #[test]
+    fn test_small_allocations_do_not_consume_reserved_block() {
+        let mut heap = ManagedHeap::new(HEAP_SIZE);
+        let reservation = heap.reserve(60).unwrap();
+        assert_eq!(1, heap.num_reservations());
+
+        // the remaining space is too small for anything else
+        let mut small = 0;
+        while heap.alloc(1).is_some() {
+            small += 1;
+        }
+        assert!(small < 2);
+
+        let address = reservation.claim();
+        assert_eq!(0, heap.num_reservations());
+        assert_eq!(Ok(0), heap.read(address, 59));
+        assert!(heap.write(address, 59, 42).is_ok());
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_dropped_reservation_returns_block() {
+        let mut heap = ManagedHeap::new(HEAP_SIZE);
+        let reservation = heap.reserve(60).unwrap();
+        assert_eq!(None, heap.alloc(60));
+
+        drop(reservation);
+        assert_eq!(0, heap.num_reservations());
+        assert!(heap.alloc(60).is_some());
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_reserve_fails_without_free_block() {
+        let mut heap = ManagedHeap::new(HEAP_SIZE);
+        heap.set_large_object_threshold(BlockHeader::MAX_SIZE);
+
+        assert_eq!(Some(AllocError::OutOfMemory(100)), heap.reserve(100).err());
+        assert_eq!(
+            Some(AllocError::TooLarge(BlockHeader::MAX_SIZE)),
+            heap.reserve(BlockHeader::MAX_SIZE).err()
+        );
+    }
+
+    #[test]
+    fn test_gc_keeps_reserved_block_until_claimed() {
+        let mut heap = ManagedHeap::new(HEAP_SIZE);
+        let reservation = heap.reserve(10).unwrap();
+
+        let mut root: MockGcRoot<IntegerObject> = MockGcRoot::new(vec![]);
+        let mut roots: Vec<&mut dyn GcRoot<IntegerObject>> = vec![&mut root];
+
+        let stats = heap.gc(&mut roots[..]);
+        assert_eq!(0, stats.freed_blocks);
+        assert_eq!(1, stats.live_blocks);
+
+        let stats = heap.gc_addresses(&[], |_, _| {});
+        assert_eq!(0, stats.freed_blocks);
+
+        // after claiming it is a normal unreachable object
+        reservation.claim();
+        let stats = heap.gc(&mut roots[..]);
+        assert_eq!(1, stats.freed_blocks);
+        assert_eq!(0, heap.num_used_blocks());
+    }
+}
@@ -0,0 +1,342 @@
+use crate::address::Address;
+use crate::managed::ManagedHeap;
+use crate::stats::GcStats;
+use crate::trace::{DynGcRoot, GcRoot, Traceable};
+
+use std::collections::BTreeSet;
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// A root, which stays registered with a heap until it is unregistered (see
+/// ManagedHeap::register_root).
+/// The heap can be sent to other threads, so the root has to be shareable
+/// between threads as well.
+pub type SharedRoot = Arc<Mutex<dyn DynGcRoot + Send>>;
+
+/// The handle of a registered root
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RootId(u64);
+
+/// A root, whose children are stored in the payload of an object on the
+/// heap, e.g. the table of global variables of a VM. The slots are read
+/// during every collection, so the object can change between them, and
+/// written when the children move (see compact_region). Slots containing 0
+/// are skipped.
+/// The object itself is not one of the children, it has to be kept alive
+/// in another way, e.g. by staying reserved (see ManagedHeap::reserve).
+pub struct HeapSliceRoot<T> {
+    slots: Address,
+    len: usize,
+    children: PhantomData<T>,
+}
+
+impl<T> HeapSliceRoot<T> {
+    /// The root of the len words starting at offset (in words) inside the
+    /// payload of object.
+    ///
+    /// # Safety
+    /// T has to have the layout of an Address (e.g. a #[repr(transparent)]
+    /// wrapper), because the children are borrowed from the slots, and the
+    /// slots have to stay inside the payload of object while the root is
+    /// used.
+    pub unsafe fn new(object: Address, offset: usize, len: usize) -> Self {
+        assert_eq!(
+            mem::size_of::<T>(),
+            mem::size_of::<Address>(),
+            "The children of a HeapSliceRoot have to be addresses"
+        );
+        HeapSliceRoot {
+            slots: object + offset,
+            len,
+            children: PhantomData,
+        }
+    }
+
+    fn used_slots(&self) -> impl Iterator<Item = Address> {
+        let slots = self.slots;
+        (0..self.len)
+            .map(move |i| slots + i)
+            .filter(|&slot| *slot != 0)
+    }
+}
+
+unsafe impl<T> GcRoot<T> for HeapSliceRoot<T>
+where
+    T: Traceable + From<Address>,
+{
+    fn children<'a>(&'a mut self) -> Box<dyn Iterator<Item = &'a mut T> + 'a> {
+        Box::new(
+            self.used_slots()
+                .map(|slot| unsafe { &mut *(usize::from(slot) as *mut T) }),
+        )
+    }
+}
+
+unsafe impl<T> DynGcRoot for HeapSliceRoot<T> {
+    fn visit(&self, visitor: &mut dyn FnMut(Address)) {
+        for slot in self.used_slots() {
+            visitor(Address::from(*slot));
+        }
+    }
+}
+
+// the slots are owned by the heap, which can be sent to other threads
+unsafe impl<T> Send for HeapSliceRoot<T> {}
+
+impl ManagedHeap {
+    /// Registers a root, which is used by every following gc_registered and
+    /// gc_begin (including gc_with_budget) in addition to the roots passed
+    /// to them, e.g. for a globals table owned by another module.
+    /// A root registered during an incremental collection is scanned
+    /// immediately, so its objects survive the collection.
+    pub fn register_root(&mut self, root: SharedRoot) -> RootId {
+        let id = RootId(self.next_root_id);
+        self.next_root_id += 1;
+
+        if self.incremental.is_some() {
+            for address in Self::addresses_of(&root) {
+                self.record_root(address);
+            }
+        }
+
+        self.registered_roots.push((id, root));
+        id
+    }
+
+    /// Removes a registered root and returns false if there is none for id.
+    /// An active incremental collection is not aborted: the objects, which
+    /// were reachable from the root when the collection began, survive it
+    /// and are freed by the next collection.
+    pub fn unregister_root(&mut self, id: RootId) -> bool {
+        let index = self.registered_roots.iter().position(|(i, _)| *i == id);
+        if let Some(index) = index {
+            self.registered_roots.remove(index);
+        }
+        index.is_some()
+    }
+
+    /// The number of registered roots
+    pub fn num_registered_roots(&self) -> usize {
+        self.registered_roots.len()
+    }
+
+    /// Like gc_shared, but with the registered roots in addition to roots.
+    /// Traceable::trace_with is used to find the children of an object.
+    pub fn gc_registered<T>(&mut self, roots: &mut [&mut dyn GcRoot<T>]) -> GcStats
+    where
+        T: Traceable + From<Address> + Into<Address> + Copy,
+    {
+        self.gc_abort();
+        let mut root_addresses: Vec<Address> = roots
+            .iter_mut()
+            .flat_map(|r| r.children())
+            .map(|t| (*t).into())
+            .collect();
+        root_addresses.extend(self.registered_root_addresses());
+
+        self.gc_addresses(&root_addresses, |address, children| {
+            T::from(address).trace_with(&mut |child| children(*child));
+        })
+    }
+
+    /// The objects of all registered roots
+    pub(crate) fn registered_root_addresses(&self) -> Vec<Address> {
+        self.registered_roots
+            .iter()
+            .flat_map(|(_, root)| Self::addresses_of(root))
+            .collect()
+    }
+
+    /// The objects the registered roots point into. Their addresses can't be
+    /// updated, so they must not be moved (see compact_region).
+    pub(crate) fn registered_root_objects(&self) -> BTreeSet<Address> {
+        self.registered_root_addresses()
+            .into_iter()
+            .filter_map(|address| self.heap.block_of(address))
+            .map(Address::from)
+            .collect()
+    }
+
+    fn addresses_of(root: &SharedRoot) -> Vec<Address> {
+        // a root, which panicked while it was locked, still knows its objects
+        let root = root.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut addresses = Vec::new();
+        root.visit(&mut |address| addresses.push(address));
+        addresses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reservation::Reservation;
+    use crate::testing::*;
+    use crate::types::{HalfWord, WORD_SIZE};
+
+    struct Globals(Vec<Address>);
+
+    unsafe impl DynGcRoot for Globals {
+        fn visit(&self, visitor: &mut dyn FnMut(Address)) {
+            self.0.iter().copied().for_each(visitor);
+        }
+    }
+
+    fn shared(objects: Vec<Address>) -> SharedRoot {
+        Arc::new(Mutex::new(Globals(objects)))
+    }
+
+    #[test]
+    fn test_registered_and_ad_hoc_roots_keep_objects_alive() {
+        let mut heap = ManagedHeap::new(1000);
+        let globals = list![&mut heap; 1, 2];
+        let strings = list![&mut heap; 3];
+        let stack = list![&mut heap; 4];
+        list![&mut heap; 5];
+
+        heap.register_root(shared(vec![globals.0]));
+        let strings_id = heap.register_root(shared(vec![strings.0]));
+        assert_eq!(2, heap.num_registered_roots());
+
+        let mut root = MockGcRoot::new(vec![stack]);
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        assert_eq!(1, heap.gc_registered(&mut roots[..]).freed_blocks);
+        assert_eq!("[1, 2]", format!("{:?}", globals));
+
+        assert!(heap.unregister_root(strings_id));
+        assert!(!heap.unregister_root(strings_id));
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        assert_eq!(1, heap.gc_registered(&mut roots[..]).freed_blocks);
+        assert!(heap.block_of(strings.0).is_none());
+        assert_eq!(3, heap.num_used_blocks());
+    }
+
+    #[test]
+    fn test_roots_changing_during_incremental_collection() {
+        let mut heap = ManagedHeap::new(1000);
+        let old = list![&mut heap; 1];
+        let new = list![&mut heap; 2];
+        let old_id = heap.register_root(shared(vec![old.0]));
+
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![];
+        heap.gc_begin(&mut roots[..]);
+        assert!(heap.unregister_root(old_id));
+        heap.register_root(shared(vec![new.0]));
+
+        // the unregistered root was scanned by gc_begin
+        assert_eq!(0, heap.gc_finish().freed_blocks);
+        assert_eq!(1, heap.gc_registered(&mut roots[..]).freed_blocks);
+        assert!(heap.block_of(old.0).is_none());
+        assert!(heap.block_of(new.0).is_some());
+    }
+
+    /// A reserved object with [mark, value, next] set to zero, so it is an
+    /// empty list node for the tracing of compact_region, followed by the
+    /// slots
+    fn globals(
+        heap: &mut ManagedHeap,
+        slots: &[Option<LinkedList>],
+    ) -> (Reservation, HeapSliceRoot<LinkedList>) {
+        let reservation = heap.reserve(3 + slots.len() as HalfWord).unwrap();
+        let object = reservation.address();
+        for (i, slot) in slots.iter().enumerate() {
+            (object + 3 + i).write(Address::encode_option(slot.map(|l| l.0)));
+        }
+        let root = unsafe { HeapSliceRoot::new(object, 3, slots.len()) };
+        (reservation, root)
+    }
+
+    #[test]
+    fn test_slots_in_heap_object_keep_objects_alive() {
+        let mut heap = ManagedHeap::new(1000);
+        let first = list![&mut heap; 1, 2];
+        let second = list![&mut heap; 3];
+        let stack = list![&mut heap; 4];
+        list![&mut heap; 5];
+        let (reservation, mut globals) = globals(&mut heap, &[Some(first), Some(second)]);
+
+        let mut root = MockGcRoot::new(vec![stack]);
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut globals, &mut root];
+        assert_eq!(1, heap.gc(&mut roots[..]).freed_blocks);
+        assert_eq!("[1, 2]", format!("{:?}", first));
+
+        // an empty slot doesn't keep anything alive
+        (reservation.address() + 4).write(0);
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut globals, &mut root];
+        assert_eq!(1, heap.gc(&mut roots[..]).freed_blocks);
+        assert!(heap.block_of(second.0).is_none());
+        assert!(heap.block_of(stack.0).is_some());
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_compaction_rewrites_slots() {
+        let mut heap = ManagedHeap::new(200 * WORD_SIZE);
+        let filler = heap.alloc(50).unwrap();
+        let (reservation, mut globals) = globals(&mut heap, &[None]);
+        heap.alloc(60).unwrap();
+        // both nodes lie in the second half of the heap
+        let list = list![&mut heap; 1, 2];
+        heap.free(filler);
+
+        let mut slot = reservation.address() + 3;
+        slot.write(list.0.into());
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut globals];
+        assert_eq!(2, heap.compact_region(2, 1, &mut roots[..]));
+
+        let moved = LinkedList(Address::from(*slot));
+        assert!(heap.offset_of(moved.0) < 100);
+        assert_eq!("[1, 2]", format!("{:?}", moved));
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_heap_slice_root_can_be_registered() {
+        let mut heap = ManagedHeap::new(1000);
+        let kept = list![&mut heap; 1];
+        list![&mut heap; 2];
+        let (_reservation, globals) = globals(&mut heap, &[Some(kept)]);
+
+        heap.register_root(Arc::new(Mutex::new(globals)));
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![];
+        assert_eq!(1, heap.gc_registered(&mut roots[..]).freed_blocks);
+        assert!(heap.block_of(kept.0).is_some());
+    }
+
+    #[test]
+    fn test_objects_of_registered_roots_are_not_moved() {
+        let mut heap = ManagedHeap::new(200 * WORD_SIZE);
+        let filler = heap.alloc(99).unwrap();
+        // both lists lie in the second half of the heap
+        let registered = list![&mut heap; 1, 2];
+        let other = list![&mut heap; 3];
+        heap.free(filler);
+        heap.register_root(shared(vec![registered.0]));
+
+        assert_eq!(None, heap.relocate(registered.0, None));
+
+        let mut root = MockGcRoot::new(vec![other]);
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        // only the tail of the registered list and the other list move
+        assert_eq!(2, heap.compact_region(2, 1, &mut roots[..]));
+        assert!(heap.offset_of(registered.0) >= 100);
+        assert!(heap.offset_of(registered.next().unwrap().0) < 100);
+        assert!(heap.offset_of(root.used_elems[0].0) < 100);
+
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+        assert_eq!(0, heap.gc_registered(&mut roots[..]).freed_blocks);
+        assert_eq!("[1, 2]", format!("{:?}", registered));
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't migrate a heap with registered roots")]
+    fn test_migrate_with_registered_roots_panics() {
+        let mut heap = ManagedHeap::new(1000);
+        let globals = list![&mut heap; 1];
+        heap.register_root(shared(vec![globals.0]));
+
+        let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![];
+        heap.migrate(1000, &mut roots[..]);
+    }
+}
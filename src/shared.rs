@@ -0,0 +1,199 @@
+use crate::address::Address;
+use crate::error::{AccessError, CorruptionError};
+use crate::managed::ManagedHeap;
+use crate::stats::GcStats;
+use crate::trace::{GcRoot, Traceable};
+use crate::types::HalfWord;
+
+use std::sync::{Mutex, MutexGuard};
+
+/// A ManagedHeap which can be shared between threads.
+/// Every operation locks the heap internally. The lock is only held while
+/// the bookkeeping of the heap is changed, so initializing the payload through
+/// the returned Address happens without holding the lock.
+pub struct SharedManagedHeap {
+    heap: Mutex<ManagedHeap>,
+}
+
+impl SharedManagedHeap {
+    /// Expects the heap size in bytes.
+    pub fn new(size: usize) -> Self {
+        SharedManagedHeap::from(ManagedHeap::new(size))
+    }
+
+    /// Gives exclusive access to the underlying heap until the guard is
+    /// dropped.
+    pub fn lock(&self) -> MutexGuard<'_, ManagedHeap> {
+        self.heap.lock().expect("Heap lock poisoned")
+    }
+}
+
+impl SharedManagedHeap {
+    /// See ManagedHeap::alloc
+    pub fn alloc(&self, size: HalfWord) -> Option<Address> {
+        self.lock().alloc(size)
+    }
+
+    /// See ManagedHeap::free
+    pub fn free(&self, address: Address) {
+        self.lock().free(address);
+    }
+
+    /// See ManagedHeap::read
+    pub fn read(&self, address: Address, offset: usize) -> Result<usize, AccessError> {
+        self.lock().read(address, offset)
+    }
+
+    /// See ManagedHeap::write
+    pub fn write(&self, address: Address, offset: usize, value: usize) -> Result<(), AccessError> {
+        self.lock().write(address, offset, value)
+    }
+
+    /// Runs the garbage collector while holding the lock for the whole
+    /// collection. All other threads have to be paused and their objects
+    /// have to be part of roots, otherwise they get freed.
+    pub fn gc<T>(&self, roots: &mut [&mut dyn GcRoot<T>]) -> GcStats
+    where
+        T: Traceable + From<Address>,
+    {
+        self.lock().gc(roots)
+    }
+
+    /// See ManagedHeap::verify
+    pub fn verify(&self) -> Result<(), CorruptionError> {
+        self.lock().verify()
+    }
+}
+
+impl From<ManagedHeap> for SharedManagedHeap {
+    fn from(heap: ManagedHeap) -> Self {
+        SharedManagedHeap {
+            heap: Mutex::new(heap),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::Leaf;
+    use std::sync::Arc;
+    use std::thread;
+
+    struct Object(Address);
+
+    unsafe impl Leaf for Object {
+        fn address(&self) -> Address {
+            self.0
+        }
+    }
+
+    impl From<Address> for Object {
+        fn from(address: Address) -> Self {
+            Object(address)
+        }
+    }
+
+    impl From<Object> for Address {
+        fn from(value: Object) -> Address {
+            value.0
+        }
+    }
+
+    struct VecRoot(Vec<Object>);
+
+    unsafe impl GcRoot<Object> for VecRoot {
+        fn children<'a>(&'a mut self) -> Box<dyn Iterator<Item = &'a mut Object> + 'a> {
+            Box::new(self.0.iter_mut())
+        }
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_shared_heap_is_send_and_sync() {
+        assert_send_sync::<SharedManagedHeap>();
+    }
+
+    #[test]
+    fn test_concurrent_alloc_and_free() {
+        let heap = Arc::new(SharedManagedHeap::new(64 * 1024));
+
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let heap = Arc::clone(&heap);
+                thread::spawn(move || {
+                    let mut owned = Vec::new();
+
+                    for i in 0..500 {
+                        let size = (i % 7 + 1) as HalfWord;
+                        if let Some(address) = heap.alloc(size) {
+                            // the payload is initialized without the lock
+                            for offset in 0..size as usize {
+                                (address + offset).write(t * 1000 + offset);
+                            }
+                            owned.push((address, size));
+                        }
+
+                        if i % 3 == 0 && !owned.is_empty() {
+                            let (address, _) = owned.remove(owned.len() / 2);
+                            heap.free(address);
+                        }
+                    }
+
+                    for (address, size) in &owned {
+                        for offset in 0..*size as usize {
+                            assert_eq!(Ok(t * 1000 + offset), heap.read(*address, offset));
+                        }
+                    }
+
+                    owned.len()
+                })
+            })
+            .collect();
+
+        let remaining: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+
+        assert_eq!(Ok(()), heap.verify());
+        assert_eq!(remaining, heap.lock().num_used_blocks());
+    }
+
+    #[test]
+    fn test_gc_reclaims_garbage_from_other_threads() {
+        let heap = Arc::new(SharedManagedHeap::new(16 * 1024));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let heap = Arc::clone(&heap);
+                thread::spawn(move || {
+                    let addresses: Vec<Address> = (0..10)
+                        .map(|_| {
+                            let address = heap.alloc(2).unwrap();
+                            heap.write(address, 0, false as usize).unwrap();
+                            address
+                        })
+                        .collect();
+
+                    // only every second object stays alive
+                    addresses.into_iter().step_by(2).collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let live: Vec<Object> = threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .map(Object)
+            .collect();
+
+        assert_eq!(40, heap.lock().num_used_blocks());
+
+        let mut root = VecRoot(live);
+        let mut roots: Vec<&mut dyn GcRoot<Object>> = vec![&mut root];
+        let stats = heap.gc(&mut roots[..]);
+
+        assert_eq!(20, stats.freed_blocks);
+        assert_eq!(20, stats.live_blocks);
+        assert_eq!(Ok(()), heap.verify());
+    }
+}
@@ -0,0 +1,322 @@
+use crate::address::Address;
+use crate::types::{HalfWord, WORD_SIZE};
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// The kind of collection, which produced a GcStats.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum GcKind {
+    /// A stop the world collection of the whole heap
+    #[default]
+    Full,
+    /// A collection of the young objects (see ManagedHeap::minor_gc)
+    Minor,
+    /// An incremental collection, which was finished by gc_finish
+    Incremental,
+}
+
+/// Statistics about a single garbage collection.
+/// All sizes are in words and include the block headers.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// The number of words which were returned to the free list
+    pub freed_words: usize,
+    /// The number of blocks which were freed
+    pub freed_blocks: usize,
+    /// The number of words still in use after the collection
+    pub live_words: usize,
+    /// The number of blocks still in use after the collection
+    pub live_blocks: usize,
+    /// The number of dirty cards scanned by a minor collection
+    pub scanned_cards: usize,
+    /// The epoch of the collection (see ManagedHeap::current_epoch)
+    pub epoch: u64,
+    pub kind: GcKind,
+    /// The time the whole collection took
+    pub duration: Duration,
+    /// The time spent marking (and tracing) the live objects
+    pub mark_duration: Duration,
+    /// The time spent freeing the unmarked objects
+    pub sweep_duration: Duration,
+    /// The time spent unmarking the survivors. Collections which store the
+    /// marks in the block headers don't need to unmark.
+    pub unmark_duration: Duration,
+    /// The number of objects, which were marked or traced by the collector
+    /// itself. Objects marked by Traceable::mark of another object are not
+    /// counted.
+    pub mark_visited: usize,
+    /// The number of objects, which were checked by the sweep
+    pub sweep_visited: usize,
+    /// The number of objects, which were unmarked
+    pub unmark_visited: usize,
+    /// The slowest trace of a single object, if trace timing is enabled
+    /// (see ManagedHeap::set_trace_timing)
+    pub slowest_trace: Option<TraceTiming>,
+    /// The number of objects, which were kept, because T::from or
+    /// Traceable::is_marked panicked for them
+    /// (see ManagedHeap::set_skip_unclassifiable)
+    pub unclassifiable: usize,
+}
+
+/// A single call of a trace function (see GcStats::slowest_trace).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TraceTiming {
+    /// The object which was traced
+    pub address: Address,
+    pub duration: Duration,
+}
+
+impl GcStats {
+    /// The fraction of the words, which were in use before the collection
+    /// and got freed by it (0 if the heap was empty)
+    pub fn reclaim_ratio(&self) -> f64 {
+        let total = self.freed_words + self.live_words;
+        if total == 0 {
+            0.0
+        } else {
+            self.freed_words as f64 / total as f64
+        }
+    }
+
+    /// Keeps the trace of address, if it was slower than all previous ones
+    pub(crate) fn record_trace(&mut self, address: Address, duration: Duration) {
+        if self.slowest_trace.is_none_or(|t| duration > t.duration) {
+            self.slowest_trace = Some(TraceTiming { address, duration });
+        }
+    }
+}
+
+/// The result of a garbage collection dry-run (see ManagedHeap::gc_estimate).
+/// All sizes are in words and include the block headers.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcEstimate {
+    /// The number of words a real collection would free
+    pub reclaimable_words: usize,
+    /// The number of blocks a real collection would free
+    pub reclaimable_blocks: usize,
+    /// The number of words which are still reachable
+    pub live_words: usize,
+    /// The number of blocks which are still reachable
+    pub live_blocks: usize,
+}
+
+/// The live objects of a heap grouped by class (see ManagedHeap::census).
+/// All sizes are in words and include the block headers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Census {
+    /// The epoch of the heap when the census was taken
+    pub epoch: u64,
+    pub classes: BTreeMap<u32, ClassStats>,
+}
+
+impl Census {
+    /// The stats of class (zero if there is no object of the class)
+    pub fn get(&self, class: u32) -> ClassStats {
+        self.classes.get(&class).copied().unwrap_or_default()
+    }
+
+    pub fn total_objects(&self) -> usize {
+        self.classes.values().map(|c| c.objects).sum()
+    }
+
+    pub fn total_words(&self) -> usize {
+        self.classes.values().map(|c| c.words).sum()
+    }
+}
+
+/// The objects of a single class in a Census
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClassStats {
+    pub objects: usize,
+    pub words: usize,
+}
+
+/// The used blocks which were allocated with the same tag or context
+/// (see ManagedHeap::leak_report and ManagedHeap::contexts_report).
+/// All sizes are in words and include the block headers.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TagStats {
+    /// The number of used blocks with the tag or context
+    pub blocks: usize,
+    /// The number of words used by those blocks
+    pub words: usize,
+}
+
+/// The usage of a region of the heap (see ManagedHeap::fragmentation_map).
+/// Blocks are counted in the region their header lies in, words are counted
+/// in the region they lie in.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegionStats {
+    /// The offset of the region in words from the start of the heap
+    pub start: usize,
+    /// The size of the region in words
+    pub words: usize,
+    /// The number of words in used blocks, including the block headers
+    pub used_words: usize,
+    /// The number of words in free blocks
+    pub free_words: usize,
+    /// The number of used blocks starting in the region
+    pub used_blocks: usize,
+    /// The number of free blocks starting in the region
+    pub free_blocks: usize,
+}
+
+/// The state of a granule of words (see ManagedHeap::occupancy_bitmap).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CellState {
+    /// All words of the granule are in free blocks
+    Free,
+    /// All words of the granule are in used blocks
+    Used,
+    /// The granule contains used and free words
+    Mixed,
+}
+
+impl CellState {
+    pub(crate) fn merge(self, other: CellState) -> CellState {
+        if self == other {
+            self
+        } else {
+            CellState::Mixed
+        }
+    }
+}
+
+/// A block of the heap (see ManagedHeap::blocks).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BlockInfo {
+    /// The address of the payload
+    pub address: Address,
+    /// The offset of the block header in words from the start of the heap
+    pub offset: usize,
+    /// The size of the block in words, including the header
+    pub size: usize,
+    pub used: bool,
+}
+
+/// A free block (see ManagedHeap::free_list_summary).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FreeBlockInfo {
+    /// The offset of the block header in words from the start of the heap
+    pub offset: usize,
+    /// The size of the block in words, including the header
+    pub size: usize,
+}
+
+/// The free blocks of the heap (see ManagedHeap::free_list_summary).
+/// All sizes are in words and include the block headers.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FreeListSummary {
+    /// The free blocks in address order
+    pub blocks: Vec<FreeBlockInfo>,
+    pub count: usize,
+    /// The size of all free blocks together
+    pub total_words: usize,
+    /// The size of the largest free block (0 if there is none)
+    pub largest: usize,
+    /// The size of the smallest free block (0 if there is none)
+    pub smallest: usize,
+    /// The mean size of the free blocks (0 if there is none)
+    pub mean: f64,
+}
+
+/// Why an allocation of the heap failed (see ManagedHeap::explain_alloc_failure).
+/// All sizes are in words and include the block headers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AllocFailureReason {
+    /// The free blocks would be big enough together, but none of them is
+    /// big enough on its own
+    Fragmentation {
+        needed: usize,
+        largest: usize,
+        total: usize,
+    },
+    /// All free blocks together are smaller than the allocation
+    InsufficientSpace { needed: usize, total: usize },
+    /// The size can't be stored in a block header
+    TooLarge { needed: usize, max: usize },
+}
+
+/// An allocation, which ran the compactor (see
+/// ManagedHeap::set_defrag_policy)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DefragReport {
+    /// The requested size in words
+    pub size: HalfWord,
+    /// Why the allocation failed before the compaction
+    pub reason: AllocFailureReason,
+    /// The number of objects moved by the compactor
+    pub moved: usize,
+    /// Whether the allocation succeeded after the compaction
+    pub succeeded: bool,
+}
+
+/// How often freed blocks were merged with their free neighbours since the
+/// heap was created. Every merge is counted once, in exactly one field.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CoalesceStats {
+    /// Merges with the preceding block only
+    pub with_pred: usize,
+    /// Merges with the following block only
+    pub with_next: usize,
+    /// Merges with both neighbours at once
+    pub three_way: usize,
+}
+
+impl CoalesceStats {
+    /// The number of all merges
+    pub fn total(&self) -> usize {
+        self.with_pred + self.with_next + self.three_way
+    }
+}
+
+/// A snapshot of the current state of a ManagedHeap.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// The size of the heap in words, including all segments
+    pub total_words: usize,
+    /// The size in words the heap can grow to without adding segments
+    pub max_words: usize,
+    /// The number of memory regions the heap consists of
+    pub segments: usize,
+    /// The number of words in use, including the block headers
+    pub used_words: usize,
+    /// The number of used words, which weren't requested, because the rest
+    /// of a free block was too small to be split off
+    pub slack_words: usize,
+    /// The number of used words, which weren't requested, because the sizes
+    /// were rounded up (see ManagedHeap::set_size_rounding)
+    pub rounding_words: usize,
+    /// The number of blocks in use
+    pub used_blocks: usize,
+    /// The number of free blocks
+    pub free_blocks: usize,
+    /// The number of objects in the large object space
+    pub large_objects: usize,
+    /// The size of all large objects in bytes, including their headers
+    pub large_object_bytes: usize,
+    pub coalesce: CoalesceStats,
+}
+
+impl HeapStats {
+    /// total_words in bytes
+    pub fn total_bytes(&self) -> usize {
+        self.total_words * WORD_SIZE
+    }
+
+    /// max_words in bytes
+    pub fn max_bytes(&self) -> usize {
+        self.max_words * WORD_SIZE
+    }
+
+    /// used_words in bytes
+    pub fn used_bytes(&self) -> usize {
+        self.used_words * WORD_SIZE
+    }
+
+    /// large_object_bytes in words
+    pub fn large_object_words(&self) -> usize {
+        self.large_object_bytes / WORD_SIZE
+    }
+}
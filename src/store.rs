@@ -0,0 +1,529 @@
+//! The bookkeeping of the free blocks of a heap, which can be replaced to
+//! experiment with other allocation strategies (see
+//! ManagedHeap::with_block_store).
+//!
+//! Heaps keep their free blocks in a SizeAddressIndex by default. Setting
+//! the environment variable MANAGED_HEAP_STORE to bins or set runs the unit
+//! tests of the crate against SizeClassBins or BlockSet:
+//! ```text
+//! MANAGED_HEAP_STORE=bins cargo test --lib
+//! ```
+
+pub use crate::block::set::BlockSet;
+pub use crate::block::{Block, ByAddress, BySize};
+use crate::heap::FitPolicy;
+use crate::types::HalfWord;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter;
+
+/// The free blocks of a heap.
+/// Blocks are identified by their address. The heap may change the size of
+/// a block while it is stored, but tells the store about it (see replace).
+pub trait BlockStore: Send {
+    /// Inserts block and returns true, unless the store already contains a
+    /// block at the same address
+    fn add(&mut self, block: Block) -> bool;
+
+    /// Removes a block with at least min_size words, which satisfies
+    /// predicate. With FitPolicy::FirstFitLowAddress this is the block with
+    /// the lowest address, with FitPolicy::BestFit the smallest block (the
+    /// lowest one of equal sizes). The heap implements
+    /// FitPolicy::PreserveWilderness with BestFit.
+    fn take_fit(
+        &mut self,
+        min_size: HalfWord,
+        policy: FitPolicy,
+        predicate: &dyn Fn(&Block) -> bool,
+    ) -> Option<Block>;
+
+    /// Removes the block at the address of block and returns false if there
+    /// is none
+    fn remove(&mut self, block: Block) -> bool;
+
+    fn contains(&self, block: Block) -> bool;
+
+    /// All blocks in address order
+    fn iter(&self) -> Box<dyn Iterator<Item = Block> + '_>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// An empty store of the same kind, e.g. for a copy of the heap
+    fn new_empty(&self) -> Box<dyn BlockStore>;
+
+    /// The block with the lowest address
+    fn first(&self) -> Option<Block> {
+        self.iter().next()
+    }
+
+    /// The block whose memory contains ptr
+    fn find_containing(&self, ptr: usize) -> Option<Block> {
+        self.iter().find(|b| b.contains(ptr))
+    }
+
+    /// Replaces the stored block at the address of old with block. The
+    /// header of old may already have been overwritten, e.g. because the
+    /// block grew in place (then old and block are the same).
+    fn replace(&mut self, old: Block, block: Block) {
+        self.remove(old);
+        self.add(block);
+    }
+}
+
+impl BlockStore for BlockSet {
+    fn add(&mut self, block: Block) -> bool {
+        self.add_block(block)
+    }
+
+    fn take_fit(
+        &mut self,
+        min_size: HalfWord,
+        policy: FitPolicy,
+        predicate: &dyn Fn(&Block) -> bool,
+    ) -> Option<Block> {
+        match policy {
+            FitPolicy::FirstFitLowAddress => self.get_first_fit(min_size, predicate),
+            _ => self.get_best_fit(min_size, predicate),
+        }
+    }
+
+    fn remove(&mut self, block: Block) -> bool {
+        let contained = BlockSet::contains(self, block);
+        self.remove_block(block);
+        contained
+    }
+
+    fn contains(&self, block: Block) -> bool {
+        BlockSet::contains(self, block)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Block> + '_> {
+        Box::new(BlockSet::iter(self).copied())
+    }
+
+    fn len(&self) -> usize {
+        BlockSet::len(self)
+    }
+
+    fn new_empty(&self) -> Box<dyn BlockStore> {
+        Box::new(BlockSet::default())
+    }
+
+    fn first(&self) -> Option<Block> {
+        BlockSet::first(self)
+    }
+
+    fn find_containing(&self, ptr: usize) -> Option<Block> {
+        BlockSet::find_containing(self, ptr)
+    }
+
+    fn replace(&mut self, old: Block, block: Block) {
+        BlockSet::replace(self, old, block);
+    }
+}
+
+/// Segregated free lists: every bin holds the blocks with a size in
+/// [2^i, 2^(i+1)) in address order, so a fit is only searched in the bins,
+/// which can contain one.
+#[derive(Default)]
+pub struct SizeClassBins {
+    bins: Vec<Vec<Block>>,
+    len: usize,
+}
+
+impl SizeClassBins {
+    fn class(size: HalfWord) -> usize {
+        (HalfWord::BITS - size.max(1).leading_zeros() - 1) as usize
+    }
+
+    /// The bin and index of the block at the address of block
+    fn position(&self, block: Block) -> Option<(usize, usize)> {
+        self.bins.iter().enumerate().find_map(|(class, bin)| {
+            Some((
+                class,
+                bin.binary_search_by_key(&ByAddress(block), |&b| ByAddress(b))
+                    .ok()?,
+            ))
+        })
+    }
+}
+
+impl BlockStore for SizeClassBins {
+    fn add(&mut self, block: Block) -> bool {
+        if self.position(block).is_some() {
+            return false;
+        }
+
+        let class = Self::class(block.size());
+        if self.bins.len() <= class {
+            self.bins.resize_with(class + 1, Vec::new);
+        }
+        let bin = &mut self.bins[class];
+        let index = bin.partition_point(|&b| ByAddress(b) < ByAddress(block));
+        bin.insert(index, block);
+        self.len += 1;
+        true
+    }
+
+    fn take_fit(
+        &mut self,
+        min_size: HalfWord,
+        policy: FitPolicy,
+        predicate: &dyn Fn(&Block) -> bool,
+    ) -> Option<Block> {
+        let first_class = Self::class(min_size).min(self.bins.len());
+        let candidates = self.bins[first_class..]
+            .iter()
+            .enumerate()
+            .flat_map(|(class, bin)| {
+                let fits = bin
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, b)| b.size() >= min_size && predicate(b));
+                iter::repeat(class + first_class).zip(fits)
+            });
+
+        let (class, index) = match policy {
+            FitPolicy::FirstFitLowAddress => candidates.min_by_key(|(_, (_, &b))| ByAddress(b)),
+            _ => candidates.min_by_key(|(_, (_, &b))| BySize(b)),
+        }
+        .map(|(class, (index, _))| (class, index))?;
+
+        self.len -= 1;
+        Some(self.bins[class].remove(index))
+    }
+
+    fn remove(&mut self, block: Block) -> bool {
+        match self.position(block) {
+            Some((class, index)) => {
+                self.bins[class].remove(index);
+                self.len -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn contains(&self, block: Block) -> bool {
+        let class = Self::class(block.size());
+        let in_class = self.bins.get(class).is_some_and(|bin| {
+            bin.binary_search_by_key(&ByAddress(block), |&b| ByAddress(b))
+                .is_ok()
+        });
+        in_class || self.position(block).is_some()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Block> + '_> {
+        let mut blocks: Vec<Block> = self.bins.iter().flatten().copied().collect();
+        blocks.sort_unstable_by_key(|&b| ByAddress(b));
+        Box::new(blocks.into_iter())
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn new_empty(&self) -> Box<dyn BlockStore> {
+        Box::new(SizeClassBins::default())
+    }
+
+    fn first(&self) -> Option<Block> {
+        self.bins
+            .iter()
+            .filter_map(|bin| bin.first().copied())
+            .min_by_key(|&b| ByAddress(b))
+    }
+
+    fn find_containing(&self, ptr: usize) -> Option<Block> {
+        self.bins.iter().find_map(|bin| {
+            let index = bin.partition_point(|b| b.as_ptr() as usize <= ptr);
+            let block = *bin.get(index.checked_sub(1)?)?;
+            Some(block).filter(|b| b.contains(ptr))
+        })
+    }
+}
+
+/// Two indices over the same blocks: one ordered by address for the
+/// neighbour and containment queries and one ordered by size (and address)
+/// for the fit queries, so a best fit is found with a single range query.
+#[derive(Default)]
+pub struct SizeAddressIndex {
+    // the size is the one the block had when it was added, because the heap
+    // may overwrite the header before it removes the block
+    by_address: BTreeMap<usize, (Block, HalfWord)>,
+    by_size: BTreeSet<(HalfWord, usize)>,
+}
+
+impl BlockStore for SizeAddressIndex {
+    fn add(&mut self, block: Block) -> bool {
+        let address = block.as_ptr() as usize;
+        if self.by_address.contains_key(&address) {
+            return false;
+        }
+
+        self.by_address.insert(address, (block, block.size()));
+        self.by_size.insert((block.size(), address));
+        true
+    }
+
+    fn take_fit(
+        &mut self,
+        min_size: HalfWord,
+        policy: FitPolicy,
+        predicate: &dyn Fn(&Block) -> bool,
+    ) -> Option<Block> {
+        let block = match policy {
+            FitPolicy::FirstFitLowAddress => {
+                self.by_address
+                    .values()
+                    .find(|(b, size)| *size >= min_size && predicate(b))?
+                    .0
+            }
+            _ => self
+                .by_size
+                .range((min_size, 0)..)
+                .map(|(_, address)| self.by_address[address].0)
+                .find(|b| predicate(b))?,
+        };
+
+        self.remove(block);
+        Some(block)
+    }
+
+    fn remove(&mut self, block: Block) -> bool {
+        let address = block.as_ptr() as usize;
+        match self.by_address.remove(&address) {
+            Some((_, size)) => {
+                self.by_size.remove(&(size, address));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn contains(&self, block: Block) -> bool {
+        self.by_address.contains_key(&(block.as_ptr() as usize))
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Block> + '_> {
+        Box::new(self.by_address.values().map(|&(block, _)| block))
+    }
+
+    fn len(&self) -> usize {
+        self.by_address.len()
+    }
+
+    fn new_empty(&self) -> Box<dyn BlockStore> {
+        Box::new(SizeAddressIndex::default())
+    }
+
+    fn first(&self) -> Option<Block> {
+        self.by_address.values().next().map(|&(block, _)| block)
+    }
+
+    fn find_containing(&self, ptr: usize) -> Option<Block> {
+        let (_, &(block, _)) = self.by_address.range(..=ptr).next_back()?;
+        Some(block).filter(|b| b.contains(ptr))
+    }
+}
+
+/// The store of new heaps
+pub(crate) fn default_store() -> Box<dyn BlockStore> {
+    // see tests::test_suite_passes_with_every_store
+    #[cfg(test)]
+    match std::env::var(tests::STORE_VAR).as_deref() {
+        Ok("bins") => return Box::new(SizeClassBins::default()),
+        Ok("set") => return Box::new(BlockSet::default()),
+        _ => {}
+    }
+
+    Box::new(SizeAddressIndex::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::managed::ManagedHeap;
+    use crate::stress::{self, HeapOp};
+    use crate::types::WORD_SIZE;
+
+    /// Selects the store of every heap created by the tests
+    pub(super) const STORE_VAR: &str = "MANAGED_HEAP_STORE";
+
+    /// Runs the whole test suite again for each of the other stores in a
+    /// child process, which uses the store for every heap
+    #[test]
+    fn test_suite_passes_with_every_store() {
+        // this is the child process
+        if std::env::var_os(STORE_VAR).is_some() {
+            return;
+        }
+
+        let test_binary = std::env::current_exe().unwrap();
+        for store in ["bins", "set"] {
+            let output = std::process::Command::new(&test_binary)
+                .env(STORE_VAR, store)
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "Tests failed with the {} store:\n{}",
+                store,
+                String::from_utf8_lossy(&output.stdout)
+            );
+        }
+    }
+
+    fn stores() -> Vec<Box<dyn BlockStore>> {
+        vec![
+            Box::new(BlockSet::default()),
+            Box::new(SizeClassBins::default()),
+            Box::new(SizeAddressIndex::default()),
+        ]
+    }
+
+    fn ops() -> Vec<HeapOp> {
+        let mut state: u64 = 0xb1d5;
+        let mut next = move |bound: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize % bound
+        };
+        (0..500)
+            .map(|_| match next(10) {
+                0..=5 => HeapOp::Alloc(next(40) as HalfWord + 1),
+                _ => HeapOp::FreeNth(next(64)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_stores_produce_the_same_layout() {
+        let policies = [
+            FitPolicy::FirstFitLowAddress,
+            FitPolicy::BestFit,
+            FitPolicy::PreserveWilderness,
+        ];
+        for &policy in policies.iter() {
+            let runs: Vec<_> = stores()
+                .into_iter()
+                .map(|store| {
+                    let mut heap = ManagedHeap::with_block_store(4000, store);
+                    heap.set_fit_policy(policy);
+                    let report = stress::run(&mut heap, &ops());
+                    let layout: Vec<_> = heap.blocks().map(|b| (b.offset, b.size)).collect();
+                    (report, layout)
+                })
+                .collect();
+
+            assert_eq!(None, runs[0].0.corruption);
+            assert_eq!(runs[0], runs[1], "{:?}", policy);
+            assert_eq!(runs[0], runs[2], "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn test_bins_keep_blocks_which_grow_in_place() {
+        let mut heap =
+            ManagedHeap::with_block_store(100 * WORD_SIZE, Box::new(SizeClassBins::default()));
+        let a = heap.alloc(3).unwrap();
+        let b = heap.alloc(3).unwrap();
+        heap.alloc(80).unwrap();
+
+        // a grows in place from 4 to 8 words, which is another size class
+        heap.free(a);
+        heap.free(b);
+        assert_eq!(Ok(()), heap.verify());
+        assert_eq!(Some(a), heap.alloc(7));
+    }
+
+    impl SizeAddressIndex {
+        fn assert_consistent(&self) {
+            let by_size: BTreeSet<_> = self
+                .by_address
+                .iter()
+                .map(|(&address, &(block, size))| {
+                    assert_eq!(address, block.as_ptr() as usize);
+                    assert_eq!(block.size(), size);
+                    (size, address)
+                })
+                .collect();
+            assert_eq!(by_size, self.by_size);
+        }
+    }
+
+    #[test]
+    fn test_index_stays_consistent() {
+        let mut state: u64 = 0x1dec;
+        let mut next = move |bound: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize % bound
+        };
+
+        // 64 slots of 32 words, each holding a block of up to 32 words
+        let mut data = vec![0usize; 64 * 32];
+        let slots: Vec<Block> = (0..64)
+            .map(|i| Block::new(unsafe { data.as_mut_ptr().add(i * 32) }, 1, 0))
+            .collect();
+        let mut index = SizeAddressIndex::default();
+        let mut reference = BlockSet::default();
+
+        for _ in 0..5000 {
+            let mut block = slots[next(64)];
+            match next(4) {
+                0 if !index.contains(block) => {
+                    block.set_size(next(32) as HalfWord + 1);
+                    assert!(index.add(block));
+                    reference.add_block(block);
+                }
+                1 => assert_eq!(
+                    BlockStore::remove(&mut reference, block),
+                    index.remove(block)
+                ),
+                2 if index.contains(block) => {
+                    // the header changes while the block is stored
+                    block.set_size(next(32) as HalfWord + 1);
+                    BlockStore::replace(&mut index, block, block);
+                }
+                _ => {
+                    let min_size = next(32) as HalfWord + 1;
+                    let policy = [FitPolicy::FirstFitLowAddress, FitPolicy::BestFit][next(2)];
+                    let expected = reference.take_fit(min_size, policy, &|_| true);
+                    assert_eq!(expected, index.take_fit(min_size, policy, &|_| true));
+                }
+            }
+
+            index.assert_consistent();
+            assert_eq!(reference.len(), index.len());
+        }
+    }
+
+    #[test]
+    fn test_best_fit_scales_to_many_free_blocks() {
+        let start = std::time::Instant::now();
+        let mut heap = ManagedHeap::with_block_store(
+            80_000 * WORD_SIZE,
+            Box::new(SizeAddressIndex::default()),
+        );
+        heap.set_fit_policy(FitPolicy::BestFit);
+
+        // every other block is freed, so none of them can be merged
+        let blocks: Vec<_> = (0..20_000).map(|_| heap.alloc(3).unwrap()).collect();
+        for &a in blocks.iter().step_by(2) {
+            heap.free(a);
+        }
+        assert_eq!(10_000, heap.num_free_blocks());
+        for _ in 0..10_000 {
+            heap.alloc(3).unwrap();
+        }
+        assert_eq!(0, heap.num_free_blocks());
+        assert!(start.elapsed().as_secs() < 2, "took {:?}", start.elapsed());
+    }
+}
@@ -0,0 +1,244 @@
+//! A harness to drive reproducible allocation patterns against a heap, e.g.
+//! to find out how much a pattern fragments the heap.
+
+use crate::address::Address;
+use crate::error::CorruptionError;
+use crate::managed::ManagedHeap;
+use crate::stats::{CoalesceStats, FreeListSummary, HeapStats};
+use crate::types::HalfWord;
+
+/// An operation of a stress run
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HeapOp {
+    /// Allocates an object with size words, which stays live until it is
+    /// freed by FreeNth
+    Alloc(HalfWord),
+    /// Frees the live object with index (modulo the number of live objects)
+    /// in allocation order. Does nothing if there is no live object.
+    FreeNth(usize),
+    /// Runs a collection with all live objects as roots, so only objects,
+    /// which were allocated outside of the run, can be collected
+    Gc,
+}
+
+/// The result of run
+#[derive(Clone, Debug, PartialEq)]
+pub struct StressReport {
+    /// The number of operations, which were executed. Smaller than the
+    /// number of operations if the heap got corrupted.
+    pub executed: usize,
+    pub failed_allocs: usize,
+    /// The number of FreeNth operations without a live object
+    pub skipped_frees: usize,
+    /// The number of objects, which were still live at the end
+    pub live_objects: usize,
+    /// The highest number of used words after any operation
+    pub peak_used_words: usize,
+    /// The merges of freed blocks during the run
+    pub coalesce: CoalesceStats,
+    /// The state of the heap at the end
+    pub stats: HeapStats,
+    pub free_list: FreeListSummary,
+    /// The index of the operation, after which the heap was inconsistent,
+    /// and the inconsistency. The run stops at the first one.
+    pub corruption: Option<(usize, CorruptionError)>,
+}
+
+/// Executes ops in order and verifies the heap after every operation.
+/// The objects, which are still live at the end, are not freed.
+pub fn run(heap: &mut ManagedHeap, ops: &[HeapOp]) -> StressReport {
+    let before = heap.stats().coalesce;
+    let mut live: Vec<Address> = Vec::new();
+    let mut report = StressReport {
+        executed: 0,
+        failed_allocs: 0,
+        skipped_frees: 0,
+        live_objects: 0,
+        peak_used_words: heap.stats().used_words,
+        coalesce: CoalesceStats::default(),
+        stats: HeapStats::default(),
+        free_list: FreeListSummary::default(),
+        corruption: None,
+    };
+
+    for (index, &op) in ops.iter().enumerate() {
+        match op {
+            HeapOp::Alloc(size) => match heap.alloc(size) {
+                Some(address) => live.push(address),
+                None => report.failed_allocs += 1,
+            },
+            HeapOp::FreeNth(_) if live.is_empty() => report.skipped_frees += 1,
+            HeapOp::FreeNth(n) => {
+                let address = live.remove(n % live.len());
+                heap.free(address);
+            }
+            HeapOp::Gc => {
+                heap.gc_addresses(&live, |_, _| {});
+            }
+        }
+
+        report.executed += 1;
+        report.peak_used_words = report.peak_used_words.max(heap.stats().used_words);
+        if let Err(e) = heap.verify() {
+            report.corruption = Some((index, e));
+            break;
+        }
+    }
+
+    let stats = heap.stats();
+    report.live_objects = live.len();
+    report.coalesce = CoalesceStats {
+        with_pred: stats.coalesce.with_pred - before.with_pred,
+        with_next: stats.coalesce.with_next - before.with_next,
+        three_way: stats.coalesce.three_way - before.three_way,
+    };
+    report.stats = stats;
+    report.free_list = heap.free_list_summary();
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allocs(n: usize) -> impl Iterator<Item = HeapOp> {
+        (0..n).map(|_| HeapOp::Alloc(4))
+    }
+
+    #[test]
+    fn test_fifo_frees_merge_with_pred() {
+        let mut heap = ManagedHeap::new(1000);
+        let ops: Vec<_> = allocs(4)
+            .chain((0..4).map(|_| HeapOp::FreeNth(0)))
+            .collect();
+
+        let report = run(&mut heap, &ops);
+        let expected = CoalesceStats {
+            with_pred: 2,
+            with_next: 0,
+            three_way: 1,
+        };
+        assert_eq!(expected, report.coalesce);
+        assert_eq!(None, report.corruption);
+        assert_eq!(8, report.executed);
+        assert_eq!(20, report.peak_used_words);
+        assert_eq!(1, report.free_list.count);
+    }
+
+    #[test]
+    fn test_lifo_frees_merge_with_next() {
+        let mut heap = ManagedHeap::new(1000);
+        let ops: Vec<_> = allocs(4).chain((0..4).rev().map(HeapOp::FreeNth)).collect();
+
+        let report = run(&mut heap, &ops);
+        let expected = CoalesceStats {
+            with_pred: 0,
+            with_next: 4,
+            three_way: 0,
+        };
+        assert_eq!(expected, report.coalesce);
+        assert_eq!(expected, heap.stats().coalesce);
+        assert_eq!(0, report.live_objects);
+        assert_eq!(1, report.free_list.count);
+    }
+
+    #[test]
+    fn test_random_ops_with_fixed_seed() {
+        let mut state: u64 = 0x5eed;
+        let mut next = move |bound: usize| {
+            // xorshift, so the sequence only depends on the seed
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize % bound
+        };
+        let ops: Vec<_> = (0..300)
+            .map(|_| match next(10) {
+                0..=5 => HeapOp::Alloc(next(16) as HalfWord + 1),
+                6..=8 => HeapOp::FreeNth(next(64)),
+                _ => HeapOp::Gc,
+            })
+            .collect();
+
+        let mut heap = ManagedHeap::new(1000);
+        let report = run(&mut heap, &ops);
+        assert_eq!(None, report.corruption);
+        assert_eq!(300, report.executed);
+        let expected = CoalesceStats {
+            with_pred: 27,
+            with_next: 12,
+            three_way: 8,
+        };
+        assert_eq!(expected, report.coalesce);
+
+        // the same operations always end up with the same result
+        let mut other = ManagedHeap::new(1000);
+        assert_eq!(report, run(&mut other, &ops));
+    }
+
+    #[test]
+    fn test_gc_only_collects_objects_outside_of_the_run() {
+        let mut heap = ManagedHeap::new(1000);
+        heap.alloc(3).unwrap();
+
+        let report = run(
+            &mut heap,
+            &[HeapOp::Alloc(2), HeapOp::Gc, HeapOp::FreeNth(5)],
+        );
+        assert_eq!(0, report.live_objects);
+        assert_eq!(0, report.skipped_frees);
+        assert_eq!(0, heap.num_used_blocks());
+    }
+
+    fn layout(heap: &ManagedHeap) -> Vec<(usize, usize, bool)> {
+        heap.blocks().map(|b| (b.offset, b.size, b.used)).collect()
+    }
+
+    #[test]
+    fn test_split_undo_doesnt_change_the_layout() {
+        // mostly temporaries, which are freed right away, mixed with frees
+        // of older objects
+        let mut state: u64 = 0xf4e3;
+        let mut next = move |bound: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize % bound
+        };
+        let mut live = 0;
+        let mut ops = Vec::new();
+        for _ in 0..400 {
+            let op = match next(8) {
+                0..=3 => HeapOp::Alloc(next(8) as HalfWord + 1),
+                4..=5 if live > 0 => HeapOp::FreeNth(live - 1),
+                6 => HeapOp::FreeNth(next(32)),
+                _ => HeapOp::Gc,
+            };
+            match op {
+                HeapOp::Alloc(_) => live += 1,
+                HeapOp::FreeNth(_) => live = live.saturating_sub(1),
+                HeapOp::Gc => {}
+            }
+            ops.push(op);
+        }
+
+        for &poison in [false, true].iter() {
+            let mut heap = ManagedHeap::new(2000);
+            let mut reference = ManagedHeap::new(2000);
+            reference.set_split_undo(false);
+            heap.set_poisoning(poison);
+            reference.set_poisoning(poison);
+
+            let report = run(&mut heap, &ops);
+            assert_eq!(None, report.corruption);
+            assert_eq!(report, run(&mut reference, &ops));
+            assert_eq!(layout(&reference), layout(&heap));
+            if poison {
+                // the payloads are only deterministic if they are poisoned
+                assert_eq!(reference.heap.raw_words(), heap.heap.raw_words());
+            }
+            assert!(heap.num_undone_splits() > 10);
+            assert_eq!(0, reference.num_undone_splits());
+        }
+    }
+}
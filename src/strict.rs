@@ -0,0 +1,168 @@
+use crate::address::Address;
+use crate::error::AddressError;
+use crate::managed::ManagedHeap;
+use crate::types::WORD_SIZE;
+
+impl ManagedHeap {
+    /// Makes the methods, which take the address of an object, validate it
+    /// before doing anything (see validate_address), e.g. to catch dangling
+    /// or forged pointers while fuzzing a VM.
+    /// Methods returning an AccessError (read, write, read_bytes, write_bytes
+    /// and the rc methods) fail with AccessError::InvalidAddress, free,
+    /// duplicate and trim panic (see try_free). copy_words and read_to_vec
+    /// accept addresses inside an object, so they are not affected.
+    /// The _unchecked variants skip the checks.
+    pub fn enable_strict(&mut self) {
+        self.strict = true;
+    }
+
+    pub fn disable_strict(&mut self) {
+        self.strict = false;
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Checks that address is the start of a used block (in the heap or in
+    /// the large object space), regardless of strict mode
+    pub fn validate_address(&self, address: Address) -> Result<(), AddressError> {
+        let ptr = usize::from(address);
+        if ptr % WORD_SIZE != 0 {
+            return Err(AddressError::Misaligned(address));
+        }
+
+        let block = self
+            .heap
+            .block_of(address)
+            .or_else(|| self.large_objects.block_of(address));
+        match block.map(Address::from) {
+            Some(object) if object == address => Ok(()),
+            Some(object) => Err(AddressError::Interior { address, object }),
+            None if self.heap.contains(ptr) => Err(AddressError::Free(address)),
+            None => Err(AddressError::OutOfRange(address)),
+        }
+    }
+
+    /// Like free, but fails instead of freeing an invalid address in strict
+    /// mode
+    pub fn try_free(&mut self, address: Address) -> Result<(), AddressError> {
+        self.check_strict(address)?;
+        self.free_unchecked(address);
+        Ok(())
+    }
+
+    /// validate_address, but only in strict mode
+    pub(crate) fn check_strict(&self, address: Address) -> Result<(), AddressError> {
+        if self.strict {
+            self.validate_address(address)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Panics with the error of check_strict
+    pub(crate) fn assert_strict(&self, address: Address) {
+        if let Err(e) = self.check_strict(address) {
+            panic!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::AccessError;
+
+    fn strict_heap() -> (ManagedHeap, Address, Address) {
+        let mut heap = ManagedHeap::new(1000);
+        heap.enable_strict();
+        let object = heap.alloc(4).unwrap();
+        let freed = heap.alloc(4).unwrap();
+        heap.alloc(4).unwrap();
+        heap.free(freed);
+        (heap, object, freed)
+    }
+
+    #[test]
+    fn test_read_and_write_reject_invalid_addresses() {
+        let (mut heap, object, freed) = strict_heap();
+        let misaligned = Address::from(usize::from(object) + 1);
+        let outside = Address::from(0x1000);
+
+        let cases = [
+            (misaligned, AddressError::Misaligned(misaligned)),
+            (
+                object + 1,
+                AddressError::Interior {
+                    address: object + 1,
+                    object,
+                },
+            ),
+            (freed, AddressError::Free(freed)),
+            (outside, AddressError::OutOfRange(outside)),
+        ];
+
+        for &(address, error) in cases.iter() {
+            assert_eq!(Err(error), heap.validate_address(address));
+            assert_eq!(
+                Err(AccessError::InvalidAddress(error)),
+                heap.read(address, 0)
+            );
+            assert_eq!(
+                Err(AccessError::InvalidAddress(error)),
+                heap.write(address, 0, 1)
+            );
+            assert_eq!(
+                Err(AccessError::InvalidAddress(error)),
+                heap.rc_count(address)
+            );
+        }
+
+        assert_eq!(Ok(()), heap.write(object, 3, 7));
+        assert_eq!(Ok(7), heap.read(object, 3));
+    }
+
+    #[test]
+    fn test_header_is_interior() {
+        let (heap, object, _) = strict_heap();
+        let header = Address::from(usize::from(object) - WORD_SIZE);
+        assert_eq!(
+            Err(AddressError::Interior {
+                address: header,
+                object
+            }),
+            heap.validate_address(header)
+        );
+    }
+
+    #[test]
+    fn test_try_free_rejects_invalid_addresses() {
+        let (mut heap, object, freed) = strict_heap();
+        assert_eq!(Err(AddressError::Free(freed)), heap.try_free(freed));
+        assert_eq!(2, heap.num_used_blocks());
+
+        assert_eq!(Ok(()), heap.try_free(object));
+        assert_eq!(1, heap.num_used_blocks());
+        // a double free is caught as well
+        assert_eq!(Err(AddressError::Free(object)), heap.try_free(object));
+    }
+
+    #[test]
+    #[should_panic(expected = "points into the object")]
+    fn test_free_panics_on_interior_address() {
+        let (mut heap, object, _) = strict_heap();
+        heap.free(object + 2);
+    }
+
+    #[test]
+    fn test_unchecked_variants_skip_the_checks() {
+        let (mut heap, object, _) = strict_heap();
+        assert_eq!(Ok(()), heap.write_unchecked(object + 1, 0, 5));
+        assert_eq!(Ok(5), heap.read_unchecked(object + 1, 0));
+        assert_eq!(Ok(5), heap.read(object, 1));
+
+        heap.disable_strict();
+        assert_eq!(Ok(5), heap.read(object + 1, 0));
+    }
+}
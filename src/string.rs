@@ -0,0 +1,273 @@
+use crate::address::Address;
+use crate::managed::ManagedHeap;
+use crate::stats::GcStats;
+use crate::trace::{GcRoot, Leaf, Traceable};
+use crate::types::{HalfWord, WORD_SIZE};
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::{ptr, slice, str};
+
+/// An immutable UTF-8 string on a ManagedHeap.
+/// Layout: [mark word, byte length, bytes...]
+/// The bytes are packed into as few words as possible.
+/// Two HeapStrings are only equal if they are the same object.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct HeapString(Address);
+
+impl HeapString {
+    const HEADER_WORDS: usize = 2;
+
+    pub fn new(heap: &mut ManagedHeap, s: &str) -> Option<Self> {
+        let words = s.len().div_ceil(WORD_SIZE);
+        let size = HalfWord::try_from(words + Self::HEADER_WORDS).ok()?;
+        let mut address = heap.alloc(size)?;
+
+        address.write(false as usize);
+        (address + 1).write(s.len());
+        unsafe {
+            let bytes = usize::from(address + Self::HEADER_WORDS) as *mut u8;
+            ptr::copy_nonoverlapping(s.as_ptr(), bytes, s.len());
+        }
+
+        Some(HeapString(address))
+    }
+
+    /// The length in bytes
+    pub fn len(&self) -> usize {
+        *(self.0 + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The content of the string.
+    /// The string must not be freed while the result is in use.
+    pub fn as_str(&self) -> &str {
+        // the bytes were copied from a valid str in new and are never changed
+        unsafe {
+            let bytes = usize::from(self.0 + Self::HEADER_WORDS) as *const u8;
+            str::from_utf8_unchecked(slice::from_raw_parts(bytes, self.len()))
+        }
+    }
+}
+
+impl fmt::Debug for HeapString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl From<Address> for HeapString {
+    fn from(address: Address) -> Self {
+        HeapString(address)
+    }
+}
+
+impl From<HeapString> for Address {
+    fn from(string: HeapString) -> Address {
+        string.0
+    }
+}
+
+unsafe impl Leaf for HeapString {
+    fn address(&self) -> Address {
+        self.0
+    }
+}
+
+/// Interns HeapStrings, so that equal content is only stored once.
+/// As a GcRoot, the table keeps all of its strings alive. Using gc_weak
+/// instead removes the strings, which are not referenced from anywhere else.
+#[derive(Default)]
+pub struct StringTable {
+    entries: HashMap<u64, Vec<HeapString>>,
+}
+
+impl StringTable {
+    pub fn new() -> Self {
+        StringTable::default()
+    }
+
+    /// Returns the existing string with the content s or allocates a new one
+    pub fn intern(&mut self, heap: &mut ManagedHeap, s: &str) -> Option<HeapString> {
+        if let Some(existing) = self.get(s) {
+            return Some(existing);
+        }
+
+        let string = HeapString::new(heap, s)?;
+        self.entries.entry(hash(s)).or_default().push(string);
+        Some(string)
+    }
+
+    /// Returns the string with the content s, if it was interned before
+    pub fn get(&self, s: &str) -> Option<HeapString> {
+        self.entries
+            .get(&hash(s))?
+            .iter()
+            .find(|string| string.as_str() == s)
+            .copied()
+    }
+
+    /// The number of interned strings
+    pub fn len(&self) -> usize {
+        self.entries.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Runs the garbage collector on heap and treats the table as a weak
+    /// reference. Entries, which are not reachable from roots, are removed
+    /// before they get freed, so the weak references are always cleared
+    /// before any finalizer or sweep callback runs.
+    /// T::from has to be able to handle the addresses of the strings.
+    pub fn gc_weak<T>(
+        &mut self,
+        heap: &mut ManagedHeap,
+        roots: &mut [&mut dyn GcRoot<T>],
+    ) -> GcStats
+    where
+        T: Traceable + From<Address>,
+    {
+        for traceable in roots.iter_mut().flat_map(|r| r.children()) {
+            traceable.mark();
+        }
+
+        for strings in self.entries.values_mut() {
+            strings.retain(|s| T::from(s.0).is_marked());
+        }
+        self.entries.retain(|_, strings| !strings.is_empty());
+
+        // marking again is a no-op, but gc also takes care of unmarking
+        heap.gc(roots)
+    }
+}
+
+unsafe impl GcRoot<HeapString> for StringTable {
+    fn children<'a>(&'a mut self) -> Box<dyn Iterator<Item = &'a mut HeapString> + 'a> {
+        Box::new(self.entries.values_mut().flat_map(|s| s.iter_mut()))
+    }
+}
+
+fn hash(s: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    s.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_multi_byte_round_trip() {
+        let mut heap = ManagedHeap::new(1000);
+        let content = "Grüße, 世界 🦀";
+        let string = HeapString::new(&mut heap, content).unwrap();
+
+        assert_eq!(content, string.as_str());
+        assert_eq!(content.len(), string.len());
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_lengths_which_are_no_multiple_of_word_size() {
+        let mut heap = ManagedHeap::new(4000);
+        let source = "abcdefghijklmnopqrstuvwxyz";
+
+        let strings: Vec<HeapString> = (0..=source.len())
+            .map(|len| HeapString::new(&mut heap, &source[..len]).unwrap())
+            .collect();
+
+        for (len, string) in strings.iter().enumerate() {
+            assert_eq!(&source[..len], string.as_str());
+        }
+        assert!(strings[0].is_empty());
+        assert_eq!(Ok(()), heap.verify());
+    }
+
+    #[test]
+    fn test_interning_returns_same_address() {
+        let mut heap = ManagedHeap::new(1000);
+        let mut table = StringTable::new();
+
+        let first = table.intern(&mut heap, "hello").unwrap();
+        let second = table.intern(&mut heap, "hello").unwrap();
+        let other = table.intern(&mut heap, "world").unwrap();
+
+        assert_eq!(Address::from(first), Address::from(second));
+        assert_ne!(first, other);
+        assert_eq!(2, table.len());
+        assert_eq!(2, heap.num_used_blocks());
+        assert_eq!(Some(other), table.get("world"));
+        assert_eq!(None, table.get("missing"));
+    }
+
+    #[test]
+    fn test_table_as_root_keeps_strings_alive() {
+        let mut heap = ManagedHeap::new(1000);
+        let mut table = StringTable::new();
+        table.intern(&mut heap, "kept").unwrap();
+        HeapString::new(&mut heap, "garbage").unwrap();
+
+        let mut roots: Vec<&mut dyn GcRoot<HeapString>> = vec![&mut table];
+        let stats = heap.gc(&mut roots[..]);
+
+        assert_eq!(1, stats.freed_blocks);
+        assert_eq!("kept", table.get("kept").unwrap().as_str());
+    }
+
+    #[test]
+    fn test_weak_table_removes_collected_strings() {
+        let mut heap = ManagedHeap::new(1000);
+        let mut table = StringTable::new();
+        let used = table.intern(&mut heap, "used").unwrap();
+        table.intern(&mut heap, "unused").unwrap();
+
+        let mut root = MockGcRoot::new(vec![used]);
+        let mut roots: Vec<&mut dyn GcRoot<HeapString>> = vec![&mut root];
+        let stats = table.gc_weak(&mut heap, &mut roots[..]);
+
+        assert_eq!(1, stats.freed_blocks);
+        assert_eq!(1, table.len());
+        assert_eq!(None, table.get("unused"));
+        assert_eq!(Some(used), table.get("used"));
+        assert!(!used.is_marked());
+
+        // the string can be interned again after it was collected
+        let unused = table.intern(&mut heap, "unused").unwrap();
+        assert_eq!("unused", unused.as_str());
+    }
+
+    #[test]
+    fn test_weak_entries_are_cleared_before_the_sweep() {
+        let mut heap = ManagedHeap::new(1000);
+        let mut table = StringTable::new();
+        let used = table.intern(&mut heap, "used").unwrap();
+        table.intern(&mut heap, "unused").unwrap();
+        table.intern(&mut heap, "gone").unwrap();
+
+        let swept = Arc::new(Mutex::new(Vec::new()));
+        let inner = Arc::clone(&swept);
+        heap.set_on_sweep(Box::new(move |address, _| {
+            let string = HeapString::from(address);
+            inner.lock().unwrap().push(string.as_str().to_owned());
+        }));
+
+        let mut root = MockGcRoot::new(vec![used]);
+        let mut roots: Vec<&mut dyn GcRoot<HeapString>> = vec![&mut root];
+        table.gc_weak(&mut heap, &mut roots[..]);
+
+        let swept = swept.lock().unwrap();
+        assert_eq!(2, swept.len());
+        assert!(swept.iter().all(|s| table.get(s).is_none()));
+    }
+}
@@ -0,0 +1,184 @@
+use crate::address::Address;
+use crate::block::Block;
+use crate::managed::ManagedHeap;
+use crate::stats::TagStats;
+use crate::types::HalfWord;
+
+use std::collections::{BTreeMap, HashMap};
+
+/// Debug tags for finding out which part of a program leaks memory.
+/// Tagging is disabled until the first call to alloc_tagged, so heaps which
+/// don't use it don't pay for the bookkeeping.
+impl ManagedHeap {
+    /// Like alloc, but remembers tag for the new object until it is freed.
+    pub fn alloc_tagged(&mut self, size: HalfWord, tag: &'static str) -> Option<Address> {
+        let address = self.alloc(size)?;
+        self.tags
+            .get_or_insert_with(HashMap::new)
+            .insert(address.into(), tag);
+        Some(address)
+    }
+
+    /// The tag of the object at address, if it was allocated with
+    /// alloc_tagged
+    pub fn tag_of(&self, address: Address) -> Option<&'static str> {
+        self.tags.as_ref()?.get(&address.into()).copied()
+    }
+
+    /// Groups all tagged objects which are still in use by their tags
+    pub fn leak_report(&self) -> BTreeMap<&'static str, TagStats> {
+        let mut report = BTreeMap::new();
+
+        for (&address, &tag) in self.tags.iter().flatten() {
+            let stats: &mut TagStats = report.entry(tag).or_default();
+            stats.blocks += 1;
+            stats.words += Block::from(Address::from(address)).size() as usize;
+        }
+
+        report
+    }
+}
+
+/// Allocation contexts, e.g. the program counter of the instruction which
+/// allocated an object, for finding out where leaked objects come from.
+/// Like tagging, this is disabled until the first call to
+/// set_alloc_context.
+impl ManagedHeap {
+    /// Records context for every following allocation until the context is
+    /// changed or cleared
+    pub fn set_alloc_context(&mut self, context: u64) {
+        self.alloc_context = Some(context);
+        self.contexts.get_or_insert_with(HashMap::new);
+    }
+
+    /// Stops recording a context for new allocations. The contexts of the
+    /// existing objects are kept.
+    pub fn clear_alloc_context(&mut self) {
+        self.alloc_context = None;
+    }
+
+    pub fn alloc_context(&self) -> Option<u64> {
+        self.alloc_context
+    }
+
+    /// The context, which was set when the object at address was allocated
+    pub fn context_of(&self, address: Address) -> Option<u64> {
+        self.contexts.as_ref()?.get(&address.into()).copied()
+    }
+
+    /// Groups all objects with a context, which are still in use, by their
+    /// contexts
+    pub fn contexts_report(&self) -> BTreeMap<u64, TagStats> {
+        let mut report = BTreeMap::new();
+
+        for (&address, &context) in self.contexts.iter().flatten() {
+            let stats: &mut TagStats = report.entry(context).or_default();
+            stats.blocks += 1;
+            stats.words += Block::from(Address::from(address)).size() as usize;
+        }
+
+        report
+    }
+
+    /// Remembers the context of a new object
+    pub(crate) fn record_context(&mut self, address: Address) {
+        if let (Some(context), Some(contexts)) = (self.alloc_context, self.contexts.as_mut()) {
+            contexts.insert(address.into(), context);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::trace::GcRoot;
+
+    fn tagged_integer(heap: &mut ManagedHeap, tag: &'static str) -> IntegerObject {
+        let mut address = heap.alloc_tagged(2, tag).unwrap();
+        address.write(false as usize);
+        IntegerObject(address)
+    }
+
+    #[test]
+    fn test_untagged_heap_has_empty_report() {
+        let mut heap = ManagedHeap::new(1000);
+        let a = heap.alloc(2).unwrap();
+
+        assert!(heap.tags.is_none());
+        assert_eq!(None, heap.tag_of(a));
+        assert!(heap.leak_report().is_empty());
+    }
+
+    #[test]
+    fn test_report_contains_only_survivors() {
+        let mut heap = ManagedHeap::new(1000);
+        let parser = tagged_integer(&mut heap, "parser");
+        let parser_garbage = tagged_integer(&mut heap, "parser");
+        let compiler = tagged_integer(&mut heap, "compiler");
+        let compiler_freed = tagged_integer(&mut heap, "compiler");
+        let untagged = IntegerObject::new(&mut heap, 1);
+        let mut big = heap.alloc_tagged(10, "compiler").unwrap();
+        big.write(false as usize);
+
+        assert_eq!(Some("parser"), heap.tag_of(parser_garbage.0));
+        heap.free(compiler_freed.0);
+
+        let mut root = MockGcRoot::new(vec![parser, compiler, untagged]);
+        let mut roots: Vec<&mut dyn GcRoot<IntegerObject>> = vec![&mut root];
+        heap.gc(&mut roots[..]);
+
+        let report = heap.leak_report();
+        assert_eq!(2, report.len());
+        assert_eq!(
+            TagStats {
+                blocks: 1,
+                words: 3
+            },
+            report["parser"]
+        );
+        assert_eq!(
+            TagStats {
+                blocks: 1,
+                words: 3
+            },
+            report["compiler"]
+        );
+        assert_eq!(3, heap.num_used_blocks());
+    }
+
+    #[test]
+    fn test_contexts_report_contains_only_survivors() {
+        let mut heap = ManagedHeap::new(1000);
+        let untracked = IntegerObject::new(&mut heap, 0);
+        assert!(heap.contexts.is_none());
+
+        heap.set_alloc_context(0x10);
+        let first = IntegerObject::new(&mut heap, 1);
+        IntegerObject::new(&mut heap, 2);
+        heap.set_alloc_context(0x20);
+        let second = IntegerObject::new(&mut heap, 3);
+        let freed = IntegerObject::new(&mut heap, 4);
+        heap.clear_alloc_context();
+        let late = IntegerObject::new(&mut heap, 5);
+
+        assert_eq!(Some(0x10), heap.context_of(first.0));
+        assert_eq!(None, heap.context_of(late.0));
+        heap.free(freed.0);
+        assert_eq!(None, heap.context_of(freed.0));
+
+        let mut root = MockGcRoot::new(vec![untracked, first, second, late]);
+        let mut roots: Vec<&mut dyn GcRoot<IntegerObject>> = vec![&mut root];
+        assert_eq!(1, heap.gc(&mut roots[..]).freed_blocks);
+
+        let report = heap.contexts_report();
+        let survivor = TagStats {
+            blocks: 1,
+            words: 3,
+        };
+        assert_eq!(
+            vec![(0x10, survivor), (0x20, survivor)],
+            report.into_iter().collect::<Vec<_>>()
+        );
+    }
+}
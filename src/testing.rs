@@ -0,0 +1,214 @@
+//! Objects shared by the tests of multiple modules.
+
+use crate::address::Address;
+use crate::managed::ManagedHeap;
+use crate::stats::GcStats;
+use crate::trace::{GcRoot, GcRootRef, Leaf, Traceable};
+
+use std::fmt;
+use std::iter::Iterator;
+use std::ops::Add;
+
+/// The fields of stats, which don't depend on how the objects were marked
+/// or how long the collection took
+pub fn outcome(stats: GcStats) -> GcStats {
+    GcStats {
+        freed_words: stats.freed_words,
+        freed_blocks: stats.freed_blocks,
+        live_words: stats.live_words,
+        live_blocks: stats.live_blocks,
+        scanned_cards: stats.scanned_cards,
+        epoch: stats.epoch,
+        kind: stats.kind,
+        ..GcStats::default()
+    }
+}
+
+pub struct MockGcRoot<T> {
+    pub used_elems: Vec<T>,
+}
+
+impl<T> MockGcRoot<T> {
+    pub fn new(used_elems: Vec<T>) -> Self {
+        MockGcRoot { used_elems }
+    }
+
+    pub fn clear(&mut self) {
+        self.used_elems.clear();
+    }
+}
+
+unsafe impl<T> GcRoot<T> for MockGcRoot<T>
+where
+    T: Traceable + From<Address>,
+{
+    fn children<'a>(&'a mut self) -> Box<dyn Iterator<Item = &'a mut T> + 'a> {
+        Box::new(self.used_elems.iter_mut())
+    }
+}
+
+unsafe impl<T> GcRootRef<T> for MockGcRoot<T>
+where
+    T: Traceable + From<Address> + Into<Address>,
+{
+    fn children<'a>(&'a self) -> Box<dyn Iterator<Item = &'a T> + 'a> {
+        Box::new(self.used_elems.iter())
+    }
+}
+
+#[derive(Debug)]
+pub struct IntegerObject(pub Address);
+
+impl IntegerObject {
+    pub fn new(heap: &mut ManagedHeap, value: isize) -> Self {
+        // reserve one usize for mark byte
+        let mut address = heap.alloc(2).unwrap();
+
+        address.write(false as usize);
+        address.add(1).write(value as usize);
+
+        IntegerObject(address)
+    }
+
+    pub fn get(&self) -> isize {
+        *self.0.add(1) as isize
+    }
+}
+
+impl From<Address> for IntegerObject {
+    fn from(address: Address) -> Self {
+        IntegerObject(address)
+    }
+}
+
+unsafe impl Leaf for IntegerObject {
+    fn address(&self) -> Address {
+        self.0
+    }
+}
+
+// transparent, so lists can be borrowed from heap slots (see HeapSliceRoot)
+#[derive(Copy, Clone)]
+#[repr(transparent)]
+pub struct LinkedList(pub Address);
+
+impl LinkedList {
+    pub fn new(heap: &mut ManagedHeap, value: isize, next: Option<LinkedList>) -> Self {
+        // [mark byte, value, next], each 1 byte
+        let mut address = heap.alloc(3).unwrap();
+
+        address.write(false as usize);
+        address.add(1).write(value as usize);
+
+        address
+            .add(2)
+            .write(Address::encode_option(next.map(|n| n.0)));
+
+        LinkedList(address)
+    }
+
+    pub fn next(self) -> Option<LinkedList> {
+        Address::decode_option(*self.0.add(2)).map(LinkedList)
+    }
+
+    pub fn set_next(self, next: Option<LinkedList>) {
+        self.0
+            .add(2)
+            .write(Address::encode_option(next.map(|n| n.0)));
+    }
+
+    pub fn value(self) -> isize {
+        *self.0.add(1) as isize
+    }
+
+    pub fn iter(self) -> Iter {
+        Iter {
+            current: Some(self),
+        }
+    }
+}
+
+impl From<Address> for LinkedList {
+    fn from(address: Address) -> Self {
+        LinkedList(address)
+    }
+}
+
+impl From<LinkedList> for Address {
+    fn from(value: LinkedList) -> Address {
+        value.0
+    }
+}
+
+unsafe impl Traceable for LinkedList {
+    fn mark(&mut self) {
+        self.0.write(true as usize);
+        if let Some(mut next) = self.next() {
+            next.mark();
+        }
+    }
+
+    fn unmark(&mut self) {
+        self.0.write(false as usize);
+    }
+
+    fn trace_with(&mut self, visitor: &mut dyn FnMut(&mut Address)) {
+        let next = unsafe { self.0.add(2).as_address_mut() };
+        if next.into_raw() != 0 {
+            visitor(next);
+        }
+    }
+
+    fn is_marked(&self) -> bool {
+        (*self.0) != 0
+    }
+}
+
+impl fmt::Debug for LinkedList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let string_list: Vec<String> = self
+            .iter()
+            .map(|l| l.value())
+            .map(|v| format!("{}", v))
+            .collect();
+
+        write!(f, "[{}]", string_list.join(", "))
+    }
+}
+
+pub struct Iter {
+    current: Option<LinkedList>,
+}
+
+impl Iterator for Iter {
+    type Item = LinkedList;
+
+    fn next(&mut self) -> Option<LinkedList> {
+        let curr = self.current;
+        self.current = self.current.and_then(|c| c.next());
+        curr
+    }
+}
+
+macro_rules! list {
+    ($heap:expr; $($elems:tt)+) => {
+        $crate::testing::construct_list!($heap; [$($elems)*])
+    };
+}
+
+macro_rules! construct_list {
+    ($heap:expr; [] $head:expr $(, $elem:expr)*) => {
+        {
+            #[allow(unused_mut)]
+            let mut list = $crate::testing::LinkedList::new($heap, $head, None);
+            $(list = $crate::testing::LinkedList::new($heap, $elem, Some(list));)*
+            list
+        }
+    };
+    ($heap:expr; [$first:tt $($rest:tt)*] $($reversed:tt)*) => {
+        $crate::testing::construct_list!($heap; [$($rest)*] $first $($reversed)*)
+    };
+}
+
+pub(crate) use construct_list;
+pub(crate) use list;
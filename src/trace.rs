@@ -1,21 +1,187 @@
 use super::address::Address;
 
-use std::iter::Iterator;
+use std::iter::{self, Iterator};
 
+/// An object living on a ManagedHeap.
+///
+/// # Safety
+/// mark has to mark every object reachable from self, otherwise the garbage
+/// collector will free objects which are still in use.
 pub unsafe trait Traceable {
     /// Mark all contained Traceable Objects
     fn mark(&mut self);
     /// Unmark this Object
     fn unmark(&mut self);
-    // /// An iterator used for updating the addresses after moving heap content
-    // fn trace(&mut self) -> Box<Iterator<Item = &mut Address>>;
+    /// An iterator used for updating the addresses after moving heap content.
+    /// Objects without references to other objects can use the default
+    /// implementation, which returns an empty iterator.
+    /// The heap only calls trace_with, so new implementations should
+    /// implement that instead.
+    fn trace<'a>(&'a mut self) -> Box<dyn Iterator<Item = &'a mut Address> + 'a> {
+        Box::new(iter::empty())
+    }
+    /// Calls visitor with every reference to another object, e.g. to update
+    /// the addresses after moving heap content. Unlike trace, this doesn't
+    /// allocate an iterator per object and the references can be computed
+    /// while visiting them.
+    /// The default implementation visits the references returned by trace, so
+    /// objects, which implement trace, keep working.
+    fn trace_with(&mut self, visitor: &mut dyn FnMut(&mut Address)) {
+        for child in self.trace() {
+            visitor(child);
+        }
+    }
     /// Checks if self is marked
     fn is_marked(&self) -> bool;
 }
 
+/// An object without references to other objects.
+/// Every Leaf is automatically Traceable by using the word at MARK_OFFSET
+/// inside the object as its mark word.
+///
+/// # Safety
+/// The object must not contain references to other heap objects and the word
+/// at MARK_OFFSET has to be reserved for the garbage collector.
+///
+/// # Example
+/// ```
+/// use managed_heap::address::Address;
+/// use managed_heap::trace::Leaf;
+///
+/// struct Integer(Address);
+/// unsafe impl Leaf for Integer {
+///     fn address(&self) -> Address { self.0 }
+/// }
+/// impl From<Address> for Integer { fn from(a: Address) -> Self { Integer(a) } }
+/// impl From<Integer> for Address { fn from(i: Integer) -> Self { i.0 } }
+/// ```
+pub unsafe trait Leaf {
+    /// The offset of the mark word in words, relative to the address
+    const MARK_OFFSET: usize = 0;
+
+    /// The address of this object on the heap
+    fn address(&self) -> Address;
+}
+
+unsafe impl<L: Leaf> Traceable for L {
+    fn mark(&mut self) {
+        (self.address() + L::MARK_OFFSET).write(true as usize);
+    }
+
+    fn unmark(&mut self) {
+        (self.address() + L::MARK_OFFSET).write(false as usize);
+    }
+
+    fn is_marked(&self) -> bool {
+        *(self.address() + L::MARK_OFFSET) != 0
+    }
+}
+
+/// A set of objects from which all live objects on the heap are reachable.
+///
+/// # Safety
+/// children has to return every object which is directly in use, otherwise
+/// the garbage collector will free objects which are still in use.
 pub unsafe trait GcRoot<I>
+where
+    I: Traceable + From<Address>,
+{
+    fn children<'a>(&'a mut self) -> Box<dyn Iterator<Item = &'a mut I> + 'a>;
+    /// Calls visitor with every child. Unlike children, this doesn't allocate
+    /// an iterator, so a collection with roots, which implement it, doesn't
+    /// have to allocate at all.
+    /// The default implementation visits the children returned by children.
+    fn visit_children(&mut self, visitor: &mut dyn FnMut(&mut I)) {
+        for child in self.children() {
+            visitor(child);
+        }
+    }
+}
+
+/// A root, which doesn't depend on the type of the objects, so it can be
+/// registered with the heap (see ManagedHeap::register_root).
+///
+/// # Safety
+/// visit has to call visitor with every object which is directly in use,
+/// otherwise the garbage collector will free objects which are still in use.
+pub unsafe trait DynGcRoot {
+    fn visit(&self, visitor: &mut dyn FnMut(Address));
+}
+
+/// Like GcRoot, but the children are only borrowed immutably, so a root can
+/// stay borrowed elsewhere during a collection (see ManagedHeap::gc_shared).
+///
+/// # Safety
+/// children has to return every object which is directly in use, otherwise
+/// the garbage collector will free objects which are still in use.
+pub unsafe trait GcRootRef<I>
 where
     I: Traceable + From<Address> + Into<Address>,
 {
-    fn children<'a>(&'a mut self) -> Box<Iterator<Item = &'a mut I> + 'a>;
+    fn children<'a>(&'a self) -> Box<dyn Iterator<Item = &'a I> + 'a>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::managed::ManagedHeap;
+    use crate::testing::*;
+
+    // only implements the iterator style
+    struct IteratorList(LinkedList);
+
+    unsafe impl Traceable for IteratorList {
+        fn mark(&mut self) {
+            self.0.mark();
+        }
+
+        fn unmark(&mut self) {
+            self.0.unmark();
+        }
+
+        fn trace<'a>(&'a mut self) -> Box<dyn Iterator<Item = &'a mut Address> + 'a> {
+            let next = unsafe { ((self.0).0 + 2).as_address_mut() };
+            Box::new(iter::once(next))
+        }
+
+        fn is_marked(&self) -> bool {
+            self.0.is_marked()
+        }
+    }
+
+    #[test]
+    fn test_default_trace_with_visits_trace() {
+        let mut heap = ManagedHeap::new(1000);
+        let list = list![&mut heap; 1, 2];
+
+        let mut visited = Vec::new();
+        IteratorList(list).trace_with(&mut |child| visited.push(*child));
+        assert_eq!(vec![list.next().unwrap().0], visited);
+    }
+
+    #[test]
+    fn test_visitor_rewrites_next_pointers() {
+        let mut heap = ManagedHeap::new(1000);
+        let list = list![&mut heap; 1, 2, 3];
+
+        // move every node into a new block, like a compaction would
+        let mut forwards = Vec::new();
+        for node in list.iter().collect::<Vec<_>>() {
+            let mut new = heap.alloc(3).unwrap();
+            node.0.copy_to(&mut new, 3);
+            forwards.push((node.0, new));
+        }
+
+        for &(old, new) in forwards.iter() {
+            LinkedList(new).trace_with(&mut |next| {
+                let (_, moved) = forwards.iter().find(|(o, _)| o == next).unwrap();
+                *next = *moved;
+            });
+            heap.free(old);
+        }
+
+        let moved = LinkedList(forwards[0].1);
+        assert_eq!("[1, 2, 3]", format!("{:?}", moved));
+        assert_eq!(3, heap.num_used_blocks());
+    }
 }
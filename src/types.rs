@@ -2,8 +2,6 @@ use std::mem;
 
 #[cfg(target_pointer_width = "64")]
 mod inner {
-    pub use std::u32;
-
     pub const HALF_WORD_MAX: u32 = u32::MAX;
 
     pub type HalfWord = u32;
@@ -13,8 +11,6 @@ mod inner {
 
 #[cfg(target_pointer_width = "32")]
 mod inner {
-    pub use std::u16;
-
     pub const HALF_WORD_MAX: u16 = u16::MAX;
 
     pub type HalfWord = u16;
@@ -25,3 +21,43 @@ mod inner {
 pub use self::inner::*;
 
 pub const WORD_SIZE: usize = mem::size_of::<usize>();
+
+/// The number of words needed to store a 64 bit value
+pub const U64_WORDS: usize = mem::size_of::<u64>() / WORD_SIZE;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_half_word_is_half_a_word() {
+        assert_eq!(WORD_SIZE, 2 * mem::size_of::<HalfWord>());
+        assert_eq!(WORD_SIZE, mem::size_of::<Word>());
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_u64_fits_in_one_word() {
+        assert_eq!(1, U64_WORDS);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "32")]
+    fn test_u64_needs_two_words() {
+        assert_eq!(2, U64_WORDS);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_half_word_max_64_bit() {
+        assert_eq!(4_294_967_295, HALF_WORD_MAX);
+        assert_eq!(None, HALF_WORD_MAX.checked_add(1));
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "32")]
+    fn test_half_word_max_32_bit() {
+        assert_eq!(65_535, HALF_WORD_MAX);
+        assert_eq!(None, HALF_WORD_MAX.checked_add(1));
+    }
+}
@@ -0,0 +1,185 @@
+use crate::address::Address;
+use crate::managed::ManagedHeap;
+
+use std::collections::HashMap;
+
+/// Gets called with the key and value of every entry, which is removed from
+/// a WeakTable because its key was freed
+pub type WeakDropCallback = Box<dyn FnMut(Address, usize) + Send>;
+
+/// A map from objects to words, which doesn't keep its keys alive.
+/// The table of a heap (see ManagedHeap::weak_table_mut) loses an entry as
+/// soon as its key is freed, either by a collection or by free, so a new
+/// object at the same address doesn't inherit it. Entries move with their
+/// objects when the heap is compacted or migrated.
+#[derive(Default)]
+pub struct WeakTable {
+    entries: HashMap<usize, usize>,
+    on_drop: Option<WeakDropCallback>,
+}
+
+impl WeakTable {
+    /// Associates value with the object at key and returns the previous
+    /// value. key has to be the start of an object, other addresses are
+    /// never freed, so their entries stay until they are removed.
+    pub fn insert(&mut self, key: Address, value: usize) -> Option<usize> {
+        self.entries.insert(key.into(), value)
+    }
+
+    pub fn get(&self, key: Address) -> Option<usize> {
+        self.entries.get(&key.into()).copied()
+    }
+
+    /// Removes the entry of key without calling the drop callback
+    pub fn remove(&mut self, key: Address) -> Option<usize> {
+        self.entries.remove(&key.into())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// All entries in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (Address, usize)> + '_ {
+        self.entries
+            .iter()
+            .map(|(&key, &value)| (Address::from(key), value))
+    }
+
+    /// Calls callback for every entry, which is removed because its key was
+    /// freed. The callback must not access the heap.
+    pub fn set_on_drop(&mut self, callback: WeakDropCallback) {
+        self.on_drop = Some(callback);
+    }
+
+    pub fn take_on_drop(&mut self) -> Option<WeakDropCallback> {
+        self.on_drop.take()
+    }
+
+    /// Removes the entry of an object, which is freed
+    pub(crate) fn prune(&mut self, key: Address) {
+        // most objects have no entry, so this must stay cheap
+        if self.entries.is_empty() {
+            return;
+        }
+
+        if let Some(value) = self.entries.remove(&key.into()) {
+            if let Some(on_drop) = self.on_drop.as_mut() {
+                on_drop(key, value);
+            }
+        }
+    }
+
+    /// Moves the entry of an object, which was copied to new
+    pub(crate) fn relocate(&mut self, old: Address, new: Address) {
+        if let Some(value) = self.entries.remove(&old.into()) {
+            self.entries.insert(new.into(), value);
+        }
+    }
+}
+
+impl ManagedHeap {
+    /// The weak table of the heap
+    pub fn weak_table(&self) -> &WeakTable {
+        &self.weak_table
+    }
+
+    pub fn weak_table_mut(&mut self) -> &mut WeakTable {
+        &mut self.weak_table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::trace::GcRoot;
+
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_entries_vanish_with_their_keys() {
+        let mut heap = ManagedHeap::new(1000);
+        let live = list![&mut heap; 1];
+        let garbage = list![&mut heap; 2];
+        heap.weak_table_mut().insert(live.0, 10);
+        heap.weak_table_mut().insert(garbage.0, 20);
+
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&dropped);
+        heap.weak_table_mut()
+            .set_on_drop(Box::new(move |key, value| {
+                log.lock().unwrap().push((key, value))
+            }));
+
+        let mut root = MockGcRoot::new(vec![live]);
+        for _ in 0..2 {
+            let mut roots: Vec<&mut dyn GcRoot<LinkedList>> = vec![&mut root];
+            heap.gc(&mut roots[..]);
+        }
+
+        assert_eq!(Some(10), heap.weak_table().get(live.0));
+        assert_eq!(None, heap.weak_table().get(garbage.0));
+        assert_eq!(1, heap.weak_table().len());
+        assert_eq!(vec![(garbage.0, 20)], *dropped.lock().unwrap());
+
+        heap.free(live.0);
+        assert!(heap.weak_table().is_empty());
+        assert_eq!(2, dropped.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_entries_are_pruned_before_the_sweep_callbacks() {
+        let mut heap = ManagedHeap::new(1000);
+        let garbage = [heap.alloc(2).unwrap(), heap.alloc(2).unwrap()];
+        for (i, &key) in garbage.iter().enumerate() {
+            heap.weak_table_mut().insert(key, i);
+        }
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let dropped = Arc::clone(&log);
+        heap.weak_table_mut().set_on_drop(Box::new(move |key, _| {
+            dropped.lock().unwrap().push(("drop", key))
+        }));
+        let swept = Arc::clone(&log);
+        heap.set_on_sweep(Box::new(move |address, _| {
+            swept.lock().unwrap().push(("sweep", address))
+        }));
+
+        heap.gc_addresses(&[], |_, _| {});
+        let expected = vec![
+            ("drop", garbage[0]),
+            ("drop", garbage[1]),
+            ("sweep", garbage[0]),
+            ("sweep", garbage[1]),
+        ];
+        assert_eq!(expected, *log.lock().unwrap());
+    }
+
+    #[test]
+    fn test_reused_address_has_no_entry() {
+        let mut heap = ManagedHeap::new(1000);
+        let old = heap.alloc(2).unwrap();
+        heap.weak_table_mut().insert(old, 7);
+
+        heap.free(old);
+        let new = heap.alloc(2).unwrap();
+        assert_eq!(old, new);
+        assert_eq!(None, heap.weak_table().get(new));
+    }
+
+    #[test]
+    fn test_entries_move_with_relocated_objects() {
+        let mut heap = ManagedHeap::new(1000);
+        let object = heap.alloc(2).unwrap();
+        heap.weak_table_mut().insert(object, 3);
+
+        let relocation = heap.relocate(object, None).unwrap();
+        assert_eq!(None, heap.weak_table().get(object));
+        assert_eq!(Some(3), heap.weak_table().get(relocation.new));
+    }
+}
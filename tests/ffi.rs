@@ -0,0 +1,136 @@
+#![cfg(feature = "ffi")]
+
+use managed_heap::ffi::*;
+
+use std::os::raw::c_void;
+use std::ptr;
+
+/// Objects are [next or 0, value], userdata counts the traced objects
+extern "C" fn trace_list(
+    address: usize,
+    mark: ManagedHeapMarkFn,
+    context: *mut c_void,
+    userdata: *mut c_void,
+) {
+    unsafe {
+        *(userdata as *mut usize) += 1;
+        let next = *(address as *const usize);
+        if next != 0 {
+            mark(context, next);
+        }
+    }
+}
+
+#[test]
+fn alloc_read_write_and_free() {
+    unsafe {
+        let heap = managed_heap_new(1024);
+        assert!(!heap.is_null());
+
+        let object = managed_heap_alloc(heap, 2);
+        assert_ne!(0, object);
+        assert_eq!(MANAGED_HEAP_OK, managed_heap_write(heap, object, 1, 42));
+
+        let mut value = 0;
+        assert_eq!(
+            MANAGED_HEAP_OK,
+            managed_heap_read(heap, object, 1, &mut value)
+        );
+        assert_eq!(42, value);
+
+        assert_eq!(
+            MANAGED_HEAP_INVALID_ADDRESS,
+            managed_heap_read(heap, object, 2, &mut value)
+        );
+        assert_eq!(
+            MANAGED_HEAP_INVALID_ADDRESS,
+            managed_heap_write(heap, object, usize::MAX, 1)
+        );
+        assert_eq!(
+            MANAGED_HEAP_INVALID_ADDRESS,
+            managed_heap_free(heap, object + 8)
+        );
+
+        assert_eq!(MANAGED_HEAP_OK, managed_heap_free(heap, object));
+        assert_eq!(
+            MANAGED_HEAP_INVALID_ADDRESS,
+            managed_heap_free(heap, object)
+        );
+        assert_eq!(0, managed_heap_alloc(heap, usize::MAX));
+
+        managed_heap_destroy(heap);
+    }
+}
+
+#[test]
+fn null_arguments_are_rejected() {
+    unsafe {
+        assert!(managed_heap_new(0).is_null());
+        assert_eq!(0, managed_heap_alloc(ptr::null_mut(), 2));
+        assert_eq!(MANAGED_HEAP_NULL, managed_heap_free(ptr::null_mut(), 8));
+        assert_eq!(
+            MANAGED_HEAP_NULL,
+            managed_heap_write(ptr::null_mut(), 8, 0, 0)
+        );
+
+        let heap = managed_heap_new(1024);
+        let object = managed_heap_alloc(heap, 2);
+        assert_eq!(
+            MANAGED_HEAP_NULL,
+            managed_heap_read(heap, object, 0, ptr::null_mut())
+        );
+        assert_eq!(
+            MANAGED_HEAP_NULL,
+            managed_heap_gc_addresses(heap, ptr::null(), 1, None, ptr::null_mut(), ptr::null_mut())
+        );
+
+        managed_heap_destroy(heap);
+        managed_heap_destroy(ptr::null_mut());
+    }
+}
+
+#[test]
+fn gc_keeps_objects_reachable_through_trace() {
+    unsafe {
+        let heap = managed_heap_new(1024);
+        let mut next = 0;
+        for value in 0..3 {
+            let node = managed_heap_alloc(heap, 2);
+            managed_heap_write(heap, node, 0, next);
+            managed_heap_write(heap, node, 1, value);
+            next = node;
+        }
+        let garbage = managed_heap_alloc(heap, 2);
+        managed_heap_write(heap, garbage, 0, 0);
+
+        let roots = [next];
+        let mut traced = 0usize;
+        let mut freed = 0;
+        let status = managed_heap_gc_addresses(
+            heap,
+            roots.as_ptr(),
+            roots.len(),
+            Some(trace_list),
+            &mut traced as *mut usize as *mut c_void,
+            &mut freed,
+        );
+
+        assert_eq!(MANAGED_HEAP_OK, status);
+        assert_eq!(1, freed);
+        assert_eq!(3, traced);
+
+        let mut value = 0;
+        assert_eq!(
+            MANAGED_HEAP_OK,
+            managed_heap_read(heap, next, 1, &mut value)
+        );
+        assert_eq!(2, value);
+
+        let status =
+            managed_heap_gc_addresses(heap, ptr::null(), 0, None, ptr::null_mut(), &mut freed);
+        assert_eq!(MANAGED_HEAP_OK, status);
+        assert_eq!(3, freed);
+
+        managed_heap_destroy(heap);
+    }
+}
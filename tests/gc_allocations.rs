@@ -0,0 +1,159 @@
+//! Checks that collections in a steady state don't use the global allocator.
+
+use managed_heap::address::Address;
+use managed_heap::managed::ManagedHeap;
+use managed_heap::store::{BlockSet, BlockStore, SizeAddressIndex, SizeClassBins};
+use managed_heap::trace::{GcRoot, Leaf};
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// A heap of size bytes for each of the block stores
+fn heaps(size: usize) -> Vec<ManagedHeap> {
+    let stores: Vec<Box<dyn BlockStore>> = vec![
+        Box::new(SizeAddressIndex::default()),
+        Box::new(SizeClassBins::default()),
+        Box::new(BlockSet::default()),
+    ];
+    stores
+        .into_iter()
+        .map(|store| ManagedHeap::with_block_store(size, store))
+        .collect()
+}
+
+fn allocations_during<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+struct Integer(Address);
+
+impl From<Address> for Integer {
+    fn from(address: Address) -> Self {
+        Integer(address)
+    }
+}
+
+impl From<Integer> for Address {
+    fn from(integer: Integer) -> Address {
+        integer.0
+    }
+}
+
+unsafe impl Leaf for Integer {
+    fn address(&self) -> Address {
+        self.0
+    }
+}
+
+/// Allocates a chain of objects, where the first word points to the next one
+fn alloc_chain(heap: &mut ManagedHeap, len: usize) -> Address {
+    let mut next = 0;
+    for _ in 0..len {
+        let mut address = heap.alloc(2).unwrap();
+        address.write(next);
+        next = address.into();
+    }
+    Address::from(next)
+}
+
+fn trace_chain(address: Address, children: &mut dyn FnMut(Address)) {
+    if *address != 0 {
+        children(Address::from(*address));
+    }
+}
+
+#[test]
+fn steady_state_gc_addresses_does_not_allocate() {
+    for mut heap in heaps(64 * 1024) {
+        let live = alloc_chain(&mut heap, 100);
+
+        for cycle in 0..5 {
+            alloc_chain(&mut heap, 200);
+
+            let allocations = allocations_during(|| {
+                let stats = heap.gc_addresses(&[live], trace_chain);
+                assert_eq!(200, stats.freed_blocks);
+                assert_eq!(100, stats.live_blocks);
+            });
+
+            // the first cycles may grow the internal buffers
+            if cycle > 1 {
+                assert_eq!(0, allocations);
+            }
+        }
+    }
+}
+
+/// A root holding its objects in a vector
+struct Integers(Vec<Integer>);
+
+unsafe impl GcRoot<Integer> for Integers {
+    fn children<'a>(&'a mut self) -> Box<dyn Iterator<Item = &'a mut Integer> + 'a> {
+        Box::new(self.0.iter_mut())
+    }
+
+    fn visit_children(&mut self, visitor: &mut dyn FnMut(&mut Integer)) {
+        self.0.iter_mut().for_each(visitor);
+    }
+}
+
+fn alloc_integers(heap: &mut ManagedHeap, len: usize) -> Vec<Integer> {
+    (0..len)
+        .map(|_| {
+            let mut address = heap.alloc(2).unwrap();
+            address.write(false as usize);
+            Integer(address)
+        })
+        .collect()
+}
+
+#[test]
+fn steady_state_gc_does_not_allocate() {
+    for mut heap in heaps(64 * 1024) {
+        let mut globals = Integers(alloc_integers(&mut heap, 50));
+        let mut stack = Integers(alloc_integers(&mut heap, 50));
+
+        for cycle in 0..5 {
+            alloc_integers(&mut heap, 200);
+
+            let allocations = allocations_during(|| {
+                let roots: &mut [&mut dyn GcRoot<Integer>] = &mut [&mut globals, &mut stack];
+                let stats = heap.gc(roots);
+                assert_eq!(200, stats.freed_blocks);
+                assert_eq!(100, stats.live_blocks);
+            });
+
+            // the roots are visited without boxing an iterator
+            if cycle > 1 {
+                assert_eq!(0, allocations);
+            }
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use managed_heap::address::Address;
+use managed_heap::raw::RawHeap;
+
+#[test]
+fn alloc_and_free_through_public_api() {
+    let mut heap = RawHeap::new(1024).unwrap();
+    let size = heap.size();
+
+    let mut addresses: Vec<Address> = (0..4).map(|_| heap.alloc(3).unwrap()).collect();
+    for (i, address) in addresses.iter_mut().enumerate() {
+        address.write(i);
+    }
+
+    assert_eq!(4, heap.num_used_blocks());
+    assert_eq!(16, heap.used_size());
+
+    let mut used: Vec<Address> = heap.used().collect();
+    used.sort();
+    let mut expected = addresses.clone();
+    expected.sort();
+    assert_eq!(expected, used);
+
+    assert!(heap.free(addresses[1]));
+    assert!(heap.free(addresses[2]));
+    assert_eq!(2, heap.num_used_blocks());
+    assert_eq!(0, *addresses[0]);
+    assert_eq!(3, *addresses[3]);
+
+    for address in [addresses[0], addresses[3]] {
+        assert!(heap.free(address));
+    }
+
+    assert_eq!(0, heap.used_size());
+    assert_eq!(1, heap.num_free_blocks());
+    assert_eq!(size, heap.size());
+    assert!(heap.verify().is_ok());
+}
+
+#[test]
+fn alloc_fails_when_heap_is_full() {
+    let mut heap = RawHeap::new(16 * std::mem::size_of::<usize>()).unwrap();
+
+    assert!(heap.alloc(16).is_none());
+    let a = heap.alloc(15).unwrap();
+    assert!(heap.alloc(0).is_none());
+    assert!(heap.free(a));
+    assert!(heap.alloc(15).is_some());
+}